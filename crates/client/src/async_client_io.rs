@@ -0,0 +1,109 @@
+//! The Async Client Io Interface
+//!
+//! This module defines the `AsyncClientIo` trait, an extension of the
+//! `ClientIo` trait for transports that can notify a task when they are
+//! ready to make progress, rather than needing to be polled periodically.
+//!
+//! The futures provided here do not depend on any specific executor. They
+//! only rely on the `Waker` provided by whatever executor is polling them,
+//! so they may be used with embassy on an embedded target, or with any
+//! async runtime on a host.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::client_io::{ClientIo, ClientIoError};
+use anachro_icd::{arbitrator::Arbitrator, component::Component};
+
+/// A trait for defining an async IO layer for a given client
+pub trait AsyncClientIo: ClientIo {
+    /// Poll whether a message is ready to be received.
+    ///
+    /// Returns `Poll::Ready` once a call to `ClientIo::recv` is expected to
+    /// make progress. Otherwise, the waker in `cx` is registered, and will be
+    /// woken when the transport should be polled again.
+    fn poll_recv_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ClientIoError>>;
+
+    /// Poll whether a message can be sent.
+    ///
+    /// Returns `Poll::Ready` once a call to `ClientIo::send` is expected to
+    /// make progress. Otherwise, the waker in `cx` is registered, and will be
+    /// woken when the transport should be polled again.
+    fn poll_send_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ClientIoError>>;
+
+    /// Receive one message FROM the Arbitrator/Broker, TO the Client
+    ///
+    /// This has the same semantics as `ClientIo::recv`, but waits until
+    /// the transport is ready before attempting to receive.
+    fn recv_async(&mut self) -> RecvFuture<'_, Self>
+    where
+        Self: Sized,
+    {
+        RecvFuture { io: Some(self) }
+    }
+
+    /// Send one message TO the Arbitrator/Broker, FROM the Client
+    ///
+    /// This has the same semantics as `ClientIo::send`, but waits until
+    /// the transport is ready before attempting to send.
+    fn send_async<'a>(&'a mut self, msg: &'a Component<'a>) -> SendFuture<'a, Self>
+    where
+        Self: Sized,
+    {
+        SendFuture { io: self, msg }
+    }
+}
+
+/// A future returned by `AsyncClientIo::recv_async`
+pub struct RecvFuture<'a, C: AsyncClientIo> {
+    io: Option<&'a mut C>,
+}
+
+impl<'a, C: AsyncClientIo> Future for RecvFuture<'a, C> {
+    type Output = Result<Option<Arbitrator<'a>>, ClientIoError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let io = self
+            .io
+            .as_mut()
+            .expect("RecvFuture polled after completion");
+
+        match io.poll_recv_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => {
+                self.io = None;
+                return Poll::Ready(Err(e));
+            }
+            Poll::Ready(Ok(())) => {}
+        }
+
+        // NOTE: The message borrows the io for the lifetime of this future,
+        // so the io is given up here, and the future is complete.
+        let io = self.io.take().unwrap();
+        Poll::Ready(ClientIo::recv(io))
+    }
+}
+
+/// A future returned by `AsyncClientIo::send_async`
+pub struct SendFuture<'a, C: AsyncClientIo> {
+    io: &'a mut C,
+    msg: &'a Component<'a>,
+}
+
+impl<'a, C: AsyncClientIo> Future for SendFuture<'a, C> {
+    type Output = Result<(), ClientIoError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.io.poll_send_ready(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Ready(Ok(())) => {
+                let this = &mut *self;
+                Poll::Ready(ClientIo::send(this.io, this.msg))
+            }
+        }
+    }
+}
@@ -0,0 +1,569 @@
+//! The Bridge interface
+//!
+//! A `Bridge` joins two Brokers, such as a host Broker used for simulation
+//! and a firmware Arbitrator. It connects to each of them as a special
+//! client, and forwards messages published on configured paths to the other
+//! side.
+//!
+//! Each side of the bridge is identified by a `Uuid`. Messages forwarded by
+//! a bridge are tagged with the side they were originally published on,
+//! and are never forwarded back to that side, so bridges may be connected
+//! in loops without messages circulating forever.
+//!
+//! Shortcodes are registered separately with each Broker, for every path
+//! that does not contain wildcards, and are rewritten when forwarding.
+
+use {
+    crate::{client::PUBLISH_SHORTCODE_OFFSET, client_io::ClientIo, Error},
+    anachro_icd::{
+        self,
+        arbitrator::{
+            Arbitrator, Control as AControl, ControlResponse, PubSubError, PubSubResponse,
+        },
+        auth::auth_mac,
+        component::{
+            Component, ComponentInfo, Control as CControl, ControlType, PubSub, PubSubShort,
+            PubSubType,
+        },
+        Name, Path, PubSubPath, Uuid, Version,
+    },
+};
+
+/// The configuration of one side of a `Bridge`
+#[derive(Debug, Clone, Copy)]
+pub struct BridgeSide {
+    /// Identifies the Broker on this side
+    ///
+    /// This must be unique among all Brokers joined by bridges, and the
+    /// same for every bridge connected to this Broker.
+    pub id: Uuid,
+
+    /// The paths forwarded FROM this side, TO the other side
+    ///
+    /// These may contain wildcards.
+    pub forward: &'static [&'static str],
+
+    /// The pre-shared key used to authenticate with the Broker on this
+    /// side, if it requires one
+    pub auth_key: Option<&'static [u8]>,
+}
+
+#[derive(Debug, PartialEq)]
+enum SideState {
+    Disconnected,
+    PendingRegistration,
+
+    /// Subscribing and registering shortcodes, one step at a time
+    Setup,
+
+    Active,
+}
+
+/// A single subscription or shortcode registration made with a Broker
+#[derive(Clone, Copy)]
+enum Step {
+    Sub(&'static str),
+    Short(&'static str, u16),
+}
+
+struct Side {
+    config: BridgeSide,
+    state: SideState,
+    ctr: u16,
+    current_tick: u8,
+    current_step: usize,
+}
+
+/// A bridge between two Brokers
+///
+/// The bridge does not own the connections to each Broker, they are
+/// passed to each call of `Bridge::process_one()`, along with which side
+/// of the bridge they connect to.
+pub struct Bridge {
+    name: Name<'static>,
+    version: Version,
+    timeout_ticks: Option<u8>,
+    sides: [Side; 2],
+}
+
+impl Bridge {
+    /// Create a new bridge between the Brokers `a` and `b`
+    ///
+    /// The bridge registers with each Broker using `name` and `version`,
+    /// and each side's control counter starts at `ctr_init`.
+    ///
+    /// `timeout_ticks` behaves the same as for `Client::new()`, with ticks
+    /// counted by calls to `Bridge::process_one()`.
+    ///
+    /// Returns `Error::NameTooLong` if `name` is longer than a `Name` can
+    /// hold.
+    pub fn new(
+        name: &str,
+        version: Version,
+        ctr_init: u16,
+        a: BridgeSide,
+        b: BridgeSide,
+        timeout_ticks: Option<u8>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            name: Name::try_from_str(name).map_err(|_| Error::NameTooLong)?,
+            version,
+            timeout_ticks,
+            sides: [Side::new(a, ctr_init), Side::new(b, ctr_init)],
+        })
+    }
+
+    /// Reset the connections to both Brokers
+    pub fn reset_connection(&mut self) {
+        defmt::error!("Resetting Bridge Connections.");
+        for side in self.sides.iter_mut() {
+            side.reset();
+        }
+    }
+
+    /// Is the bridge connected to both Brokers?
+    ///
+    /// Messages are only forwarded while both sides are connected.
+    pub fn is_connected(&self) -> bool {
+        self.sides.iter().all(|s| s.state == SideState::Active)
+    }
+
+    /// Process a single incoming message from each Broker
+    ///
+    /// `a` and `b` are the connections to the Brokers given as `a` and `b`
+    /// when creating the bridge. This function *must* be called regularly,
+    /// in the same way as `Client::process_one()`.
+    ///
+    /// If either side fails, the error is returned once both sides have
+    /// been processed.
+    pub fn process_one<A: ClientIo, B: ClientIo>(
+        &mut self,
+        a: &mut A,
+        b: &mut B,
+    ) -> Result<(), Error> {
+        let Bridge {
+            name,
+            version,
+            timeout_ticks,
+            sides: [side_a, side_b],
+        } = self;
+        let info = ComponentInfo {
+            name: name.as_borrowed(),
+            version: *version,
+        };
+
+        let res_a = side_a.process(&info, *timeout_ticks, a, side_b, b);
+        let res_b = side_b.process(&info, *timeout_ticks, b, side_a, a);
+        res_a.and(res_b)
+    }
+}
+
+// Private interfaces for each side of the bridge
+impl Side {
+    fn new(config: BridgeSide, ctr_init: u16) -> Self {
+        Side {
+            config,
+            state: SideState::Disconnected,
+            ctr: ctr_init,
+            current_tick: 0,
+            current_step: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = SideState::Disconnected;
+        self.current_tick = 0;
+        self.current_step = 0;
+    }
+
+    fn timeout_violated(&self, timeout_ticks: Option<u8>) -> bool {
+        match timeout_ticks {
+            Some(ticks) => ticks <= self.current_tick,
+            None => false,
+        }
+    }
+
+    /// Process one message from this side's Broker, forwarding it to the
+    /// `other` side if necessary
+    fn process<C: ClientIo, O: ClientIo>(
+        &mut self,
+        info: &ComponentInfo,
+        timeout_ticks: Option<u8>,
+        cio: &mut C,
+        other: &Side,
+        other_cio: &mut O,
+    ) -> Result<(), Error> {
+        match self.state {
+            SideState::Disconnected => {
+                self.ctr = self.ctr.wrapping_add(1);
+
+                let msg = Component::Control(CControl {
+                    seq: self.ctr,
+                    ty: ControlType::RegisterBridge(ComponentInfo {
+                        name: info.name.as_borrowed(),
+                        version: info.version,
+                    }),
+                });
+
+                cio.send(&msg)?;
+
+                self.state = SideState::PendingRegistration;
+                self.current_tick = 0;
+            }
+            SideState::PendingRegistration => {
+                self.pending_registration(info, cio, other)?;
+
+                if self.timeout_violated(timeout_ticks) {
+                    defmt::warn!("Bridge registration timeout violated!");
+                    self.reset();
+                }
+            }
+            SideState::Setup => {
+                self.setup(cio, other)?;
+
+                if self.timeout_violated(timeout_ticks) {
+                    defmt::info!("Bridge setup timeout. Resending");
+                    self.send_step(cio, other)?;
+                }
+            }
+            SideState::Active => {
+                self.forward(cio, other, other_cio)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pending_registration<C: ClientIo>(
+        &mut self,
+        info: &ComponentInfo,
+        cio: &mut C,
+        other: &Side,
+    ) -> Result<(), Error> {
+        let response = match cio.recv()? {
+            Some(Arbitrator::Control(AControl { seq, response })) if seq == self.ctr => response,
+            _ => {
+                self.current_tick = self.current_tick.saturating_add(1);
+                return Ok(());
+            }
+        };
+
+        match response {
+            Ok(ControlResponse::ComponentRegistration(_)) => {
+                defmt::info!("Bridge registered!");
+                self.state = SideState::Setup;
+                self.current_step = 0;
+                self.skip_wildcard_steps(other);
+                self.send_step(cio, other)
+            }
+            Ok(ControlResponse::AuthChallenge(nonce)) => {
+                let key = self.config.auth_key.ok_or_else(|| {
+                    defmt::warn!("Authentication required, but no key is set");
+                    Error::UnexpectedMessage
+                })?;
+
+                self.ctr = self.ctr.wrapping_add(1);
+                let msg = Component::Control(CControl {
+                    seq: self.ctr,
+                    ty: ControlType::Authenticate {
+                        mac: auth_mac(key, &nonce, info.name.as_str()),
+                    },
+                });
+
+                cio.send(&msg)?;
+
+                self.current_tick = 0;
+                Ok(())
+            }
+            _ => {
+                self.current_tick = self.current_tick.saturating_add(1);
+                Err(Error::UnexpectedMessage)
+            }
+        }
+    }
+
+    /// Process messages while subscribing and registering shortcodes
+    fn setup<C: ClientIo>(&mut self, cio: &mut C, other: &Side) -> Result<(), Error> {
+        let msg = match cio.recv()? {
+            Some(msg) => msg,
+            None => {
+                self.current_tick = self.current_tick.saturating_add(1);
+                return Ok(());
+            }
+        };
+
+        let accepted = match (self.step(other), msg) {
+            (
+                Some(Step::Sub(sub)),
+                Arbitrator::PubSub(Ok(PubSubResponse::SubAck {
+                    path: PubSubPath::Long(pth),
+                })),
+            ) => pth.as_str() == sub,
+            (Some(Step::Sub(_)), Arbitrator::PubSub(Err(PubSubError::NotAuthorized))) => {
+                // Retrying would be denied again, so move on without it
+                defmt::warn!("Bridge subscription not authorized, skipping");
+                true
+            }
+            (
+                Some(Step::Short(_, short)),
+                Arbitrator::Control(AControl {
+                    seq,
+                    response: Ok(ControlResponse::PubSubShortRegistration(sid)),
+                }),
+            ) => seq == self.ctr && sid == short,
+            _ => false,
+        };
+
+        if !accepted {
+            self.current_tick = self.current_tick.saturating_add(1);
+            return Ok(());
+        }
+
+        self.current_step += 1;
+        self.skip_wildcard_steps(other);
+
+        if self.step(other).is_some() {
+            self.send_step(cio, other)
+        } else {
+            defmt::info!("Bridge side active!");
+            self.state = SideState::Active;
+            self.current_tick = 0;
+            Ok(())
+        }
+    }
+
+    /// The setup step at `self.current_step`, if any remain
+    ///
+    /// The bridge first subscribes to each path forwarded FROM this side,
+    /// then registers a subscribe shortcode for each of them, then a
+    /// publish shortcode for each path forwarded TO this side. Shortcodes
+    /// use the index of the path in its `BridgeSide::forward` list.
+    fn step(&self, other: &Side) -> Option<Step> {
+        let subs = self.config.forward;
+        let pubs = other.config.forward;
+        let idx = self.current_step;
+
+        if let Some(sub) = subs.get(idx) {
+            return Some(Step::Sub(sub));
+        }
+
+        let idx = idx - subs.len();
+        if let Some(sub) = subs.get(idx) {
+            return Some(Step::Short(sub, idx as u16));
+        }
+
+        let idx = idx - subs.len();
+        pubs.get(idx)
+            .map(|pb| Step::Short(pb, (idx as u16) | PUBLISH_SHORTCODE_OFFSET))
+    }
+
+    /// Advance past shortcodes for paths with wildcards, which the Broker
+    /// would reject
+    fn skip_wildcard_steps(&mut self, other: &Side) {
+        while let Some(Step::Short(path, _)) = self.step(other) {
+            if !is_wildcard(path) {
+                break;
+            }
+            self.current_step += 1;
+        }
+    }
+
+    fn send_step<C: ClientIo>(&mut self, cio: &mut C, other: &Side) -> Result<(), Error> {
+        let msg = match self.step(other) {
+            Some(Step::Sub(path)) => Component::PubSub(PubSub {
+                path: PubSubPath::Long(Path::borrow_from_str(path)),
+                ty: PubSubType::Sub,
+            }),
+            Some(Step::Short(long_name, short_id)) => {
+                self.ctr = self.ctr.wrapping_add(1);
+                Component::Control(CControl {
+                    seq: self.ctr,
+                    ty: ControlType::RegisterPubSubShortId(PubSubShort {
+                        long_name,
+                        short_id,
+                    }),
+                })
+            }
+            None => return Ok(()),
+        };
+
+        cio.send(&msg)?;
+
+        self.current_tick = 0;
+        Ok(())
+    }
+
+    /// Forward a message from this side to the other side, unless it
+    /// originated there
+    fn forward<C: ClientIo, O: ClientIo>(
+        &mut self,
+        cio: &mut C,
+        other: &Side,
+        other_cio: &mut O,
+    ) -> Result<(), Error> {
+        let msg = cio.recv()?;
+        let (sub_msg, origin) = match msg {
+            Some(Arbitrator::PubSub(Ok(PubSubResponse::BridgedSubMsg { ref msg, origin }))) => {
+                (msg, origin)
+            }
+            Some(Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(ref msg)))) => (msg, None),
+            _ => return Ok(()),
+        };
+
+        // Messages published on this side are tagged with its id
+        let origin = origin.unwrap_or(self.config.id);
+        if origin == other.config.id || other.state != SideState::Active {
+            return Ok(());
+        }
+
+        // Determine the path
+        let path = match &sub_msg.path {
+            PubSubPath::Short(sid) => *self
+                .config
+                .forward
+                .get(*sid as usize)
+                .ok_or(Error::UnexpectedMessage)?,
+            PubSubPath::Long(ms) => ms.as_str(),
+        };
+
+        // Only forward paths configured for this side
+        if !matches_any(self.config.forward, path) {
+            return Ok(());
+        }
+
+        // Use the shortcode registered with the other side, if any
+        let forward = self.config.forward;
+        let path = match forward.iter().position(|f| *f == path && !is_wildcard(f)) {
+            Some(idx) => PubSubPath::Short((idx as u16) | PUBLISH_SHORTCODE_OFFSET),
+            None => PubSubPath::Long(Path::borrow_from_str(path)),
+        };
+
+        let msg = Component::PubSub(PubSub {
+            path,
+            ty: PubSubType::BridgedPub {
+                payload: sub_msg.payload,
+                origin,
+            },
+        });
+
+        other_cio.send(&msg)?;
+
+        Ok(())
+    }
+}
+
+fn is_wildcard(path: &str) -> bool {
+    path.contains('#') || path.contains('+')
+}
+
+fn matches_any(patterns: &[&str], path: &str) -> bool {
+    patterns.iter().any(|pat| anachro_icd::matches(pat, path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{pump, Link};
+    use anachro_icd::arbitrator::SubMsg;
+    use anachro_server::Broker;
+
+    const VERSION: Version = Version {
+        major: 0,
+        minor: 1,
+        trivial: 0,
+        misc: 0,
+    };
+
+    fn send(link: &mut Link, path: &str, ty: PubSubType) {
+        let msg = Component::PubSub(PubSub {
+            path: PubSubPath::Long(Path::borrow_from_str(path)),
+            ty,
+        });
+        link.send(&msg).unwrap();
+    }
+
+    #[test]
+    fn invalid_name() {
+        let side = BridgeSide {
+            id: Uuid::from_bytes([0; 16]),
+            forward: &[],
+            auth_key: None,
+        };
+        let name = "a bridge with a name far too long to be registered";
+        let bridge = Bridge::new(name, VERSION, 0, side, side, None);
+        assert_eq!(bridge.err(), Some(Error::NameTooLong));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut broker_x = Broker::new();
+        let mut broker_y = Broker::new();
+        let mut bridge_x = Link::new(&mut broker_x, 1);
+        let mut client_x = Link::new(&mut broker_x, 2);
+        let mut bridge_y = Link::new(&mut broker_y, 3);
+        let mut client_y = Link::new(&mut broker_y, 4);
+
+        let side_x = BridgeSide {
+            id: Uuid::from_bytes([0xA0; 16]),
+            forward: &["both/#"],
+            auth_key: None,
+        };
+        let side_y = BridgeSide {
+            id: Uuid::from_bytes([0xB0; 16]),
+            forward: &["both/#"],
+            auth_key: None,
+        };
+        let mut bridge = Bridge::new("bridge", VERSION, 0, side_x, side_y, Some(10)).unwrap();
+
+        let mut step = |bridge: &mut Bridge, client_x: &mut Link, client_y: &mut Link| {
+            bridge.process_one(&mut bridge_x, &mut bridge_y).unwrap();
+            pump(&mut broker_x, &mut [&mut bridge_x, client_x]);
+            pump(&mut broker_y, &mut [&mut bridge_y, client_y]);
+        };
+
+        for _ in 0..10 {
+            step(&mut bridge, &mut client_x, &mut client_y);
+        }
+        assert!(bridge.is_connected());
+
+        for client in [&mut client_x, &mut client_y].iter_mut() {
+            client
+                .send(&Component::Control(CControl {
+                    seq: 0,
+                    ty: ControlType::RegisterComponent(ComponentInfo {
+                        name: Name::borrow_from_str("client"),
+                        version: VERSION,
+                    }),
+                }))
+                .unwrap();
+            send(client, "both/t", PubSubType::Sub);
+        }
+        step(&mut bridge, &mut client_x, &mut client_y);
+        client_x.clear();
+        client_y.clear();
+
+        send(
+            &mut client_x,
+            "both/t",
+            PubSubType::Pub {
+                payload: &[1, 2, 3],
+            },
+        );
+        for _ in 0..4 {
+            step(&mut bridge, &mut client_x, &mut client_y);
+        }
+
+        // Delivered to the other side exactly once
+        match client_y.recv().unwrap() {
+            Some(Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(SubMsg { path, payload })))) => {
+                assert_eq!(path, PubSubPath::Long(Path::borrow_from_str("both/t")));
+                assert_eq!(payload, &[1, 2, 3]);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+        assert!(client_y.recv().unwrap().is_none());
+
+        // And never echoed back to where it was published
+        assert!(client_x.recv().unwrap().is_none());
+    }
+}
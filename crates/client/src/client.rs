@@ -7,21 +7,29 @@ use {
     crate::{client_io::ClientIo, table::Table, Error, RecvMsg},
     anachro_icd::{
         self,
-        arbitrator::{Arbitrator, Control as AControl, ControlResponse, PubSubResponse},
+        arbitrator::{
+            Arbitrator, Control as AControl, ControlError, ControlResponse, PubSubError,
+            PubSubResponse, SubMsg,
+        },
+        auth::auth_mac,
         component::{
-            Component, ComponentInfo, Control as CControl, ControlType, PubSub, PubSubShort,
-            PubSubType,
+            Component, ComponentInfo, Control as CControl, ControlType, PubSub, PubSubType,
         },
+        schema::SchemaHash,
         ManagedString, Name, Path, PubSubPath, Uuid, Version,
     },
+    heapless::{consts, Vec},
 };
 
 /// The shortcode offset used for Publish topics
 ///
-/// For now, I have defined `0x0000..=0x7FFF` as the range used
-/// for subscription topic shortcodes, and `0x8000..=0xFFFF` as
-/// the range used for publish topic shortcodes. This is an
-/// implementation detail, and should not be relied upon.
+/// For clients that choose their own shortcodes, such as a `Bridge`,
+/// `0x0000..=0x7FFF` is the range used for subscription topic
+/// shortcodes, and `0x8000..=0xFFFF` is the range used for publish
+/// topic shortcodes. This is an implementation detail, and should not
+/// be relied upon.
+///
+/// The `Client` uses shortcodes assigned by the broker instead.
 pub const PUBLISH_SHORTCODE_OFFSET: u16 = 0x8000;
 
 #[derive(Debug)]
@@ -29,9 +37,9 @@ enum ClientState {
     Disconnected,
     PendingRegistration,
     Registered,
+    AssigningShortCodes,
     Subscribing,
     Subscribed,
-    ShortCodingSub,
     ShortCodingPub,
     Active,
 }
@@ -45,10 +53,28 @@ impl ClientState {
     }
 }
 
+/// A shortcode assigned by the broker
+#[derive(Debug)]
+struct Shortcode {
+    long: Path<'static>,
+    short: u16,
+}
+
+/// The schema hash of the payload type of a publish path
+#[derive(Debug)]
+struct PubSchema {
+    path: &'static str,
+    schema: SchemaHash,
+}
+
 /// The Client interface
 ///
 /// This is the primary interface used by clients. It is used to track
 /// the state of a connection, and process any incoming or outgoing messages
+///
+/// Shortcodes are assigned by the broker, for each path in `pub_short_paths`
+/// while connecting, and for the path of each message delivered to the
+/// client, including paths matched by wildcard subscriptions.
 pub struct Client {
     state: ClientState,
     // TODO: This should probably just be a &'static str
@@ -58,9 +84,21 @@ pub struct Client {
     sub_paths: &'static [&'static str],
     pub_short_paths: &'static [&'static str],
     timeout_ticks: Option<u8>,
+    auth_key: Option<&'static [u8]>,
     uuid: Uuid,
     current_tick: u8,
     current_idx: usize,
+
+    /// The number of times the current subscription has been resent
+    sub_resends: u8,
+
+    /// Errors still expected in answer to resent subscriptions that have
+    /// already been answered. These are ignored, rather than being taken
+    /// as the answer to a later subscription.
+    stale_sub_errors: u8,
+
+    shortcodes: Vec<Shortcode, consts::U8>,
+    pub_schemas: Vec<PubSchema, consts::U8>,
 }
 
 impl Client {
@@ -125,12 +163,25 @@ impl Client {
             sub_paths,
             pub_short_paths,
             timeout_ticks,
+            auth_key: None,
             uuid: Uuid::from_bytes([0u8; 16]),
             current_tick: 0,
             current_idx: 0,
+            sub_resends: 0,
+            stale_sub_errors: 0,
+            shortcodes: Vec::new(),
+            pub_schemas: Vec::new(),
         }
     }
 
+    /// Set the pre-shared key used to authenticate with the broker
+    ///
+    /// This is only necessary if the broker requires authentication. The
+    /// broker's challenge is answered automatically while registering.
+    pub fn set_auth_key(&mut self, key: &'static [u8]) {
+        self.auth_key = Some(key);
+    }
+
     /// Reset the client connection
     ///
     /// This immediately disconnects the client, at which point
@@ -180,6 +231,10 @@ impl Client {
     /// The serialized payload to publish. This is typically created by using
     /// the `Table::serialize()` method, which returns a path and the serialized
     /// payload
+    ///
+    /// Payloads published on a path in `pub_short_paths` are checked by the
+    /// broker against the type in the table, even if the path could not be
+    /// assigned a shortcode.
     pub fn publish<'a, 'b: 'a, C: ClientIo>(
         &'b self,
         cio: &mut C,
@@ -189,15 +244,24 @@ impl Client {
         defmt::info!("Publishing message.");
         self.state.as_active()?;
 
-        let path = match self.pub_short_paths.iter().position(|pth| &path == pth) {
-            Some(short) => PubSubPath::Short((short as u16) | PUBLISH_SHORTCODE_OFFSET),
-            None => PubSubPath::Long(ManagedString::Borrow(path)),
+        // The broker knows the type of a path with a shortcode
+        let msg = match self.shortcodes.iter().find(|sc| sc.long.as_str() == path) {
+            Some(sc) => PubSub {
+                path: PubSubPath::Short(sc.short),
+                ty: PubSubType::Pub { payload },
+            },
+            None => PubSub {
+                path: PubSubPath::Long(ManagedString::Borrow(path)),
+                ty: match self.pub_schemas.iter().find(|ps| ps.path == path) {
+                    Some(ps) => PubSubType::TypedPub {
+                        payload,
+                        schema: ps.schema,
+                    },
+                    None => PubSubType::Pub { payload },
+                },
+            },
         };
-
-        let msg = Component::PubSub(PubSub {
-            path,
-            ty: PubSubType::Pub { payload },
-        });
+        let msg = Component::PubSub(msg);
 
         cio.send(&msg)?;
 
@@ -253,18 +317,18 @@ impl Client {
             }
 
             // =====================================
-            // Subscribing
+            // Assigning ShortCodes
             // =====================================
-            ClientState::Subscribing => {
-                self.subscribing(cio)?;
+            ClientState::AssigningShortCodes => {
+                self.assigning_shortcodes::<C, T>(cio)?;
 
                 if self.timeout_violated() {
-                    defmt::info!("Sub timeout. Resending");
-                    let msg = Component::PubSub(PubSub {
-                        path: PubSubPath::Long(Path::borrow_from_str(
-                            self.sub_paths[self.current_idx],
-                        )),
-                        ty: PubSubType::Sub,
+                    defmt::info!("ASC timeout. Resending");
+                    self.ctr = self.ctr.wrapping_add(1);
+
+                    let msg = Component::Control(CControl {
+                        seq: self.ctr,
+                        ty: ControlType::AssignPubSubShortIds,
                     });
 
                     cio.send(&msg)?;
@@ -274,52 +338,37 @@ impl Client {
             }
 
             // =====================================
-            // Subscribed
-            // =====================================
-            ClientState::Subscribed => {
-                self.subscribed(cio)?;
-            }
-
-            // =====================================
-            // ShortCoding
+            // Subscribing
             // =====================================
-            ClientState::ShortCodingSub => {
-                self.shortcoding_sub(cio)?;
+            ClientState::Subscribing => {
+                self.subscribing::<C, T>(cio)?;
 
                 if self.timeout_violated() {
-                    defmt::info!("SCS timeout. Resending");
-                    self.ctr = self.ctr.wrapping_add(1);
-
-                    let msg = Component::Control(CControl {
-                        seq: self.ctr,
-                        ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                            long_name: self.sub_paths[self.current_idx],
-                            short_id: self.current_idx as u16,
-                        }),
-                    });
-
-                    cio.send(&msg)?;
+                    defmt::info!("Sub timeout. Resending");
+                    cio.send(&self.sub_msg::<T>(self.current_idx))?;
+                    self.sub_resends = self.sub_resends.saturating_add(1);
 
                     self.current_tick = 0;
                 }
             }
 
+            // =====================================
+            // Subscribed
+            // =====================================
+            ClientState::Subscribed => {
+                self.subscribed::<C, T>(cio)?;
+            }
+
+            // =====================================
+            // ShortCoding
+            // =====================================
             ClientState::ShortCodingPub => {
-                self.shortcoding_pub(cio)?;
+                self.shortcoding_pub::<C, T>(cio)?;
 
                 if self.timeout_violated() {
                     defmt::info!("SCP timeout. Resending");
                     self.ctr = self.ctr.wrapping_add(1);
-
-                    let msg = Component::Control(CControl {
-                        seq: self.ctr,
-                        ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                            long_name: self.pub_short_paths[self.current_idx],
-                            short_id: (self.current_idx as u16) | PUBLISH_SHORTCODE_OFFSET,
-                        }),
-                    });
-
-                    cio.send(&msg)?;
+                    cio.send(&self.short_id_request::<T>(self.current_idx))?;
 
                     self.current_tick = 0;
                 }
@@ -349,9 +398,40 @@ impl Client {
         }
     }
 
+    /// The message subscribing to the subscription path at `idx`, with the
+    /// schema hash of its type in `T`, if it has one
+    fn sub_msg<T: Table>(&self, idx: usize) -> Component<'static> {
+        let path = self.sub_paths[idx];
+        let ty = match T::schema_hash(path) {
+            Some(schema) => PubSubType::TypedSub { schema },
+            None => PubSubType::Sub,
+        };
+
+        Component::PubSub(PubSub {
+            path: PubSubPath::Long(Path::borrow_from_str(path)),
+            ty,
+        })
+    }
+
+    /// The message requesting a shortcode for the publish path at `idx`,
+    /// with the schema hash of its type in `T`, if it has one
+    fn short_id_request<T: Table>(&self, idx: usize) -> Component<'static> {
+        let long_name = self.pub_short_paths[idx];
+
+        Component::Control(CControl {
+            seq: self.ctr,
+            ty: ControlType::RequestPubSubShortId {
+                long_name,
+                schema: T::schema_hash(long_name),
+            },
+        })
+    }
+
     /// Process messages while in a `ClientState::Disconnected` state
     fn disconnected<C: ClientIo>(&mut self, cio: &mut C) -> Result<(), Error> {
         self.ctr += 1;
+        self.shortcodes.clear();
+        self.pub_schemas.clear();
 
         defmt::info!("Disconnected -> Pending Registration");
 
@@ -397,6 +477,30 @@ impl Client {
                 defmt::info!("Registered!");
                 self.uuid = uuid;
                 self.state = ClientState::Registered;
+                self.current_tick = 0;
+                Ok(())
+            } else if let Ok(ControlResponse::AuthChallenge(nonce)) = response {
+                let key = match self.auth_key {
+                    Some(key) => key,
+                    None => {
+                        defmt::warn!("Authentication required, but no key is set");
+                        self.current_tick = self.current_tick.saturating_add(1);
+                        return Err(Error::UnexpectedMessage);
+                    }
+                };
+
+                defmt::info!("Answering auth challenge");
+                self.ctr = self.ctr.wrapping_add(1);
+
+                let msg = Component::Control(CControl {
+                    seq: self.ctr,
+                    ty: ControlType::Authenticate {
+                        mac: auth_mac(key, &nonce, self.name.as_str()),
+                    },
+                });
+
+                cio.send(&msg)?;
+
                 self.current_tick = 0;
                 Ok(())
             } else {
@@ -415,29 +519,24 @@ impl Client {
 
     /// Process messages while in a `ClientState::Registered` state
     fn registered<C: ClientIo>(&mut self, cio: &mut C) -> Result<(), Error> {
-        if self.sub_paths.is_empty() {
-            defmt::info!("No subscriptions");
-            self.state = ClientState::Subscribed;
-            self.current_tick = 0;
-        } else {
-            defmt::info!("Start subscribing");
-            let msg = Component::PubSub(PubSub {
-                path: PubSubPath::Long(Path::borrow_from_str(self.sub_paths[0])),
-                ty: PubSubType::Sub,
-            });
+        defmt::info!("Requesting shortcode assignment");
+        self.ctr = self.ctr.wrapping_add(1);
 
-            cio.send(&msg)?;
+        let msg = Component::Control(CControl {
+            seq: self.ctr,
+            ty: ControlType::AssignPubSubShortIds,
+        });
 
-            self.state = ClientState::Subscribing;
-            self.current_idx = 0;
-            self.current_tick = 0;
-        }
+        cio.send(&msg)?;
+
+        self.state = ClientState::AssigningShortCodes;
+        self.current_tick = 0;
 
         Ok(())
     }
 
-    /// Process messages while in a `ClientState::Subscribing` state
-    fn subscribing<C: ClientIo>(&mut self, cio: &mut C) -> Result<(), Error> {
+    /// Process messages while in a `ClientState::AssigningShortCodes` state
+    fn assigning_shortcodes<C: ClientIo, T: Table>(&mut self, cio: &mut C) -> Result<(), Error> {
         let msg = cio.recv()?;
         let msg = match msg {
             Some(msg) => msg,
@@ -447,85 +546,42 @@ impl Client {
             }
         };
 
-        if let Arbitrator::PubSub(Ok(PubSubResponse::SubAck {
-            path: PubSubPath::Long(pth),
-        })) = msg
+        if let Arbitrator::Control(AControl {
+            seq,
+            response: Ok(ControlResponse::PubSubShortAssignmentEnabled),
+        }) = msg
         {
-            if pth.as_str() == self.sub_paths[self.current_idx] {
-                self.current_idx += 1;
-                if self.current_idx >= self.sub_paths.len() {
-                    self.state = ClientState::Subscribed;
-                    self.current_tick = 0;
-                } else {
-                    let msg = Component::PubSub(PubSub {
-                        path: PubSubPath::Long(Path::borrow_from_str(
-                            self.sub_paths[self.current_idx],
-                        )),
-                        ty: PubSubType::Sub,
-                    });
-
-                    cio.send(&msg)?;
-
-                    self.state = ClientState::Subscribing;
-                    self.current_tick = 0;
-                }
-            } else {
-                self.current_tick = self.current_tick.saturating_add(1);
+            if seq == self.ctr {
+                return self.start_subscribing::<C, T>(cio);
             }
-        } else {
-            self.current_tick = self.current_tick.saturating_add(1);
         }
 
+        self.current_tick = self.current_tick.saturating_add(1);
         Ok(())
     }
 
-    /// Process messages while in a `ClientState::Subscribed` state
-    fn subscribed<C: ClientIo>(&mut self, cio: &mut C) -> Result<(), Error> {
-        match (self.sub_paths.len(), self.pub_short_paths.len()) {
-            (0, 0) => {
-                self.state = ClientState::Active;
-                self.current_tick = 0;
-            }
-            (0, _n) => {
-                self.ctr = self.ctr.wrapping_add(1);
-                let msg = Component::Control(CControl {
-                    seq: self.ctr,
-                    ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                        long_name: self.pub_short_paths[0],
-                        short_id: PUBLISH_SHORTCODE_OFFSET,
-                    }),
-                });
-
-                cio.send(&msg)?;
-
-                self.state = ClientState::ShortCodingPub;
-                self.current_tick = 0;
-                self.current_idx = 0;
-            }
-            (_n, _) => {
-                // TODO: This doesn't handle the case when the subscribe shortcode is
-                // a wildcard, which the broker will reject
-                self.ctr = self.ctr.wrapping_add(1);
-                let msg = Component::Control(CControl {
-                    seq: self.ctr,
-                    ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                        long_name: self.sub_paths[0],
-                        short_id: 0x0000,
-                    }),
-                });
-
-                cio.send(&msg)?;
+    /// Subscribe to the first subscription path, if any
+    fn start_subscribing<C: ClientIo, T: Table>(&mut self, cio: &mut C) -> Result<(), Error> {
+        if self.sub_paths.is_empty() {
+            defmt::info!("No subscriptions");
+            self.state = ClientState::Subscribed;
+            self.current_tick = 0;
+        } else {
+            defmt::info!("Start subscribing");
+            cio.send(&self.sub_msg::<T>(0))?;
 
-                self.state = ClientState::ShortCodingSub;
-                self.current_tick = 0;
-                self.current_idx = 0;
-            }
+            self.state = ClientState::Subscribing;
+            self.current_idx = 0;
+            self.current_tick = 0;
+            self.sub_resends = 0;
+            self.stale_sub_errors = 0;
         }
+
         Ok(())
     }
 
-    /// Process messages while in a `ClientState::ShortcodingSub` state
-    fn shortcoding_sub<C: ClientIo>(&mut self, cio: &mut C) -> Result<(), Error> {
+    /// Process messages while in a `ClientState::Subscribing` state
+    fn subscribing<C: ClientIo, T: Table>(&mut self, cio: &mut C) -> Result<(), Error> {
         let msg = cio.recv()?;
         let msg = match msg {
             Some(msg) => msg,
@@ -535,53 +591,57 @@ impl Client {
             }
         };
 
-        if let Arbitrator::Control(AControl {
-            seq,
-            response: Ok(ControlResponse::PubSubShortRegistration(sid)),
-        }) = msg
-        {
-            if seq == self.ctr && sid == (self.current_idx as u16) {
-                self.current_idx += 1;
-
-                if self.current_idx >= self.sub_paths.len() {
-                    if self.pub_short_paths.is_empty() {
-                        self.state = ClientState::Active;
-                        self.current_tick = 0;
-                    } else {
-                        self.ctr = self.ctr.wrapping_add(1);
-
-                        let msg = Component::Control(CControl {
-                            seq: self.ctr,
-                            ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                                long_name: self.pub_short_paths[0],
-                                short_id: PUBLISH_SHORTCODE_OFFSET,
-                            }),
-                        });
-
-                        cio.send(&msg)?;
-
-                        self.current_tick = 0;
-                        self.current_idx = 0;
-                        self.state = ClientState::ShortCodingPub;
-                    }
-                } else {
-                    self.ctr = self.ctr.wrapping_add(1);
+        // Messages on earlier subscriptions may already be delivered, but
+        // the shortcodes assigned to them must not be missed
+        if record_shortcode(&mut self.shortcodes, &msg) {
+            return Ok(());
+        }
 
-                    // TODO: This doesn't handle subscriptions with wildcards
-                    let msg = Component::Control(CControl {
-                        seq: self.ctr,
-                        ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                            long_name: self.sub_paths[self.current_idx],
-                            short_id: self.current_idx as u16,
-                        }),
-                    });
+        // Errors do not name the path they are about, so one may be a late
+        // answer to a resent subscription that was already answered
+        let rejected = matches!(
+            msg,
+            Arbitrator::PubSub(Err(PubSubError::NotAuthorized))
+                | Arbitrator::PubSub(Err(PubSubError::SchemaMismatch { .. }))
+        );
+        if rejected && self.stale_sub_errors > 0 {
+            defmt::info!("Ignoring error for an earlier subscription");
+            self.stale_sub_errors -= 1;
+            return Ok(());
+        }
 
-                    cio.send(&msg)?;
+        let accepted = match msg {
+            Arbitrator::PubSub(Ok(PubSubResponse::SubAck {
+                path: PubSubPath::Long(pth),
+            })) => pth.as_str() == self.sub_paths[self.current_idx],
+            Arbitrator::PubSub(Err(PubSubError::NotAuthorized)) => {
+                // Retrying would be denied again, so move on without it
+                defmt::warn!("Subscription not authorized, skipping");
+                true
+            }
+            Arbitrator::PubSub(Err(PubSubError::SchemaMismatch { .. })) => {
+                // Another client uses a different type on this path
+                defmt::error!("Subscription schema mismatch, skipping");
+                true
+            }
+            _ => false,
+        };
 
-                    self.current_tick = 0;
-                }
+        if accepted {
+            if rejected {
+                // Each resend of this subscription will be rejected too
+                self.stale_sub_errors = self.stale_sub_errors.saturating_add(self.sub_resends);
+            }
+            self.sub_resends = 0;
+            self.current_idx += 1;
+            if self.current_idx >= self.sub_paths.len() {
+                self.state = ClientState::Subscribed;
+                self.current_tick = 0;
             } else {
-                self.current_tick = self.current_tick.saturating_add(1);
+                cio.send(&self.sub_msg::<T>(self.current_idx))?;
+
+                self.state = ClientState::Subscribing;
+                self.current_tick = 0;
             }
         } else {
             self.current_tick = self.current_tick.saturating_add(1);
@@ -590,8 +650,24 @@ impl Client {
         Ok(())
     }
 
+    /// Process messages while in a `ClientState::Subscribed` state
+    fn subscribed<C: ClientIo, T: Table>(&mut self, cio: &mut C) -> Result<(), Error> {
+        if self.pub_short_paths.is_empty() {
+            self.state = ClientState::Active;
+            self.current_tick = 0;
+        } else {
+            self.ctr = self.ctr.wrapping_add(1);
+            cio.send(&self.short_id_request::<T>(0))?;
+
+            self.state = ClientState::ShortCodingPub;
+            self.current_tick = 0;
+            self.current_idx = 0;
+        }
+        Ok(())
+    }
+
     /// Process messages while in a `ClientState::ShortcodingPub` state
-    fn shortcoding_pub<C: ClientIo>(&mut self, cio: &mut C) -> Result<(), Error> {
+    fn shortcoding_pub<C: ClientIo, T: Table>(&mut self, cio: &mut C) -> Result<(), Error> {
         let msg = cio.recv()?;
         let msg = match msg {
             Some(msg) => msg,
@@ -601,34 +677,46 @@ impl Client {
             }
         };
 
-        if let Arbitrator::Control(AControl {
-            seq,
-            response: Ok(ControlResponse::PubSubShortRegistration(sid)),
-        }) = msg
-        {
-            if seq == self.ctr && sid == ((self.current_idx as u16) | PUBLISH_SHORTCODE_OFFSET) {
-                self.current_idx += 1;
+        record_shortcode(&mut self.shortcodes, &msg);
 
-                if self.current_idx >= self.pub_short_paths.len() {
-                    self.state = ClientState::Active;
-                    self.current_tick = 0;
-                } else {
-                    self.ctr = self.ctr.wrapping_add(1);
+        let accepted = match msg {
+            Arbitrator::Control(AControl { seq, response }) if seq == self.ctr => match response {
+                Ok(ControlResponse::PubSubShortAssignment { long_name, .. }) => {
+                    long_name == self.pub_short_paths[self.current_idx]
+                }
+                Err(ControlError::NoWildcardsInShorts) | Err(ControlError::ShortIdsExhausted) => {
+                    // Messages on this path will be published with the long path
+                    defmt::warn!("Shortcode not assigned, skipping");
+                    true
+                }
+                Err(ControlError::SchemaMismatch { .. }) => {
+                    // Another client uses a different type on this path
+                    defmt::error!("Publish schema mismatch, skipping");
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        };
 
-                    let msg = Component::Control(CControl {
-                        seq: self.ctr,
-                        ty: ControlType::RegisterPubSubShortId(PubSubShort {
-                            long_name: self.pub_short_paths[self.current_idx],
-                            short_id: ((self.current_idx as u16) | PUBLISH_SHORTCODE_OFFSET),
-                        }),
-                    });
+        if accepted {
+            // Kept for publishing on the long path, if not assigned
+            let path = self.pub_short_paths[self.current_idx];
+            if let Some(schema) = T::schema_hash(path) {
+                // NOTE: Paths beyond the capacity are published untyped
+                self.pub_schemas.push(PubSchema { path, schema }).ok();
+            }
 
-                    cio.send(&msg)?;
+            self.current_idx += 1;
 
-                    self.current_tick = 0;
-                }
+            if self.current_idx >= self.pub_short_paths.len() {
+                self.state = ClientState::Active;
+                self.current_tick = 0;
             } else {
-                self.current_tick = self.current_tick.saturating_add(1);
+                self.ctr = self.ctr.wrapping_add(1);
+                cio.send(&self.short_id_request::<T>(self.current_idx))?;
+
+                self.current_tick = 0;
             }
         } else {
             self.current_tick = self.current_tick.saturating_add(1);
@@ -639,34 +727,45 @@ impl Client {
 
     /// Process messages while in a Connected state
     fn active<C: ClientIo, T: Table>(&mut self, cio: &mut C) -> Result<Option<RecvMsg<T>>, Error> {
-        let msg = cio.recv()?;
-        let pubsub = match msg {
-            Some(Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(ref ps)))) => ps,
-            Some(_) => {
-                // TODO: Maybe something else? return err?
+        let msg = match cio.recv()? {
+            Some(msg) => msg,
+            None => {
                 return Ok(None);
             }
-            None => {
+        };
+
+        if record_shortcode(&mut self.shortcodes, &msg) {
+            return Ok(None);
+        }
+
+        let pubsub = match msg {
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(ref ps))) => ps,
+            _ => {
+                // TODO: Maybe something else? return err?
                 return Ok(None);
             }
         };
 
         // Determine the path
         let path = match &pubsub.path {
-            PubSubPath::Short(sid) => {
-                Path::Borrow(
-                *self
-                    .sub_paths
-                    .get(*sid as usize)
-                    .ok_or(Error::UnexpectedMessage)?,
-                )
-            },
-            PubSubPath::Long(ms) => {
-                ms.try_to_owned().map_err(|_| Error::UnexpectedMessage)?
-            },
+            PubSubPath::Short(sid) => self
+                .shortcodes
+                .iter()
+                .find(|sc| sc.short == *sid)
+                .ok_or(Error::UnexpectedMessage)?
+                .long
+                .clone(),
+            PubSubPath::Long(ms) => ms.try_to_owned().map_err(|_| Error::UnexpectedMessage)?,
+        };
+
+        // NOTE: Shortcodes are assigned by the broker, so the table is
+        // always given the long path
+        let resolved = SubMsg {
+            path: PubSubPath::Long(path.as_borrowed()),
+            payload: pubsub.payload,
         };
 
-        let payload = match T::from_pub_sub(pubsub) {
+        let payload = match T::from_pub_sub(&resolved) {
             Ok(msg) => msg,
             Err(_e) => {
                 defmt::error!("fps err!");
@@ -674,9 +773,232 @@ impl Client {
             }
         };
 
-        Ok(Some(RecvMsg {
-            path,
-            payload,
-        }))
+        Ok(Some(RecvMsg { path, payload }))
+    }
+}
+
+/// Record a shortcode assigned by the broker, if `msg` announces one
+///
+/// Returns `true` if `msg` was an announcement.
+fn record_shortcode(shortcodes: &mut Vec<Shortcode, consts::U8>, msg: &Arbitrator) -> bool {
+    let (long_name, short_id) = match msg {
+        Arbitrator::Control(AControl {
+            response:
+                Ok(ControlResponse::PubSubShortAssignment {
+                    long_name,
+                    short_id,
+                }),
+            ..
+        }) => (*long_name, *short_id),
+        _ => return false,
+    };
+
+    // A shortcode may be re-assigned to a different path
+    if let Some(pos) = shortcodes
+        .iter()
+        .position(|sc| sc.short == short_id || sc.long.as_str() == long_name)
+    {
+        shortcodes.swap_remove(pos);
+    }
+
+    let pushed = match Path::try_from_str(long_name) {
+        Ok(long) => shortcodes
+            .push(Shortcode {
+                long,
+                short: short_id,
+            })
+            .is_ok(),
+        Err(()) => false,
+    };
+
+    if !pushed {
+        defmt::warn!("Too many shortcodes assigned");
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        pubsub_table,
+        test::{pump, Link},
+    };
+    use anachro_server::{AclRule, Broker, ClientMatch};
+    use std::vec::Vec as StdVec;
+
+    const VERSION: Version = Version {
+        major: 0,
+        minor: 1,
+        trivial: 0,
+        misc: 0,
+    };
+
+    pubsub_table! {
+        LedTable,
+        Subs => {
+            Color: "led/+/color" => u8,
+        },
+        Pubs => {
+            Status: "led/status" => bool,
+        },
+    }
+
+    // Only the paths of this table are used
+    #[allow(dead_code)]
+    mod sensors {
+        crate::pubsub_table! {
+            SensorTable,
+            Subs => {
+                Secret: "secret/temp" => u8,
+                Temp: "sensors/temp" => u8,
+                Humidity: "sensors/humidity" => u8,
+            },
+            Pubs => {
+                Status: "sensors/status" => bool,
+            },
+        }
+    }
+    use sensors::SensorTable;
+
+    #[test]
+    fn late_subscription_error() {
+        let mut broker = Broker::new();
+        broker
+            .add_acl_rule(AclRule {
+                client: ClientMatch::Any,
+                publish: &["#"],
+                subscribe: &["sensors/#"],
+            })
+            .unwrap();
+        let mut link = Link::new(&mut broker, 1);
+        let mut client = Client::new(
+            "client",
+            VERSION,
+            0,
+            SensorTable::sub_paths(),
+            SensorTable::pub_paths(),
+            Some(2),
+        );
+
+        loop {
+            client.process_one::<_, SensorTable>(&mut link).unwrap();
+            if matches!(client.state, ClientState::Subscribing) {
+                break;
+            }
+            pump(&mut broker, &mut [&mut link]);
+        }
+
+        // The first subscription times out, and is resent, but both
+        // requests are answered
+        while client.sub_resends == 0 {
+            client.process_one::<_, SensorTable>(&mut link).unwrap();
+        }
+        pump(&mut broker, &mut [&mut link]);
+        assert_eq!(link.received().count(), 2);
+
+        client.process_one::<_, SensorTable>(&mut link).unwrap();
+        assert_eq!(client.current_idx, 1);
+        pump(&mut broker, &mut [&mut link]);
+
+        // The second error is not taken as the answer to the next request
+        client.process_one::<_, SensorTable>(&mut link).unwrap();
+        assert_eq!(client.current_idx, 1);
+        client.process_one::<_, SensorTable>(&mut link).unwrap();
+        assert_eq!(client.current_idx, 2);
+        pump(&mut broker, &mut [&mut link]);
+
+        client.process_one::<_, SensorTable>(&mut link).unwrap();
+        assert!(matches!(client.state, ClientState::Subscribed));
+    }
+
+    #[test]
+    fn shortcode_delivery() {
+        let mut broker = Broker::new();
+        let mut sub_link = Link::new(&mut broker, 1);
+        let mut pub_link = Link::new(&mut broker, 2);
+        let mut sub = Client::new(
+            "sub",
+            VERSION,
+            0,
+            LedTable::sub_paths(),
+            LedTable::pub_paths(),
+            Some(10),
+        );
+        let mut publ = Client::new("pub", VERSION, 0, &[], &[], Some(10));
+
+        for _ in 0..10 {
+            assert!(sub
+                .process_one::<_, LedTable>(&mut sub_link)
+                .unwrap()
+                .is_none());
+            assert!(publ
+                .process_one::<_, LedTable>(&mut pub_link)
+                .unwrap()
+                .is_none());
+            pump(&mut broker, &mut [&mut sub_link, &mut pub_link]);
+        }
+        assert!(sub.is_connected());
+        assert!(publ.is_connected());
+
+        // Publish paths in the table are given a shortcode too
+        let status = LedTable::Status(true);
+        assert_eq!(status.get_pub_path(), Some("led/status"));
+        let mut buf = [0u8; 8];
+        let msg = status.serialize(&mut buf).unwrap();
+        sub.publish(&mut sub_link, msg.path, msg.buf).unwrap();
+        pump(&mut broker, &mut [&mut sub_link, &mut pub_link]);
+        assert_eq!(sub_link.received().count(), 0);
+
+        // The first message on the path is preceded by its assignment
+        publ.publish(&mut pub_link, "led/front/color", &[7])
+            .unwrap();
+        pump(&mut broker, &mut [&mut sub_link, &mut pub_link]);
+
+        let received: StdVec<_> = sub_link.received().collect();
+        assert_eq!(received.len(), 2);
+        let short = match received[0] {
+            Arbitrator::Control(AControl {
+                response:
+                    Ok(ControlResponse::PubSubShortAssignment {
+                        long_name: "led/front/color",
+                        short_id,
+                    }),
+                ..
+            }) => short_id,
+            ref other => panic!("Unexpected message: {:?}", other),
+        };
+        assert_eq!(
+            received[1],
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(SubMsg {
+                path: PubSubPath::Short(short),
+                payload: &[7],
+            })))
+        );
+
+        assert!(sub
+            .process_one::<_, LedTable>(&mut sub_link)
+            .unwrap()
+            .is_none());
+        let msg = sub
+            .process_one::<_, LedTable>(&mut sub_link)
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.path.as_str(), "led/front/color");
+        assert!(matches!(msg.payload, LedTable::Color(7)));
+
+        // Later messages only use the shortcode
+        publ.publish(&mut pub_link, "led/front/color", &[8])
+            .unwrap();
+        pump(&mut broker, &mut [&mut sub_link, &mut pub_link]);
+        assert_eq!(sub_link.received().count(), 1);
+
+        let msg = sub
+            .process_one::<_, LedTable>(&mut sub_link)
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.path.as_str(), "led/front/color");
+        assert!(matches!(msg.payload, LedTable::Color(8)));
     }
 }
@@ -18,6 +18,8 @@
 
 use anachro_icd::{arbitrator::Arbitrator, component::Component};
 use defmt::Format;
+use postcard::{flavors::SerFlavor, serialize_with_flavor};
+use serde::Serialize;
 
 /// The Error type of the ClientIo interface
 #[derive(Debug, PartialEq, Eq, Format)]
@@ -32,6 +34,16 @@ pub enum ClientIoError {
 
     /// The ClientIo is unable to send a packet, as the interface is full/busy
     OutputFull,
+
+    /// The message can never be sent, as it is larger than the interface
+    /// is able to carry
+    MessageTooLarge,
+
+    /// The connection to the Arbitrator/Broker has been lost
+    Disconnected,
+
+    /// The underlying transport reported an error while driving the link
+    LinkFailure,
 }
 
 /// A trait for defining the IO layer for a given client
@@ -42,3 +54,48 @@ pub trait ClientIo {
     /// Attempt to send one message TO the Arbitrator/Broker, FROM the Client
     fn send(&mut self, msg: &Component) -> Result<(), ClientIoError>;
 }
+
+/// Calculate the exact number of bytes `to_slice_cobs` will use to
+/// serialize `msg`, including the trailing frame delimiter
+///
+/// This is useful for requesting an appropriately sized grant from a
+/// queue BEFORE serializing a message directly into it.
+pub fn serialized_size_cobs<T: Serialize + ?Sized>(msg: &T) -> Result<usize, postcard::Error> {
+    serialize_with_flavor(msg, CobsSizer { len: 0, run: 0 })
+}
+
+/// A serialization flavor that counts the size of the COBS encoded
+/// output, without storing anything
+struct CobsSizer {
+    /// Bytes of output in all completed COBS blocks
+    len: usize,
+
+    /// Non-zero bytes in the current COBS block
+    run: usize,
+}
+
+impl SerFlavor for CobsSizer {
+    type Output = usize;
+
+    fn try_push(&mut self, data: u8) -> Result<(), ()> {
+        if data == 0 {
+            // The zero is replaced by the code byte of this block
+            self.len += self.run + 1;
+            self.run = 0;
+        } else {
+            self.run += 1;
+
+            // A full block is emitted without an implied zero
+            if self.run == 254 {
+                self.len += 255;
+                self.run = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn release(self) -> Result<Self::Output, ()> {
+        // Code byte and contents of the final block, plus the delimiter
+        Ok(self.len + self.run + 2)
+    }
+}
@@ -4,17 +4,24 @@
 
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 pub use {
     crate::{
+        async_client_io::{AsyncClientIo, RecvFuture, SendFuture},
+        bridge::{Bridge, BridgeSide},
         client::{Client, PUBLISH_SHORTCODE_OFFSET},
-        client_io::{ClientIo, ClientIoError},
-        table::{Table, TableError},
+        client_io::{serialized_size_cobs, ClientIo, ClientIoError},
+        table::{Table, TableError, TableVisitor},
     },
     anachro_icd::{self, arbitrator::SubMsg, ManagedString, Path, PubSubPath, Version},
     defmt::Format,
     postcard::{from_bytes, from_bytes_cobs, to_slice, to_slice_cobs},
 };
 
+mod async_client_io;
+mod bridge;
 mod client;
 mod client_io;
 mod table;
@@ -26,6 +33,9 @@ pub enum Error {
     Busy,
     UnexpectedMessage,
     ClientIoError(ClientIoError),
+
+    /// The name is longer than a `Name` can hold
+    NameTooLong,
 }
 
 impl From<ClientIoError> for Error {
@@ -47,3 +57,108 @@ pub struct SendMsg<'a> {
     pub buf: &'a [u8],
     pub path: &'static str,
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::{ClientIo, ClientIoError};
+    use anachro_icd::{arbitrator::Arbitrator, component::Component, Uuid};
+    use anachro_server::{Broker, Request, Response, ServerIoError, ServerIoIn};
+    use heapless::{consts, Vec};
+    use postcard::{from_bytes, to_slice};
+    use std::{collections::VecDeque, vec::Vec as StdVec};
+
+    /// A connection to a `Broker`, through in-memory queues
+    pub(crate) struct Link {
+        pub(crate) id: Uuid,
+        to_broker: VecDeque<StdVec<u8>>,
+        from_broker: VecDeque<StdVec<u8>>,
+        current: StdVec<u8>,
+    }
+
+    impl Link {
+        pub(crate) fn new(broker: &mut Broker, id: u8) -> Self {
+            let id = Uuid::from_bytes([id; 16]);
+            broker.register_client(&id).unwrap();
+            Link {
+                id,
+                to_broker: VecDeque::new(),
+                from_broker: VecDeque::new(),
+                current: StdVec::new(),
+            }
+        }
+
+        /// Drop all messages received from the Broker
+        pub(crate) fn clear(&mut self) {
+            self.from_broker.clear();
+        }
+
+        /// The messages received from the Broker, that have not yet
+        /// been processed
+        pub(crate) fn received(&self) -> impl Iterator<Item = Arbitrator<'_>> {
+            self.from_broker.iter().map(|msg| from_bytes(msg).unwrap())
+        }
+    }
+
+    impl ClientIo for Link {
+        fn recv(&mut self) -> Result<Option<Arbitrator>, ClientIoError> {
+            match self.from_broker.pop_front() {
+                Some(msg) => {
+                    self.current = msg;
+                    from_bytes(&self.current)
+                        .map(Some)
+                        .map_err(|_| ClientIoError::ParsingError)
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn send(&mut self, msg: &Component) -> Result<(), ClientIoError> {
+            let mut buf = [0u8; 1024];
+            let used = to_slice(msg, &mut buf).map_err(|_| ClientIoError::MessageTooLarge)?;
+            self.to_broker.push_back(used.to_vec());
+            Ok(())
+        }
+    }
+
+    struct Incoming {
+        source: Uuid,
+        msg: Option<StdVec<u8>>,
+    }
+
+    impl ServerIoIn for Incoming {
+        fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+            match self.msg {
+                Some(ref msg) => from_bytes(msg)
+                    .map(|msg| {
+                        Some(Request {
+                            source: self.source,
+                            msg,
+                        })
+                    })
+                    .map_err(|_| ServerIoError::DeserializeFailure),
+                None => Ok(None),
+            }
+        }
+    }
+
+    /// Process every message sent by each link, delivering the responses
+    pub(crate) fn pump(broker: &mut Broker, links: &mut [&mut Link]) {
+        for idx in 0..links.len() {
+            while let Some(msg) = links[idx].to_broker.pop_front() {
+                let mut incoming = Incoming {
+                    source: links[idx].id,
+                    msg: Some(msg),
+                };
+                let mut responses: Vec<Response, consts::U16> = Vec::new();
+                broker.process_msg(&mut incoming, &mut responses).unwrap();
+
+                for resp in responses.iter() {
+                    let mut buf = [0u8; 1024];
+                    let used = to_slice(&resp.msg, &mut buf).unwrap();
+                    let link = links.iter_mut().find(|l| l.id == resp.dest).unwrap();
+                    link.from_broker.push_back(used.to_vec());
+                }
+            }
+        }
+    }
+}
@@ -9,9 +9,18 @@
 //! do not need to have the same table, but for successful operation, all
 //! clients must agree on the same data type used for a given path or wildcard
 //! path topic.
-
-use anachro_icd::arbitrator::SubMsg;
+//!
+//! To check this, the `Client` sends the `Table::schema_hash` of each type
+//! when subscribing to or registering a path, and the Arbitrator rejects
+//! registrations that conflict with a type already registered for an
+//! overlapping path.
+
+use anachro_icd::{
+    arbitrator::SubMsg,
+    schema::{schema_hash, SchemaHash},
+};
 use postcard;
+use serde::{de::DeserializeOwned, Serialize};
 
 /// An error type used by the `Table` trait
 #[derive(Debug, PartialEq, Eq)]
@@ -32,6 +41,41 @@ pub trait Table: Sized {
 
     /// Create a Table item from a given SubMsg`
     fn from_pub_sub<'a>(msg: &'a SubMsg<'a>) -> Result<Self, TableError>;
+
+    /// Visit each subscribe path, then each publish path, along with the
+    /// type of its payload
+    fn visit_types<V: TableVisitor>(visitor: &mut V);
+
+    /// The schema hash of the payload type of the given path
+    ///
+    /// Returns `None` if the path is not in the table.
+    fn schema_hash(path: &str) -> Option<SchemaHash> {
+        struct Find<'a> {
+            path: &'a str,
+            hash: Option<SchemaHash>,
+        }
+
+        impl<'a> TableVisitor for Find<'a> {
+            fn visit<T: Serialize + DeserializeOwned>(&mut self, path: &'static str) {
+                if self.hash.is_none() && path == self.path {
+                    self.hash = Some(schema_hash::<T>());
+                }
+            }
+        }
+
+        let mut find = Find { path, hash: None };
+        Self::visit_types(&mut find);
+        find.hash
+    }
+}
+
+/// A visitor over the paths of a `Table`, and the types of their payloads
+///
+/// This allows tools that do not know the table ahead of time, such as a
+/// host gateway, to work with each payload by its type.
+pub trait TableVisitor {
+    /// Visit a path, which may contain wildcards, and the type of its payload
+    fn visit<T: Serialize + DeserializeOwned>(&mut self, path: &'static str);
 }
 
 /// A macro for defining a publish and subscribe table
@@ -109,7 +153,7 @@ macro_rules! pubsub_table {
                     },
                 };
                 $(
-                    if $crate::anachro_icd::matches(msg_path, $sub_path) {
+                    if $crate::anachro_icd::matches($sub_path, msg_path) {
                         return Ok(
                             $enum_ty::$sub_variant_name(
                                 $crate::from_bytes(msg.payload)
@@ -119,7 +163,7 @@ macro_rules! pubsub_table {
                     }
                 )+
                 $(
-                    if $crate::anachro_icd::matches(msg_path, $pub_path) {
+                    if $crate::anachro_icd::matches($pub_path, msg_path) {
                         return Ok(
                             $enum_ty::$pub_variant_name(
                                 $crate::from_bytes(msg.payload)
@@ -131,6 +175,15 @@ macro_rules! pubsub_table {
                 Err($crate::TableError::NoMatch)
             }
 
+            fn visit_types<V: $crate::TableVisitor>(visitor: &mut V) {
+                $(
+                    visitor.visit::<$sub_variant_ty>($sub_path);
+                )+
+                $(
+                    visitor.visit::<$pub_variant_ty>($pub_path);
+                )+
+            }
+
             fn sub_paths() -> &'static [&'static str] {
                 Self::sub_paths()
             }
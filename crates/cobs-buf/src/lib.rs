@@ -0,0 +1,381 @@
+//! # A streaming COBS deframer
+//!
+//! This crate provides [`Buffer`](struct.Buffer.html), which accumulates
+//! COBS encoded frames from a byte stream that arrives in arbitrarily
+//! sized chunks, such as from a UART DMA buffer, or a socket read.
+//!
+//! Frames are delimited by `0x00`. A frame that does not fit in the buffer
+//! is reported once, and the rest of it is discarded, so that reception
+//! resumes cleanly at the next delimiter. Empty frames (e.g. repeated
+//! delimiters, sent to resynchronize the link) are skipped.
+
+#![no_std]
+
+use serde::de::{Deserialize, DeserializeOwned};
+
+/// A buffer for accumulating a single COBS encoded frame of up to `N`
+/// bytes, including the delimiter
+pub struct Buffer<const N: usize> {
+    buf: [u8; N],
+    idx: usize,
+
+    /// The length of the most recently completed frame
+    last: usize,
+
+    /// Are we discarding the rest of an oversized frame?
+    discarding: bool,
+}
+
+pub enum FeedResult<'a, T> {
+    /// Consumed all data, still pending
+    Consumed,
+
+    /// Buffer was filled. Contains remaining section of input, if any
+    OverFull(&'a [u8]),
+
+    /// Reached end of chunk, but deserialization failed. Contains
+    /// remaining section of input, if any
+    DeserError(&'a [u8]),
+
+    /// Deserialization complete. Contains deserialized data and
+    /// remaining section of input, if any
+    Success { data: T, remaining: &'a [u8] },
+}
+
+pub enum SimpleResult<'input, 'buffer> {
+    /// Consumed all data, still pending
+    Consumed,
+
+    /// Buffer was filled. Contains remaining section of input, if any
+    OverFull(&'input [u8]),
+
+    /// Deframing complete. Contains the encoded frame, including the
+    /// delimiter, and remaining section of input, if any
+    Success {
+        data: &'buffer mut [u8],
+        remaining: &'input [u8],
+    },
+}
+
+pub enum WithResult<'a, R> {
+    /// Consumed all data, still pending
+    Consumed,
+
+    /// Buffer was filled. Contains remaining section of input, if any
+    OverFull(&'a [u8]),
+
+    /// Reached end of chunk, but deserialization failed. Contains
+    /// remaining section of input, if any
+    DeserError(&'a [u8]),
+
+    /// Deserialization complete. Contains deserialized data and
+    /// remaining section of input, if any
+    SuccessWith { result: R, remaining: &'a [u8] },
+}
+
+/// The outcome of feeding input, before any deserialization
+enum Step<'a> {
+    Consumed,
+    OverFull(&'a [u8]),
+
+    /// A frame of `len` bytes is at the start of the buffer
+    Frame {
+        len: usize,
+        remaining: &'a [u8],
+    },
+}
+
+impl<const N: usize> Default for Buffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Buffer<N> {
+    pub const fn new() -> Self {
+        Buffer {
+            buf: [0u8; N],
+            idx: 0,
+            last: 0,
+            discarding: false,
+        }
+    }
+
+    /// Discard any partially received frame
+    pub fn clear(&mut self) {
+        self.idx = 0;
+        self.discarding = false;
+    }
+
+    /// The most recently completed frame, as returned by `feed_simple`
+    ///
+    /// This is useful when the borrow returned by `feed_simple` can't be
+    /// held, e.g. when returning the frame from inside a loop. The frame is
+    /// only available until the buffer is fed again.
+    pub fn last_frame_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.last]
+    }
+
+    pub fn feed_with<'a: 'me, 'de, 'me: 'de, T, F, R>(
+        &'me mut self,
+        input: &'a [u8],
+        fun: F,
+    ) -> WithResult<'a, R>
+    where
+        T: 'de + Deserialize<'de>,
+        F: FnOnce(T) -> R,
+        R: 'static,
+    {
+        match self.step(input) {
+            Step::Consumed => WithResult::Consumed,
+            Step::OverFull(remaining) => WithResult::OverFull(remaining),
+            Step::Frame { len, remaining } => {
+                match postcard::from_bytes_cobs::<T>(&mut self.buf[..len]) {
+                    Ok(t) => WithResult::SuccessWith {
+                        result: fun(t),
+                        remaining,
+                    },
+                    Err(_) => WithResult::DeserError(remaining),
+                }
+            }
+        }
+    }
+
+    pub fn feed<'a, T: DeserializeOwned>(&mut self, input: &'a [u8]) -> FeedResult<'a, T> {
+        match self.step(input) {
+            Step::Consumed => FeedResult::Consumed,
+            Step::OverFull(remaining) => FeedResult::OverFull(remaining),
+            Step::Frame { len, remaining } => {
+                match postcard::from_bytes_cobs::<T>(&mut self.buf[..len]) {
+                    Ok(data) => FeedResult::Success { data, remaining },
+                    Err(_) => FeedResult::DeserError(remaining),
+                }
+            }
+        }
+    }
+
+    pub fn feed_simple<'input, 'buffer>(
+        &'buffer mut self,
+        input: &'input [u8],
+    ) -> SimpleResult<'input, 'buffer> {
+        match self.step(input) {
+            Step::Consumed => SimpleResult::Consumed,
+            Step::OverFull(remaining) => SimpleResult::OverFull(remaining),
+            Step::Frame { len, remaining } => SimpleResult::Success {
+                data: &mut self.buf[..len],
+                remaining,
+            },
+        }
+    }
+
+    /// Accumulate input until a frame is complete, the buffer is filled,
+    /// or the input is exhausted
+    fn step<'a>(&mut self, mut input: &'a [u8]) -> Step<'a> {
+        self.last = 0;
+
+        loop {
+            if input.is_empty() {
+                return Step::Consumed;
+            }
+
+            let zero_pos = match input.iter().position(|&i| i == 0) {
+                Some(n) => n,
+                None if self.discarding => return Step::Consumed,
+                None => {
+                    // Does it fit?
+                    if (self.idx + input.len()) > N {
+                        // nope. The rest of this frame will be discarded
+                        self.idx = 0;
+                        self.discarding = true;
+                        return Step::OverFull(&input[input.len()..]);
+                    }
+
+                    self.extend_unchecked(input);
+                    return Step::Consumed;
+                }
+            };
+
+            // Yes! We have an end of message here.
+            // Add one to include the zero in the "take" portion
+            // of the buffer, rather than in "release".
+            let (take, release) = input.split_at(zero_pos + 1);
+
+            if self.discarding {
+                // This was the tail of an oversized frame, which has
+                // already been reported
+                self.discarding = false;
+                input = release;
+                continue;
+            }
+
+            if self.idx == 0 && zero_pos == 0 {
+                // Empty frame, skip it
+                input = release;
+                continue;
+            }
+
+            // Does it fit?
+            if (self.idx + take.len()) > N {
+                self.idx = 0;
+                return Step::OverFull(release);
+            }
+
+            self.extend_unchecked(take);
+            let len = self.idx;
+            self.idx = 0;
+            self.last = len;
+
+            return Step::Frame {
+                len,
+                remaining: release,
+            };
+        }
+    }
+
+    /// extend the internal buffer with the given input. Will panic
+    /// if the input does not fit in the internal buffer.
+    fn extend_unchecked(&mut self, input: &[u8]) {
+        let new_end = self.idx + input.len();
+        self.buf[self.idx..new_end].copy_from_slice(input);
+        self.idx = new_end;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    struct Demo {
+        a: u32,
+        b: u8,
+    }
+
+    #[test]
+    fn loop_test() {
+        let mut raw_buf = [0u8; 64];
+        let mut cobs_buf: Buffer<64> = Buffer::new();
+
+        let ser = postcard::to_slice_cobs(&Demo { a: 10, b: 20 }, &mut raw_buf).unwrap();
+
+        if let FeedResult::Success { data, remaining } = cobs_buf.feed(ser) {
+            assert_eq!(Demo { a: 10, b: 20 }, data);
+            assert_eq!(remaining.len(), 0);
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn split_frames() {
+        let mut raw_buf = [0u8; 64];
+        let mut cobs_buf: Buffer<64> = Buffer::new();
+
+        let ser = postcard::to_slice_cobs(&Demo { a: 1, b: 2 }, &mut raw_buf).unwrap();
+        let (first, second) = ser.split_at(2);
+
+        assert!(matches!(cobs_buf.feed::<Demo>(first), FeedResult::Consumed));
+        match cobs_buf.feed_simple(second) {
+            SimpleResult::Success { data, remaining } => {
+                assert_eq!(data, &ser[..]);
+                assert!(remaining.is_empty());
+            }
+            _ => panic!(),
+        }
+        assert_eq!(cobs_buf.last_frame_mut(), &ser[..]);
+
+        // Two frames, and the start of a third, in a single chunk
+        let mut stream = [0u8; 64];
+        let frame = [0x02, 0x05, 0x00];
+        for (i, chunk) in stream.chunks_mut(3).take(3).enumerate() {
+            chunk.copy_from_slice(&frame);
+            chunk[1] += i as u8;
+        }
+
+        let mut input = &stream[..8];
+        for i in 0..2u8 {
+            input = match cobs_buf.feed_simple(input) {
+                SimpleResult::Success { data, remaining } => {
+                    assert_eq!(data, &[0x02, 0x05 + i, 0x00]);
+                    remaining
+                }
+                _ => panic!(),
+            };
+        }
+        assert!(matches!(
+            cobs_buf.feed_simple(input),
+            SimpleResult::Consumed
+        ));
+        match cobs_buf.feed_simple(&stream[8..9]) {
+            SimpleResult::Success { data, .. } => assert_eq!(data, &[0x02, 0x07, 0x00]),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn overfull_frames() {
+        let mut cobs_buf: Buffer<4> = Buffer::new();
+
+        // Exactly fills the buffer, including the delimiter
+        match cobs_buf.feed_simple(&[0x04, 0x01, 0x02, 0x00]) {
+            SimpleResult::Success { data, .. } => assert_eq!(data.len(), 4),
+            _ => panic!(),
+        }
+
+        // One byte too many, with the delimiter in the same chunk
+        match cobs_buf.feed_simple(&[0x05, 0x01, 0x02, 0x03, 0x00, 0x02, 0x09, 0x00]) {
+            SimpleResult::OverFull(remaining) => assert_eq!(remaining, &[0x02, 0x09, 0x00]),
+            _ => panic!(),
+        }
+
+        // Overflowing across chunks is reported once, and the tail of the
+        // frame is discarded
+        assert!(matches!(
+            cobs_buf.feed_simple(&[0x07, 0x01, 0x02]),
+            SimpleResult::Consumed
+        ));
+        match cobs_buf.feed_simple(&[0x03, 0x04]) {
+            SimpleResult::OverFull(remaining) => assert!(remaining.is_empty()),
+            _ => panic!(),
+        }
+        assert!(matches!(
+            cobs_buf.feed_simple(&[0x05, 0x06]),
+            SimpleResult::Consumed
+        ));
+        match cobs_buf.feed_simple(&[0x00, 0x02, 0x09, 0x00]) {
+            SimpleResult::Success { data, remaining } => {
+                assert_eq!(data, &[0x02, 0x09, 0x00]);
+                assert!(remaining.is_empty());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut raw_buf = [0u8; 64];
+        let mut cobs_buf: Buffer<64> = Buffer::new();
+        let ser = postcard::to_slice_cobs(&Demo { a: 3, b: 4 }, &mut raw_buf).unwrap();
+
+        // Joining mid-frame, followed by repeated delimiters
+        let mut stream = [0u8; 64];
+        let garbage = [0x13, 0x37, 0x00, 0x00, 0x00];
+        stream[..garbage.len()].copy_from_slice(&garbage);
+        stream[garbage.len()..][..ser.len()].copy_from_slice(ser);
+        let stream = &stream[..garbage.len() + ser.len()];
+
+        let remaining = match cobs_buf.feed::<Demo>(stream) {
+            FeedResult::DeserError(remaining) => remaining,
+            _ => panic!(),
+        };
+
+        match cobs_buf.feed::<Demo>(remaining) {
+            FeedResult::Success { data, remaining } => {
+                assert_eq!(data, Demo { a: 3, b: 4 });
+                assert!(remaining.is_empty());
+            }
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,594 @@
+//! # A COBS framed transport for the Anachro Protocol
+//!
+//! This crate provides [`CobsStream`](struct.CobsStream.html), which sends
+//! and receives Anachro messages over any `Read + Write` byte stream, such
+//! as a serial port, TCP stream, Unix socket, or pty.
+//!
+//! Each message is serialized with `postcard`, and framed with COBS, using
+//! `0x00` as the frame delimiter. Frames that can not be decoded (e.g. due
+//! to line noise, or joining a stream mid-frame) are discarded, and
+//! reception resumes at the next delimiter.
+//!
+//! `CobsStream` never blocks on its own. If the underlying stream is
+//! blocking, calls to `recv` will block until a frame is received. For
+//! polling use, the stream should be set as non-blocking, or given a read
+//! timeout.
+//!
+//! With the `secure` feature enabled, `CobsStream` also implements
+//! `FrameIo`, so it may be wrapped in an `anachro_secure_link::SecureLink`
+//! to encrypt and authenticate each message.
+
+use std::io::{ErrorKind, Read, Write};
+
+use anachro_client::{
+    anachro_icd::{arbitrator::Arbitrator, component::Component},
+    ClientIo, ClientIoError,
+};
+#[cfg(feature = "secure")]
+use anachro_secure_link::{FrameError, FrameIo};
+use anachro_server::{
+    HostedTransport, Request, Response, ServerIoError, ServerIoIn, ServerIoOut, Uuid,
+};
+use postcard::{from_bytes, to_stdvec_cobs};
+use serde::Serialize;
+
+/// The default maximum size of an encoded frame, not including the delimiter
+pub const DEFAULT_MAX_FRAME: usize = 1024;
+
+/// The number of bytes requested from the stream per read
+const READ_CHUNK: usize = 256;
+
+/// The underlying stream has been closed, or has failed
+struct Closed;
+
+/// The reasons a message could not be sent
+enum SendError {
+    Serialize,
+
+    /// Nothing was sent, and the message may be sent again later
+    Full,
+
+    Closed,
+
+    /// The encoded message is larger than the maximum frame size
+    TooLarge,
+}
+
+/// A COBS framed Anachro transport over a byte stream
+///
+/// `CobsStream` implements `ClientIo` for use by a Client, as well as
+/// `ServerIoIn` and `ServerIoOut` for use by a Broker. When used by a
+/// Broker, each stream represents a single Client, identified by the
+/// `Uuid` given at creation.
+pub struct CobsStream<T: Read + Write> {
+    io: T,
+    uuid: Uuid,
+    max_frame: usize,
+
+    /// Received bytes that do not yet form a complete frame
+    pending: Vec<u8>,
+
+    /// The most recently received frame, COBS decoded
+    frame: Vec<u8>,
+
+    /// Are we discarding an oversized frame until the next delimiter?
+    discarding: bool,
+
+    /// The rest of a frame that could only be partially written, which
+    /// must be sent before any other frame
+    unsent: Vec<u8>,
+
+    dropped_frames: usize,
+}
+
+impl<T: Read + Write> CobsStream<T> {
+    /// Create a new stream for use by a Client
+    ///
+    /// Frames larger than `max_frame` bytes (once encoded, not including
+    /// the delimiter) will be neither sent nor received.
+    pub fn new(io: T, max_frame: usize) -> Self {
+        Self::new_server(io, Uuid::from_bytes([0u8; 16]), max_frame)
+    }
+
+    /// Create a new stream for use by a Broker
+    ///
+    /// All requests received on this stream will be reported as coming from
+    /// `uuid`, and only responses to `uuid` will be accepted.
+    pub fn new_server(io: T, uuid: Uuid, max_frame: usize) -> Self {
+        Self {
+            io,
+            uuid,
+            max_frame,
+            pending: Vec::new(),
+            frame: Vec::new(),
+            discarding: false,
+            unsent: Vec::new(),
+            dropped_frames: 0,
+        }
+    }
+
+    /// The Uuid of the Client on the other side of this stream
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// The number of incoming frames that have been discarded, due to
+    /// being oversized or malformed
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped_frames
+    }
+
+    /// Obtain a reference to the underlying stream
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    /// Obtain a mutable reference to the underlying stream
+    ///
+    /// Reading from the stream directly will likely corrupt the framing.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    /// Release the underlying stream
+    ///
+    /// Any partially received frame is discarded.
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Send an encoded frame, including the delimiter
+    ///
+    /// Once any part of the frame has been written, the frame is considered
+    /// sent, and the rest is written before any later frame.
+    fn send_encoded(&mut self, encoded: &[u8]) -> Result<(), SendError> {
+        if encoded.len() > self.max_frame + 1 {
+            return Err(SendError::TooLarge);
+        }
+
+        if !self.flush_unsent().map_err(|Closed| SendError::Closed)? {
+            return Err(SendError::Full);
+        }
+
+        match write_some(&mut self.io, encoded).map_err(|Closed| SendError::Closed)? {
+            0 => Err(SendError::Full),
+            written => {
+                self.unsent.extend_from_slice(&encoded[written..]);
+                Ok(())
+            }
+        }
+    }
+
+    /// Attempt to write the rest of a partially sent frame, returning
+    /// whether it has now been sent completely
+    fn flush_unsent(&mut self) -> Result<bool, Closed> {
+        if !self.unsent.is_empty() {
+            let written = write_some(&mut self.io, &self.unsent)?;
+            self.unsent.drain(..written);
+        }
+        Ok(self.unsent.is_empty())
+    }
+
+    /// Serialize and send a single message
+    fn send_msg<M: Serialize + ?Sized>(&mut self, msg: &M) -> Result<(), SendError> {
+        let ser = to_stdvec_cobs(msg).map_err(|_| SendError::Serialize)?;
+        self.send_encoded(&ser)
+    }
+
+    /// Read from the stream until a frame accepted by `valid` is available
+    /// in `self.frame`. Returns `Ok(false)` if the stream has no more data
+    /// available at the moment.
+    fn next_frame<F: Fn(&[u8]) -> bool>(&mut self, valid: F) -> Result<bool, Closed> {
+        let mut scratch = [0u8; READ_CHUNK];
+        self.flush_unsent()?;
+
+        loop {
+            while let Some(pos) = self.pending.iter().position(|b| *b == 0x00) {
+                let mut frame: Vec<u8> = self.pending.drain(..=pos).collect();
+                frame.pop();
+
+                if self.discarding {
+                    // This was the tail of an oversized frame
+                    self.discarding = false;
+                    continue;
+                }
+
+                // Ignore empty frames, such as repeated delimiters
+                if frame.is_empty() {
+                    continue;
+                }
+
+                if frame.len() > self.max_frame {
+                    self.dropped_frames += 1;
+                    continue;
+                }
+
+                match postcard_cobs::decode_in_place(&mut frame) {
+                    Ok(len) if valid(&frame[..len]) => {
+                        frame.truncate(len);
+                        self.frame = frame;
+                        return Ok(true);
+                    }
+                    _ => self.dropped_frames += 1,
+                }
+            }
+
+            // No delimiter is pending. Don't buffer forever if we never see one.
+            if self.pending.len() > self.max_frame {
+                self.pending.clear();
+                if !self.discarding {
+                    self.discarding = true;
+                    self.dropped_frames += 1;
+                }
+            }
+
+            match self.io.read(&mut scratch) {
+                Ok(0) => return Err(Closed),
+                Ok(n) => self.pending.extend_from_slice(&scratch[..n]),
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => return Ok(false),
+                    ErrorKind::Interrupted => {}
+                    _ => return Err(Closed),
+                },
+            }
+        }
+    }
+}
+
+impl<T: Read + Write> ClientIo for CobsStream<T> {
+    /// Receive one message FROM the Broker
+    ///
+    /// Returns `Ok(None)` if no complete message is available yet
+    fn recv(&mut self) -> Result<Option<Arbitrator>, ClientIoError> {
+        match self.next_frame(|frame| from_bytes::<Arbitrator>(frame).is_ok()) {
+            // The frame was already checked by `next_frame`
+            Ok(true) => Ok(from_bytes(&self.frame).ok()),
+            Ok(false) => Ok(None),
+            Err(Closed) => Err(ClientIoError::Disconnected),
+        }
+    }
+
+    /// Send one message TO the Broker
+    fn send(&mut self, msg: &Component) -> Result<(), ClientIoError> {
+        self.send_msg(msg).map_err(|e| match e {
+            SendError::Serialize => ClientIoError::ParsingError,
+            SendError::Full => ClientIoError::OutputFull,
+            SendError::Closed => ClientIoError::Disconnected,
+            SendError::TooLarge => ClientIoError::MessageTooLarge,
+        })
+    }
+}
+
+impl<T: Read + Write> ServerIoIn for CobsStream<T> {
+    /// Receive one request FROM the Client
+    ///
+    /// Returns `Ok(None)` if no complete message is available yet
+    fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+        let source = self.uuid;
+        match self.next_frame(|frame| from_bytes::<Component>(frame).is_ok()) {
+            // The frame was already checked by `next_frame`
+            Ok(true) => Ok(from_bytes(&self.frame)
+                .ok()
+                .map(|msg| Request { source, msg })),
+            Ok(false) => Ok(None),
+            Err(Closed) => Err(ServerIoError::Disconnected),
+        }
+    }
+}
+
+impl<'resp, T: Read + Write> ServerIoOut<'resp> for CobsStream<T> {
+    /// Send one response TO the Client
+    ///
+    /// Responses addressed to any Client other than the one on this stream
+    /// are rejected.
+    fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+        if resp.dest != self.uuid {
+            return Err(ServerIoError::MisroutedResponse);
+        }
+
+        self.send_msg(&resp.msg).map_err(|e| match e {
+            SendError::Serialize | SendError::Full | SendError::TooLarge => {
+                ServerIoError::ResponsePushFailed
+            }
+            SendError::Closed => ServerIoError::Disconnected,
+        })
+    }
+}
+
+impl<T: Read + Write> HostedTransport for CobsStream<T> {
+    /// Send the rest of any partially sent response
+    ///
+    /// A closed stream is otherwise detected when receiving.
+    fn poll(&mut self) -> Result<(), ServerIoError> {
+        self.flush_unsent()
+            .map(drop)
+            .map_err(|Closed| ServerIoError::Disconnected)
+    }
+
+    fn client(&self) -> Option<Uuid> {
+        Some(self.uuid)
+    }
+}
+
+#[cfg(feature = "secure")]
+impl<T: Read + Write> FrameIo for CobsStream<T> {
+    /// Send one frame, COBS encoded
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+        let mut encoded = vec![0u8; postcard_cobs::max_encoding_length(frame.len()) + 1];
+        let len = postcard_cobs::encode(frame, &mut encoded);
+        if len > self.max_frame {
+            return Err(FrameError::TooLarge);
+        }
+        encoded.truncate(len);
+        encoded.push(0x00);
+
+        self.send_encoded(&encoded).map_err(|e| match e {
+            SendError::Serialize | SendError::Full => FrameError::Full,
+            SendError::Closed => FrameError::Disconnected,
+            SendError::TooLarge => FrameError::TooLarge,
+        })
+    }
+
+    /// Receive one frame, COBS decoded
+    fn recv_frame(&mut self) -> Result<Option<&[u8]>, FrameError> {
+        match self.next_frame(|_| true) {
+            Ok(true) => Ok(Some(&self.frame)),
+            Ok(false) => Ok(None),
+            Err(Closed) => Err(FrameError::Disconnected),
+        }
+    }
+}
+
+/// Write as much of `buf` as the stream accepts without blocking,
+/// returning the number of bytes written
+fn write_some<T: Write>(io: &mut T, buf: &[u8]) -> Result<usize, Closed> {
+    let mut written = 0;
+    while written < buf.len() {
+        match io.write(&buf[written..]) {
+            Ok(0) => return Err(Closed),
+            Ok(n) => written += n,
+            Err(e) => match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => break,
+                ErrorKind::Interrupted => {}
+                _ => return Err(Closed),
+            },
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anachro_client::anachro_icd::{
+        arbitrator::{Control as AControl, ControlResponse},
+        component::{ComponentInfo, Control, ControlType},
+        Name, Version,
+    };
+    use std::{
+        collections::VecDeque,
+        io::{self, Read, Write},
+    };
+
+    /// An in-memory stream, which returns each pushed chunk from a
+    /// separate call to `read`
+    #[derive(Default)]
+    struct MockIo {
+        incoming: VecDeque<Vec<u8>>,
+        sent: Vec<u8>,
+        closed: bool,
+
+        /// The number of bytes that may be written before blocking, if limited
+        writable: Option<usize>,
+    }
+
+    impl Read for MockIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.incoming.pop_front() {
+                Some(mut chunk) => {
+                    let len = chunk.len().min(buf.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    if len < chunk.len() {
+                        self.incoming.push_front(chunk.split_off(len));
+                    }
+                    Ok(len)
+                }
+                None if self.closed => Ok(0),
+                None => Err(ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    impl Write for MockIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.closed {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            let len = match self.writable {
+                Some(0) => return Err(ErrorKind::WouldBlock.into()),
+                Some(ref mut writable) => {
+                    let len = buf.len().min(*writable);
+                    *writable -= len;
+                    len
+                }
+                None => buf.len(),
+            };
+            self.sent.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn register() -> Component<'static> {
+        Component::Control(Control {
+            seq: 7,
+            ty: ControlType::RegisterComponent(ComponentInfo {
+                name: Name::borrow_from_str("test"),
+                version: Version {
+                    major: 0,
+                    minor: 1,
+                    trivial: 0,
+                    misc: 0,
+                },
+            }),
+        })
+    }
+
+    fn registered(uuid: Uuid) -> Arbitrator<'static> {
+        Arbitrator::Control(AControl {
+            seq: 7,
+            response: Ok(ControlResponse::ComponentRegistration(uuid)),
+        })
+    }
+
+    #[test]
+    fn chunked_frames() {
+        let uuid = Uuid::from_bytes([1; 16]);
+        let mut bytes = to_stdvec_cobs(&registered(uuid)).unwrap();
+        bytes.extend(to_stdvec_cobs(&registered(uuid)).unwrap());
+
+        let mut io = MockIo::default();
+        io.incoming.extend(bytes.chunks(3).map(|c| c.to_vec()));
+        let mut stream = CobsStream::new(io, DEFAULT_MAX_FRAME);
+
+        assert_eq!(ClientIo::recv(&mut stream).unwrap(), Some(registered(uuid)));
+        assert_eq!(ClientIo::recv(&mut stream).unwrap(), Some(registered(uuid)));
+        assert_eq!(ClientIo::recv(&mut stream).unwrap(), None);
+        assert_eq!(stream.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let uuid = Uuid::from_bytes([1; 16]);
+        let mut io = MockIo::default();
+
+        // A frame that is not valid COBS, a frame that is not a valid
+        // message, and a frame that is too large
+        let good = to_stdvec_cobs(&registered(uuid)).unwrap();
+        io.incoming.push_back(vec![0x05, 0x01, 0x00]);
+        io.incoming.push_back(vec![0x02, 0xFF, 0x00]);
+        io.incoming.push_back(vec![0x22; 100]);
+        io.incoming.push_back(vec![0x22; 100]);
+        io.incoming.push_back(vec![0x00]);
+        io.incoming.push_back(good);
+
+        let mut stream = CobsStream::new(io, 64);
+        assert_eq!(ClientIo::recv(&mut stream).unwrap(), Some(registered(uuid)));
+        assert_eq!(stream.dropped_frames(), 3);
+    }
+
+    #[test]
+    fn disconnect() {
+        let io = MockIo {
+            closed: true,
+            ..MockIo::default()
+        };
+        let mut stream = CobsStream::new(io, DEFAULT_MAX_FRAME);
+
+        assert_eq!(
+            ClientIo::recv(&mut stream),
+            Err(ClientIoError::Disconnected)
+        );
+        assert_eq!(stream.send(&register()), Err(ClientIoError::Disconnected));
+        assert!(matches!(
+            ServerIoIn::recv(&mut stream),
+            Err(ServerIoError::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn server_roundtrip() {
+        let uuid = Uuid::from_bytes([2; 16]);
+        let mut io = MockIo::default();
+        io.incoming.push_back(to_stdvec_cobs(&register()).unwrap());
+        let mut stream = CobsStream::new_server(io, uuid, DEFAULT_MAX_FRAME);
+
+        match ServerIoIn::recv(&mut stream).unwrap() {
+            Some(req) => {
+                assert_eq!(req.source, uuid);
+                assert_eq!(req.msg, register());
+            }
+            None => panic!("No request"),
+        }
+
+        // Only responses to this client are accepted
+        let other = Uuid::from_bytes([3; 16]);
+        assert!(matches!(
+            stream.push_response(Response {
+                dest: other,
+                msg: registered(other),
+            }),
+            Err(ServerIoError::MisroutedResponse)
+        ));
+        stream
+            .push_response(Response {
+                dest: uuid,
+                msg: registered(uuid),
+            })
+            .unwrap();
+
+        let mut sent = stream.into_inner().sent;
+        assert_eq!(
+            postcard::from_bytes_cobs::<Arbitrator>(&mut sent).unwrap(),
+            registered(uuid)
+        );
+    }
+
+    #[test]
+    fn partial_write() {
+        let io = MockIo {
+            writable: Some(5),
+            ..MockIo::default()
+        };
+        let mut stream = CobsStream::new(io, DEFAULT_MAX_FRAME);
+
+        // Once started, a frame is finished before the next is sent
+        stream.send(&register()).unwrap();
+        assert_eq!(stream.get_ref().sent.len(), 5);
+        assert_eq!(stream.send(&register()), Err(ClientIoError::OutputFull));
+
+        stream.get_mut().writable = Some(2);
+        assert_eq!(ClientIo::recv(&mut stream), Ok(None));
+        assert_eq!(stream.get_ref().sent.len(), 7);
+
+        stream.get_mut().writable = None;
+        stream.send(&register()).unwrap();
+
+        let sent = core::mem::take(&mut stream.get_mut().sent);
+        let mut frames = sent.split_inclusive(|b| *b == 0x00);
+        for _ in 0..2 {
+            let mut frame = frames.next().unwrap().to_vec();
+            assert_eq!(
+                postcard::from_bytes_cobs::<Component>(&mut frame).unwrap(),
+                register()
+            );
+        }
+        assert!(frames.next().is_none());
+
+        // A message that could never be sent is not retryable
+        let mut stream = CobsStream::new(MockIo::default(), 8);
+        assert_eq!(
+            stream.send(&register()),
+            Err(ClientIoError::MessageTooLarge)
+        );
+        assert!(stream.get_ref().sent.is_empty());
+    }
+
+    #[cfg(feature = "secure")]
+    #[test]
+    fn raw_frames() {
+        let frame = [0x03, 0x00, 0x00, 0xFF, 0x00, 0x42];
+        let mut stream = CobsStream::new(MockIo::default(), 8);
+        stream.send_frame(&frame).unwrap();
+        assert_eq!(stream.send_frame(&[0x01; 9]), Err(FrameError::TooLarge));
+
+        let sent = core::mem::take(&mut stream.get_mut().sent);
+        assert_eq!(sent.iter().filter(|b| **b == 0x00).count(), 1);
+        stream.get_mut().incoming.push_back(sent);
+        assert_eq!(stream.recv_frame(), Ok(Some(&frame[..])));
+        assert_eq!(stream.recv_frame(), Ok(None));
+    }
+}
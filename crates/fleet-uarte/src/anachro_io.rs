@@ -1,43 +1,78 @@
 use crate::app::UarteApp;
-use crate::cobs_buf::{Buffer, SimpleResult};
 use bbqueue::ArrayLength;
 
-use anachro_client::{to_slice_cobs, ClientIo, ClientIoError};
+use anachro_client::{serialized_size_cobs, to_slice_cobs, ClientIo, ClientIoError};
+use anachro_cobs_buf::{Buffer, SimpleResult};
 use anachro_icd::{arbitrator::Arbitrator, component::Component, Uuid};
-use anachro_server::{from_bytes_cobs, Request, ServerIoError, ServerIoIn};
+use anachro_server::{
+    from_bytes_cobs, HostedTransport, Request, Response, ServerIoError, ServerIoIn, ServerIoOut,
+};
+use serde::Serialize;
 
-pub struct AnachroUarte<OutgoingLen, IncomingLen, BufferLen>
+#[cfg(feature = "secure")]
+use anachro_secure_link::{FrameError, FrameIo};
+
+/// The reasons a message could not be enqueued
+enum SendError {
+    /// The message could not be serialized
+    Serialize,
+
+    /// The encoded message is larger than the outgoing queue
+    TooLarge,
+
+    /// There is currently not enough room in the outgoing queue
+    Full,
+}
+
+pub struct AnachroUarte<OutgoingLen, IncomingLen, const BUFFER_LEN: usize>
 where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
-    BufferLen: ArrayLength<u8>,
 {
     app: UarteApp<OutgoingLen, IncomingLen>,
-    buf: Buffer<BufferLen>,
+    buf: Buffer<BUFFER_LEN>,
     uuid: Uuid,
 }
 
-impl<OutgoingLen, IncomingLen, BufferLen> AnachroUarte<OutgoingLen, IncomingLen, BufferLen>
+impl<OutgoingLen, IncomingLen, const BUFFER_LEN: usize>
+    AnachroUarte<OutgoingLen, IncomingLen, BUFFER_LEN>
 where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
-    BufferLen: ArrayLength<u8>,
 {
     pub fn new(
         app: UarteApp<OutgoingLen, IncomingLen>,
-        buf: Buffer<BufferLen>,
+        buf: Buffer<BUFFER_LEN>,
         uuid: Uuid,
     ) -> Self {
         Self { app, buf, uuid }
     }
 
     pub fn enqueue(&mut self, out: &[u8]) -> Result<(), ()> {
-        let mut grant = self.app.write_grant(out.len()).map_err(drop)?;
-        grant.copy_from_slice(out);
-        grant.commit(out.len());
+        let len = out.len();
+        self.enqueue_with(len, |buf| {
+            buf.copy_from_slice(out);
+            len
+        })?;
         Ok(())
     }
 
+    /// Enqueue a message by writing it directly into the outgoing queue
+    ///
+    /// `f` is given a buffer of `max_len` bytes, and returns the number of
+    /// bytes it actually used. If `f` returns zero, nothing is sent.
+    pub fn enqueue_with<F>(&mut self, max_len: usize, f: F) -> Result<usize, ()>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut grant = self.app.write_grant(max_len).map_err(drop)?;
+        let used = f(&mut grant).min(max_len);
+        if used != 0 {
+            grant.commit(used);
+        }
+        Ok(used)
+    }
+
     pub fn dequeue<'a>(&'a mut self) -> Result<Option<&'a mut [u8]>, ()> {
         loop {
             if let Ok(rgr) = self.app.read() {
@@ -50,13 +85,15 @@ where
                         let len_rem = remaining.len();
                         rgr.release(len - len_rem);
                     }
-                    SimpleResult::Success { data, .. } => {
-                        let len = data.len();
-                        rgr.release(len);
+                    SimpleResult::Success { remaining, .. } => {
+                        // The frame may have started in an earlier grant, so
+                        // only release what was used from this one
+                        let len_rem = remaining.len();
+                        rgr.release(len - len_rem);
 
                         // TODO: We *SHOULD* be able to just return `data` here, but
-                        // borrow checker is sad. We know that the buffer always matches
-                        return Ok(Some(&mut self.buf.buf.as_mut_slice()[..len]));
+                        // borrow checker is sad.
+                        return Ok(Some(self.buf.last_frame_mut()));
                     }
                 }
             } else {
@@ -64,14 +101,32 @@ where
             }
         }
     }
+
+    /// Serialize a message directly into the outgoing queue
+    fn send_cobs<T: Serialize + ?Sized>(&mut self, msg: &T) -> Result<(), SendError> {
+        let len = serialized_size_cobs(msg).map_err(|_| SendError::Serialize)?;
+
+        if len > OutgoingLen::to_usize() {
+            return Err(SendError::TooLarge);
+        }
+
+        let mut wgr = self.app.write_grant(len).map_err(|_| SendError::Full)?;
+
+        // The grant is exactly sized, so this can only fail if the message
+        // does not serialize at all
+        let used = to_slice_cobs(msg, &mut wgr)
+            .map_err(|_| SendError::Serialize)?
+            .len();
+        wgr.commit(used);
+        Ok(())
+    }
 }
 
-impl<OutgoingLen, IncomingLen, BufferLen> ClientIo
-    for AnachroUarte<OutgoingLen, IncomingLen, BufferLen>
+impl<OutgoingLen, IncomingLen, const BUFFER_LEN: usize> ClientIo
+    for AnachroUarte<OutgoingLen, IncomingLen, BUFFER_LEN>
 where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
-    BufferLen: ArrayLength<u8>,
 {
     /// Attempt to receive one message FROM the Arbitrator/Broker, TO the Client
     fn recv(&mut self) -> Result<Option<Arbitrator>, ClientIoError> {
@@ -89,33 +144,24 @@ where
     }
 
     /// Attempt to send one message TO the Arbitrator/Broker, FROM the Client
+    ///
+    /// Returns `ClientIoError::MessageTooLarge` if the encoded message could
+    /// never fit in the outgoing queue, or `ClientIoError::OutputFull` if it
+    /// does not fit right now.
     fn send(&mut self, msg: &Component) -> Result<(), ClientIoError> {
-        // HACK: Actual sizing. /4 is based on nothing actually
-        match self.app.write_grant(BufferLen::to_usize() / 4) {
-            Ok(mut wgr) => {
-                match to_slice_cobs(msg, &mut wgr) {
-                    Ok(amt) => {
-                        let len = amt.len();
-                        wgr.commit(len);
-                        Ok(())
-                    }
-                    Err(_e) => {
-                        // TODO: See hack above, might not really be a parsing error
-                        Err(ClientIoError::ParsingError)
-                    }
-                }
-            }
-            Err(_e) => Err(ClientIoError::OutputFull),
-        }
+        self.send_cobs(msg).map_err(|e| match e {
+            SendError::Serialize => ClientIoError::ParsingError,
+            SendError::TooLarge => ClientIoError::MessageTooLarge,
+            SendError::Full => ClientIoError::OutputFull,
+        })
     }
 }
 
-impl<OutgoingLen, IncomingLen, BufferLen> ServerIoIn
-    for AnachroUarte<OutgoingLen, IncomingLen, BufferLen>
+impl<OutgoingLen, IncomingLen, const BUFFER_LEN: usize> ServerIoIn
+    for AnachroUarte<OutgoingLen, IncomingLen, BUFFER_LEN>
 where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
-    BufferLen: ArrayLength<u8>,
 {
     fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
         let uuid = self.uuid.clone();
@@ -136,6 +182,88 @@ where
     }
 }
 
+impl<'resp, OutgoingLen, IncomingLen, const BUFFER_LEN: usize> ServerIoOut<'resp>
+    for AnachroUarte<OutgoingLen, IncomingLen, BUFFER_LEN>
+where
+    OutgoingLen: ArrayLength<u8>,
+    IncomingLen: ArrayLength<u8>,
+{
+    /// Serialize a response for this Client directly into the outgoing queue
+    ///
+    /// Responses addressed to any Client other than the one on this link
+    /// are rejected with `ServerIoError::MisroutedResponse`.
+    fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+        if resp.dest != self.uuid {
+            defmt::error!("Response is not for this client!");
+            return Err(ServerIoError::MisroutedResponse);
+        }
+
+        self.send_cobs(&resp.msg).map_err(|e| {
+            if let SendError::TooLarge = e {
+                defmt::error!("Response is larger than the outgoing queue!");
+            }
+            ServerIoError::ResponsePushFailed
+        })
+    }
+}
+
+impl<OutgoingLen, IncomingLen, const BUFFER_LEN: usize> HostedTransport
+    for AnachroUarte<OutgoingLen, IncomingLen, BUFFER_LEN>
+where
+    OutgoingLen: ArrayLength<u8>,
+    IncomingLen: ArrayLength<u8>,
+{
+    /// The link is driven by the UARTE and timer interrupts, so there is
+    /// nothing to do here
+    fn poll(&mut self) -> Result<(), ServerIoError> {
+        Ok(())
+    }
+
+    fn client(&self) -> Option<Uuid> {
+        Some(self.uuid)
+    }
+}
+
+#[cfg(feature = "secure")]
+impl<OutgoingLen, IncomingLen, const BUFFER_LEN: usize> FrameIo
+    for AnachroUarte<OutgoingLen, IncomingLen, BUFFER_LEN>
+where
+    OutgoingLen: ArrayLength<u8>,
+    IncomingLen: ArrayLength<u8>,
+{
+    /// COBS encode a frame directly into the outgoing queue
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+        // Room for the encoded frame, and the delimiter
+        let max_len = postcard_cobs::max_encoding_length(frame.len()) + 1;
+
+        if max_len > OutgoingLen::to_usize() {
+            return Err(FrameError::TooLarge);
+        }
+
+        self.enqueue_with(max_len, |buf| {
+            let used = postcard_cobs::encode(frame, buf);
+            buf[used] = 0x00;
+            used + 1
+        })
+        .map_err(|_| FrameError::Full)?;
+        Ok(())
+    }
+
+    /// Receive one frame, COBS decoded in place
+    fn recv_frame(&mut self) -> Result<Option<&[u8]>, FrameError> {
+        let frame = match self.dequeue() {
+            Ok(Some(frame)) => frame,
+            _ => return Ok(None),
+        };
+
+        // NOTE: Malformed frames are passed on as empty, and discarded
+        // by the `SecureLink`
+        let end = frame.iter().position(|b| *b == 0x00).unwrap_or(frame.len());
+        let len = postcard_cobs::decode_in_place(&mut frame[..end]).unwrap_or(0);
+        Ok(Some(&frame[..len]))
+    }
+}
+
 // pub struct UarteApp<OutgoingLen, IncomingLen>
 // where
 //     OutgoingLen: ArrayLength<u8>,
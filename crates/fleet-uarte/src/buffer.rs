@@ -42,6 +42,11 @@ where
     OutgoingLen: ArrayLength<u8>,
     IncomingLen: ArrayLength<u8>,
 {
+    /// Split the buffer into the parts used by the application, the
+    /// timer interrupt, and the UARTE interrupt
+    ///
+    /// To enable hardware flow control, provide both `pins.rts` and
+    /// `pins.cts`. Providing only one of them is an error.
     pub fn try_split<
         Timer: TimerInstance,
         Channel: Ppi + ConfigurablePpi,
@@ -57,6 +62,11 @@ where
         rx_block_size: usize,
         idle_us: u32,
     ) -> Result<UarteParts<OutgoingLen, IncomingLen, Timer, Channel, Uarte>, Error> {
+        // Checked before the queues are split, as that can only happen once
+        if pins.rts.is_some() != pins.cts.is_some() {
+            return Err(Error::FlowControlPins);
+        }
+
         let (txd_prod, txd_cons) = self.txd_buf.try_split().map_err(|_| Error::Todo)?;
         let (rxd_prod, rxd_cons) = self.rxd_buf.try_split().map_err(|_| Error::Todo)?;
 
@@ -96,7 +106,7 @@ where
         };
 
         utim.init(idle_us);
-        uirq.init(pins, parity, baudrate);
+        uirq.init(pins, parity, baudrate)?;
 
         // ...
         Ok(UarteParts {
@@ -6,6 +6,7 @@ use crate::hal::{
     timer::Instance as TimerInstance,
     uarte::{Baudrate, Instance as UarteInstance, Parity, Pins},
 };
+use crate::Error;
 use bbqueue::{ArrayLength, Consumer, GrantR, GrantW, Producer};
 use core::sync::atomic::{compiler_fence, AtomicBool, Ordering::SeqCst};
 use embedded_hal::digital::v2::OutputPin;
@@ -66,7 +67,16 @@ where
     Channel: Ppi + ConfigurablePpi,
     Uarte: FleetUarteInstance,
 {
-    pub fn init(&mut self, pins: Pins, parity: Parity, baudrate: Baudrate) {
+    /// Configure the UARTE peripheral, and begin receiving
+    ///
+    /// Hardware flow control is enabled if both `pins.rts` and `pins.cts`
+    /// are provided. While enabled, the peer is held off with RTS whenever
+    /// the incoming queue is full, instead of bytes being dropped.
+    pub fn init(&mut self, pins: Pins, parity: Parity, baudrate: Baudrate) -> Result<(), Error> {
+        if pins.rts.is_some() != pins.cts.is_some() {
+            return Err(Error::FlowControlPins);
+        }
+
         uarte_setup(&self.uarte, pins, parity, baudrate);
 
         self.ppi_ch.enable();
@@ -75,6 +85,8 @@ where
             uarte_start_read(&self.uarte, &mut gr).unwrap();
             self.rx_grant = Some(gr);
         }
+
+        Ok(())
     }
 
     pub fn interrupt(&mut self) {
@@ -265,6 +277,10 @@ fn uarte_setup<T: FleetUarteInstance>(
         }
     });
 
+    // Hold off the peer until reception has started
+    if let Some(ref mut pin) = pins.rts {
+        pin.set_high().unwrap();
+    }
     uarte.psel.rts.write(|w| {
         if let Some(ref pin) = pins.rts {
             let w = unsafe { w.pin().bits(pin.pin()) };
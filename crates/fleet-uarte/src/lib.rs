@@ -10,10 +10,15 @@ use nrf52840_hal as hal;
 pub mod anachro_io;
 pub mod app;
 pub mod buffer;
-pub mod cobs_buf;
 pub mod irq;
 
+pub use anachro_cobs_buf as cobs_buf;
+
 #[derive(Debug)]
 pub enum Error {
     Todo,
+
+    /// Only one of the RTS and CTS pins was provided. Hardware flow
+    /// control requires both.
+    FlowControlPins,
 }
@@ -0,0 +1,119 @@
+//! Extending a 32-bit `RollingTimer` to a monotonic 64-bit tick
+
+use crate::RollingTimer;
+
+/// A monotonic 64-bit tick, built from a `RollingTimer` that rolls over
+/// at 32 bits
+///
+/// At one tick per microsecond, a 32-bit timer rolls over roughly every
+/// 71 minutes, while a 64-bit tick will not roll over in practice.
+///
+/// Rollovers of the inner timer are counted in one (or both) of two ways:
+///
+/// * By calling `get_ticks()` (or any of the other getters) at least once
+///   per period of the inner timer, or
+/// * By calling `on_overflow()` from the interrupt that fires when the
+///   inner timer rolls over
+///
+/// If neither happens within a full period of the inner timer, a rollover
+/// will be missed, and the extended tick will fall behind.
+///
+/// As counting rollovers requires updating state, all methods take
+/// `&mut self`. If the timer is shared between contexts, such as an
+/// interrupt and the idle loop, it must be wrapped in a mutex.
+pub struct ExtendedTimer<T>
+where
+    T: RollingTimer<Tick = u32>,
+{
+    timer: T,
+
+    /// The upper 32 bits of the extended tick
+    high: u32,
+
+    /// The last tick observed from the inner timer
+    last: u32,
+
+    /// Has a rollover been counted by polling, that the overflow
+    /// interrupt has not seen yet?
+    polled_wrap: bool,
+}
+
+impl<T> ExtendedTimer<T>
+where
+    T: RollingTimer<Tick = u32>,
+{
+    /// Create a new extended timer
+    ///
+    /// The extended tick starts at the current tick of `timer`.
+    pub fn new(timer: T) -> Self {
+        let last = timer.get_ticks();
+        Self {
+            timer,
+            high: 0,
+            last,
+            polled_wrap: false,
+        }
+    }
+
+    /// The inner timer
+    pub fn inner(&self) -> &T {
+        &self.timer
+    }
+
+    /// Get the current extended tick
+    pub fn get_ticks(&mut self) -> u64 {
+        let now = self.timer.get_ticks();
+        if now < self.last {
+            self.high = self.high.wrapping_add(1);
+            self.polled_wrap = true;
+        }
+        self.last = now;
+        self.extended(now)
+    }
+
+    /// Record that the inner timer has rolled over
+    ///
+    /// This must be called exactly once for every rollover, shortly
+    /// after it happens, e.g. from the overflow interrupt of the hardware
+    /// timer. Rollovers that have already been seen by polling are not
+    /// counted twice.
+    pub fn on_overflow(&mut self) {
+        if self.polled_wrap {
+            self.polled_wrap = false;
+        } else {
+            self.high = self.high.wrapping_add(1);
+        }
+        self.last = self.timer.get_ticks();
+    }
+
+    /// Get the current extended time, in whole seconds
+    pub fn get_secs(&mut self) -> u64 {
+        self.get_ticks() / u64::from(T::TICKS_PER_SECOND)
+    }
+
+    /// Get the current extended time, in whole milliseconds
+    pub fn get_millis(&mut self) -> u64 {
+        let ticks = self.get_ticks();
+        Self::scale(ticks, 1_000)
+    }
+
+    /// Get the current extended time, in whole microseconds
+    pub fn get_micros(&mut self) -> u64 {
+        let ticks = self.get_ticks();
+        Self::scale(ticks, 1_000_000)
+    }
+
+    fn extended(&self, low: u32) -> u64 {
+        (u64::from(self.high) << 32) | u64::from(low)
+    }
+
+    /// Convert ticks to `units_per_second`, without overflowing the
+    /// intermediate product
+    fn scale(ticks: u64, units_per_second: u64) -> u64 {
+        let tps = u64::from(T::TICKS_PER_SECOND);
+        let secs = ticks / tps;
+        let rem = ticks % tps;
+        secs.saturating_mul(units_per_second)
+            .saturating_add((rem * units_per_second) / tps)
+    }
+}
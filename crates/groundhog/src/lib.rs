@@ -3,12 +3,40 @@
 //! Sometimes you just want a simple rolling timer.
 //!
 //! Make sure you poll it often enough.
+//!
+//! Raw ticks may be wrapped in a `RollingInstant` or `RollingDuration`,
+//! and timeouts can be tracked with a `Deadline`, rather than comparing
+//! tick counts by hand.
+//!
+//! When a 32-bit timer rolls over too quickly, an `ExtendedTimer` can
+//! count its rollovers to provide a monotonic 64-bit tick.
+//!
+//! `SoftTimers` manages a fixed number of one-shot and periodic timers on
+//! top of a single `RollingTimer`, for scheduling protocol timeouts.
+//!
+//! With the `std` feature enabled, this crate also provides `StdTimer`,
+//! which is backed by `std::time::Instant`, and `MockTimer`, which is
+//! advanced manually, for use in tests and simulations.
 
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
 use core::ops::Div;
 use sealed::{Promote, RollingSince};
 
+pub use crate::extended::ExtendedTimer;
+pub use crate::soft::{SoftTimers, TimerId};
+pub use crate::time::{Deadline, RollingDuration, RollingInstant};
+
+#[cfg(feature = "std")]
+pub use crate::std_timers::{MockTimer, StdTimer};
+
+#[cfg(feature = "std")]
+mod std_timers;
+
+mod extended;
+mod soft;
+mod time;
+
 pub trait RollingTimer {
     type Tick: RollingSince + Promote + Div<Output = Self::Tick>;
 
@@ -17,6 +45,14 @@ pub trait RollingTimer {
     /// Get the current tick
     fn get_ticks(&self) -> Self::Tick;
 
+    /// Get the current time
+    fn now(&self) -> RollingInstant<Self>
+    where
+        Self: Sized,
+    {
+        RollingInstant::now(self)
+    }
+
     /// Get the number of ticks since the other measurement
     ///
     /// Make sure the old value isn't too stale.
@@ -62,6 +98,7 @@ pub trait RollingTimer {
 
 mod sealed {
     use core::convert::TryInto;
+    use core::fmt::Debug;
     use core::ops::{Div, Mul};
 
     pub trait Promote: Sized + Copy {
@@ -87,10 +124,13 @@ mod sealed {
         }
     }
 
-    pub trait RollingSince {
+    pub trait RollingSince: Copy + Ord + Debug {
+        const ONE: Self;
         const MILLIS_PER_SECOND: Self;
         const MICROS_PER_SECOND: Self;
         fn since(&self, other: Self) -> Self;
+        fn wrapping_add(&self, other: Self) -> Self;
+        fn saturating_sub(&self, other: Self) -> Self;
     }
 
     impl Promote for u32 {
@@ -105,19 +145,33 @@ mod sealed {
     }
 
     impl RollingSince for u32 {
+        const ONE: u32 = 1;
         const MILLIS_PER_SECOND: u32 = 1_000;
         const MICROS_PER_SECOND: u32 = 1_000_000;
         fn since(&self, other: u32) -> u32 {
-            self.wrapping_sub(other)
+            u32::wrapping_sub(*self, other)
+        }
+        fn wrapping_add(&self, other: u32) -> u32 {
+            u32::wrapping_add(*self, other)
+        }
+        fn saturating_sub(&self, other: u32) -> u32 {
+            u32::saturating_sub(*self, other)
         }
     }
 
     #[cfg(feature = "u128")]
     impl RollingSince for u64 {
+        const ONE: u64 = 1;
         const MILLIS_PER_SECOND: u64 = 1_000;
         const MICROS_PER_SECOND: u64 = 1_000_000;
         fn since(&self, other: u64) -> u64 {
-            self.wrapping_sub(other)
+            u64::wrapping_sub(*self, other)
+        }
+        fn wrapping_add(&self, other: u64) -> u64 {
+            u64::wrapping_add(*self, other)
+        }
+        fn saturating_sub(&self, other: u64) -> u64 {
+            u64::saturating_sub(*self, other)
         }
     }
 }
@@ -171,4 +225,169 @@ mod test {
         assert_eq!(timer.millis_since(0xC000_0000), 0xFFFF_FFFF);
         assert_eq!(timer.micros_since(0xC000_0000), 0xFFFF_FFFF);
     }
+
+    #[test]
+    fn durations() {
+        type Dur = RollingDuration<TestTimer>;
+
+        assert_eq!(Dur::from_secs(3).ticks(), 30);
+        assert_eq!(Dur::from_millis(250).ticks(), 2);
+        assert_eq!(Dur::from_micros(99_999).ticks(), 0);
+        assert_eq!(Dur::from_ticks(25).as_millis(), 2500);
+        assert_eq!(Dur::from_ticks(25).as_secs(), 2);
+
+        // Saturating conversions
+        assert_eq!(Dur::from_secs(0xFFFF_FFFF).ticks(), 0xFFFF_FFFF);
+        assert_eq!(Dur::from_ticks(0xFFFF_FFFF).as_micros(), 0xFFFF_FFFF);
+        assert_eq!(
+            Dur::from_ticks(3)
+                .saturating_sub(Dur::from_ticks(5))
+                .ticks(),
+            0
+        );
+    }
+
+    #[test]
+    fn deadline() {
+        static TIMER: AtomicU32 = AtomicU32::new(0xFFFF_FFF0);
+        let timer = TestTimer(&TIMER);
+
+        let start = timer.now();
+        let mut deadline = Deadline::new(&timer, RollingDuration::from_secs(2));
+        assert!(!deadline.is_expired(&timer));
+        assert_eq!(deadline.remaining(&timer).as_millis(), 2000);
+
+        // Across the rollover
+        TIMER.store(0x0000_0003, Ordering::SeqCst);
+        assert_eq!((timer.now() - start).ticks(), 0x13);
+        assert!(!deadline.is_expired(&timer));
+        assert_eq!(deadline.remaining(&timer).as_millis(), 100);
+        assert_eq!(start + RollingDuration::from_ticks(0x13), timer.now());
+
+        TIMER.store(0x0000_0004, Ordering::SeqCst);
+        assert!(deadline.is_expired(&timer));
+        assert_eq!(deadline.remaining(&timer).ticks(), 0);
+
+        deadline.restart(&timer);
+        assert!(!deadline.is_expired(&timer));
+        TIMER.store(0x0000_0018, Ordering::SeqCst);
+        assert_eq!(deadline.remaining(&timer).ticks(), 0);
+        assert!(deadline.is_expired(&timer));
+    }
+
+    #[test]
+    fn extended_polling() {
+        static TIMER: AtomicU32 = AtomicU32::new(0xFFFF_FF00);
+        let mut ext = ExtendedTimer::new(TestTimer(&TIMER));
+
+        assert_eq!(ext.get_ticks(), 0x0_FFFF_FF00);
+
+        for wraps in 1..=3u64 {
+            TIMER.store(0x0000_0010, Ordering::SeqCst);
+            assert_eq!(ext.get_ticks(), (wraps << 32) | 0x10);
+            TIMER.store(0x8000_0000, Ordering::SeqCst);
+            assert_eq!(ext.get_ticks(), (wraps << 32) | 0x8000_0000);
+            TIMER.store(0xFFFF_FFFF, Ordering::SeqCst);
+            assert_eq!(ext.get_ticks(), (wraps << 32) | 0xFFFF_FFFF);
+        }
+
+        // At 10 ticks per second
+        assert_eq!(ext.get_secs(), 0x3_FFFF_FFFF / 10);
+        assert_eq!(ext.get_millis(), 0x3_FFFF_FFFF * 100);
+        assert_eq!(ext.get_micros(), 0x3_FFFF_FFFF * 100_000);
+    }
+
+    #[test]
+    fn extended_overflow_hook() {
+        static TIMER: AtomicU32 = AtomicU32::new(0x0000_0002);
+        let mut ext = ExtendedTimer::new(TestTimer(&TIMER));
+
+        // Never polled between interrupts
+        TIMER.store(0x0000_0003, Ordering::SeqCst);
+        ext.on_overflow();
+        TIMER.store(0x0000_0001, Ordering::SeqCst);
+        ext.on_overflow();
+        assert_eq!(ext.get_ticks(), 0x2_0000_0001);
+
+        // Polled before the wrap, then the interrupt is handled
+        TIMER.store(0xFFFF_FFF0, Ordering::SeqCst);
+        assert_eq!(ext.get_ticks(), 0x2_FFFF_FFF0);
+        TIMER.store(0x0000_0004, Ordering::SeqCst);
+        ext.on_overflow();
+        assert_eq!(ext.get_ticks(), 0x3_0000_0004);
+
+        // Polled after the wrap, but before the interrupt is handled
+        TIMER.store(0xFFFF_FFF0, Ordering::SeqCst);
+        assert_eq!(ext.get_ticks(), 0x3_FFFF_FFF0);
+        TIMER.store(0x0000_0002, Ordering::SeqCst);
+        assert_eq!(ext.get_ticks(), 0x4_0000_0002);
+        TIMER.store(0x0000_0005, Ordering::SeqCst);
+        ext.on_overflow();
+        assert_eq!(ext.get_ticks(), 0x4_0000_0005);
+    }
+
+    #[test]
+    fn soft_timers() {
+        static TIMER: AtomicU32 = AtomicU32::new(0xFFFF_FFF0);
+        let mut timers: SoftTimers<_, 2> = SoftTimers::new(TestTimer(&TIMER));
+
+        let once = timers
+            .start_oneshot(RollingDuration::from_ticks(5))
+            .unwrap();
+        let every = timers
+            .start_periodic(RollingDuration::from_ticks(10))
+            .unwrap();
+        assert!(timers
+            .start_oneshot(RollingDuration::from_ticks(1))
+            .is_none());
+        assert_eq!(timers.next_expiry(), Some(RollingDuration::from_ticks(5)));
+        assert_eq!(timers.poll_expired(), None);
+
+        TIMER.store(0xFFFF_FFF5, Ordering::SeqCst);
+        assert_eq!(timers.poll_expired(), Some(once));
+        assert_eq!(timers.poll_expired(), None);
+        assert!(!timers.is_running(once));
+        assert!(!timers.cancel(once));
+
+        // A new timer in the old slot doesn't match the stale handle
+        let again = timers
+            .start_oneshot(RollingDuration::from_ticks(3))
+            .unwrap();
+        assert_ne!(again, once);
+        assert!(!timers.is_running(once));
+
+        // Periodic timers don't drift, even when polled late
+        TIMER.store(0x0000_0001, Ordering::SeqCst);
+        assert_eq!(timers.poll_expired(), Some(again));
+        assert_eq!(timers.poll_expired(), Some(every));
+        assert_eq!(timers.poll_expired(), None);
+        assert_eq!(timers.next_expiry(), Some(RollingDuration::from_ticks(3)));
+
+        TIMER.store(0x0000_0009, Ordering::SeqCst);
+        assert!(timers.restart(every));
+        TIMER.store(0x0000_000A, Ordering::SeqCst);
+        assert_eq!(timers.poll_expired(), None);
+
+        assert!(timers.cancel(every));
+        assert!(!timers.is_running(every));
+        assert_eq!(timers.next_expiry(), None);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn mock_timer() {
+        let timer = MockTimer::new();
+        let handle = timer.clone();
+
+        handle.advance_ms(3);
+        handle.advance_us(250);
+        assert_eq!(timer.get_ticks(), 3_250);
+        assert_eq!(timer.millis_since(0), 3);
+
+        handle.set_ticks(0xFFFF_FFF0);
+        let start = timer.get_ticks();
+        handle.advance_ticks(0x20);
+        assert_eq!(timer.get_ticks(), 0x10);
+        assert_eq!(timer.micros_since(start), 0x20);
+    }
 }
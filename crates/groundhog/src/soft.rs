@@ -0,0 +1,196 @@
+//! A fixed size set of software timers, driven by a `RollingTimer`
+
+use crate::{Deadline, RollingDuration, RollingTimer};
+
+/// A handle to a timer started in a `SoftTimers` set
+///
+/// Handles are not reused when a timer is cancelled or expires, so a stale
+/// handle will never refer to a timer started later in the same slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    idx: usize,
+    generation: u32,
+}
+
+struct Slot<T: RollingTimer> {
+    generation: u32,
+    deadline: Option<Deadline<T>>,
+    periodic: bool,
+}
+
+/// A set of up to `N` one-shot or periodic software timers
+///
+/// Timers do not fire on their own. Instead, `poll_expired()` should be
+/// called regularly (and at least once per period of the underlying
+/// timer), and returns the timers that have expired since the last call.
+///
+/// ```rust
+/// # use groundhog::{RollingDuration, RollingTimer, SoftTimers};
+/// # struct Timer;
+/// # impl RollingTimer for Timer {
+/// #     type Tick = u32;
+/// #     const TICKS_PER_SECOND: u32 = 1_000_000;
+/// #     fn get_ticks(&self) -> u32 { 0 }
+/// # }
+/// let mut timers: SoftTimers<Timer, 4> = SoftTimers::new(Timer);
+/// let retry = timers
+///     .start_periodic(RollingDuration::from_millis(100))
+///     .unwrap();
+///
+/// while let Some(id) = timers.poll_expired() {
+///     if id == retry {
+///         // resend the request...
+///     }
+/// }
+/// ```
+pub struct SoftTimers<T: RollingTimer, const N: usize> {
+    timer: T,
+    slots: [Slot<T>; N],
+}
+
+impl<T: RollingTimer, const N: usize> SoftTimers<T, N> {
+    pub fn new(timer: T) -> Self {
+        Self {
+            timer,
+            slots: [Slot::EMPTY; N],
+        }
+    }
+
+    /// The underlying timer
+    pub fn timer(&self) -> &T {
+        &self.timer
+    }
+
+    /// Start a timer that expires once, after `duration`
+    ///
+    /// Returns `None` if all `N` timers are already in use.
+    pub fn start_oneshot(&mut self, duration: RollingDuration<T>) -> Option<TimerId> {
+        self.start(duration, false)
+    }
+
+    /// Start a timer that expires every `period`, until it is cancelled
+    ///
+    /// Returns `None` if all `N` timers are already in use.
+    pub fn start_periodic(&mut self, period: RollingDuration<T>) -> Option<TimerId> {
+        self.start(period, true)
+    }
+
+    /// Stop a timer. Returns `false` if the timer was not running
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        match self.slot_mut(id) {
+            Some(slot) => {
+                slot.release();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Start a running timer over again, from the current time
+    ///
+    /// This is useful for timeouts that should only expire after a period
+    /// of inactivity. Returns `false` if the timer was not running.
+    pub fn restart(&mut self, id: TimerId) -> bool {
+        let timer = &self.timer;
+        match self.slots.get_mut(id.idx).filter(|s| s.is(id)) {
+            Some(Slot {
+                deadline: Some(deadline),
+                ..
+            }) => {
+                deadline.restart(timer);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Is the timer still running?
+    ///
+    /// One-shot timers stop once they have been returned by
+    /// `poll_expired()`. Periodic timers run until they are cancelled.
+    pub fn is_running(&self, id: TimerId) -> bool {
+        matches!(self.slots.get(id.idx), Some(s) if s.is(id))
+    }
+
+    /// Return the next expired timer, if any
+    ///
+    /// This should be called until it returns `None`. Periodic timers are
+    /// rescheduled relative to when they were due, rather than when they
+    /// were polled, so they do not drift. If a periodic timer falls more
+    /// than a period behind, it will be returned once for each missed
+    /// period.
+    pub fn poll_expired(&mut self) -> Option<TimerId> {
+        let timer = &self.timer;
+        let (idx, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, s)| matches!(&s.deadline, Some(dl) if dl.is_expired(timer)))?;
+
+        let id = TimerId {
+            idx,
+            generation: slot.generation,
+        };
+
+        match slot.deadline.as_mut() {
+            Some(dl) if slot.periodic => {
+                *dl = Deadline::starting_at(dl.start() + dl.duration(), dl.duration());
+            }
+            _ => slot.release(),
+        }
+
+        Some(id)
+    }
+
+    /// The time until the next timer expires, if any are running
+    ///
+    /// This is useful for deciding how long to sleep, or when to schedule
+    /// the next call to `poll_expired()`.
+    pub fn next_expiry(&self) -> Option<RollingDuration<T>> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.deadline.as_ref())
+            .map(|dl| dl.remaining(&self.timer))
+            .min()
+    }
+
+    fn start(&mut self, duration: RollingDuration<T>, periodic: bool) -> Option<TimerId> {
+        let deadline = Deadline::new(&self.timer, duration);
+        let (idx, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, s)| s.deadline.is_none())?;
+
+        slot.deadline = Some(deadline);
+        slot.periodic = periodic;
+
+        Some(TimerId {
+            idx,
+            generation: slot.generation,
+        })
+    }
+
+    fn slot_mut(&mut self, id: TimerId) -> Option<&mut Slot<T>> {
+        self.slots.get_mut(id.idx).filter(|s| s.is(id))
+    }
+}
+
+impl<T: RollingTimer> Slot<T> {
+    const EMPTY: Self = Slot {
+        generation: 0,
+        deadline: None,
+        periodic: false,
+    };
+
+    /// Is this slot running the timer `id`?
+    fn is(&self, id: TimerId) -> bool {
+        self.deadline.is_some() && self.generation == id.generation
+    }
+
+    /// Stop the timer, and invalidate any handles to it
+    fn release(&mut self) {
+        self.deadline = None;
+        self.generation = self.generation.wrapping_add(1);
+    }
+}
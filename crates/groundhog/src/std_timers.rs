@@ -0,0 +1,90 @@
+//! `RollingTimer` implementations for hosted environments
+
+use crate::RollingTimer;
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// A timer backed by `std::time::Instant`, with one tick per microsecond
+///
+/// Ticks are counted from when the timer was created. Clones share the same
+/// starting point, so ticks taken from clones may be compared.
+#[derive(Clone, Copy, Debug)]
+pub struct StdTimer {
+    start: Instant,
+}
+
+impl StdTimer {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for StdTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RollingTimer for StdTimer {
+    type Tick = u32;
+    const TICKS_PER_SECOND: u32 = 1_000_000;
+
+    fn get_ticks(&self) -> u32 {
+        // Truncation is intended, as the timer rolls over
+        self.start.elapsed().as_micros() as u32
+    }
+}
+
+/// A manually advanced timer, with one tick per microsecond
+///
+/// Time only moves when the timer is advanced, which makes tests and
+/// simulations deterministic. All clones of a `MockTimer` share the same
+/// time, so one handle may be given to the code under test while another
+/// is kept to drive it.
+#[derive(Clone, Debug, Default)]
+pub struct MockTimer {
+    ticks: Arc<AtomicU32>,
+}
+
+impl MockTimer {
+    /// Create a new timer, starting at zero ticks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move time forward by the given number of ticks
+    pub fn advance_ticks(&self, ticks: u32) {
+        self.ticks.fetch_add(ticks, Ordering::SeqCst);
+    }
+
+    /// Move time forward by the given number of microseconds
+    pub fn advance_us(&self, us: u32) {
+        self.advance_ticks(us);
+    }
+
+    /// Move time forward by the given number of milliseconds
+    pub fn advance_ms(&self, ms: u32) {
+        self.advance_ticks(ms.wrapping_mul(1_000));
+    }
+
+    /// Set the current tick, e.g. to test behavior around a rollover
+    pub fn set_ticks(&self, ticks: u32) {
+        self.ticks.store(ticks, Ordering::SeqCst);
+    }
+}
+
+impl RollingTimer for MockTimer {
+    type Tick = u32;
+    const TICKS_PER_SECOND: u32 = 1_000_000;
+
+    fn get_ticks(&self) -> u32 {
+        self.ticks.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,275 @@
+//! Typed instants, durations, and deadlines for a `RollingTimer`
+//!
+//! These wrap the raw `Tick` values of a specific timer, so that ticks of
+//! timers with different rates can't be mixed up, and conversions to and
+//! from real time units happen in one place.
+
+use crate::{
+    sealed::{Promote, RollingSince},
+    RollingTimer,
+};
+use core::{
+    cmp::Ordering,
+    fmt,
+    marker::PhantomData,
+    ops::{Add, Sub},
+};
+
+/// A point in time, as measured by the timer `T`
+///
+/// As the timer rolls over, instants can not be ordered, only compared
+/// for equality. The time elapsed since an instant is only correct if
+/// less than one full period of the timer has passed.
+pub struct RollingInstant<T: RollingTimer> {
+    tick: T::Tick,
+    _timer: PhantomData<fn() -> T>,
+}
+
+/// A span of time, measured in ticks of the timer `T`
+pub struct RollingDuration<T: RollingTimer> {
+    ticks: T::Tick,
+    _timer: PhantomData<fn() -> T>,
+}
+
+/// A point in time that is a given duration after it was started
+///
+/// The deadline must be checked at least once per period of the timer,
+/// otherwise the timer may roll over, and an expired deadline will appear
+/// to not have expired yet.
+pub struct Deadline<T: RollingTimer> {
+    start: RollingInstant<T>,
+    duration: RollingDuration<T>,
+}
+
+impl<T: RollingTimer> RollingInstant<T> {
+    /// The current time of `timer`
+    pub fn now(timer: &T) -> Self {
+        Self::from_ticks(timer.get_ticks())
+    }
+
+    pub fn from_ticks(tick: T::Tick) -> Self {
+        Self {
+            tick,
+            _timer: PhantomData,
+        }
+    }
+
+    pub fn ticks(&self) -> T::Tick {
+        self.tick
+    }
+
+    /// The time elapsed from `earlier` until this instant
+    pub fn duration_since(&self, earlier: Self) -> RollingDuration<T> {
+        RollingDuration::from_ticks(self.tick.since(earlier.tick))
+    }
+
+    /// The time elapsed since this instant
+    pub fn elapsed(&self, timer: &T) -> RollingDuration<T> {
+        Self::now(timer).duration_since(*self)
+    }
+}
+
+impl<T: RollingTimer> RollingDuration<T> {
+    pub fn from_ticks(ticks: T::Tick) -> Self {
+        Self {
+            ticks,
+            _timer: PhantomData,
+        }
+    }
+
+    /// A duration of the given number of whole seconds
+    ///
+    /// If the number of ticks is larger than `T::Tick::max()`, then it
+    /// will saturate at the max value
+    pub fn from_secs(secs: T::Tick) -> Self {
+        Self::from_ticks(secs.mul_then_div(T::TICKS_PER_SECOND, T::Tick::ONE))
+    }
+
+    /// A duration of the given number of milliseconds, rounded down to a
+    /// whole number of ticks
+    ///
+    /// If the number of ticks is larger than `T::Tick::max()`, then it
+    /// will saturate at the max value
+    pub fn from_millis(millis: T::Tick) -> Self {
+        Self::from_ticks(millis.mul_then_div(
+            T::TICKS_PER_SECOND,
+            <T::Tick as RollingSince>::MILLIS_PER_SECOND,
+        ))
+    }
+
+    /// A duration of the given number of microseconds, rounded down to a
+    /// whole number of ticks
+    ///
+    /// If the number of ticks is larger than `T::Tick::max()`, then it
+    /// will saturate at the max value
+    pub fn from_micros(micros: T::Tick) -> Self {
+        Self::from_ticks(micros.mul_then_div(
+            T::TICKS_PER_SECOND,
+            <T::Tick as RollingSince>::MICROS_PER_SECOND,
+        ))
+    }
+
+    pub fn ticks(&self) -> T::Tick {
+        self.ticks
+    }
+
+    /// The number of whole seconds in this duration
+    pub fn as_secs(&self) -> T::Tick {
+        self.ticks / T::TICKS_PER_SECOND
+    }
+
+    /// The number of whole milliseconds in this duration
+    ///
+    /// If the number of milliseconds is larger than `T::Tick::max()`,
+    /// then it will saturate at the max value
+    pub fn as_millis(&self) -> T::Tick {
+        self.ticks.mul_then_div(
+            <T::Tick as RollingSince>::MILLIS_PER_SECOND,
+            T::TICKS_PER_SECOND,
+        )
+    }
+
+    /// The number of whole microseconds in this duration
+    ///
+    /// If the number of microseconds is larger than `T::Tick::max()`,
+    /// then it will saturate at the max value
+    pub fn as_micros(&self) -> T::Tick {
+        self.ticks.mul_then_div(
+            <T::Tick as RollingSince>::MICROS_PER_SECOND,
+            T::TICKS_PER_SECOND,
+        )
+    }
+
+    /// Subtract `rhs` from this duration, stopping at zero
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self::from_ticks(self.ticks.saturating_sub(rhs.ticks))
+    }
+}
+
+impl<T: RollingTimer> Deadline<T> {
+    /// A deadline `duration` after the current time of `timer`
+    pub fn new(timer: &T, duration: RollingDuration<T>) -> Self {
+        Self::starting_at(RollingInstant::now(timer), duration)
+    }
+
+    /// A deadline `duration` after `start`
+    pub fn starting_at(start: RollingInstant<T>, duration: RollingDuration<T>) -> Self {
+        Self { start, duration }
+    }
+
+    pub fn start(&self) -> RollingInstant<T> {
+        self.start
+    }
+
+    pub fn duration(&self) -> RollingDuration<T> {
+        self.duration
+    }
+
+    /// Has the full duration passed since the deadline was started?
+    pub fn is_expired(&self, timer: &T) -> bool {
+        self.start.elapsed(timer) >= self.duration
+    }
+
+    /// The time left until the deadline expires, or zero if it has
+    /// already expired
+    pub fn remaining(&self, timer: &T) -> RollingDuration<T> {
+        self.duration.saturating_sub(self.start.elapsed(timer))
+    }
+
+    /// Start the same duration again, from the current time of `timer`
+    pub fn restart(&mut self, timer: &T) {
+        self.start = RollingInstant::now(timer);
+    }
+}
+
+impl<T: RollingTimer> Add<RollingDuration<T>> for RollingInstant<T> {
+    type Output = Self;
+
+    fn add(self, rhs: RollingDuration<T>) -> Self {
+        Self::from_ticks(self.tick.wrapping_add(rhs.ticks))
+    }
+}
+
+impl<T: RollingTimer> Sub for RollingInstant<T> {
+    type Output = RollingDuration<T>;
+
+    fn sub(self, rhs: Self) -> RollingDuration<T> {
+        self.duration_since(rhs)
+    }
+}
+
+// NOTE: These are implemented by hand, as deriving them would require the
+// timer itself to implement each trait.
+
+impl<T: RollingTimer> Clone for RollingInstant<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: RollingTimer> Copy for RollingInstant<T> {}
+
+impl<T: RollingTimer> PartialEq for RollingInstant<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.tick == other.tick
+    }
+}
+
+impl<T: RollingTimer> Eq for RollingInstant<T> {}
+
+impl<T: RollingTimer> fmt::Debug for RollingInstant<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RollingInstant").field(&self.tick).finish()
+    }
+}
+
+impl<T: RollingTimer> Clone for RollingDuration<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: RollingTimer> Copy for RollingDuration<T> {}
+
+impl<T: RollingTimer> PartialEq for RollingDuration<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ticks == other.ticks
+    }
+}
+
+impl<T: RollingTimer> Eq for RollingDuration<T> {}
+
+impl<T: RollingTimer> PartialOrd for RollingDuration<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: RollingTimer> Ord for RollingDuration<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ticks.cmp(&other.ticks)
+    }
+}
+
+impl<T: RollingTimer> fmt::Debug for RollingDuration<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RollingDuration").field(&self.ticks).finish()
+    }
+}
+
+impl<T: RollingTimer> Clone for Deadline<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: RollingTimer> Copy for Deadline<T> {}
+
+impl<T: RollingTimer> fmt::Debug for Deadline<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deadline")
+            .field("start", &self.start)
+            .field("duration", &self.duration)
+            .finish()
+    }
+}
@@ -6,7 +6,7 @@
 //! The [`Arbitrator` enum](enum.Arbitrator.html) is the top level
 //! message sent by the Arbitrator.
 
-use crate::{PubSubPath, Uuid};
+use crate::{auth::AUTH_NONCE_LEN, schema::SchemaHash, PubSubPath, Uuid};
 use serde::{Deserialize, Serialize};
 
 /// The primary Arbitrator mesage
@@ -20,7 +20,8 @@ pub enum Arbitrator<'a> {
     /// Control messages are intended to be the primary
     /// management channel between an Arbitrator and a
     /// Component/Client
-    Control(Control),
+    #[serde(borrow)]
+    Control(Control<'a>),
 
     /// Pub/Sub messages
     ///
@@ -103,6 +104,18 @@ pub enum PubSubResponse<'a> {
     /// This is a "subscribed to" message, containing a
     /// payload sent by another Client
     SubMsg(SubMsg<'a>),
+
+    /// Bridged Subscription Message
+    ///
+    /// This is a "subscribed to" message, sent to a bridge in place
+    /// of a `SubMsg`. The origin is the Arbitrator the message was
+    /// originally published on, or `None` if it was published on this
+    /// one.
+    BridgedSubMsg {
+        #[serde(borrow)]
+        msg: SubMsg<'a>,
+        origin: Option<Uuid>,
+    },
 }
 
 /// Subscription Message
@@ -128,7 +141,7 @@ pub struct SubMsg<'a> {
 /// and managing connections between the Arbitrator and
 /// Client(s).
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
-pub struct Control {
+pub struct Control<'a> {
     /// Sequence Number
     ///
     /// This number is provided by the client. The Arbitrator
@@ -139,31 +152,83 @@ pub struct Control {
     /// Response
     ///
     /// The arbitrator response to the client request
-    pub response: Result<ControlResponse, ControlError>,
+    #[serde(borrow)]
+    pub response: Result<ControlResponse<'a>, ControlError>,
 }
 
 /// Control Response
 ///
 /// A successful response to a Client's request
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
-pub enum ControlResponse {
+pub enum ControlResponse<'a> {
     /// The client/component has registered
     ComponentRegistration(Uuid),
 
     /// The client has registered a Pub/Sub path shortcode
     PubSubShortRegistration(u16),
+
+    /// The client must authenticate before it is registered
+    ///
+    /// The client should answer with a `ControlType::Authenticate`, using
+    /// this nonce
+    AuthChallenge([u8; AUTH_NONCE_LEN]),
+
+    /// The Arbitrator will assign shortcodes to the paths of messages
+    /// delivered to the client
+    ///
+    /// Sent in reply to a `ControlType::AssignPubSubShortIds`
+    PubSubShortAssignmentEnabled,
+
+    /// The Arbitrator has assigned a shortcode to a path
+    ///
+    /// This is sent in reply to a `ControlType::RequestPubSubShortId`, or
+    /// once the client has sent a `ControlType::AssignPubSubShortIds`,
+    /// before the first `SubMsg` that uses the shortcode. The latter
+    /// carries the sequence number of the `AssignPubSubShortIds` request.
+    PubSubShortAssignment { long_name: &'a str, short_id: u16 },
 }
 
 /// Control Message Errors
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
 pub enum ControlError {
+    /// A shortcode may not be registered for a path containing wildcards
+    ///
+    /// Messages matched by wildcard subscriptions are assigned shortcodes
+    /// for their paths instead, see `ControlType::AssignPubSubShortIds`
     NoWildcardsInShorts,
     ResetConnection,
+
+    /// The client has no key, or answered the challenge incorrectly
+    AuthenticationFailed,
+
+    /// The client has been assigned as many shortcodes as it may hold
+    ShortIdsExhausted,
+
+    /// A different payload type has already been registered for an
+    /// overlapping path, with the given schema hash
+    SchemaMismatch {
+        registered: SchemaHash,
+    },
+
+    /// The shortcode is already registered, or has been assigned, for a
+    /// different path
+    ShortIdInUse,
+
+    /// Only a client registered with `ControlType::RegisterBridge` may send
+    /// a `PubSubType::BridgedPub`
+    NotBridge,
 }
 
 /// Publish/Subscribe Errors
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
-pub enum PubSubError {}
+pub enum PubSubError {
+    /// The client is not allowed to publish or subscribe to this path
+    NotAuthorized,
+
+    /// A different payload type has already been registered for an
+    /// overlapping path, with the given schema hash
+    SchemaMismatch { registered: SchemaHash },
+}
 
 #[cfg(test)]
 mod test {
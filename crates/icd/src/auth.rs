@@ -0,0 +1,54 @@
+//! # Pre-Shared Key Authentication
+//!
+//! A Broker may require Components to prove that they hold a pre-shared
+//! key before they are registered:
+//!
+//! 1. The Component sends a `ControlType::RegisterComponent` as usual
+//! 2. The Arbitrator answers with a `ControlResponse::AuthChallenge`,
+//!    containing a nonce
+//! 3. The Component answers with a `ControlType::Authenticate`, containing
+//!    the MAC calculated by [`auth_mac`](fn.auth_mac.html)
+//! 4. The Arbitrator answers with a `ControlResponse::ComponentRegistration`
+//!    if the MAC is correct, or a `ControlError::AuthenticationFailed`
+//!    otherwise
+//!
+//! The MAC is an HMAC-SHA256 over the nonce, followed by the UTF-8 name
+//! the Component registered with.
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// The length of an authentication challenge nonce
+pub const AUTH_NONCE_LEN: usize = 16;
+
+/// The length of an authentication MAC
+pub const AUTH_MAC_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Calculate the MAC a Component answers an authentication challenge with
+pub fn auth_mac(key: &[u8], nonce: &[u8; AUTH_NONCE_LEN], name: &str) -> [u8; AUTH_MAC_LEN] {
+    let mut out = [0u8; AUTH_MAC_LEN];
+    out.copy_from_slice(&hmac(key, nonce, name).finalize().into_bytes());
+    out
+}
+
+/// Check the MAC a Component answered an authentication challenge with
+///
+/// The comparison is made in constant time.
+pub fn verify_auth_mac(
+    key: &[u8],
+    nonce: &[u8; AUTH_NONCE_LEN],
+    name: &str,
+    mac: &[u8; AUTH_MAC_LEN],
+) -> bool {
+    hmac(key, nonce, name).verify(mac).is_ok()
+}
+
+fn hmac(key: &[u8], nonce: &[u8; AUTH_NONCE_LEN], name: &str) -> HmacSha256 {
+    // NOTE: HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_varkey(key).unwrap();
+    mac.update(nonce);
+    mac.update(name.as_bytes());
+    mac
+}
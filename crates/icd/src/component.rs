@@ -6,7 +6,7 @@
 //! The [`Component` enum](enum.Component.html) is the top level
 //! message sent by Component/Clients.
 
-use crate::{PubSubPath, Version};
+use crate::{auth::AUTH_MAC_LEN, schema::SchemaHash, PubSubPath, Uuid, Version};
 use serde::{Deserialize, Serialize};
 
 /// Component Message
@@ -62,6 +62,32 @@ pub enum PubSubType<'a> {
     ///
     /// Unsubscribe to the given path
     Unsub,
+
+    /// Bridged Publish Message
+    ///
+    /// Publish a message/payload that was forwarded from another
+    /// Arbitrator by a bridge, tagged with the Arbitrator it was
+    /// originally published on
+    BridgedPub { payload: &'a [u8], origin: Uuid },
+
+    /// Typed Subscribe Message
+    ///
+    /// Subscribe to the given path, expecting payloads of the type with
+    /// the given `schema::schema_hash`. The Arbitrator rejects this with
+    /// `PubSubError::SchemaMismatch` if a different type has already been
+    /// registered for an overlapping path.
+    TypedSub { schema: SchemaHash },
+
+    /// Typed Publish Message
+    ///
+    /// Publish the given message/payload on the given path, which is of
+    /// the type with the given `schema::schema_hash`. The Arbitrator
+    /// rejects this with `PubSubError::SchemaMismatch` if a different type
+    /// has been registered for an overlapping path.
+    TypedPub {
+        payload: &'a [u8],
+        schema: SchemaHash,
+    },
 }
 
 /// Control Messages
@@ -101,6 +127,43 @@ pub enum ControlType<'a> {
     /// message bandwidth
     #[serde(borrow)]
     RegisterPubSubShortId(PubSubShort<'a>),
+
+    /// Authenticate
+    ///
+    /// This message answers a `ControlResponse::AuthChallenge`, with the
+    /// MAC calculated by `auth::auth_mac`
+    Authenticate { mac: [u8; AUTH_MAC_LEN] },
+
+    /// Register Bridge
+    ///
+    /// This message is used in place of `RegisterComponent` by a bridge
+    /// between Arbitrators. Messages are delivered to a bridge as
+    /// `PubSubResponse::BridgedSubMsg`, tagged with their origin.
+    #[serde(borrow)]
+    RegisterBridge(ComponentInfo<'a>),
+
+    /// Assign PubSubShortIds
+    ///
+    /// This message asks the Arbitrator to assign a shortcode to each
+    /// path the first time a message on it is delivered to this
+    /// Component, including paths matched by wildcard subscriptions. Each
+    /// assignment is announced with a `ControlResponse::PubSubShortAssignment`
+    /// before the `SubMsg` that uses it.
+    AssignPubSubShortIds,
+
+    /// Request PubSubShortID
+    ///
+    /// This message is used to request a path "short code" chosen by the
+    /// Arbitrator, typically for a path the Component publishes on. The
+    /// path may not contain wildcards.
+    ///
+    /// If a `schema::schema_hash` of the payload type is given, the
+    /// Arbitrator rejects this with `ControlError::SchemaMismatch` if a
+    /// different type has already been registered for an overlapping path.
+    RequestPubSubShortId {
+        long_name: &'a str,
+        schema: Option<SchemaHash>,
+    },
 }
 
 /// Information about this Component/Client needed for
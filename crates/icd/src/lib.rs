@@ -20,7 +20,10 @@ use serde::{
 };
 
 pub mod arbitrator;
+pub mod auth;
 pub mod component;
+pub mod schema;
+pub mod sys;
 
 /// A type alias for the Maximum Pub/Sub Path
 pub type MaxPathLen = consts::U127;
@@ -214,11 +217,25 @@ impl Uuid {
 ///  "/dev_1/temperature/front",
 /// ));
 /// ```
+///
+/// Paths starting with `$`, such as the [`sys`](sys/index.html) paths,
+/// are not matched by a wildcard in the first segment:
+///
+/// ```
+/// # use anachro_icd::matches;
+/// #
+/// assert!(!matches("#", "$sys/stats"));
+/// assert!(matches("$sys/#", "$sys/stats"));
+/// ```
 pub fn matches(subscr: &str, publ: &str) -> bool {
     if subscr.is_empty() || publ.is_empty() {
         return false;
     }
 
+    if publ.starts_with('$') && (subscr.starts_with('#') || subscr.starts_with('+')) {
+        return false;
+    }
+
     let mut s_iter = subscr.split('/');
     let mut p_iter = publ.split('/');
 
@@ -231,3 +248,40 @@ pub fn matches(subscr: &str, publ: &str) -> bool {
         }
     }
 }
+
+/// A function for checking whether two pub/sub paths, either of which may
+/// contain wildcards, could both match the same published path
+///
+/// ## Examples
+///
+/// ```
+/// # use anachro_icd::overlaps;
+/// #
+/// assert!(overlaps("/+/temperature", "/dev_1/#"));
+/// assert!(!overlaps("/dev_1/temperature", "/dev_2/+"));
+/// assert!(!overlaps("#", "$sys/stats"));
+/// ```
+pub fn overlaps(lhs: &str, rhs: &str) -> bool {
+    if lhs.is_empty() || rhs.is_empty() {
+        return false;
+    }
+
+    let leading_wildcard = |path: &str| path.starts_with('#') || path.starts_with('+');
+    if (lhs.starts_with('$') && leading_wildcard(rhs))
+        || (rhs.starts_with('$') && leading_wildcard(lhs))
+    {
+        return false;
+    }
+
+    let mut l_iter = lhs.split('/');
+    let mut r_iter = rhs.split('/');
+
+    loop {
+        match (l_iter.next(), r_iter.next()) {
+            (Some("#"), _) | (_, Some("#")) | (None, None) => return true,
+            (Some("+"), Some(_)) | (Some(_), Some("+")) => continue,
+            (Some(l), Some(r)) if l == r => continue,
+            _ => return false,
+        }
+    }
+}
@@ -0,0 +1,564 @@
+//! # Payload Schemas
+//!
+//! All Components must agree on the type of the payload sent on a given
+//! path. To check this, a Component may send a fingerprint of the type it
+//! uses when it subscribes to, or registers a shortcode for, a path. The
+//! Arbitrator records these per path, and rejects registrations whose
+//! fingerprint does not match one already recorded for an overlapping path.
+//!
+//! The fingerprint is calculated by [`schema_hash`](fn.schema_hash.html)
+//! from the shape of the type as seen by `serde`: the kind of each value,
+//! and the names of struct fields and enum variants. The names of the types
+//! themselves are not included, so two structs with the same fields have
+//! the same fingerprint.
+
+use core::fmt;
+use serde::de::{
+    self, DeserializeSeed, Deserializer, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::Deserialize;
+
+/// A fingerprint of a payload type, as calculated by `schema_hash`
+pub type SchemaHash = u64;
+
+/// Values nested deeper than this, such as those of recursive types,
+/// are not traced
+const MAX_DEPTH: u8 = 16;
+
+/// Enums beyond this many in a single pass always take their first variant
+const MAX_ENUMS: usize = 32;
+
+/// Bounds the time taken for types with many enums, as every combination
+/// of their variants is traced
+const MAX_PASSES: u32 = 1024;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Calculate the fingerprint of the type `T`
+///
+/// The fingerprint is stable across builds and platforms, and changes when
+/// the shape of the type as seen by `serde` does.
+///
+/// ## Examples
+///
+/// ```
+/// # use anachro_icd::schema::schema_hash;
+/// # use serde::Deserialize;
+/// #
+/// #[derive(Deserialize)]
+/// struct Celsius {
+///     temp: f32,
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct Fahrenheit {
+///     temp: f32,
+/// }
+///
+/// assert_eq!(schema_hash::<Celsius>(), schema_hash::<Fahrenheit>());
+/// assert_ne!(schema_hash::<Celsius>(), schema_hash::<f32>());
+/// assert_ne!(schema_hash::<u8>(), schema_hash::<i8>());
+/// ```
+pub fn schema_hash<T: Deserialize<'static>>() -> SchemaHash {
+    let mut state = State {
+        hash: FNV_OFFSET_BASIS,
+        cursors: [Cursor { index: 0, count: 0 }; MAX_ENUMS],
+        len: 0,
+        next: 0,
+    };
+
+    // Each pass traces a single variant of every enum it meets, so make
+    // passes until every combination of variants has been traced
+    for _ in 0..MAX_PASSES {
+        state.next = 0;
+
+        // A failed pass still hashed everything it traced, which is all
+        // the fingerprint needs
+        let _ = T::deserialize(Tracer {
+            state: &mut state,
+            depth: 0,
+        });
+
+        if !state.advance() {
+            break;
+        }
+    }
+
+    state.hash
+}
+
+/// The variant traced for one enum
+#[derive(Clone, Copy)]
+struct Cursor {
+    index: u32,
+    count: u32,
+}
+
+struct State {
+    hash: u64,
+
+    /// One cursor per enum, in the order they are met in a pass
+    cursors: [Cursor; MAX_ENUMS],
+
+    /// The number of cursors in use
+    len: usize,
+
+    /// The cursor for the next enum met in the current pass
+    next: usize,
+}
+
+impl State {
+    /// Choose the variant to trace for the next enum of the pass
+    ///
+    /// Passes take the same variants up to the last cursor in use, so meet
+    /// the same enums, and enums met after that start at their first
+    /// variant.
+    fn variant(&mut self, count: u32) -> u32 {
+        let next = self.next;
+        self.next += 1;
+
+        if next >= MAX_ENUMS {
+            return 0;
+        }
+        if next == self.len {
+            self.cursors[next] = Cursor { index: 0, count };
+            self.len += 1;
+        }
+        self.cursors[next].index
+    }
+
+    /// Move on to the next combination of variants, in depth first order
+    ///
+    /// Returns `false` once every combination has been traced.
+    fn advance(&mut self) -> bool {
+        self.len = self.len.min(self.next);
+        while let Some(cursor) = self.cursors[..self.len].last_mut() {
+            cursor.index += 1;
+            if cursor.index < cursor.count {
+                return true;
+            }
+            self.len -= 1;
+        }
+        false
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= u64::from(*byte);
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn write_len(&mut self, len: usize) {
+        self.write(&(len as u32).to_le_bytes());
+    }
+
+    fn write_str(&mut self, s: &str) {
+        self.write_len(s.len());
+        self.write(s.as_bytes());
+    }
+}
+
+#[derive(Debug)]
+struct TraceError;
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("schema trace failed")
+    }
+}
+
+impl de::StdError for TraceError {}
+
+impl de::Error for TraceError {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        TraceError
+    }
+}
+
+/// A `Deserializer` that records what is asked of it, and answers with
+/// placeholder values
+struct Tracer<'s> {
+    state: &'s mut State,
+    depth: u8,
+}
+
+impl<'s> Tracer<'s> {
+    fn nested(&mut self) -> Result<Tracer, TraceError> {
+        if self.depth >= MAX_DEPTH {
+            self.state.write(b"...");
+            return Err(TraceError);
+        }
+        Ok(Tracer {
+            state: self.state,
+            depth: self.depth + 1,
+        })
+    }
+
+    fn elements(self, len: usize) -> Elements<'s> {
+        Elements {
+            state: self.state,
+            depth: self.depth,
+            remaining: len,
+        }
+    }
+}
+
+macro_rules! trace_primitive {
+    ($($method:ident => $visit:ident($($value:expr)?);)*) => {
+        $(
+            fn $method<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+                self.state.write_str(stringify!($method));
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'s> Deserializer<'static> for Tracer<'s> {
+    type Error = TraceError;
+
+    trace_primitive! {
+        deserialize_any => visit_unit();
+        deserialize_bool => visit_bool(false);
+        deserialize_i8 => visit_i8(0);
+        deserialize_i16 => visit_i16(0);
+        deserialize_i32 => visit_i32(0);
+        deserialize_i64 => visit_i64(0);
+        deserialize_u8 => visit_u8(0);
+        deserialize_u16 => visit_u16(0);
+        deserialize_u32 => visit_u32(0);
+        deserialize_u64 => visit_u64(0);
+        deserialize_f32 => visit_f32(0.0);
+        deserialize_f64 => visit_f64(0.0);
+        deserialize_char => visit_char('\0');
+        deserialize_str => visit_borrowed_str("");
+        deserialize_string => visit_borrowed_str("");
+        deserialize_bytes => visit_borrowed_bytes(&[]);
+        deserialize_byte_buf => visit_borrowed_bytes(&[]);
+        deserialize_unit => visit_unit();
+        deserialize_identifier => visit_u64(0);
+        deserialize_ignored_any => visit_unit();
+    }
+
+    fn deserialize_option<V: Visitor<'static>>(
+        mut self,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("option");
+        visitor.visit_some(self.nested()?)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'static>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("unit_struct");
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'static>>(
+        mut self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("newtype_struct");
+        visitor.visit_newtype_struct(self.nested()?)
+    }
+
+    fn deserialize_seq<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.state.write_str("seq");
+        visitor.visit_seq(self.elements(1))
+    }
+
+    fn deserialize_tuple<V: Visitor<'static>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("tuple");
+        self.state.write_len(len);
+        visitor.visit_seq(self.elements(len))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'static>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("tuple_struct");
+        self.state.write_len(len);
+        visitor.visit_seq(self.elements(len))
+    }
+
+    fn deserialize_map<V: Visitor<'static>>(self, visitor: V) -> Result<V::Value, TraceError> {
+        self.state.write_str("map");
+        visitor.visit_map(Entries {
+            state: self.state,
+            depth: self.depth,
+            remaining: 1,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'static>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("struct");
+        self.state.write_len(fields.len());
+        for field in fields {
+            self.state.write_str(field);
+        }
+        visitor.visit_seq(self.elements(fields.len()))
+    }
+
+    fn deserialize_enum<V: Visitor<'static>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.state.write_str("enum");
+        self.state.write_len(variants.len());
+        for variant in variants {
+            self.state.write_str(variant);
+        }
+        if variants.is_empty() {
+            return Err(TraceError);
+        }
+
+        let index = self.state.variant(variants.len() as u32);
+        self.state.write_len(index as usize);
+
+        visitor.visit_enum(Variant {
+            tracer: self,
+            index,
+        })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+struct Elements<'s> {
+    state: &'s mut State,
+    depth: u8,
+    remaining: usize,
+}
+
+impl<'s> SeqAccess<'static> for Elements<'s> {
+    type Error = TraceError;
+
+    fn next_element_seed<T: DeserializeSeed<'static>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        let mut tracer = Tracer {
+            state: self.state,
+            depth: self.depth,
+        };
+        seed.deserialize(tracer.nested()?).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+struct Entries<'s> {
+    state: &'s mut State,
+    depth: u8,
+    remaining: usize,
+}
+
+impl<'s> MapAccess<'static> for Entries<'s> {
+    type Error = TraceError;
+
+    fn next_key_seed<K: DeserializeSeed<'static>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TraceError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        let mut tracer = Tracer {
+            state: self.state,
+            depth: self.depth,
+        };
+        seed.deserialize(tracer.nested()?).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'static>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, TraceError> {
+        let mut tracer = Tracer {
+            state: self.state,
+            depth: self.depth,
+        };
+        seed.deserialize(tracer.nested()?)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+struct Variant<'s> {
+    tracer: Tracer<'s>,
+    index: u32,
+}
+
+impl<'s> EnumAccess<'static> for Variant<'s> {
+    type Error = TraceError;
+    type Variant = Tracer<'s>;
+
+    fn variant_seed<V: DeserializeSeed<'static>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Tracer<'s>), TraceError> {
+        let index: de::value::U32Deserializer<TraceError> = self.index.into_deserializer();
+        Ok((seed.deserialize(index)?, self.tracer))
+    }
+}
+
+impl<'s> VariantAccess<'static> for Tracer<'s> {
+    type Error = TraceError;
+
+    fn unit_variant(self) -> Result<(), TraceError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'static>>(
+        mut self,
+        seed: T,
+    ) -> Result<T::Value, TraceError> {
+        seed.deserialize(self.nested()?)
+    }
+
+    fn tuple_variant<V: Visitor<'static>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'static>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TraceError> {
+        self.deserialize_struct("", fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{sys::Stats, Path};
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Light {
+        Off,
+        On { brightness: u8 },
+        Blink(u16, u16),
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Switch {
+        Off,
+        On { brightness: u8 },
+        Blink(u16, u32),
+    }
+
+    #[test]
+    fn enum_variants() {
+        // Differ only in the last variant
+        assert_ne!(schema_hash::<Light>(), schema_hash::<Switch>());
+        assert_eq!(schema_hash::<Light>(), schema_hash::<Light>());
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Outer<I> {
+        Inner(I),
+        Off,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Inner {
+        A,
+        B(u8),
+        C { level: u16 },
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum InnerAdded {
+        A,
+        B(u8),
+        C { level: u16 },
+        D,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum InnerReordered {
+        B(u8),
+        A,
+        C { level: u16 },
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum InnerRetyped {
+        A,
+        B(u8),
+        C { level: u32 },
+    }
+
+    #[test]
+    fn nested_enum_variants() {
+        let inner = schema_hash::<Outer<Inner>>();
+        assert_eq!(inner, schema_hash::<Outer<Inner>>());
+        assert_ne!(inner, schema_hash::<Outer<InnerAdded>>());
+        assert_ne!(inner, schema_hash::<Outer<InnerReordered>>());
+        assert_ne!(inner, schema_hash::<Outer<InnerRetyped>>());
+
+        // Each enum in a struct is traced through all of its variants
+        assert_ne!(
+            schema_hash::<(Outer<Inner>, Outer<Inner>)>(),
+            schema_hash::<(Outer<Inner>, Outer<InnerRetyped>)>()
+        );
+        assert_ne!(
+            schema_hash::<Option<Outer<Inner>>>(),
+            schema_hash::<Option<Outer<InnerRetyped>>>()
+        );
+    }
+
+    #[test]
+    fn borrowed_types() {
+        assert_eq!(schema_hash::<Path>(), schema_hash::<&str>());
+        assert_ne!(schema_hash::<Stats>(), schema_hash::<Option<Stats>>());
+        assert_ne!(schema_hash::<[u8; 4]>(), schema_hash::<[u8; 3]>());
+    }
+}
@@ -0,0 +1,97 @@
+//! # Broker System Topics
+//!
+//! The Arbitrator publishes messages about itself on paths starting with
+//! [`SYS_PREFIX`](constant.SYS_PREFIX.html). Components may subscribe to
+//! these like any other path, but may not publish on them.
+//!
+//! | Path                               | Payload                                              |
+//! | :--------------------------------- | :--------------------------------------------------- |
+//! | `$sys/clients/<name>/connected`    | [`ClientConnected`](struct.ClientConnected.html)     |
+//! | `$sys/clients/<name>/disconnected` | The [`Uuid`](../struct.Uuid.html) of the Component   |
+//! | `$sys/stats`                       | [`Stats`](struct.Stats.html)                         |
+//!
+//! Wildcards at the start of a subscription do not match system paths, so
+//! a Component subscribed to `#` does not receive these messages, unless
+//! it also subscribes to e.g. `$sys/#`.
+
+use crate::{Name, Path, Uuid, Version};
+use heapless::{consts, Vec};
+use serde::{Deserialize, Serialize};
+
+/// The prefix of all paths published by the Arbitrator
+pub const SYS_PREFIX: &str = "$sys/";
+
+/// The path that statistics are periodically published on
+pub const STATS_PATH: &str = "$sys/stats";
+
+/// Is `path` reserved for messages published by the Arbitrator?
+pub fn is_sys_path(path: &str) -> bool {
+    path.starts_with(SYS_PREFIX)
+}
+
+/// Published on `$sys/clients/<name>/connected` when a Component
+/// completes its registration
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+pub struct ClientConnected {
+    pub uuid: Uuid,
+    pub version: Version,
+}
+
+/// Published on `$sys/stats`
+///
+/// All counts are totals since the Arbitrator was started, and wrap
+/// on overflow.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct Stats<'a> {
+    /// Messages published by all Components
+    pub messages: u32,
+
+    /// Payload bytes published by all Components
+    pub bytes: u32,
+
+    /// Messages that could not be delivered to a subscriber
+    pub dropped: u32,
+
+    /// Statistics for each path published on
+    ///
+    /// At most eight paths are tracked. Paths published on after that are
+    /// only counted in the totals.
+    #[serde(borrow)]
+    pub topics: Vec<TopicStats<'a>, consts::U8>,
+
+    /// Statistics for each connected Component
+    #[serde(borrow)]
+    pub clients: Vec<ClientStats<'a>, consts::U8>,
+}
+
+/// Statistics for a single path
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct TopicStats<'a> {
+    #[serde(borrow)]
+    pub path: Path<'a>,
+    pub messages: u32,
+    pub bytes: u32,
+}
+
+/// Statistics for a single Component, since it connected
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct ClientStats<'a> {
+    #[serde(borrow)]
+    pub name: Name<'a>,
+    pub uuid: Uuid,
+
+    /// Messages published by the Component
+    pub published: u32,
+
+    /// Payload bytes published by the Component
+    pub bytes_in: u32,
+
+    /// Messages delivered to the Component
+    pub delivered: u32,
+
+    /// Payload bytes delivered to the Component
+    pub bytes_out: u32,
+
+    /// Messages that could not be delivered to the Component
+    pub dropped: u32,
+}
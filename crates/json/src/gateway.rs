@@ -0,0 +1,532 @@
+use std::{
+    collections::VecDeque,
+    io::{self, stdin, stdout, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+    thread::spawn,
+};
+
+use anachro_server::{
+    anachro_icd::{
+        arbitrator::{Arbitrator, Control as AControl, ControlError, ControlResponse},
+        arbitrator::{PubSubError, PubSubResponse, SubMsg},
+        auth::{auth_mac, AUTH_MAC_LEN},
+        component::{Component, ComponentInfo, Control, ControlType, PubSub, PubSubType},
+    },
+    HostEvent, HostedTransport, Name, Path, PubSubPath, Request, Response, ServerIoError,
+    ServerIoIn, ServerIoOut, Uuid, Version,
+};
+use rand::random;
+
+use crate::{
+    protocol::{Command, Event},
+    schema::Schemas,
+};
+
+/// A request to be made to the Broker on behalf of a session
+enum Pending {
+    Register(String),
+    Authenticate([u8; AUTH_MAC_LEN]),
+    Sub(String),
+    Unsub(String),
+    Pub { path: String, payload: Vec<u8> },
+}
+
+/// A request that the Broker is expected to answer
+#[derive(Clone, Copy, PartialEq)]
+enum Awaiting {
+    Registration,
+    SubAck,
+}
+
+#[derive(PartialEq)]
+enum SessionState {
+    Unregistered,
+
+    /// Waiting for the Broker to accept the registration
+    Registering,
+
+    Registered,
+}
+
+struct Session {
+    uuid: Uuid,
+    lines: Receiver<String>,
+    out: Box<dyn Write + Send>,
+    state: SessionState,
+    name: String,
+    key: Option<Vec<u8>>,
+
+    /// Has the session been reported to the host?
+    announced: bool,
+}
+
+/// A gateway that allows scripts to take part in the Pub/Sub plane,
+/// using newline-delimited JSON
+///
+/// Each session, such as a TCP connection or the standard input and
+/// output of the process, is registered with the Broker as a separate
+/// client once it sends a `register` command.
+///
+/// The gateway is a `HostedTransport`, and should be serviced by a
+/// `BrokerHost`. As with other transports, at most one message is
+/// processed each time the host is polled.
+pub struct JsonGateway {
+    schemas: Schemas,
+    listener: Option<TcpListener>,
+    sessions: Vec<Session>,
+    events: VecDeque<HostEvent>,
+    requests: VecDeque<(Uuid, Pending)>,
+
+    /// The request most recently passed to the Broker
+    held: Option<(Uuid, Pending)>,
+
+    /// The session whose request is still waiting for an answer from the
+    /// Broker. If no answer arrived by the time the next request is
+    /// received, the request has failed.
+    awaiting: Option<(Uuid, Awaiting)>,
+}
+
+impl JsonGateway {
+    /// Create a gateway with no sessions, translating payloads using
+    /// `schemas`
+    pub fn new(schemas: Schemas) -> Self {
+        JsonGateway {
+            schemas,
+            listener: None,
+            sessions: Vec::new(),
+            events: VecDeque::new(),
+            requests: VecDeque::new(),
+            held: None,
+            awaiting: None,
+        }
+    }
+
+    /// Accept sessions over TCP on `addr`
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    /// The local address of the TCP listener, if any
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// Add a session, reading commands from `reader`, and writing events
+    /// to `writer`
+    ///
+    /// Commands are read on a background thread. The session ends once
+    /// `reader` is closed.
+    pub fn attach<R, W>(&mut self, reader: R, writer: W)
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (tx, lines) = channel();
+        spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                match line {
+                    Ok(line) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let uuid_buf: u128 = random();
+        self.sessions.push(Session {
+            uuid: Uuid::from_bytes(uuid_buf.to_le_bytes()),
+            lines,
+            out: Box::new(writer),
+            state: SessionState::Unregistered,
+            name: String::new(),
+            key: None,
+            announced: false,
+        });
+    }
+
+    /// Add a session using the standard input and output of the process
+    pub fn attach_stdio(&mut self) {
+        self.attach(stdin(), stdout());
+    }
+
+    /// The number of sessions currently attached
+    pub fn sessions(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn accept(&mut self) {
+        let listener = match &self.listener {
+            Some(listener) => listener,
+            None => return,
+        };
+
+        let mut accepted = Vec::new();
+        loop {
+            match listener.accept() {
+                Ok((stream, _addr)) => accepted.push(stream),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+
+        for stream in accepted {
+            // Sessions are serviced by blocking reads on a background thread
+            if stream.set_nonblocking(false).is_err() {
+                continue;
+            }
+            if let Ok(reader) = stream.try_clone() {
+                self.attach(reader, stream);
+            }
+        }
+    }
+
+    /// Handle all available commands from a session, returning whether
+    /// the session is still open
+    fn service(&mut self, idx: usize) -> bool {
+        // Commands are not handled while registering, as the Broker would
+        // reject them
+        while self.sessions[idx].state != SessionState::Registering {
+            let line = match self.sessions[idx].lines.try_recv() {
+                Ok(line) => line,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return false,
+            };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let res = match serde_json::from_str(&line) {
+                Ok(cmd) => self.command(idx, cmd),
+                Err(e) => Err(format!("Invalid command: {}", e)),
+            };
+
+            if let Err(message) = res {
+                if self.sessions[idx].send(&Event::Error { message }).is_err() {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Handle a command from a session
+    fn command(&mut self, idx: usize, cmd: Command) -> Result<(), String> {
+        let session = &mut self.sessions[idx];
+        let uuid = session.uuid;
+
+        let pending = match cmd {
+            Command::Register { name, key } => {
+                if Name::try_from_str(&name).is_err() {
+                    return Err("Name is too long".into());
+                }
+
+                session.name = name.clone();
+                session.key = key.map(String::into_bytes);
+                session.state = SessionState::Registering;
+                session.announced = true;
+
+                // Re-registering resets the client
+                self.events.push_back(HostEvent::Connected(uuid));
+                Pending::Register(name)
+            }
+            _ if session.state != SessionState::Registered => {
+                return Err("Not registered".into());
+            }
+            Command::Subscribe { path } => {
+                if Path::try_from_str(&path).is_err() {
+                    return Err("Path is too long".into());
+                }
+                Pending::Sub(path)
+            }
+            Command::Unsubscribe { path } => {
+                // The Broker does not answer unsubscriptions
+                session
+                    .send(&Event::Unsubscribed { path: &path })
+                    .map_err(|e| e.to_string())?;
+                Pending::Unsub(path)
+            }
+            Command::Publish { path, payload, raw } => {
+                if path.contains('#') || path.contains('+') {
+                    return Err("Wildcards can not be published to".into());
+                }
+
+                let payload = match (payload, raw) {
+                    (Some(value), None) => self
+                        .schemas
+                        .from_json(&path, value)
+                        .ok_or_else(|| format!("No schema for '{}', use 'raw'", path))?
+                        .map_err(|e| format!("Invalid payload: {:?}", e))?,
+                    (None, Some(raw)) => raw,
+                    _ => return Err("Exactly one of 'payload' or 'raw' is required".into()),
+                };
+
+                Pending::Pub { path, payload }
+            }
+        };
+
+        self.requests.push_back((uuid, pending));
+        Ok(())
+    }
+
+    /// Remove a session, and all of its requests
+    fn close(&mut self, idx: usize) {
+        let session = self.sessions.swap_remove(idx);
+
+        if session.announced {
+            self.events.push_back(HostEvent::Disconnected(session.uuid));
+        }
+        self.requests.retain(|(uuid, _)| *uuid != session.uuid);
+        if matches!(self.awaiting, Some((uuid, _)) if uuid == session.uuid) {
+            self.awaiting = None;
+        }
+    }
+
+    /// Handle a request that the Broker did not answer
+    fn unanswered(&mut self, uuid: Uuid, awaiting: Awaiting) {
+        let session = match self.sessions.iter_mut().find(|s| s.uuid == uuid) {
+            Some(session) => session,
+            None => return,
+        };
+
+        let message = match awaiting {
+            // e.g. the Broker has no room for another client
+            Awaiting::Registration => {
+                session.state = SessionState::Unregistered;
+                "Registration failed"
+            }
+            // e.g. the client has subscribed to too many paths
+            Awaiting::SubAck => "Subscription failed",
+        };
+
+        session
+            .send(&Event::Error {
+                message: message.into(),
+            })
+            .ok();
+    }
+
+    /// Handle a response from the Broker
+    fn respond(&mut self, resp: &Response) -> Result<(), ServerIoError> {
+        let awaiting = match self.awaiting {
+            Some((uuid, awaiting)) if uuid == resp.dest => Some(awaiting),
+            _ => None,
+        };
+
+        let session = self
+            .sessions
+            .iter_mut()
+            .find(|s| s.uuid == resp.dest)
+            .ok_or(ServerIoError::MisroutedResponse)?;
+
+        let mut answered = true;
+        let event = match &resp.msg {
+            Arbitrator::Control(AControl { response, .. }) => match response {
+                Ok(ControlResponse::ComponentRegistration(uuid)) => {
+                    session.state = SessionState::Registered;
+                    Event::Registered {
+                        uuid: uuid_string(uuid),
+                    }
+                }
+                Ok(ControlResponse::AuthChallenge(nonce)) => match &session.key {
+                    Some(key) => {
+                        let mac = auth_mac(key, nonce, &session.name);
+                        self.requests
+                            .push_front((session.uuid, Pending::Authenticate(mac)));
+                        return Ok(());
+                    }
+                    None => {
+                        session.state = SessionState::Unregistered;
+                        Event::Error {
+                            message: "Authentication required, but no key given".into(),
+                        }
+                    }
+                },
+                Err(ControlError::AuthenticationFailed) => {
+                    session.state = SessionState::Unregistered;
+                    Event::Error {
+                        message: "Authentication failed".into(),
+                    }
+                }
+                Err(ControlError::ResetConnection) => {
+                    session.state = SessionState::Unregistered;
+                    Event::Error {
+                        message: "Connection reset by the Broker".into(),
+                    }
+                }
+                _ => return Ok(()),
+            },
+            Arbitrator::PubSub(Ok(PubSubResponse::SubAck {
+                path: PubSubPath::Long(path),
+            })) => Event::Subscribed {
+                path: path.as_str(),
+            },
+            Arbitrator::PubSub(Err(PubSubError::NotAuthorized)) => {
+                answered = awaiting == Some(Awaiting::SubAck);
+                Event::Error {
+                    message: "Not authorized".into(),
+                }
+            }
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(sub)))
+            | Arbitrator::PubSub(Ok(PubSubResponse::BridgedSubMsg { msg: sub, .. })) => {
+                answered = false;
+
+                // Sessions never register shortcodes
+                let SubMsg { path, payload } = sub;
+                let path = match path {
+                    PubSubPath::Long(path) => path.as_str(),
+                    PubSubPath::Short(_) => return Ok(()),
+                };
+
+                // Payloads that do not match their schema are sent raw
+                let json = self.schemas.to_json(path, payload).and_then(Result::ok);
+                Event::Message {
+                    path,
+                    raw: if json.is_none() { Some(payload) } else { None },
+                    payload: json,
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        if answered && awaiting.is_some() {
+            self.awaiting = None;
+        }
+
+        // Sessions that can no longer be written to are closed once their
+        // reader ends
+        session.send(&event).ok();
+        Ok(())
+    }
+}
+
+impl Session {
+    fn send(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, event)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()
+    }
+}
+
+/// Format a Uuid as hexadecimal
+fn uuid_string(uuid: &Uuid) -> String {
+    uuid.as_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl HostedTransport for JsonGateway {
+    /// Accept new TCP sessions, and read commands from all sessions
+    ///
+    /// Failures of individual sessions are not reported, they are closed
+    /// and removed from the Broker.
+    fn poll(&mut self) -> Result<(), ServerIoError> {
+        self.accept();
+
+        let mut idx = 0;
+        while idx < self.sessions.len() {
+            if self.service(idx) {
+                idx += 1;
+            } else {
+                self.close(idx);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<HostEvent> {
+        self.events.pop_front()
+    }
+
+    fn routes_to(&self, uuid: &Uuid) -> bool {
+        self.sessions.iter().any(|s| &s.uuid == uuid)
+    }
+}
+
+impl ServerIoIn for JsonGateway {
+    /// Receive one request FROM a session
+    ///
+    /// Returns `Ok(None)` if no request is available, or if there are
+    /// events that have not yet been taken with `next_event`.
+    fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+        if !self.events.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some((uuid, awaiting)) = self.awaiting.take() {
+            self.unanswered(uuid, awaiting);
+        }
+
+        self.held = self.requests.pop_front();
+        let (source, pending) = match &self.held {
+            Some(held) => held,
+            None => return Ok(None),
+        };
+
+        let msg = match pending {
+            Pending::Register(name) => {
+                self.awaiting = Some((*source, Awaiting::Registration));
+                Component::Control(Control {
+                    seq: 0,
+                    ty: ControlType::RegisterComponent(ComponentInfo {
+                        name: Name::borrow_from_str(name),
+
+                        // Sessions do not report a version
+                        version: Version {
+                            major: 0,
+                            minor: 0,
+                            trivial: 0,
+                            misc: 0,
+                        },
+                    }),
+                })
+            }
+            Pending::Authenticate(mac) => {
+                self.awaiting = Some((*source, Awaiting::Registration));
+                Component::Control(Control {
+                    seq: 0,
+                    ty: ControlType::Authenticate { mac: *mac },
+                })
+            }
+            Pending::Sub(path) => {
+                self.awaiting = Some((*source, Awaiting::SubAck));
+                Component::PubSub(PubSub {
+                    path: PubSubPath::Long(Path::borrow_from_str(path)),
+                    ty: PubSubType::Sub,
+                })
+            }
+            Pending::Unsub(path) => Component::PubSub(PubSub {
+                path: PubSubPath::Long(Path::borrow_from_str(path)),
+                ty: PubSubType::Unsub,
+            }),
+            Pending::Pub { path, payload } => Component::PubSub(PubSub {
+                path: PubSubPath::Long(Path::borrow_from_str(path)),
+                ty: PubSubType::Pub { payload },
+            }),
+        };
+
+        Ok(Some(Request {
+            source: *source,
+            msg,
+        }))
+    }
+}
+
+impl<'resp> ServerIoOut<'resp> for JsonGateway {
+    /// Send one response TO a session
+    ///
+    /// Responses to unknown sessions are rejected.
+    fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+        self.respond(&resp)
+    }
+}
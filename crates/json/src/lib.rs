@@ -0,0 +1,225 @@
+//! # A JSON gateway for the Anachro Protocol
+//!
+//! This crate allows scripts to take part in the Pub/Sub plane of a Broker,
+//! without needing to encode postcard payloads themselves.
+//!
+//! The [`JsonGateway`](struct.JsonGateway.html) is serviced by a
+//! `BrokerHost` like any other transport. Each session, either a TCP
+//! connection or the standard input and output of the process, sends one
+//! JSON command per line:
+//!
+//! ```text
+//! {"cmd": "register", "name": "script"}
+//! {"cmd": "register", "name": "script", "key": "pre-shared key"}
+//! {"cmd": "subscribe", "path": "keyboard/#"}
+//! {"cmd": "unsubscribe", "path": "keyboard/#"}
+//! {"cmd": "publish", "path": "ident/led/keyboard", "payload": {"r": 255, "g": 0, "b": 0}}
+//! {"cmd": "publish", "path": "some/other/path", "raw": [1, 2, 3]}
+//! ```
+//!
+//! and receives one JSON event per line:
+//!
+//! ```text
+//! {"event": "registered", "uuid": "0123456789abcdef0123456789abcdef"}
+//! {"event": "subscribed", "path": "keyboard/#"}
+//! {"event": "unsubscribed", "path": "keyboard/#"}
+//! {"event": "message", "path": "keyboard/keypress/printable", "payload": {"character": "a"}}
+//! {"event": "message", "path": "some/other/path", "raw": [1, 2, 3]}
+//! {"event": "error", "message": "Not registered"}
+//! ```
+//!
+//! Payloads are translated between JSON and postcard using the
+//! [`Schemas`](struct.Schemas.html) of known paths, which are usually taken
+//! from the `pubsub_table!()`s shared with the firmware:
+//!
+//! ```rust,ignore
+//! let mut schemas = Schemas::new();
+//! schemas.add_table::<stargazer_icd::KeyboardTable>();
+//!
+//! let mut gateway = JsonGateway::new(schemas);
+//! gateway.listen("127.0.0.1:8081")?;
+//! host.add_transport(gateway);
+//! ```
+//!
+//! Messages on paths without a known schema, or that do not match their
+//! schema, are sent `raw`.
+
+mod gateway;
+mod protocol;
+mod schema;
+
+pub use crate::{
+    gateway::JsonGateway,
+    schema::{Schemas, TranslateError},
+};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anachro_client::pubsub_table;
+    use anachro_server::StdBrokerHost;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpStream,
+        sync::mpsc::{channel, Receiver},
+        thread::{sleep, spawn},
+        time::{Duration, Instant},
+    };
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct ColorMe {
+        pub r: u8,
+        pub g: u8,
+        pub b: u8,
+    }
+
+    pubsub_table! {
+        TestTable,
+        Subs => {
+            Color: "led/+/color" => ColorMe,
+        },
+        Pubs => {
+            Key: "keyboard/key" => char,
+        },
+    }
+
+    /// A session over TCP, with events read by a background thread
+    struct TestSession {
+        stream: TcpStream,
+        events: Receiver<Value>,
+    }
+
+    impl TestSession {
+        fn connect(gateway: &JsonGateway) -> Self {
+            let stream = TcpStream::connect(gateway.local_addr().unwrap()).unwrap();
+            let reader = stream.try_clone().unwrap();
+            let (tx, events) = channel();
+            spawn(move || {
+                for line in BufReader::new(reader).lines() {
+                    let event = serde_json::from_str(&line.unwrap()).unwrap();
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
+            });
+            TestSession { stream, events }
+        }
+
+        fn send(&mut self, cmd: Value) {
+            writeln!(self.stream, "{}", cmd).unwrap();
+        }
+
+        /// Poll the host until an event is received, or `timeout` has passed
+        fn wait(&self, host: &mut StdBrokerHost, timeout: Duration) -> Option<Value> {
+            let start = Instant::now();
+            while start.elapsed() < timeout {
+                host.poll().ok();
+                match self.events.try_recv() {
+                    Ok(event) => return Some(event),
+                    Err(_) => sleep(Duration::from_millis(1)),
+                }
+            }
+            None
+        }
+
+        fn expect(&self, host: &mut StdBrokerHost, expected: Value) {
+            let event = self.wait(host, Duration::from_secs(1));
+            assert_eq!(event, Some(expected));
+        }
+
+        fn register(&mut self, host: &mut StdBrokerHost, name: &str) {
+            self.send(json!({"cmd": "register", "name": name}));
+            let event = self.wait(host, Duration::from_secs(1)).unwrap();
+            assert_eq!(event["event"], "registered");
+        }
+    }
+
+    fn new_host() -> (StdBrokerHost, JsonGateway) {
+        let mut schemas = Schemas::new();
+        schemas.add_table::<TestTable>();
+
+        let mut gateway = JsonGateway::new(schemas);
+        gateway.listen("127.0.0.1:0").unwrap();
+        (StdBrokerHost::new(), gateway)
+    }
+
+    #[test]
+    fn translate() {
+        let (mut host, gateway) = new_host();
+        let mut publisher = TestSession::connect(&gateway);
+        let mut subscriber = TestSession::connect(&gateway);
+        host.add_transport(gateway);
+
+        publisher.register(&mut host, "publisher");
+        subscriber.register(&mut host, "subscriber");
+
+        for path in &["led/#", "keyboard/key", "raw/data"] {
+            subscriber.send(json!({"cmd": "subscribe", "path": path}));
+            subscriber.expect(&mut host, json!({"event": "subscribed", "path": path}));
+        }
+
+        // JSON payloads are translated to postcard, and back again
+        publisher.send(json!({
+            "cmd": "publish",
+            "path": "led/front/color",
+            "payload": {"r": 1, "g": 2, "b": 255},
+        }));
+        subscriber.expect(
+            &mut host,
+            json!({
+                "event": "message",
+                "path": "led/front/color",
+                "payload": {"r": 1, "g": 2, "b": 255},
+            }),
+        );
+
+        // Raw postcard payloads are translated to JSON
+        publisher.send(json!({"cmd": "publish", "path": "keyboard/key", "raw": [1, 0x61]}));
+        subscriber.expect(
+            &mut host,
+            json!({"event": "message", "path": "keyboard/key", "payload": "a"}),
+        );
+
+        // Payloads without a schema are passed through
+        publisher.send(json!({"cmd": "publish", "path": "raw/data", "raw": [1, 2, 3]}));
+        subscriber.expect(
+            &mut host,
+            json!({"event": "message", "path": "raw/data", "raw": [1, 2, 3]}),
+        );
+
+        subscriber.send(json!({"cmd": "unsubscribe", "path": "raw/data"}));
+        subscriber.expect(
+            &mut host,
+            json!({"event": "unsubscribed", "path": "raw/data"}),
+        );
+        publisher.send(json!({"cmd": "publish", "path": "raw/data", "raw": [4]}));
+        assert_eq!(subscriber.wait(&mut host, Duration::from_millis(100)), None);
+    }
+
+    #[test]
+    fn errors() {
+        let (mut host, gateway) = new_host();
+        let mut session = TestSession::connect(&gateway);
+        host.add_transport(gateway);
+
+        let is_error = |event: Option<Value>| event.unwrap()["event"] == "error";
+
+        session.send(json!({"cmd": "subscribe", "path": "led/#"}));
+        assert!(is_error(session.wait(&mut host, Duration::from_secs(1))));
+
+        session.register(&mut host, "errors");
+
+        session.send(json!({"cmd": "launch"}));
+        assert!(is_error(session.wait(&mut host, Duration::from_secs(1))));
+
+        // No schema for the path
+        session.send(json!({"cmd": "publish", "path": "raw/data", "payload": 3}));
+        assert!(is_error(session.wait(&mut host, Duration::from_secs(1))));
+
+        // Does not match the schema
+        session.send(json!({"cmd": "publish", "path": "led/a/color", "payload": {"r": 1}}));
+        assert!(is_error(session.wait(&mut host, Duration::from_secs(1))));
+    }
+}
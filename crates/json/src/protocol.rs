@@ -0,0 +1,62 @@
+//! The JSON messages exchanged with each session, one per line
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A command sent by a session
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub(crate) enum Command {
+    /// Register with the Broker, authenticating with `key` if required
+    Register {
+        name: String,
+        #[serde(default)]
+        key: Option<String>,
+    },
+    Subscribe {
+        path: String,
+    },
+    Unsubscribe {
+        path: String,
+    },
+
+    /// Publish either a JSON `payload`, translated using the schema of the
+    /// path, or a `raw` postcard payload
+    Publish {
+        path: String,
+        #[serde(default)]
+        payload: Option<Value>,
+        #[serde(default)]
+        raw: Option<Vec<u8>>,
+    },
+}
+
+/// An event sent to a session
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    Registered {
+        uuid: String,
+    },
+    Subscribed {
+        path: &'a str,
+    },
+    Unsubscribed {
+        path: &'a str,
+    },
+
+    /// A message received on a subscribed path
+    ///
+    /// Payloads are translated to JSON if the schema of the path is known,
+    /// and are sent `raw` otherwise.
+    Message {
+        path: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        payload: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        raw: Option<&'a [u8]>,
+    },
+    Error {
+        message: String,
+    },
+}
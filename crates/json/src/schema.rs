@@ -0,0 +1,99 @@
+use anachro_client::{anachro_icd::matches, Table, TableVisitor};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+/// An error translating a payload between JSON and postcard
+#[derive(Debug)]
+pub enum TranslateError {
+    Json(serde_json::Error),
+    Postcard(postcard::Error),
+}
+
+impl From<serde_json::Error> for TranslateError {
+    fn from(other: serde_json::Error) -> Self {
+        TranslateError::Json(other)
+    }
+}
+
+impl From<postcard::Error> for TranslateError {
+    fn from(other: postcard::Error) -> Self {
+        TranslateError::Postcard(other)
+    }
+}
+
+/// The payload type of a path
+struct Schema {
+    path: &'static str,
+    to_json: fn(&[u8]) -> Result<Value, TranslateError>,
+    from_json: fn(Value) -> Result<Vec<u8>, TranslateError>,
+}
+
+/// The payload types of known paths
+///
+/// Types are usually added a whole table at a time, using the tables
+/// shared by the firmware, e.g. `schemas.add_table::<KeyboardTable>()`.
+#[derive(Default)]
+pub struct Schemas {
+    schemas: Vec<Schema>,
+}
+
+impl Schemas {
+    /// Create an empty set of schemas
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the payload type of each path in a `pubsub_table!()`
+    pub fn add_table<T: Table>(&mut self) {
+        T::visit_types(self);
+    }
+
+    /// Add the payload type of a single path, which may contain wildcards
+    ///
+    /// If a type was already added for the same path, it is kept.
+    pub fn add<T: Serialize + DeserializeOwned>(&mut self, path: &'static str) {
+        if self.schemas.iter().any(|s| s.path == path) {
+            return;
+        }
+
+        self.schemas.push(Schema {
+            path,
+            to_json: to_json::<T>,
+            from_json: from_json::<T>,
+        });
+    }
+
+    /// Translate a postcard payload received on `path` to JSON
+    ///
+    /// Returns `None` if the payload type of the path is not known.
+    pub fn to_json(&self, path: &str, payload: &[u8]) -> Option<Result<Value, TranslateError>> {
+        self.find(path).map(|s| (s.to_json)(payload))
+    }
+
+    /// Translate a JSON value to a postcard payload to publish on `path`
+    ///
+    /// Returns `None` if the payload type of the path is not known.
+    pub fn from_json(&self, path: &str, value: Value) -> Option<Result<Vec<u8>, TranslateError>> {
+        self.find(path).map(|s| (s.from_json)(value))
+    }
+
+    fn find(&self, path: &str) -> Option<&Schema> {
+        self.schemas.iter().find(|s| matches(s.path, path))
+    }
+}
+
+impl TableVisitor for Schemas {
+    fn visit<T: Serialize + DeserializeOwned>(&mut self, path: &'static str) {
+        self.add::<T>(path);
+    }
+}
+
+fn to_json<T: Serialize + DeserializeOwned>(payload: &[u8]) -> Result<Value, TranslateError> {
+    let msg: T = postcard::from_bytes(payload)?;
+    Ok(serde_json::to_value(msg)?)
+}
+
+fn from_json<T: Serialize + DeserializeOwned>(value: Value) -> Result<Vec<u8>, TranslateError> {
+    let msg: T = serde_json::from_value(value)?;
+    Ok(postcard::to_stdvec(&msg)?)
+}
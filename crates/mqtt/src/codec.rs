@@ -0,0 +1,361 @@
+//! Encoding and decoding of the MQTT 3.1.1 control packets used by the
+//! gateway
+
+use std::str::from_utf8;
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// The protocol level of MQTT 3.1.1
+const PROTOCOL_LEVEL: u8 = 4;
+
+/// A CONNACK return code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectReturn {
+    Accepted = 0,
+    UnacceptableProtocol = 1,
+    ServerUnavailable = 3,
+    BadCredentials = 4,
+    NotAuthorized = 5,
+}
+
+/// The SUBACK return code for a rejected subscription
+pub(crate) const SUBACK_FAILURE: u8 = 0x80;
+
+/// A control packet sent by an MQTT client
+#[derive(Debug, PartialEq)]
+pub(crate) enum Packet<'a> {
+    Connect {
+        client_id: &'a str,
+        password: Option<&'a [u8]>,
+        keep_alive: u16,
+    },
+    Publish {
+        topic: &'a str,
+        qos: u8,
+        packet_id: Option<u16>,
+        payload: &'a [u8],
+    },
+    Subscribe {
+        packet_id: u16,
+        filters: Vec<&'a str>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<&'a str>,
+    },
+    PingReq,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CodecError {
+    /// The packet does not follow the MQTT 3.1.1 specification
+    Malformed,
+
+    /// The packet is larger than the maximum allowed size
+    TooLarge,
+
+    /// The client requested a protocol level other than MQTT 3.1.1
+    UnsupportedProtocol,
+
+    /// The packet is valid, but not supported by the gateway
+    Unsupported,
+}
+
+/// Attempt to decode one packet from the start of `buf`
+///
+/// Returns the packet, and the number of bytes it used, or `None` if
+/// `buf` does not yet contain a whole packet.
+pub(crate) fn decode(
+    buf: &[u8],
+    max_packet: usize,
+) -> Result<Option<(Packet<'_>, usize)>, CodecError> {
+    let header = match buf.first() {
+        Some(header) => *header,
+        None => return Ok(None),
+    };
+
+    // The remaining length is encoded in up to four bytes, seven bits at a time
+    let mut len = 0usize;
+    let mut used = 1;
+    loop {
+        let byte = match buf.get(used) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        len |= ((byte & 0x7F) as usize) << (7 * (used - 1));
+        used += 1;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if used > 4 {
+            return Err(CodecError::Malformed);
+        }
+    }
+
+    if used + len > max_packet {
+        return Err(CodecError::TooLarge);
+    }
+    if buf.len() < used + len {
+        return Ok(None);
+    }
+
+    let mut rdr = Reader(&buf[used..used + len]);
+    let flags = header & 0x0F;
+
+    let packet = match header >> 4 {
+        CONNECT => decode_connect(&mut rdr)?,
+        PUBLISH => {
+            let qos = (flags >> 1) & 0b11;
+            let topic = rdr.string()?;
+            let packet_id = match qos {
+                0 => None,
+                1 | 2 => Some(rdr.u16()?),
+                _ => return Err(CodecError::Malformed),
+            };
+            Packet::Publish {
+                topic,
+                qos,
+                packet_id,
+                payload: rdr.rest(),
+            }
+        }
+        SUBSCRIBE if flags == 0b0010 => {
+            let packet_id = rdr.u16()?;
+            let mut filters = Vec::new();
+            while !rdr.0.is_empty() {
+                filters.push(rdr.string()?);
+
+                // The requested QoS is ignored, only QoS 0 is granted
+                rdr.u8()?;
+            }
+            if filters.is_empty() {
+                return Err(CodecError::Malformed);
+            }
+            Packet::Subscribe { packet_id, filters }
+        }
+        UNSUBSCRIBE if flags == 0b0010 => {
+            let packet_id = rdr.u16()?;
+            let mut filters = Vec::new();
+            while !rdr.0.is_empty() {
+                filters.push(rdr.string()?);
+            }
+            if filters.is_empty() {
+                return Err(CodecError::Malformed);
+            }
+            Packet::Unsubscribe { packet_id, filters }
+        }
+        // The reserved flags of these packets are fixed
+        SUBSCRIBE | UNSUBSCRIBE => return Err(CodecError::Malformed),
+        PINGREQ => Packet::PingReq,
+        DISCONNECT => Packet::Disconnect,
+
+        // Acknowledgements of QoS 1 and 2 messages are never expected, as
+        // the gateway only sends QoS 0 messages
+        _ => return Err(CodecError::Unsupported),
+    };
+
+    Ok(Some((packet, used + len)))
+}
+
+fn decode_connect<'a>(rdr: &mut Reader<'a>) -> Result<Packet<'a>, CodecError> {
+    if rdr.string()? != "MQTT" {
+        return Err(CodecError::UnsupportedProtocol);
+    }
+    if rdr.u8()? != PROTOCOL_LEVEL {
+        return Err(CodecError::UnsupportedProtocol);
+    }
+
+    let flags = rdr.u8()?;
+    if flags & 0x01 != 0 {
+        return Err(CodecError::Malformed);
+    }
+    let keep_alive = rdr.u16()?;
+    let client_id = rdr.string()?;
+
+    // A will message is accepted, but never published
+    if flags & 0x04 != 0 {
+        rdr.string()?;
+        rdr.bytes()?;
+    }
+    if flags & 0x80 != 0 {
+        rdr.string()?;
+    }
+    let password = match flags & 0x40 {
+        0 => None,
+        _ => Some(rdr.bytes()?),
+    };
+
+    Ok(Packet::Connect {
+        client_id,
+        password,
+        keep_alive,
+    })
+}
+
+/// Reads the fields of a packet's variable header and payload
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if self.0.len() < len {
+            return Err(CodecError::Malformed);
+        }
+        let (now, later) = self.0.split_at(len);
+        self.0 = later;
+        Ok(now)
+    }
+
+    fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CodecError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], CodecError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Result<&'a str, CodecError> {
+        from_utf8(self.bytes()?).map_err(|_| CodecError::Malformed)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        self.take(self.0.len()).unwrap_or(&[])
+    }
+}
+
+/// Append the fixed header of a packet to `out`
+fn header(out: &mut Vec<u8>, ty: u8, flags: u8, mut len: usize) {
+    out.push((ty << 4) | flags);
+    loop {
+        let byte = (len & 0x7F) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn connack(out: &mut Vec<u8>, code: ConnectReturn) {
+    header(out, CONNACK, 0, 2);
+    out.extend_from_slice(&[0, code as u8]);
+}
+
+/// Append a QoS 0 PUBLISH packet to `out`
+pub(crate) fn publish(out: &mut Vec<u8>, topic: &str, payload: &[u8]) {
+    header(out, PUBLISH, 0, 2 + topic.len() + payload.len());
+    out.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+    out.extend_from_slice(topic.as_bytes());
+    out.extend_from_slice(payload);
+}
+
+pub(crate) fn puback(out: &mut Vec<u8>, packet_id: u16) {
+    header(out, PUBACK, 0, 2);
+    out.extend_from_slice(&packet_id.to_be_bytes());
+}
+
+pub(crate) fn suback(out: &mut Vec<u8>, packet_id: u16, codes: &[u8]) {
+    header(out, SUBACK, 0, 2 + codes.len());
+    out.extend_from_slice(&packet_id.to_be_bytes());
+    out.extend_from_slice(codes);
+}
+
+pub(crate) fn unsuback(out: &mut Vec<u8>, packet_id: u16) {
+    header(out, UNSUBACK, 0, 2);
+    out.extend_from_slice(&packet_id.to_be_bytes());
+}
+
+pub(crate) fn pingresp(out: &mut Vec<u8>) {
+    header(out, PINGRESP, 0, 0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAX: usize = 1024;
+
+    /// A packet with the given type, flags and body
+    fn packet(ty: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        header(&mut out, ty, flags, body.len());
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn remaining_length() {
+        assert_eq!(decode(&[], MAX), Ok(None));
+        assert_eq!(
+            decode(&[PINGREQ << 4, 0], MAX),
+            Ok(Some((Packet::PingReq, 2)))
+        );
+
+        // Truncated
+        assert_eq!(decode(&[PINGREQ << 4], MAX), Ok(None));
+        assert_eq!(decode(&[PUBLISH << 4, 0x80], MAX), Ok(None));
+        assert_eq!(decode(&[PUBLISH << 4, 0x80, 0x01], MAX), Ok(None));
+
+        // The length is encoded in at most four bytes
+        assert_eq!(
+            decode(&[PUBLISH << 4, 0x80, 0x80, 0x80, 0x80, 0x01], MAX),
+            Err(CodecError::Malformed)
+        );
+        assert_eq!(
+            decode(&[PUBLISH << 4, 0xFF, 0xFF, 0xFF, 0x7F], MAX),
+            Err(CodecError::TooLarge)
+        );
+    }
+
+    #[test]
+    fn subscribe() {
+        let body = [0, 7, 0, 3, b'a', b'/', b'b', 0];
+        let sub = packet(SUBSCRIBE, 0b0010, &body);
+        assert_eq!(
+            decode(&sub, MAX),
+            Ok(Some((
+                Packet::Subscribe {
+                    packet_id: 7,
+                    filters: vec!["a/b"],
+                },
+                sub.len()
+            )))
+        );
+
+        // Bad reserved flags
+        for flags in &[0b0000, 0b0011, 0b1010] {
+            let sub = packet(SUBSCRIBE, *flags, &body);
+            assert_eq!(decode(&sub, MAX), Err(CodecError::Malformed));
+            let unsub = packet(UNSUBSCRIBE, *flags, &body[..7]);
+            assert_eq!(decode(&unsub, MAX), Err(CodecError::Malformed));
+        }
+
+        // Empty filter lists
+        let sub = packet(SUBSCRIBE, 0b0010, &body[..2]);
+        assert_eq!(decode(&sub, MAX), Err(CodecError::Malformed));
+        let unsub = packet(UNSUBSCRIBE, 0b0010, &body[..2]);
+        assert_eq!(decode(&unsub, MAX), Err(CodecError::Malformed));
+
+        // A filter without its requested QoS
+        let sub = packet(SUBSCRIBE, 0b0010, &body[..7]);
+        assert_eq!(decode(&sub, MAX), Err(CodecError::Malformed));
+    }
+}
@@ -0,0 +1,584 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use anachro_server::{
+    anachro_icd::{
+        arbitrator::{Arbitrator, Control as AControl, ControlError, ControlResponse},
+        arbitrator::{PubSubError, PubSubResponse, SubMsg},
+        auth::{auth_mac, AUTH_MAC_LEN},
+        component::{Component, ComponentInfo, Control, ControlType, PubSub, PubSubType},
+    },
+    HostEvent, HostedTransport, Name, Path, PubSubPath, Request, Response, ServerIoError,
+    ServerIoIn, ServerIoOut, Uuid, Version,
+};
+use rand::random;
+
+use crate::codec::{self, CodecError, ConnectReturn, Packet, SUBACK_FAILURE};
+
+/// The largest packet that will be accepted from an MQTT client
+pub const MAX_PACKET: usize = 16 * 1024;
+
+/// The number of bytes that may be waiting to be sent to a single MQTT
+/// client. Messages for clients that are not keeping up are dropped.
+const MAX_PENDING_TX: usize = 64 * 1024;
+
+/// A request to be made to the Broker on behalf of an MQTT client
+enum Pending {
+    Register(String),
+    Authenticate([u8; AUTH_MAC_LEN]),
+    Sub(String),
+    Unsub(String),
+    Pub { topic: String, payload: Vec<u8> },
+}
+
+/// A request that the Broker is expected to answer
+#[derive(Clone, Copy, PartialEq)]
+enum Awaiting {
+    Registration,
+    SubAck,
+}
+
+#[derive(PartialEq)]
+enum ConnState {
+    /// Waiting for the CONNECT packet
+    AwaitingConnect,
+
+    /// Waiting for the Broker to accept the registration
+    Registering,
+
+    Connected,
+
+    /// The connection will be closed once all pending data has been sent
+    Closing,
+}
+
+/// A SUBSCRIBE packet, waiting for the Broker to answer each subscription
+struct PendingSubAck {
+    packet_id: u16,
+    codes: Vec<Option<u8>>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    uuid: Uuid,
+    name: String,
+    state: ConnState,
+    password: Option<Vec<u8>>,
+    keep_alive: Option<Duration>,
+    last_seen: Instant,
+    rx: Vec<u8>,
+    tx: Vec<u8>,
+    subacks: VecDeque<PendingSubAck>,
+}
+
+/// An MQTT 3.1.1 gateway, reaching many MQTT clients through a single
+/// TCP listener
+///
+/// Each MQTT client is registered with the Broker as a separate client,
+/// using its MQTT client identifier as its name. If the Broker requires
+/// authentication, the MQTT password is used as the client's pre-shared
+/// key.
+///
+/// The gateway is a `HostedTransport`, and should be serviced by a
+/// `BrokerHost`. As with other transports, at most one message is
+/// processed each time the host is polled.
+pub struct MqttGateway {
+    listener: TcpListener,
+    conns: Vec<Connection>,
+    events: VecDeque<HostEvent>,
+    requests: VecDeque<(Uuid, Pending)>,
+
+    /// The request most recently passed to the Broker
+    held: Option<(Uuid, Pending)>,
+
+    /// The client whose request is still waiting for an answer from the
+    /// Broker. If no answer arrived by the time the next request is
+    /// received, the request has failed.
+    awaiting: Option<(Uuid, Awaiting)>,
+
+    dropped_messages: usize,
+}
+
+impl MqttGateway {
+    /// Bind a non-blocking listener to `addr`
+    ///
+    /// MQTT uses port 1883 by default.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(MqttGateway {
+            listener,
+            conns: Vec::new(),
+            events: VecDeque::new(),
+            requests: VecDeque::new(),
+            held: None,
+            awaiting: None,
+            dropped_messages: 0,
+        })
+    }
+
+    /// The local address of the listener
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The number of MQTT clients currently connected
+    pub fn clients(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// The number of messages that have been dropped, due to an MQTT
+    /// client not receiving them quickly enough
+    pub fn dropped_messages(&self) -> usize {
+        self.dropped_messages
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (stream, _addr) = match self.listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+
+            if stream.set_nonblocking(true).is_err() {
+                continue;
+            }
+            stream.set_nodelay(true).ok();
+
+            let uuid_buf: u128 = random();
+            self.conns.push(Connection {
+                stream,
+                uuid: Uuid::from_bytes(uuid_buf.to_le_bytes()),
+                name: String::new(),
+                state: ConnState::AwaitingConnect,
+                password: None,
+                keep_alive: None,
+                last_seen: Instant::now(),
+                rx: Vec::new(),
+                tx: Vec::new(),
+                subacks: VecDeque::new(),
+            });
+        }
+    }
+
+    /// Read and handle all available packets from a connection, returning
+    /// whether the connection is still open
+    fn service(&mut self, idx: usize) -> bool {
+        let conn = &mut self.conns[idx];
+
+        if conn.flush().is_err() {
+            return false;
+        }
+        if conn.state == ConnState::Closing {
+            return !conn.tx.is_empty();
+        }
+
+        let mut buf = [0u8; 1024];
+        loop {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    conn.rx.extend_from_slice(&buf[..n]);
+                    conn.last_seen = Instant::now();
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+
+        // Packets are not handled until the client has been accepted by
+        // the Broker, which would otherwise reject its requests
+        while matches!(
+            conn.state,
+            ConnState::AwaitingConnect | ConnState::Connected
+        ) {
+            let (packet, used) = match codec::decode(&conn.rx, MAX_PACKET) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(CodecError::UnsupportedProtocol) => {
+                    codec::connack(&mut conn.tx, ConnectReturn::UnacceptableProtocol);
+                    conn.state = ConnState::Closing;
+                    break;
+                }
+                Err(_) => return false,
+            };
+
+            let connected = conn.state == ConnState::Connected;
+            let uuid = conn.uuid;
+
+            match packet {
+                Packet::Connect { .. } if connected => return false,
+                Packet::Connect {
+                    client_id,
+                    password,
+                    keep_alive,
+                } => {
+                    conn.password = password.map(<[u8]>::to_vec);
+                    conn.keep_alive = match keep_alive {
+                        0 => None,
+                        secs => Some(Duration::from_secs(secs as u64) * 3 / 2),
+                    };
+                    conn.name = client_name(client_id);
+                    conn.state = ConnState::Registering;
+
+                    self.events.push_back(HostEvent::Connected(uuid));
+                    self.requests
+                        .push_back((uuid, Pending::Register(conn.name.clone())));
+                }
+                _ if !connected => return false,
+                Packet::Publish {
+                    topic,
+                    qos,
+                    packet_id,
+                    payload,
+                } => {
+                    // Wildcards are not allowed in the topic of a PUBLISH
+                    if qos > 1 || topic.contains('#') || topic.contains('+') {
+                        return false;
+                    }
+                    if let Some(packet_id) = packet_id {
+                        codec::puback(&mut conn.tx, packet_id);
+                    }
+                    self.requests.push_back((
+                        uuid,
+                        Pending::Pub {
+                            topic: topic.to_string(),
+                            payload: payload.to_vec(),
+                        },
+                    ));
+                }
+                Packet::Subscribe { packet_id, filters } => {
+                    let mut codes = Vec::with_capacity(filters.len());
+                    for filter in filters {
+                        if Path::try_from_str(filter).is_err() {
+                            codes.push(Some(SUBACK_FAILURE));
+                        } else {
+                            codes.push(None);
+                            self.requests
+                                .push_back((uuid, Pending::Sub(filter.to_string())));
+                        }
+                    }
+                    conn.subacks.push_back(PendingSubAck { packet_id, codes });
+                    conn.complete_subacks();
+                }
+                Packet::Unsubscribe { packet_id, filters } => {
+                    for filter in filters {
+                        self.requests
+                            .push_back((uuid, Pending::Unsub(filter.to_string())));
+                    }
+
+                    // The Broker does not answer unsubscriptions
+                    codec::unsuback(&mut conn.tx, packet_id);
+                }
+                Packet::PingReq => codec::pingresp(&mut conn.tx),
+                Packet::Disconnect => return false,
+            }
+
+            conn.rx.drain(..used);
+        }
+
+        if let Some(keep_alive) = conn.keep_alive {
+            if conn.last_seen.elapsed() > keep_alive {
+                return false;
+            }
+        }
+
+        conn.flush().is_ok()
+    }
+
+    /// Remove a connection, and all of its requests
+    fn close(&mut self, idx: usize) {
+        let conn = self.conns.swap_remove(idx);
+        conn.stream.shutdown(Shutdown::Both).ok();
+
+        if conn.state != ConnState::AwaitingConnect {
+            self.events.push_back(HostEvent::Disconnected(conn.uuid));
+        }
+        self.requests.retain(|(uuid, _)| *uuid != conn.uuid);
+        if matches!(self.awaiting, Some((uuid, _)) if uuid == conn.uuid) {
+            self.awaiting = None;
+        }
+    }
+
+    fn conn_mut(&mut self, uuid: &Uuid) -> Option<&mut Connection> {
+        self.conns.iter_mut().find(|c| &c.uuid == uuid)
+    }
+
+    /// Handle a request that the Broker did not answer
+    fn unanswered(&mut self, uuid: Uuid, awaiting: Awaiting) {
+        let conn = match self.conn_mut(&uuid) {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        match awaiting {
+            // e.g. the Broker has no room for another client
+            Awaiting::Registration => {
+                codec::connack(&mut conn.tx, ConnectReturn::ServerUnavailable);
+                conn.state = ConnState::Closing;
+            }
+            // e.g. the client has subscribed to too many topics
+            Awaiting::SubAck => conn.record_suback(SUBACK_FAILURE),
+        }
+    }
+
+    /// Handle a response from the Broker, returning whether it was
+    /// delivered
+    fn respond(&mut self, resp: &Response) -> Result<bool, ServerIoError> {
+        let awaiting = match self.awaiting {
+            Some((uuid, awaiting)) if uuid == resp.dest => Some(awaiting),
+            _ => None,
+        };
+
+        let conn = self
+            .conns
+            .iter_mut()
+            .find(|c| c.uuid == resp.dest)
+            .ok_or(ServerIoError::MisroutedResponse)?;
+
+        let mut answered = true;
+        match &resp.msg {
+            Arbitrator::Control(AControl { response, .. }) => match response {
+                Ok(ControlResponse::ComponentRegistration(_)) => {
+                    codec::connack(&mut conn.tx, ConnectReturn::Accepted);
+                    conn.state = ConnState::Connected;
+                }
+                Ok(ControlResponse::AuthChallenge(nonce)) => match &conn.password {
+                    Some(key) => {
+                        let mac = auth_mac(key, nonce, &conn.name);
+                        self.requests
+                            .push_front((conn.uuid, Pending::Authenticate(mac)));
+                    }
+                    None => {
+                        codec::connack(&mut conn.tx, ConnectReturn::NotAuthorized);
+                        conn.state = ConnState::Closing;
+                    }
+                },
+                Err(ControlError::AuthenticationFailed) => {
+                    let code = match conn.password {
+                        Some(_) => ConnectReturn::BadCredentials,
+                        None => ConnectReturn::NotAuthorized,
+                    };
+                    codec::connack(&mut conn.tx, code);
+                    conn.state = ConnState::Closing;
+                }
+                Err(ControlError::ResetConnection) => conn.state = ConnState::Closing,
+                _ => answered = false,
+            },
+            Arbitrator::PubSub(Ok(PubSubResponse::SubAck { .. })) => conn.record_suback(0),
+            Arbitrator::PubSub(Err(PubSubError::NotAuthorized))
+                if awaiting == Some(Awaiting::SubAck) =>
+            {
+                conn.record_suback(SUBACK_FAILURE)
+            }
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(msg)))
+            | Arbitrator::PubSub(Ok(PubSubResponse::BridgedSubMsg { msg, .. })) => {
+                answered = false;
+
+                // MQTT clients never register shortcodes
+                let SubMsg { path, payload } = msg;
+                let topic = match path {
+                    PubSubPath::Long(path) => path.as_str(),
+                    PubSubPath::Short(_) => return Ok(false),
+                };
+
+                if conn.tx.len() > MAX_PENDING_TX {
+                    return Ok(false);
+                }
+                codec::publish(&mut conn.tx, topic, payload);
+            }
+
+            // Denied publishes can not be reported with MQTT 3.1.1
+            _ => answered = false,
+        }
+
+        if answered && awaiting.is_some() {
+            self.awaiting = None;
+        }
+
+        let conn = self
+            .conn_mut(&resp.dest)
+            .ok_or(ServerIoError::MisroutedResponse)?;
+        if conn.flush().is_err() {
+            conn.state = ConnState::Closing;
+        }
+
+        Ok(true)
+    }
+}
+
+impl Connection {
+    /// Send as much pending data as possible without blocking
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.tx.is_empty() {
+            match self.stream.write(&self.tx) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => drop(self.tx.drain(..n)),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the Broker's answer to the next pending subscription
+    fn record_suback(&mut self, code: u8) {
+        let slot = self
+            .subacks
+            .iter_mut()
+            .flat_map(|s| s.codes.iter_mut())
+            .find(|c| c.is_none());
+
+        if let Some(slot) = slot {
+            *slot = Some(code);
+        }
+
+        self.complete_subacks();
+    }
+
+    /// Send a SUBACK for each SUBSCRIBE that has been fully answered
+    fn complete_subacks(&mut self) {
+        while let Some(pending) = self.subacks.front() {
+            let codes: Option<Vec<u8>> = pending.codes.iter().copied().collect();
+            match codes {
+                Some(codes) => {
+                    codec::suback(&mut self.tx, pending.packet_id, &codes);
+                    self.subacks.pop_front();
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Fit an MQTT client identifier into an Anachro client name
+fn client_name(client_id: &str) -> String {
+    if client_id.is_empty() {
+        return "mqtt".to_string();
+    }
+
+    let mut end = client_id.len();
+    while !client_id.is_char_boundary(end) || Name::try_from_str(&client_id[..end]).is_err() {
+        end -= 1;
+    }
+    client_id[..end].to_string()
+}
+
+impl HostedTransport for MqttGateway {
+    /// Accept new MQTT clients, and read packets from all connected clients
+    ///
+    /// Failures of individual MQTT clients are not reported, they are
+    /// disconnected and removed from the Broker.
+    fn poll(&mut self) -> Result<(), ServerIoError> {
+        self.accept();
+
+        let mut idx = 0;
+        while idx < self.conns.len() {
+            if self.service(idx) {
+                idx += 1;
+            } else {
+                self.close(idx);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<HostEvent> {
+        self.events.pop_front()
+    }
+
+    fn routes_to(&self, uuid: &Uuid) -> bool {
+        self.conns.iter().any(|c| &c.uuid == uuid)
+    }
+}
+
+impl ServerIoIn for MqttGateway {
+    /// Receive one request FROM an MQTT client
+    ///
+    /// Returns `Ok(None)` if no request is available, or if there are
+    /// events that have not yet been taken with `next_event`.
+    fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+        if !self.events.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some((uuid, awaiting)) = self.awaiting.take() {
+            self.unanswered(uuid, awaiting);
+        }
+
+        self.held = self.requests.pop_front();
+        let (source, pending) = match &self.held {
+            Some(held) => held,
+            None => return Ok(None),
+        };
+
+        let msg = match pending {
+            Pending::Register(name) => {
+                self.awaiting = Some((*source, Awaiting::Registration));
+                Component::Control(Control {
+                    seq: 0,
+                    ty: ControlType::RegisterComponent(ComponentInfo {
+                        name: Name::borrow_from_str(name),
+
+                        // MQTT clients do not report a version
+                        version: Version {
+                            major: 0,
+                            minor: 0,
+                            trivial: 0,
+                            misc: 0,
+                        },
+                    }),
+                })
+            }
+            Pending::Authenticate(mac) => {
+                self.awaiting = Some((*source, Awaiting::Registration));
+                Component::Control(Control {
+                    seq: 0,
+                    ty: ControlType::Authenticate { mac: *mac },
+                })
+            }
+            Pending::Sub(filter) => {
+                self.awaiting = Some((*source, Awaiting::SubAck));
+                Component::PubSub(PubSub {
+                    path: PubSubPath::Long(Path::borrow_from_str(filter)),
+                    ty: PubSubType::Sub,
+                })
+            }
+            Pending::Unsub(filter) => Component::PubSub(PubSub {
+                path: PubSubPath::Long(Path::borrow_from_str(filter)),
+                ty: PubSubType::Unsub,
+            }),
+            Pending::Pub { topic, payload } => Component::PubSub(PubSub {
+                path: PubSubPath::Long(Path::borrow_from_str(topic)),
+                ty: PubSubType::Pub { payload },
+            }),
+        };
+
+        Ok(Some(Request {
+            source: *source,
+            msg,
+        }))
+    }
+}
+
+impl<'resp> ServerIoOut<'resp> for MqttGateway {
+    /// Send one response TO an MQTT client
+    ///
+    /// Messages that can not be sent without blocking are dropped, as
+    /// allowed for QoS 0. Responses to unknown clients are rejected.
+    fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+        if !self.respond(&resp)? {
+            self.dropped_messages += 1;
+        }
+        Ok(())
+    }
+}
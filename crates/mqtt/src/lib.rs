@@ -0,0 +1,184 @@
+//! # An MQTT gateway for the Anachro Protocol
+//!
+//! This crate allows standard MQTT 3.1.1 clients, such as `mosquitto_sub`
+//! and `mosquitto_pub`, to take part in the Pub/Sub plane of a Broker. This
+//! is intended for observing and injecting traffic from a development
+//! machine.
+//!
+//! The [`MqttGateway`](struct.MqttGateway.html) listens for TCP connections,
+//! and is serviced by a `BrokerHost` like any other transport. Each MQTT
+//! client is registered with the Broker as a separate client.
+//!
+//! Packets are mapped to the Pub/Sub plane as follows:
+//!
+//! * CONNECT registers the client, answering the Broker's authentication
+//!   challenge with the MQTT password, if one is required
+//! * SUBSCRIBE and UNSUBSCRIBE subscribe and unsubscribe to each topic
+//!   filter. MQTT and Anachro share the same wildcards.
+//! * PUBLISH publishes the payload as-is. Payloads are not translated, so
+//!   they contain the raw postcard serialized bytes in both directions.
+//!
+//! Only QoS 0 is supported. Subscriptions are always granted at QoS 0, and
+//! QoS 1 publishes are acknowledged as soon as they are received. QoS 2,
+//! retained messages, and will messages are not supported.
+
+mod codec;
+mod gateway;
+
+pub use crate::gateway::{MqttGateway, MAX_PACKET};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anachro_server::{anachro_icd::auth::AUTH_NONCE_LEN, AuthConfig, StdBrokerHost};
+    use rumqttc::{
+        Client, ConnectReturnCode, ConnectionError, Event, Incoming, MqttOptions, QoS,
+        SubscribeReasonCode,
+    };
+    use std::{
+        sync::mpsc::{channel, Receiver},
+        thread::{sleep, spawn},
+        time::{Duration, Instant},
+    };
+
+    /// An MQTT client, with its connection driven by a background thread
+    struct TestClient {
+        client: Client,
+        events: Receiver<Result<Event, ConnectionError>>,
+    }
+
+    fn connect(gateway: &MqttGateway, id: &str, password: Option<&str>) -> TestClient {
+        let port = gateway.local_addr().unwrap().port();
+        let mut opts = MqttOptions::new(id, "127.0.0.1", port);
+        opts.set_keep_alive(Duration::from_secs(5));
+        if let Some(password) = password {
+            opts.set_credentials(id, password);
+        }
+
+        let (client, mut connection) = Client::new(opts, 16);
+        let (tx, events) = channel();
+        spawn(move || {
+            for evt in connection.iter() {
+                let failed = evt.is_err();
+                if tx.send(evt).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        TestClient { client, events }
+    }
+
+    /// Poll the host until the client receives a packet, or `timeout` has
+    /// passed
+    fn wait(
+        host: &mut StdBrokerHost,
+        client: &TestClient,
+        timeout: Duration,
+    ) -> Option<Result<Incoming, ConnectionError>> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            host.poll().ok();
+
+            match client.events.try_recv() {
+                Ok(Ok(Event::Incoming(Incoming::PingResp))) => {}
+                Ok(Ok(Event::Incoming(packet))) => return Some(Ok(packet)),
+                Ok(Ok(Event::Outgoing(_))) => {}
+                Ok(Err(e)) => return Some(Err(e)),
+                Err(_) => sleep(Duration::from_millis(1)),
+            }
+        }
+        None
+    }
+
+    fn wait_connack(host: &mut StdBrokerHost, client: &TestClient) {
+        match wait(host, client, Duration::from_secs(1)) {
+            Some(Ok(Incoming::ConnAck(ack))) => assert_eq!(ack.code, ConnectReturnCode::Success),
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+    }
+
+    fn new_host() -> (StdBrokerHost, MqttGateway) {
+        let gateway = MqttGateway::bind("127.0.0.1:0").unwrap();
+        let host = StdBrokerHost::new();
+        (host, gateway)
+    }
+
+    #[test]
+    fn publish_subscribe() {
+        let (mut host, gateway) = new_host();
+        let publisher = connect(&gateway, "publisher", None);
+        let subscriber = connect(&gateway, "subscriber", None);
+        host.add_transport(gateway);
+
+        wait_connack(&mut host, &publisher);
+        wait_connack(&mut host, &subscriber);
+
+        subscriber
+            .client
+            .subscribe("sensors/+/temp", QoS::AtMostOnce)
+            .unwrap();
+        match wait(&mut host, &subscriber, Duration::from_secs(1)) {
+            Some(Ok(Incoming::SubAck(ack))) => assert_eq!(
+                ack.return_codes,
+                [SubscribeReasonCode::Success(QoS::AtMostOnce)]
+            ),
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+
+        publisher
+            .client
+            .publish("sensors/1/temp", QoS::AtMostOnce, false, vec![0x01, 0xFF])
+            .unwrap();
+        match wait(&mut host, &subscriber, Duration::from_secs(1)) {
+            Some(Ok(Incoming::Publish(msg))) => {
+                assert_eq!(msg.topic, "sensors/1/temp");
+                assert_eq!(&msg.payload[..], &[0x01, 0xFF]);
+            }
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+
+        subscriber.client.unsubscribe("sensors/+/temp").unwrap();
+        match wait(&mut host, &subscriber, Duration::from_secs(1)) {
+            Some(Ok(Incoming::UnsubAck(_))) => {}
+            other => panic!("Unexpected packet: {:?}", other),
+        }
+
+        publisher
+            .client
+            .publish("sensors/1/temp", QoS::AtMostOnce, false, vec![0x02])
+            .unwrap();
+        assert!(wait(&mut host, &subscriber, Duration::from_millis(100)).is_none());
+    }
+
+    #[test]
+    fn authentication() {
+        fn nonce() -> [u8; AUTH_NONCE_LEN] {
+            rand::random()
+        }
+
+        let (mut host, gateway) = new_host();
+        host.broker_mut().set_auth(Some(AuthConfig {
+            keys: &[("alice", b"correct horse")],
+            nonce,
+        }));
+
+        let good = connect(&gateway, "alice", Some("correct horse"));
+        let bad = connect(&gateway, "alice", Some("battery staple"));
+        let unknown = connect(&gateway, "mallory", None);
+        host.add_transport(gateway);
+
+        wait_connack(&mut host, &good);
+        for (client, code) in &[
+            (bad, ConnectReturnCode::BadUserNamePassword),
+            (unknown, ConnectReturnCode::NotAuthorized),
+        ] {
+            match wait(&mut host, client, Duration::from_secs(1)) {
+                Some(Err(ConnectionError::ConnectionRefused(refused))) => {
+                    assert_eq!(refused, *code)
+                }
+                other => panic!("Unexpected packet: {:?}", other),
+            }
+        }
+    }
+}
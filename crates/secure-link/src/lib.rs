@@ -0,0 +1,882 @@
+//! # Secure links for the Anachro Protocol
+//!
+//! This crate provides [`SecureLink`](struct.SecureLink.html), which wraps
+//! any transport that carries frames of bytes, such as a COBS framed serial
+//! port or TCP stream, and encrypts and authenticates each message sent
+//! over it. It is intended for links that may be reached by untrusted
+//! parties, and can be used in `no_std` environments.
+//!
+//! Both ends of a link share a pre-shared key. When the link comes up:
+//!
+//! 1. The Client sends a Hello frame, containing a fresh nonce
+//! 2. The Broker answers with an Accept frame, containing a fresh nonce of
+//!    its own, along with the Client's nonce
+//! 3. Both sides derive one session key for each direction, using
+//!    HMAC-SHA256 over the pre-shared key and both nonces
+//!
+//! Each message is then sent as a Data frame, encrypted and authenticated
+//! with ChaCha20-Poly1305. Every frame carries a counter, which is used as
+//! the AEAD nonce, and must increase with each frame, so recorded frames
+//! can not be replayed. Frames that fail authentication are discarded.
+//!
+//! If the Broker receives a Data frame without a session (e.g. after a
+//! reboot), it answers with a Reset frame, and the Client starts a new
+//! handshake. Each new session is reported to a `BrokerHost` as a
+//! `HostEvent::Connected`, which resets the Client's state in the Broker.
+//!
+//! Note that link management frames are not authenticated. An attacker
+//! on the link can prevent a session from being established, but can not
+//! read or forge messages. A Hello received while a session is established
+//! does not replace that session until the Client sends its first Data
+//! frame under the new one.
+
+#![no_std]
+
+use anachro_client::{
+    anachro_icd::{arbitrator::Arbitrator, component::Component},
+    ClientIo, ClientIoError,
+};
+use anachro_server::{
+    HostEvent, HostedTransport, Request, Response, ServerIoError, ServerIoIn, ServerIoOut, Uuid,
+};
+use chacha20poly1305::{
+    aead::{AeadInPlace, NewAead},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use groundhog::{Deadline, RollingDuration, RollingTimer};
+use hmac::{Hmac, Mac, NewMac};
+use postcard::{from_bytes, to_slice};
+use serde::Serialize;
+use sha2::Sha256;
+
+/// The length of the nonces exchanged when a link comes up
+pub const LINK_NONCE_LEN: usize = 16;
+
+/// The number of bytes each Data frame adds to the serialized message
+pub const OVERHEAD: usize = DATA_HEADER_LEN + TAG_LEN;
+
+const KIND_HELLO: u8 = 0x01;
+const KIND_ACCEPT: u8 = 0x02;
+const KIND_DATA: u8 = 0x03;
+const KIND_RESET: u8 = 0x04;
+
+const HELLO_LEN: usize = 1 + LINK_NONCE_LEN;
+const ACCEPT_LEN: usize = 1 + (2 * LINK_NONCE_LEN);
+
+/// The kind, followed by the counter as a little endian u64
+const DATA_HEADER_LEN: usize = 9;
+const TAG_LEN: usize = 16;
+
+/// How long a Client waits for an Accept before resending its Hello
+const HELLO_RETRY_US: u32 = 500_000;
+
+/// The Error type of the `FrameIo` interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// There is currently no room to send the frame
+    Full,
+
+    /// The frame is larger than the transport is able to carry
+    TooLarge,
+
+    /// The transport has been closed, or has failed
+    Disconnected,
+}
+
+impl From<FrameError> for ClientIoError {
+    fn from(other: FrameError) -> Self {
+        match other {
+            FrameError::Full => ClientIoError::OutputFull,
+            FrameError::TooLarge => ClientIoError::MessageTooLarge,
+            FrameError::Disconnected => ClientIoError::Disconnected,
+        }
+    }
+}
+
+impl From<FrameError> for ServerIoError {
+    fn from(other: FrameError) -> Self {
+        match other {
+            FrameError::Full | FrameError::TooLarge => ServerIoError::ResponsePushFailed,
+            FrameError::Disconnected => ServerIoError::Disconnected,
+        }
+    }
+}
+
+/// A transport that carries frames of bytes, such as a COBS framed stream
+///
+/// The transport is responsible for delimiting frames, but does not need
+/// to detect corrupted frames, as they will fail authentication.
+pub trait FrameIo {
+    /// Send one frame
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), FrameError>;
+
+    /// Receive one frame, if one is available
+    fn recv_frame(&mut self) -> Result<Option<&[u8]>, FrameError>;
+}
+
+/// The pre-shared key of a link
+#[derive(Clone, Copy)]
+pub struct LinkConfig {
+    /// The key shared by both ends of the link
+    pub key: &'static [u8],
+
+    /// Produce a nonce for a new handshake
+    ///
+    /// Nonces should be taken from a random number generator. If a side
+    /// of the link ever repeats a nonce, the same session keys may be used
+    /// twice, which allows messages to be decrypted.
+    pub nonce: fn() -> [u8; LINK_NONCE_LEN],
+}
+
+/// The reasons a message could not be sent
+enum SendError {
+    Serialize,
+    NoSession,
+    Frame(FrameError),
+}
+
+/// Link management frames sent by a Broker
+enum Reply {
+    Accept([u8; ACCEPT_LEN]),
+    Reset,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Role {
+    Client,
+    Server,
+}
+
+enum LinkState<RT: RollingTimer> {
+    /// No session, and no handshake in progress
+    Idle,
+
+    /// A Client waiting for an answer to its Hello, until the deadline
+    Hello(Deadline<RT>),
+
+    Established(Session),
+}
+
+struct Session {
+    tx: ChaCha20Poly1305,
+    rx: ChaCha20Poly1305,
+    tx_ctr: u64,
+
+    /// The lowest counter that will be accepted
+    rx_next: u64,
+}
+
+/// An encrypted and authenticated Anachro transport over a `FrameIo`
+///
+/// `SecureLink` implements `ClientIo` for use by a Client, as well as
+/// `ServerIoIn`, `ServerIoOut` and `HostedTransport` for use by a Broker.
+/// When used by a Broker, each link represents a single Client, identified
+/// by the `Uuid` given at creation.
+///
+/// Messages are serialized into, and decrypted into, a buffer of `N`
+/// bytes. Messages larger than `N - OVERHEAD` bytes once serialized can
+/// be neither sent nor received.
+///
+/// A Client starts the handshake the first time the link is used. Until
+/// the handshake completes, sending fails with `ClientIoError::OutputFull`.
+pub struct SecureLink<F: FrameIo, RT: RollingTimer<Tick = u32>, const N: usize> {
+    inner: F,
+    role: Role,
+    uuid: Uuid,
+    config: LinkConfig,
+    timer: RT,
+    state: LinkState<RT>,
+
+    /// The nonce of the Client's most recent Hello
+    hello: [u8; LINK_NONCE_LEN],
+
+    /// A session offered by a Broker in answer to a Hello, while another
+    /// session was established. It replaces that session once the Client
+    /// uses it.
+    pending: Option<Session>,
+
+    /// Has a session been established, that has not been reported as a
+    /// `HostEvent` yet?
+    connected: bool,
+
+    dropped_frames: usize,
+    buf: [u8; N],
+}
+
+impl<F: FrameIo, RT: RollingTimer<Tick = u32>, const N: usize> SecureLink<F, RT, N> {
+    /// Create a new link for use by a Client
+    pub fn new(inner: F, config: LinkConfig, timer: RT) -> Self {
+        Self::with_role(
+            inner,
+            Role::Client,
+            Uuid::from_bytes([0u8; 16]),
+            config,
+            timer,
+        )
+    }
+
+    /// Create a new link for use by a Broker
+    ///
+    /// All requests received on this link will be reported as coming from
+    /// `uuid`, and only responses to `uuid` will be accepted.
+    pub fn new_server(inner: F, uuid: Uuid, config: LinkConfig, timer: RT) -> Self {
+        Self::with_role(inner, Role::Server, uuid, config, timer)
+    }
+
+    fn with_role(inner: F, role: Role, uuid: Uuid, config: LinkConfig, timer: RT) -> Self {
+        Self {
+            inner,
+            role,
+            uuid,
+            config,
+            timer,
+            state: LinkState::Idle,
+            hello: [0u8; LINK_NONCE_LEN],
+            pending: None,
+            connected: false,
+            dropped_frames: 0,
+            buf: [0u8; N],
+        }
+    }
+
+    /// The Uuid of the Client on the other side of this link
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Has the handshake completed?
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, LinkState::Established(_))
+    }
+
+    /// Discard the current session
+    ///
+    /// A Client will start a new handshake the next time the link is used.
+    /// A Broker will wait for the Client to start one.
+    pub fn restart(&mut self) {
+        self.state = LinkState::Idle;
+        self.pending = None;
+    }
+
+    /// The number of incoming frames that have been discarded, due to
+    /// being malformed, failing authentication, or being replayed
+    pub fn dropped_frames(&self) -> usize {
+        self.dropped_frames
+    }
+
+    /// Obtain a reference to the underlying transport
+    pub fn get_ref(&self) -> &F {
+        &self.inner
+    }
+
+    /// Obtain a mutable reference to the underlying transport
+    ///
+    /// Frames sent or received directly will not be encrypted.
+    pub fn get_mut(&mut self) -> &mut F {
+        &mut self.inner
+    }
+
+    /// Release the underlying transport
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Start a Client's handshake, or resend its Hello if it has not been
+    /// answered in time
+    fn handshake(&mut self) -> Result<(), FrameError> {
+        let send = match &self.state {
+            LinkState::Idle => {
+                self.hello = (self.config.nonce)();
+                true
+            }
+            LinkState::Hello(deadline) => deadline.is_expired(&self.timer),
+            LinkState::Established(_) => false,
+        };
+
+        if !send {
+            return Ok(());
+        }
+
+        let mut frame = [0u8; HELLO_LEN];
+        frame[0] = KIND_HELLO;
+        frame[1..].copy_from_slice(&self.hello);
+
+        let retry = RollingDuration::from_micros(HELLO_RETRY_US);
+        self.state = LinkState::Hello(Deadline::new(&self.timer, retry));
+
+        match self.inner.send_frame(&frame) {
+            // The Hello will be resent once the deadline expires
+            Ok(()) | Err(FrameError::Full) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Serialize, encrypt and send a single message
+    fn send_msg<M: Serialize + ?Sized>(&mut self, msg: &M) -> Result<(), SendError> {
+        let session = match &mut self.state {
+            LinkState::Established(session) => session,
+            _ => return Err(SendError::NoSession),
+        };
+
+        let body = self
+            .buf
+            .get_mut(DATA_HEADER_LEN..N.saturating_sub(TAG_LEN))
+            .ok_or(SendError::Frame(FrameError::TooLarge))?;
+
+        let len = match to_slice(msg, body) {
+            Ok(used) => used.len(),
+            Err(postcard::Error::SerializeBufferFull) => {
+                return Err(SendError::Frame(FrameError::TooLarge))
+            }
+            Err(_) => return Err(SendError::Serialize),
+        };
+
+        let len = session
+            .seal(&mut self.buf, len)
+            .ok_or(SendError::Frame(FrameError::TooLarge))?;
+
+        self.inner
+            .send_frame(&self.buf[..len])
+            .map_err(SendError::Frame)
+    }
+
+    /// Receive a single frame, handling any link management frames.
+    ///
+    /// Returns the length of the message decrypted into `self.buf`, if a
+    /// Data frame was received.
+    fn recv_plaintext(&mut self) -> Result<Option<usize>, FrameError> {
+        let frame = match self.inner.recv_frame()? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+
+        let kind = frame.first().copied();
+        let reply = match (self.role, kind) {
+            (_, Some(KIND_DATA)) => {
+                let established = match &mut self.state {
+                    LinkState::Established(session) => match session.open(frame, &mut self.buf) {
+                        Some(len) => return Ok(Some(len)),
+                        None => true,
+                    },
+                    _ => false,
+                };
+
+                // The Client confirms a new session by sending a message
+                // under it
+                if let Some(mut session) = self.pending.take() {
+                    match session.open(frame, &mut self.buf) {
+                        Some(len) => {
+                            defmt::info!("SecureLink: New session");
+                            self.state = LinkState::Established(session);
+                            self.connected = true;
+                            return Ok(Some(len));
+                        }
+                        None => self.pending = Some(session),
+                    }
+                }
+
+                self.dropped_frames += 1;
+                match (established, self.role) {
+                    (true, _) => {
+                        defmt::warn!("SecureLink: Rejected frame");
+                        None
+                    }
+                    (false, Role::Server) => Some(Reply::Reset),
+                    (false, Role::Client) => None,
+                }
+            }
+            (Role::Server, Some(KIND_HELLO)) if frame.len() == HELLO_LEN => {
+                let mut client = [0u8; LINK_NONCE_LEN];
+                client.copy_from_slice(&frame[1..]);
+                let server = (self.config.nonce)();
+                let session = Session::new(self.config.key, Role::Server, &client, &server);
+
+                // Anyone on the link can send a Hello, so an established
+                // session is kept until the Client uses the new one
+                if self.is_established() {
+                    self.pending = Some(session);
+                } else {
+                    defmt::info!("SecureLink: New session");
+                    self.state = LinkState::Established(session);
+                    self.connected = true;
+                }
+
+                let mut accept = [0u8; ACCEPT_LEN];
+                accept[0] = KIND_ACCEPT;
+                accept[1..][..LINK_NONCE_LEN].copy_from_slice(&server);
+                accept[1 + LINK_NONCE_LEN..].copy_from_slice(&client);
+                Some(Reply::Accept(accept))
+            }
+            // NOTE: Accepts are handled even with a session established, as
+            // the Broker starts a new session for each Hello it receives,
+            // including any that were resent.
+            (Role::Client, Some(KIND_ACCEPT))
+                if frame.len() == ACCEPT_LEN && frame[1 + LINK_NONCE_LEN..] == self.hello =>
+            {
+                let mut server = [0u8; LINK_NONCE_LEN];
+                server.copy_from_slice(&frame[1..][..LINK_NONCE_LEN]);
+
+                self.state = LinkState::Established(Session::new(
+                    self.config.key,
+                    Role::Client,
+                    &self.hello,
+                    &server,
+                ));
+                None
+            }
+            (Role::Client, Some(KIND_RESET)) if frame.len() == 1 => {
+                defmt::warn!("SecureLink: Session reset by Broker");
+                self.state = LinkState::Idle;
+                None
+            }
+            _ => {
+                self.dropped_frames += 1;
+                None
+            }
+        };
+
+        let sent = match reply {
+            Some(Reply::Accept(accept)) => self.inner.send_frame(&accept),
+            Some(Reply::Reset) => self.inner.send_frame(&[KIND_RESET]),
+            None => Ok(()),
+        };
+
+        // Lost replies are recovered by the Client resending its Hello
+        match sent {
+            Err(FrameError::Disconnected) => Err(FrameError::Disconnected),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Session {
+    fn new(
+        key: &[u8],
+        role: Role,
+        client: &[u8; LINK_NONCE_LEN],
+        server: &[u8; LINK_NONCE_LEN],
+    ) -> Self {
+        let to_server = derive_key(key, b"anachro c2s", client, server);
+        let to_client = derive_key(key, b"anachro s2c", client, server);
+
+        let (tx, rx) = match role {
+            Role::Client => (to_server, to_client),
+            Role::Server => (to_client, to_server),
+        };
+
+        Session {
+            tx: ChaCha20Poly1305::new(Key::from_slice(&tx)),
+            rx: ChaCha20Poly1305::new(Key::from_slice(&rx)),
+            tx_ctr: 0,
+            rx_next: 0,
+        }
+    }
+
+    /// Encrypt a message of `len` bytes, already serialized after the
+    /// header in `buf`, and fill in the header and tag.
+    ///
+    /// Returns the length of the frame.
+    fn seal(&mut self, buf: &mut [u8], len: usize) -> Option<usize> {
+        let ctr = self.tx_ctr;
+
+        // NOTE: A u64 counter can not realistically be exhausted
+        self.tx_ctr += 1;
+
+        let (header, rest) = buf.split_at_mut(DATA_HEADER_LEN);
+        header[0] = KIND_DATA;
+        header[1..].copy_from_slice(&ctr.to_le_bytes());
+
+        let (body, rest) = rest.split_at_mut(len);
+        let tag = self
+            .tx
+            .encrypt_in_place_detached(&nonce(ctr), header, body)
+            .ok()?;
+        rest.get_mut(..TAG_LEN)?.copy_from_slice(tag.as_slice());
+
+        Some(DATA_HEADER_LEN + len + TAG_LEN)
+    }
+
+    /// Authenticate a Data frame, and decrypt its message into `buf`
+    ///
+    /// Returns the length of the message, or `None` if the frame is
+    /// malformed, forged, or has been replayed.
+    fn open(&mut self, frame: &[u8], buf: &mut [u8]) -> Option<usize> {
+        let len = frame.len().checked_sub(OVERHEAD)?;
+        let (header, rest) = frame.split_at(DATA_HEADER_LEN);
+        let (body, tag) = rest.split_at(len);
+
+        let mut ctr = [0u8; 8];
+        ctr.copy_from_slice(&header[1..]);
+        let ctr = u64::from_le_bytes(ctr);
+
+        // Counters may skip ahead if frames are lost, but never go back
+        if ctr < self.rx_next {
+            return None;
+        }
+
+        let msg = buf.get_mut(..len)?;
+        msg.copy_from_slice(body);
+        self.rx
+            .decrypt_in_place_detached(&nonce(ctr), header, msg, Tag::from_slice(tag))
+            .ok()?;
+
+        self.rx_next = ctr.saturating_add(1);
+        Some(len)
+    }
+}
+
+/// Derive the key for one direction of a session
+fn derive_key(
+    key: &[u8],
+    label: &[u8],
+    client: &[u8; LINK_NONCE_LEN],
+    server: &[u8; LINK_NONCE_LEN],
+) -> [u8; 32] {
+    // NOTE: HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.update(label);
+    mac.update(client);
+    mac.update(server);
+
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+/// The AEAD nonce for a frame counter
+///
+/// Each direction of a session has its own key, so the same counter may
+/// safely be used in both directions.
+fn nonce(ctr: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&ctr.to_le_bytes());
+    Nonce::clone_from_slice(&nonce)
+}
+
+impl<F: FrameIo, RT: RollingTimer<Tick = u32>, const N: usize> ClientIo for SecureLink<F, RT, N> {
+    /// Receive one message FROM the Broker
+    ///
+    /// Returns `Ok(None)` if no complete message is available yet
+    fn recv(&mut self) -> Result<Option<Arbitrator>, ClientIoError> {
+        self.handshake()?;
+        match self.recv_plaintext()? {
+            Some(len) => from_bytes(&self.buf[..len])
+                .map(Some)
+                .map_err(|_| ClientIoError::ParsingError),
+            None => Ok(None),
+        }
+    }
+
+    /// Send one message TO the Broker
+    fn send(&mut self, msg: &Component) -> Result<(), ClientIoError> {
+        self.handshake()?;
+        self.send_msg(msg).map_err(|e| match e {
+            SendError::Serialize => ClientIoError::ParsingError,
+            SendError::NoSession => ClientIoError::OutputFull,
+            SendError::Frame(e) => e.into(),
+        })
+    }
+}
+
+impl<F: FrameIo, RT: RollingTimer<Tick = u32>, const N: usize> ServerIoIn for SecureLink<F, RT, N> {
+    /// Receive one request FROM the Client
+    ///
+    /// Returns `Ok(None)` if no complete message is available yet
+    fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+        let source = self.uuid;
+        match self.recv_plaintext()? {
+            Some(len) => from_bytes(&self.buf[..len])
+                .map(|msg| Some(Request { source, msg }))
+                .map_err(|_| ServerIoError::DeserializeFailure),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'resp, F: FrameIo, RT: RollingTimer<Tick = u32>, const N: usize> ServerIoOut<'resp>
+    for SecureLink<F, RT, N>
+{
+    /// Send one response TO the Client
+    ///
+    /// Responses addressed to any Client other than the one on this link
+    /// are rejected.
+    fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+        if resp.dest != self.uuid {
+            return Err(ServerIoError::MisroutedResponse);
+        }
+
+        self.send_msg(&resp.msg).map_err(|e| match e {
+            SendError::Frame(e) => e.into(),
+            _ => ServerIoError::ResponsePushFailed,
+        })
+    }
+}
+
+impl<F: FrameIo, RT: RollingTimer<Tick = u32>, const N: usize> HostedTransport
+    for SecureLink<F, RT, N>
+{
+    /// Handshakes are handled when receiving
+    fn poll(&mut self) -> Result<(), ServerIoError> {
+        Ok(())
+    }
+
+    fn client(&self) -> Option<Uuid> {
+        Some(self.uuid)
+    }
+
+    fn next_event(&mut self) -> Option<HostEvent> {
+        if core::mem::replace(&mut self.connected, false) {
+            Some(HostEvent::Connected(self.uuid))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::*;
+    use anachro_client::anachro_icd::{
+        arbitrator::{Control as AControl, ControlResponse},
+        component::{ComponentInfo, Control, ControlType},
+        Name, Version,
+    };
+    use groundhog::MockTimer;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+    type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+    /// One end of an in-memory frame transport
+    struct MockFrames {
+        incoming: Queue,
+        outgoing: Queue,
+        last: Vec<u8>,
+    }
+
+    impl FrameIo for MockFrames {
+        fn send_frame(&mut self, frame: &[u8]) -> Result<(), FrameError> {
+            self.outgoing.borrow_mut().push_back(frame.to_vec());
+            Ok(())
+        }
+
+        fn recv_frame(&mut self) -> Result<Option<&[u8]>, FrameError> {
+            match self.incoming.borrow_mut().pop_front() {
+                Some(frame) => self.last = frame,
+                None => return Ok(None),
+            }
+            Ok(Some(&self.last))
+        }
+    }
+
+    type Link = SecureLink<MockFrames, MockTimer, 256>;
+
+    const KEY: LinkConfig = LinkConfig {
+        key: b"correct horse",
+        nonce: counting_nonce,
+    };
+
+    fn counting_nonce() -> [u8; LINK_NONCE_LEN] {
+        static NEXT: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+        [NEXT.fetch_add(1, std::sync::atomic::Ordering::SeqCst); LINK_NONCE_LEN]
+    }
+
+    /// Create a connected Client and Broker link, along with the queues
+    /// carrying frames to the Broker and to the Client
+    fn pair(server_cfg: LinkConfig, timer: &MockTimer) -> (Link, Link, Queue, Queue) {
+        let to_server = Queue::default();
+        let to_client = Queue::default();
+        let client = SecureLink::new(
+            MockFrames {
+                incoming: to_client.clone(),
+                outgoing: to_server.clone(),
+                last: Vec::new(),
+            },
+            KEY,
+            timer.clone(),
+        );
+        let server = SecureLink::new_server(
+            MockFrames {
+                incoming: to_server.clone(),
+                outgoing: to_client.clone(),
+                last: Vec::new(),
+            },
+            Uuid::from_bytes([1; 16]),
+            server_cfg,
+            timer.clone(),
+        );
+        (client, server, to_server, to_client)
+    }
+
+    fn register() -> Component<'static> {
+        Component::Control(Control {
+            seq: 7,
+            ty: ControlType::RegisterComponent(ComponentInfo {
+                name: Name::borrow_from_str("secret-sensor"),
+                version: Version {
+                    major: 0,
+                    minor: 1,
+                    trivial: 0,
+                    misc: 0,
+                },
+            }),
+        })
+    }
+
+    fn registered(uuid: Uuid) -> Arbitrator<'static> {
+        Arbitrator::Control(AControl {
+            seq: 7,
+            response: Ok(ControlResponse::ComponentRegistration(uuid)),
+        })
+    }
+
+    /// Receive a request, checking that it is the registration
+    fn recv_register(server: &mut Link) -> bool {
+        match ServerIoIn::recv(server).unwrap() {
+            Some(req) => {
+                assert_eq!(req.msg, register());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run the handshake to completion
+    fn connect(client: &mut Link, server: &mut Link) {
+        assert_eq!(client.send(&register()), Err(ClientIoError::OutputFull));
+        assert!(!recv_register(server));
+        assert_eq!(ClientIo::recv(client), Ok(None));
+        assert!(client.is_established());
+        assert!(server.is_established());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let timer = MockTimer::new();
+        let (mut client, mut server, to_server, _) = pair(KEY, &timer);
+        let uuid = server.uuid();
+
+        connect(&mut client, &mut server);
+        assert_eq!(server.next_event(), Some(HostEvent::Connected(uuid)));
+        assert_eq!(server.next_event(), None);
+
+        client.send(&register()).unwrap();
+        let sent = to_server.borrow().back().unwrap().clone();
+        assert!(!sent.windows(6).any(|w| w == b"secret"));
+        assert!(recv_register(&mut server));
+
+        server
+            .push_response(Response {
+                dest: uuid,
+                msg: registered(uuid),
+            })
+            .unwrap();
+        assert_eq!(ClientIo::recv(&mut client), Ok(Some(registered(uuid))));
+        assert_eq!(client.dropped_frames(), 0);
+        assert_eq!(server.dropped_frames(), 0);
+    }
+
+    #[test]
+    fn replay_and_forgery() {
+        let timer = MockTimer::new();
+        let (mut client, mut server, to_server, _) = pair(KEY, &timer);
+        connect(&mut client, &mut server);
+
+        client.send(&register()).unwrap();
+        let frame = to_server.borrow().back().unwrap().clone();
+        assert!(recv_register(&mut server));
+
+        // A replayed frame
+        to_server.borrow_mut().push_back(frame.clone());
+        assert!(!recv_register(&mut server));
+
+        // A modified frame, with a fresh counter
+        let mut forged = frame;
+        forged[1] = 5;
+        to_server.borrow_mut().push_back(forged);
+        assert!(!recv_register(&mut server));
+        assert_eq!(server.dropped_frames(), 2);
+
+        // Frames after a lost frame are still accepted
+        client.send(&register()).unwrap();
+        to_server.borrow_mut().clear();
+        client.send(&register()).unwrap();
+        assert!(recv_register(&mut server));
+    }
+
+    #[test]
+    fn wrong_key() {
+        let timer = MockTimer::new();
+        let other = LinkConfig {
+            key: b"battery staple",
+            ..KEY
+        };
+        let (mut client, mut server, _, _) = pair(other, &timer);
+        connect(&mut client, &mut server);
+
+        client.send(&register()).unwrap();
+        assert!(!recv_register(&mut server));
+        assert_eq!(server.dropped_frames(), 1);
+    }
+
+    #[test]
+    fn recovery() {
+        let timer = MockTimer::new();
+        let (mut client, mut server, to_server, _) = pair(KEY, &timer);
+
+        // A lost Hello is resent once the retry deadline expires
+        assert_eq!(client.send(&register()), Err(ClientIoError::OutputFull));
+        to_server.borrow_mut().clear();
+        assert_eq!(client.send(&register()), Err(ClientIoError::OutputFull));
+        assert!(to_server.borrow().is_empty());
+        timer.advance_us(HELLO_RETRY_US);
+        assert_eq!(client.send(&register()), Err(ClientIoError::OutputFull));
+        assert!(!recv_register(&mut server));
+        assert_eq!(ClientIo::recv(&mut client), Ok(None));
+        assert!(client.is_established());
+
+        // A Broker that lost its session asks the Client to start over
+        server.restart();
+        client.send(&register()).unwrap();
+        assert!(!recv_register(&mut server));
+        assert_eq!(ClientIo::recv(&mut client), Ok(None));
+        assert!(!client.is_established());
+
+        connect(&mut client, &mut server);
+        client.send(&register()).unwrap();
+        assert!(recv_register(&mut server));
+    }
+
+    #[test]
+    fn hello_during_session() {
+        let timer = MockTimer::new();
+        let (mut client, mut server, to_server, _) = pair(KEY, &timer);
+        let uuid = server.uuid();
+        connect(&mut client, &mut server);
+        assert_eq!(server.next_event(), Some(HostEvent::Connected(uuid)));
+
+        // A Hello from someone else does not end the session
+        let mut hello = [0xAA; HELLO_LEN];
+        hello[0] = KIND_HELLO;
+        to_server.borrow_mut().push_back(hello.to_vec());
+        assert!(!recv_register(&mut server));
+        assert_eq!(ClientIo::recv(&mut client), Ok(None));
+
+        client.send(&register()).unwrap();
+        let old = to_server.borrow().back().unwrap().clone();
+        assert!(recv_register(&mut server));
+        assert_eq!(server.next_event(), None);
+
+        // A new session from the Client replaces it once used
+        client.restart();
+        connect(&mut client, &mut server);
+        assert_eq!(server.next_event(), None);
+        client.send(&register()).unwrap();
+        assert!(recv_register(&mut server));
+        assert_eq!(server.next_event(), Some(HostEvent::Connected(uuid)));
+
+        to_server.borrow_mut().push_back(old);
+        assert!(!recv_register(&mut server));
+    }
+}
@@ -0,0 +1,210 @@
+//! # Topic Access Control
+//!
+//! By default, any registered client may publish or subscribe to any path.
+//! Once an `AclRule` has been added to a `Broker`, each publish and
+//! subscribe must be allowed by a rule that applies to the client, and
+//! denied operations are answered with `PubSubError::NotAuthorized`.
+//!
+//! Patterns use the same `+` and `#` wildcards as subscriptions. A
+//! subscription containing wildcards is only allowed if every path it
+//! could match is allowed, e.g. an allowed pattern of `sensors/#` permits
+//! a subscription to `sensors/+/temp`, but `sensors/+` does not permit
+//! `sensors/#`.
+
+use crate::{ServerError, Uuid};
+use heapless::{consts, Vec};
+
+/// The clients that an `AclRule` applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientMatch {
+    /// Every client
+    Any,
+
+    /// The client with the given `Uuid`
+    Uuid(Uuid),
+
+    /// Any client that registered with the given `Name`
+    Name(&'static str),
+}
+
+/// The paths that a set of clients may publish and subscribe to
+#[derive(Debug, Clone, Copy)]
+pub struct AclRule {
+    pub client: ClientMatch,
+
+    /// The patterns the clients may publish to
+    pub publish: &'static [&'static str],
+
+    /// The patterns the clients may subscribe to
+    pub subscribe: &'static [&'static str],
+}
+
+#[derive(Default)]
+pub(crate) struct Acl {
+    rules: Vec<AclRule, consts::U16>,
+}
+
+impl Acl {
+    pub(crate) fn add(&mut self, rule: AclRule) -> Result<(), ServerError> {
+        self.rules
+            .push(rule)
+            .map_err(|_| ServerError::ResourcesExhausted)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    pub(crate) fn allows_publish(&self, id: &Uuid, name: &str, path: &str) -> bool {
+        self.allows(id, name, path, |rule| rule.publish)
+    }
+
+    pub(crate) fn allows_subscribe(&self, id: &Uuid, name: &str, path: &str) -> bool {
+        self.allows(id, name, path, |rule| rule.subscribe)
+    }
+
+    fn allows<F>(&self, id: &Uuid, name: &str, path: &str, patterns: F) -> bool
+    where
+        F: Fn(&AclRule) -> &'static [&'static str],
+    {
+        if self.rules.is_empty() {
+            return true;
+        }
+
+        self.rules
+            .iter()
+            .filter(|rule| match rule.client {
+                ClientMatch::Any => true,
+                ClientMatch::Uuid(uuid) => &uuid == id,
+                ClientMatch::Name(n) => n == name,
+            })
+            .any(|rule| patterns(rule).iter().any(|pat| covers(pat, path)))
+    }
+}
+
+/// Does the pattern `allowed` match every path that `requested` matches?
+fn covers(allowed: &str, requested: &str) -> bool {
+    if allowed.is_empty() || requested.is_empty() {
+        return false;
+    }
+
+    let mut a_iter = allowed.split('/');
+    let mut r_iter = requested.split('/');
+
+    loop {
+        match (a_iter.next(), r_iter.next()) {
+            (Some("#"), _) | (None, None) => return true,
+            (Some("+"), Some(seg)) if seg != "#" => continue,
+            (Some(lhs), Some(rhs)) if lhs == rhs => continue,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{connect, long, process, publish, pubsub, sub_msg, subscribe, uuid};
+    use crate::{Broker, PubSubType};
+    use anachro_icd::arbitrator::{Arbitrator, PubSubError};
+
+    #[test]
+    fn covers_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            // Exact
+            ("a/b", "a/b", true),
+            ("a/b", "a/c", false),
+            ("a/b", "a", false),
+            ("a", "a/b", false),
+            ("a/b", "a/+", false),
+            ("a/b", "a/#", false),
+            // Single level
+            ("a/+", "a/b", true),
+            ("a/+", "a/+", true),
+            ("+/b", "a/b", true),
+            ("a/+", "a", false),
+            ("a/+", "a/b/c", false),
+            ("a/+", "a/#", false),
+            // Multi level
+            ("#", "a/b", true),
+            ("a/#", "a", true),
+            ("a/#", "a/b", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "a/+/c", true),
+            ("a/#", "a/#", true),
+            ("a/#", "ab", false),
+            ("a/#", "b/a", false),
+            ("a/b/#", "a/#", false),
+            // Empty paths and levels
+            ("", "a", false),
+            ("a", "", false),
+            ("a/+/b", "a//b", true),
+        ];
+
+        for (allowed, requested, expected) in cases {
+            assert_eq!(
+                covers(allowed, requested),
+                *expected,
+                "{:?} covers {:?}",
+                allowed,
+                requested
+            );
+        }
+    }
+
+    #[test]
+    fn not_authorized() {
+        let mut broker = Broker::new();
+        let (sensor, monitor, other) = (uuid(1), uuid(2), uuid(3));
+        broker
+            .add_acl_rule(AclRule {
+                client: ClientMatch::Name("sensor"),
+                publish: &["sensors/#"],
+                subscribe: &["cmd/+"],
+            })
+            .unwrap();
+        broker
+            .add_acl_rule(AclRule {
+                client: ClientMatch::Uuid(monitor),
+                publish: &[],
+                subscribe: &["#"],
+            })
+            .unwrap();
+
+        connect(&mut broker, sensor, "sensor");
+        connect(&mut broker, monitor, "monitor");
+        connect(&mut broker, other, "other");
+        let denied = Arbitrator::PubSub(Err(PubSubError::NotAuthorized));
+
+        // Subscribe
+        subscribe(&mut broker, sensor, "cmd/reset");
+        subscribe(&mut broker, monitor, "sensors/#");
+        for (client, path) in &[
+            (sensor, "cmd/#"),
+            (sensor, "sensors/t"),
+            (other, "cmd/reset"),
+        ] {
+            let resp = process(&mut broker, *client, pubsub(path, PubSubType::Sub));
+            assert_eq!(resp.len(), 1);
+            assert_eq!(resp[0].dest, *client);
+            assert_eq!(resp[0].msg(), denied);
+        }
+
+        // Publish
+        for (client, path) in &[
+            (sensor, "cmd/reset"),
+            (monitor, "sensors/t"),
+            (other, "sensors/t"),
+        ] {
+            let resp = publish(&mut broker, *client, path, &[1]);
+            assert_eq!(resp.len(), 1);
+            assert_eq!(resp[0].dest, *client);
+            assert_eq!(resp[0].msg(), denied);
+        }
+
+        let resp = publish(&mut broker, sensor, "sensors/t", &[2]);
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, monitor);
+        assert_eq!(resp[0].msg(), sub_msg(long("sensors/t"), &[2]));
+    }
+}
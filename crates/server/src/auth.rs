@@ -0,0 +1,174 @@
+//! # Component Authentication
+//!
+//! By default, the Broker registers any client that sends a
+//! `RegisterComponent` message. With an `AuthConfig` set, clients must
+//! also answer a challenge using a pre-shared key for the name they
+//! registered with, as described in `anachro_icd::auth`. Until they have,
+//! they are treated as unregistered.
+
+use anachro_icd::auth::AUTH_NONCE_LEN;
+
+/// The pre-shared keys clients must authenticate with
+#[derive(Clone, Copy)]
+pub struct AuthConfig {
+    /// The key of each client, by the name it registers with
+    ///
+    /// Clients with a name that is not listed can not register.
+    pub keys: &'static [(&'static str, &'static [u8])],
+
+    /// Produce a nonce for a new challenge
+    ///
+    /// Nonces must not repeat, including across reboots of the Broker, as
+    /// a recorded answer to a repeated challenge could be replayed. They
+    /// should be taken from a random number generator.
+    pub nonce: fn() -> [u8; AUTH_NONCE_LEN],
+}
+
+impl AuthConfig {
+    pub(crate) fn key_for(&self, name: &str) -> Option<&'static [u8]> {
+        self.keys
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, key)| *key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{control, control_reply, process, register, uuid};
+    use crate::{Broker, ControlResponse, ControlType, Uuid};
+    use anachro_icd::{
+        arbitrator::{Arbitrator, Control, ControlError},
+        auth::{auth_mac, AUTH_MAC_LEN},
+    };
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    const KEY: &[u8] = b"sensor key";
+
+    fn nonce() -> [u8; AUTH_NONCE_LEN] {
+        static NEXT: AtomicU8 = AtomicU8::new(0);
+        [NEXT.fetch_add(1, Ordering::Relaxed); AUTH_NONCE_LEN]
+    }
+
+    fn broker() -> Broker {
+        let mut broker = Broker::new();
+        broker.set_auth(Some(AuthConfig {
+            keys: &[("sensor", KEY)],
+            nonce,
+        }));
+        broker
+    }
+
+    /// Register `id` as "sensor", returning the challenge
+    fn challenge(broker: &mut Broker, id: Uuid) -> [u8; AUTH_NONCE_LEN] {
+        let resp = process(broker, id, control(1, register("sensor")));
+        assert_eq!(resp.len(), 1);
+        match resp[0].msg() {
+            Arbitrator::Control(Control {
+                seq: 1,
+                response: Ok(ControlResponse::AuthChallenge(nonce)),
+            }) => nonce,
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    /// Answer the challenge with `mac`, expecting the reply `expected`
+    fn authenticate(
+        broker: &mut Broker,
+        id: Uuid,
+        mac: [u8; AUTH_MAC_LEN],
+        expected: Arbitrator<'static>,
+    ) {
+        let resp = process(broker, id, control(2, ControlType::Authenticate { mac }));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, id);
+        assert_eq!(resp[0].msg(), expected);
+    }
+
+    fn is_connected(broker: &Broker, id: Uuid) -> bool {
+        broker
+            .clients
+            .iter()
+            .any(|c| c.id == id && c.state.as_connected().is_ok())
+    }
+
+    #[test]
+    fn good_mac() {
+        let mut broker = broker();
+        let id = uuid(1);
+        broker.register_client(&id).unwrap();
+
+        let nonce = challenge(&mut broker, id);
+        assert!(!is_connected(&broker, id));
+        authenticate(
+            &mut broker,
+            id,
+            auth_mac(KEY, &nonce, "sensor"),
+            control_reply(2, Ok(ControlResponse::ComponentRegistration(id))),
+        );
+        assert!(is_connected(&broker, id));
+    }
+
+    #[test]
+    fn wrong_key() {
+        let mut broker = broker();
+        let id = uuid(1);
+        broker.register_client(&id).unwrap();
+
+        let nonce = challenge(&mut broker, id);
+        let failed = || control_reply(2, Err(ControlError::AuthenticationFailed));
+        authenticate(
+            &mut broker,
+            id,
+            auth_mac(b"wrong key", &nonce, "sensor"),
+            failed(),
+        );
+        assert!(!is_connected(&broker, id));
+
+        // The challenge can't be answered again
+        authenticate(&mut broker, id, auth_mac(KEY, &nonce, "sensor"), failed());
+        assert!(!is_connected(&broker, id));
+    }
+
+    #[test]
+    fn unknown_name() {
+        let mut broker = broker();
+        let id = uuid(1);
+        broker.register_client(&id).unwrap();
+
+        let resp = process(&mut broker, id, control(1, register("intruder")));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(1, Err(ControlError::AuthenticationFailed))
+        );
+        assert!(!is_connected(&broker, id));
+    }
+
+    #[test]
+    fn replayed_nonce() {
+        let mut broker = broker();
+        let id = uuid(1);
+        broker.register_client(&id).unwrap();
+
+        let nonce = challenge(&mut broker, id);
+        let mac = auth_mac(KEY, &nonce, "sensor");
+        authenticate(
+            &mut broker,
+            id,
+            mac,
+            control_reply(2, Ok(ControlResponse::ComponentRegistration(id))),
+        );
+
+        // A recorded answer does not answer a new challenge
+        assert_ne!(challenge(&mut broker, id), nonce);
+        authenticate(
+            &mut broker,
+            id,
+            mac,
+            control_reply(2, Err(ControlError::AuthenticationFailed)),
+        );
+        assert!(!is_connected(&broker, id));
+    }
+}
@@ -0,0 +1,769 @@
+//! # Broker Hosting
+//!
+//! A `Broker` only processes one message at a time, from a single
+//! `ServerIoIn`, and leaves it to the caller to route the `Response`s
+//! to the correct transport. This module provides a `BrokerHost`, which
+//! owns a `Broker`, and services a set of heterogeneous transports,
+//! automatically registering and evicting clients, and routing each
+//! response by its destination `Uuid`.
+//!
+//! Transports implement the `HostedTransport` trait. Transports that
+//! connect a single client (such as an SPI or UART link) only need to
+//! report that client's `Uuid`. Transports that connect many clients
+//! report them as `HostEvent`s.
+
+use crate::{Broker, Request, Response, ServerError, ServerIoError, ServerIoIn, ServerIoOut, Uuid};
+use anachro_icd::arbitrator::Arbitrator;
+use core::cell::Cell;
+use heapless::{consts, ArrayLength, Vec};
+use postcard::{from_bytes, to_slice};
+
+/// The size of the header of each deferred response: The destination
+/// Uuid, and the length of the serialized message as a little endian u16
+const DEFERRED_HEADER: usize = 18;
+
+/// The most responses routed for a single request, or a single system
+/// message
+///
+/// This is two for each client the Broker can hold, enough for a message
+/// to every client, and a shortcode announcement before each.
+type MaxResponses = consts::U16;
+
+/// A change in the set of clients reached through a transport
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostEvent {
+    /// A client is now reachable through the transport.
+    ///
+    /// If the client was already registered, it will be reset.
+    Connected(Uuid),
+
+    /// A client is no longer reachable through the transport
+    Disconnected(Uuid),
+}
+
+/// A transport that can be serviced by a `BrokerHost`
+pub trait HostedTransport: ServerIoIn + for<'resp> ServerIoOut<'resp> {
+    /// Make progress on the transport
+    ///
+    /// If `ServerIoError::Disconnected` is returned, here or when receiving,
+    /// the transport has closed, and all clients reached through it will be
+    /// removed from the Broker. Other errors are reported, and the transport
+    /// is serviced again on the next poll.
+    fn poll(&mut self) -> Result<(), ServerIoError>;
+
+    /// The single client reached through this transport, if any
+    ///
+    /// This client is registered with the Broker whenever the transport
+    /// is polled successfully.
+    fn client(&self) -> Option<Uuid> {
+        None
+    }
+
+    /// Take the next change in the set of clients reached through this
+    /// transport, if any
+    fn next_event(&mut self) -> Option<HostEvent> {
+        None
+    }
+
+    /// Is the client `uuid` reached through this transport?
+    fn routes_to(&self, uuid: &Uuid) -> bool {
+        self.client().as_ref() == Some(uuid)
+    }
+}
+
+/// A Broker, along with the storage necessary to route responses between
+/// transports
+///
+/// Responses addressed to a client on the same transport as the request
+/// that caused them can not be sent until the request has been released.
+/// These are serialized into a buffer of `N` bytes in the meantime.
+///
+/// System messages are sent one at a time, so that there is always room
+/// to route a response to every client. Responses that can not be routed,
+/// e.g. because a transport is full, are dropped and counted in the
+/// statistics, and `ServerError::ResourcesExhausted` is returned.
+pub struct BrokerHost<N: ArrayLength<u8>> {
+    broker: Broker,
+    deferred: Vec<u8, N>,
+}
+
+impl<N: ArrayLength<u8>> Default for BrokerHost<N> {
+    fn default() -> Self {
+        BrokerHost {
+            broker: Broker::default(),
+            deferred: Vec::new(),
+        }
+    }
+}
+
+impl<N: ArrayLength<u8>> BrokerHost<N> {
+    /// Create a new host with no clients registered
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Obtain a reference to the hosted Broker
+    pub fn broker(&self) -> &Broker {
+        &self.broker
+    }
+
+    /// Obtain a mutable reference to the hosted Broker
+    ///
+    /// This can be used to register clients ahead-of-time.
+    pub fn broker_mut(&mut self) -> &mut Broker {
+        &mut self.broker
+    }
+
+    /// Service each transport once, processing at most one message from each
+    ///
+    /// Any pending system messages are then sent, see `Broker::process_sys`.
+    ///
+    /// A failure of one transport does not prevent the others from being
+    /// serviced. The first error encountered is returned.
+    pub fn poll(&mut self, transports: &mut [&mut dyn HostedTransport]) -> Result<(), ServerError> {
+        let mut result = Ok(());
+
+        for idx in 0..transports.len() {
+            if let Err(e) = self.service(transports, idx) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result.and(self.publish_sys(transports))
+    }
+
+    /// Send any pending system messages from the Broker
+    ///
+    /// Messages are sent one at a time, so that the responses to each can
+    /// always be routed.
+    fn publish_sys(
+        &mut self,
+        transports: &mut [&mut dyn HostedTransport],
+    ) -> Result<(), ServerError> {
+        let mut result = Ok(());
+        let mut more = true;
+
+        while more {
+            let mut undelivered: Vec<Uuid, MaxResponses> = Vec::new();
+            let mut responses: Vec<Response, MaxResponses> = Vec::new();
+            match self.broker.process_sys_next(&mut responses) {
+                Ok(next) => more = next,
+                Err(e) => {
+                    result = result.and(Err(e));
+                    more = false;
+                }
+            }
+
+            for resp in responses {
+                let dest = resp.dest;
+                let res = match transports.iter_mut().find(|t| t.routes_to(&dest)) {
+                    Some(transport) => transport.push_response(resp).map_err(drop),
+                    None => Err(()),
+                };
+
+                if res.is_err() {
+                    defmt::error!("BrokerHost: Response delivery failed");
+                    undelivered.push(dest).ok();
+                    result = result.and(Err(ServerError::ResourcesExhausted));
+                }
+            }
+
+            for dest in undelivered.iter() {
+                self.broker.sys.counters.dropped(dest);
+            }
+        }
+
+        result
+    }
+
+    /// Service a single transport
+    ///
+    /// If the transport has closed, `ServerError::ConnectionError` is
+    /// returned, and its clients have been removed from the Broker.
+    fn service(
+        &mut self,
+        transports: &mut [&mut dyn HostedTransport],
+        idx: usize,
+    ) -> Result<(), ServerError> {
+        let (before, rest) = transports.split_at_mut(idx);
+        let (cur, after) = match rest.split_first_mut() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+
+        match cur.poll() {
+            Ok(()) => {}
+            Err(ServerIoError::Disconnected) => {
+                defmt::warn!("BrokerHost: Transport closed");
+                evict(&mut self.broker, &**cur);
+                return Err(ServerError::ConnectionError);
+            }
+            Err(_) => {
+                defmt::warn!("BrokerHost: Transport failed");
+                return Err(ServerError::LinkFailure);
+            }
+        }
+
+        if let Some(uuid) = cur.client() {
+            if !self.broker.clients.iter().any(|c| c.id == uuid) {
+                self.broker.register_client(&uuid)?;
+            }
+        }
+
+        while let Some(event) = cur.next_event() {
+            match event {
+                HostEvent::Connected(uuid) => match self.broker.register_client(&uuid) {
+                    Err(ServerError::ClientAlreadyRegistered) => self.broker.reset_client(&uuid)?,
+                    res => res?,
+                },
+                HostEvent::Disconnected(uuid) => {
+                    self.broker.remove_client(&uuid).ok();
+                }
+            }
+        }
+
+        let BrokerHost { broker, deferred } = self;
+        let mut result = Ok(());
+        deferred.clear();
+
+        let closed = Cell::new(false);
+        let mut watched = Watched {
+            transport: &mut **cur,
+            closed: &closed,
+        };
+
+        let mut undelivered: Vec<Uuid, MaxResponses> = Vec::new();
+        let mut responses: Vec<Response, MaxResponses> = Vec::new();
+        if let Err(e) = broker.process_msg(&mut watched, &mut responses) {
+            defmt::error!("BrokerHost: Failed to process message");
+            result = Err(e);
+        }
+
+        for resp in responses {
+            let dest = resp.dest;
+            let other = before
+                .iter_mut()
+                .chain(after.iter_mut())
+                .find(|t| t.routes_to(&dest));
+
+            let res = match other {
+                Some(other) => other.push_response(resp).map_err(|_| ()),
+
+                // The transport that the request came from is still borrowed
+                None => defer(deferred, &resp),
+            };
+
+            if res.is_err() {
+                defmt::error!("BrokerHost: Response delivery failed");
+                undelivered.push(dest).ok();
+                result = result.and(Err(ServerError::ResourcesExhausted));
+            }
+        }
+
+        let mut remaining = &deferred[..];
+        while remaining.len() >= DEFERRED_HEADER {
+            let mut dest = [0u8; 16];
+            dest.copy_from_slice(&remaining[..16]);
+            let len = u16::from_le_bytes([remaining[16], remaining[17]]) as usize;
+            let (msg, rest) = remaining[DEFERRED_HEADER..].split_at(len);
+            remaining = rest;
+
+            let resp = match from_bytes::<Arbitrator>(msg) {
+                Ok(msg) => Response {
+                    dest: Uuid::from_bytes(dest),
+                    msg,
+                },
+                Err(_) => continue,
+            };
+
+            // Responses to clients that are not reached through this transport
+            // are rejected by the transport
+            let dest = resp.dest;
+            if cur.push_response(resp).is_err() {
+                defmt::error!("BrokerHost: Response delivery failed");
+                undelivered.push(dest).ok();
+                result = result.and(Err(ServerError::ResourcesExhausted));
+            }
+        }
+
+        for dest in undelivered.iter() {
+            broker.sys.counters.dropped(dest);
+        }
+
+        // NOTE: The responses borrow the transport, so eviction must wait
+        // until they have been sent.
+        if closed.get() {
+            defmt::warn!("BrokerHost: Transport closed");
+            evict(broker, &**cur);
+            result = Err(ServerError::ConnectionError);
+        }
+
+        result
+    }
+}
+
+/// A transport that notes whether it reported being closed when the Broker
+/// received from it
+///
+/// Only a closed transport has its clients evicted. Other errors, such as
+/// a request from a client that has not registered, only concern the
+/// client that sent it.
+struct Watched<'a, 'b> {
+    transport: &'a mut dyn HostedTransport,
+    closed: &'b Cell<bool>,
+}
+
+impl<'a, 'b> ServerIoIn for Watched<'a, 'b> {
+    fn recv<'c, 'd: 'c>(&'d mut self) -> Result<Option<Request<'d>>, ServerIoError> {
+        let res = self.transport.recv();
+        if let Err(ServerIoError::Disconnected) = res {
+            self.closed.set(true);
+        }
+        res
+    }
+}
+
+/// Serialize a response to be sent once the current request is released
+fn defer<N: ArrayLength<u8>>(deferred: &mut Vec<u8, N>, resp: &Response) -> Result<(), ()> {
+    let start = deferred.len();
+    if N::to_usize() < start + DEFERRED_HEADER {
+        return Err(());
+    }
+
+    deferred.resize_default(N::to_usize())?;
+    let used = to_slice(&resp.msg, &mut deferred[start + DEFERRED_HEADER..])
+        .map(|used| used.len())
+        .map_err(drop);
+
+    let len = match used {
+        Ok(len) if len <= u16::MAX as usize => len,
+        _ => {
+            deferred.truncate(start);
+            return Err(());
+        }
+    };
+
+    deferred[start..start + 16].copy_from_slice(resp.dest.as_bytes());
+    deferred[start + 16..start + DEFERRED_HEADER].copy_from_slice(&(len as u16).to_le_bytes());
+    deferred.truncate(start + DEFERRED_HEADER + len);
+    Ok(())
+}
+
+/// Remove all clients reached through the given transport
+fn evict(broker: &mut Broker, transport: &dyn HostedTransport) {
+    while let Some(pos) = broker
+        .clients
+        .iter()
+        .position(|c| transport.routes_to(&c.id))
+    {
+        broker.remove_at(pos);
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::std_host::StdBrokerHost;
+
+#[cfg(feature = "std")]
+mod std_host {
+    use super::{BrokerHost, HostedTransport};
+    use crate::{Broker, ServerError};
+    use heapless::consts;
+    use std::{
+        boxed::Box,
+        time::{Duration, Instant},
+        vec::Vec,
+    };
+
+    /// A `BrokerHost` that owns its transports
+    ///
+    /// Transports are added at runtime, e.g. when a TCP connection is
+    /// accepted, and are dropped once they have failed.
+    ///
+    /// Statistics are published on `$sys/stats` every 10 seconds by
+    /// default.
+    pub struct StdBrokerHost {
+        host: BrokerHost<consts::U4096>,
+        transports: Vec<Box<dyn HostedTransport>>,
+        stats_interval: Option<Duration>,
+        last_stats: Instant,
+    }
+
+    impl Default for StdBrokerHost {
+        fn default() -> Self {
+            StdBrokerHost {
+                host: BrokerHost::default(),
+                transports: Vec::new(),
+                stats_interval: Some(Duration::from_secs(10)),
+                last_stats: Instant::now(),
+            }
+        }
+    }
+
+    impl StdBrokerHost {
+        /// Create a new host with no transports
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Obtain a reference to the hosted Broker
+        pub fn broker(&self) -> &Broker {
+            self.host.broker()
+        }
+
+        /// Obtain a mutable reference to the hosted Broker
+        pub fn broker_mut(&mut self) -> &mut Broker {
+            self.host.broker_mut()
+        }
+
+        /// Add a transport to be serviced
+        ///
+        /// Any clients reached through it are registered the next time
+        /// the host is polled.
+        pub fn add_transport<T: HostedTransport + 'static>(&mut self, transport: T) {
+            self.transports.push(Box::new(transport));
+        }
+
+        /// The number of transports currently being serviced
+        pub fn transports(&self) -> usize {
+            self.transports.len()
+        }
+
+        /// Set how often statistics are published on `$sys/stats`
+        ///
+        /// Set to `None` to stop publishing statistics.
+        pub fn set_stats_interval(&mut self, interval: Option<Duration>) {
+            self.stats_interval = interval;
+            self.last_stats = Instant::now();
+        }
+
+        /// Service each transport once, processing at most one message
+        /// from each, then send any pending system messages
+        ///
+        /// Transports that have failed are dropped, and their clients are
+        /// removed from the Broker. Other errors do not prevent the
+        /// remaining transports from being serviced. The first error
+        /// encountered is returned.
+        pub fn poll(&mut self) -> Result<(), ServerError> {
+            if let Some(interval) = self.stats_interval {
+                if self.last_stats.elapsed() >= interval {
+                    self.host.broker_mut().schedule_stats();
+                    self.last_stats = Instant::now();
+                }
+            }
+
+            let mut refs: Vec<&mut dyn HostedTransport> =
+                self.transports.iter_mut().map(|t| &mut **t as _).collect();
+
+            let mut result = Ok(());
+            let mut failed = Vec::new();
+
+            for idx in 0..refs.len() {
+                match self.host.service(&mut refs, idx) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        if e == ServerError::ConnectionError {
+                            failed.push(idx);
+                        }
+                        if result.is_ok() {
+                            result = Err(e);
+                        }
+                    }
+                }
+            }
+
+            let sys = self.host.publish_sys(&mut refs);
+            if result.is_ok() {
+                result = sys;
+            }
+
+            drop(refs);
+            for idx in failed.into_iter().rev() {
+                self.transports.remove(idx);
+            }
+
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{control, pubsub, register, uuid, Sent, VERSION};
+    use crate::{Component, ControlResponse, MaxClients, PubSubType, RESET_MESSAGE};
+    use anachro_icd::{
+        arbitrator::{Control as AControl, PubSubResponse},
+        sys::{ClientConnected, STATS_PATH},
+        Path, PubSubPath,
+    };
+    use std::{collections::VecDeque, vec::Vec as StdVec};
+
+    /// A transport reaching `clients`, which receives the requests queued
+    /// by the test
+    struct Mock {
+        clients: StdVec<Uuid>,
+        events: VecDeque<HostEvent>,
+        requests: VecDeque<(Uuid, StdVec<u8>)>,
+        current: StdVec<u8>,
+        sent: StdVec<Sent>,
+        failed: bool,
+    }
+
+    impl Mock {
+        fn new(clients: &[u8]) -> Self {
+            let clients: StdVec<Uuid> = clients.iter().map(|n| uuid(*n)).collect();
+            Mock {
+                events: clients.iter().map(|c| HostEvent::Connected(*c)).collect(),
+                clients,
+                requests: VecDeque::new(),
+                current: StdVec::new(),
+                sent: StdVec::new(),
+                failed: false,
+            }
+        }
+
+        fn send(&mut self, source: Uuid, msg: Component) {
+            let mut buf = [0u8; 1024];
+            let used = to_slice(&msg, &mut buf).unwrap();
+            self.requests.push_back((source, used.to_vec()));
+        }
+
+        fn disconnect(&mut self, client: Uuid) {
+            self.clients.retain(|c| *c != client);
+            self.events.push_back(HostEvent::Disconnected(client));
+        }
+
+        fn take(&mut self) -> StdVec<Sent> {
+            self.sent.drain(..).collect()
+        }
+    }
+
+    impl ServerIoIn for Mock {
+        fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+            let source = match self.requests.pop_front() {
+                Some((source, msg)) => {
+                    self.current = msg;
+                    source
+                }
+                None => return Ok(None),
+            };
+            from_bytes(&self.current)
+                .map(|msg| Some(Request { source, msg }))
+                .map_err(|_| ServerIoError::DeserializeFailure)
+        }
+    }
+
+    impl<'resp> ServerIoOut<'resp> for Mock {
+        fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+            if !self.routes_to(&resp.dest) {
+                return Err(ServerIoError::MisroutedResponse);
+            }
+            self.sent.push(Sent::new(&resp));
+            Ok(())
+        }
+    }
+
+    impl HostedTransport for Mock {
+        fn poll(&mut self) -> Result<(), ServerIoError> {
+            match self.failed {
+                true => Err(ServerIoError::Disconnected),
+                false => Ok(()),
+            }
+        }
+
+        fn next_event(&mut self) -> Option<HostEvent> {
+            self.events.pop_front()
+        }
+
+        fn routes_to(&self, uuid: &Uuid) -> bool {
+            self.clients.contains(uuid)
+        }
+    }
+
+    type Host = BrokerHost<consts::U1024>;
+
+    fn poll(host: &mut Host, transports: &mut [&mut Mock]) -> Result<(), ServerError> {
+        let mut refs: StdVec<&mut dyn HostedTransport> =
+            transports.iter_mut().map(|t| &mut **t as _).collect();
+        host.poll(&mut refs)
+    }
+
+    /// The clients registered with the broker, in order
+    fn registered(host: &Host) -> StdVec<Uuid> {
+        let mut clients: StdVec<Uuid> = host.broker().clients.iter().map(|c| c.id).collect();
+        clients.sort_by_key(|c| *c.as_bytes());
+        clients
+    }
+
+    /// Register every client of `transport`
+    fn connect(host: &mut Host, transport: &mut Mock) {
+        for client in transport.clients.clone() {
+            transport.send(client, control(1, register("client")));
+            poll(host, &mut [transport]).unwrap();
+        }
+        for sent in transport.take() {
+            match sent.msg() {
+                Arbitrator::Control(AControl {
+                    response: Ok(ControlResponse::ComponentRegistration(uuid)),
+                    ..
+                }) => assert_eq!(uuid, sent.dest),
+                other => panic!("unexpected: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn deferred_delivery() {
+        let mut host = Host::new();
+        let mut multi = Mock::new(&[1, 2]);
+        let mut single = Mock::new(&[3]);
+        poll(&mut host, &mut [&mut multi, &mut single]).unwrap();
+        assert_eq!(registered(&host), [uuid(1), uuid(2), uuid(3)]);
+
+        connect(&mut host, &mut multi);
+        connect(&mut host, &mut single);
+
+        multi.send(uuid(2), pubsub("a/b", PubSubType::Sub));
+        single.send(uuid(3), pubsub("a/b", PubSubType::Sub));
+        poll(&mut host, &mut [&mut multi, &mut single]).unwrap();
+        assert_eq!(multi.take().len(), 1);
+        assert_eq!(single.take().len(), 1);
+
+        // The message to the publisher's own transport is deferred until
+        // the request has been released
+        multi.send(uuid(1), pubsub("a/b", PubSubType::Pub { payload: &[7] }));
+        poll(&mut host, &mut [&mut multi, &mut single]).unwrap();
+
+        for (transport, dest) in &mut [(&mut multi, uuid(2)), (&mut single, uuid(3))] {
+            let sent = transport.take();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].dest, *dest);
+            match sent[0].msg() {
+                Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(msg))) => {
+                    assert_eq!(msg.payload, &[7])
+                }
+                other => panic!("unexpected: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn evict_on_disconnect() {
+        let mut host = Host::new();
+        let mut multi = Mock::new(&[1, 2, 3]);
+        let mut single = Mock::new(&[4]);
+        poll(&mut host, &mut [&mut multi, &mut single]).unwrap();
+        connect(&mut host, &mut multi);
+
+        multi.disconnect(uuid(2));
+        poll(&mut host, &mut [&mut multi, &mut single]).unwrap();
+        assert_eq!(registered(&host), [uuid(1), uuid(3), uuid(4)]);
+
+        // Every client of a failed transport is removed
+        multi.failed = true;
+        assert_eq!(
+            poll(&mut host, &mut [&mut multi, &mut single]),
+            Err(ServerError::ConnectionError)
+        );
+        assert_eq!(registered(&host), [uuid(4)]);
+    }
+
+    #[test]
+    fn reset_unregistered_client() {
+        let mut host = Host::new();
+        let mut multi = Mock::new(&[1, 2]);
+        poll(&mut host, &mut [&mut multi]).unwrap();
+        multi.send(uuid(1), control(1, register("client")));
+        poll(&mut host, &mut [&mut multi]).unwrap();
+        multi.take();
+
+        // A request before registering only resets the client that sent it
+        multi.send(uuid(2), pubsub("a/b", PubSubType::Sub));
+        poll(&mut host, &mut [&mut multi]).unwrap();
+        let sent = multi.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].dest, uuid(2));
+        assert_eq!(sent[0].msg(), RESET_MESSAGE);
+        assert_eq!(registered(&host), [uuid(1), uuid(2)]);
+
+        multi.send(uuid(1), pubsub("a/b", PubSubType::Sub));
+        poll(&mut host, &mut [&mut multi]).unwrap();
+        let sent = multi.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].dest, uuid(1));
+        match sent[0].msg() {
+            Arbitrator::PubSub(Ok(PubSubResponse::SubAck { .. })) => {}
+            other => panic!("unexpected: {:?}", other),
+        }
+
+        // The reset client can then register as usual
+        connect(&mut host, &mut multi);
+    }
+
+    #[test]
+    fn responses_fit_clients() {
+        fn capacity<N: ArrayLength<u8>>() -> usize {
+            N::to_usize()
+        }
+        assert!(capacity::<MaxResponses>() >= 2 * capacity::<MaxClients>());
+    }
+
+    #[test]
+    fn many_sys_messages() {
+        let mut host = Host::new();
+        let mut transports: StdVec<Mock> = (1..=8).map(|n| Mock::new(&[n])).collect();
+        let mut refs: StdVec<&mut Mock> = transports.iter_mut().collect();
+        poll(&mut host, &mut refs).unwrap();
+
+        for sub in refs[..3].iter_mut() {
+            connect(&mut host, sub);
+            let client = sub.clients[0];
+            sub.send(client, pubsub("$sys/#", PubSubType::Sub));
+        }
+        poll(&mut host, &mut refs).unwrap();
+        for sub in refs.iter_mut() {
+            sub.take();
+        }
+
+        // Five connections and the statistics, for each of the three
+        // subscribers, are more responses than are routed at once
+        for transport in refs[3..].iter_mut() {
+            let client = transport.clients[0];
+            transport.send(client, control(1, register("client")));
+        }
+        host.broker_mut().schedule_stats();
+        poll(&mut host, &mut refs).unwrap();
+
+        let sent: StdVec<StdVec<Sent>> = refs[..3].iter_mut().map(|sub| sub.take()).collect();
+        assert!(sent.iter().all(|s| s.len() == 6));
+        assert_eq!(host.broker().sys.counters.stats().dropped, 0);
+
+        // Messages are sent in the order they occurred
+        let mut buf = [0u8; 64];
+        let payload = to_slice(
+            &ClientConnected {
+                uuid: uuid(4),
+                version: VERSION,
+            },
+            &mut buf,
+        )
+        .unwrap();
+        match sent[0][0].msg() {
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(msg))) => {
+                assert_eq!(msg.payload, &payload[..]);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+        match sent[0][5].msg() {
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(msg))) => {
+                assert_eq!(
+                    msg.path,
+                    PubSubPath::Long(Path::borrow_from_str(STATS_PATH))
+                );
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+}
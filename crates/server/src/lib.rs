@@ -4,23 +4,61 @@
 
 #![no_std]
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
 use {
+    crate::{
+        acl::Acl,
+        sys::{Counters, Sys},
+    },
     anachro_icd::{
-        arbitrator::{self, Arbitrator, Control as AControl, ControlError, SubMsg},
+        arbitrator::{
+            self, Arbitrator, Control as AControl, ControlError, ControlResponse, PubSubError,
+            SubMsg,
+        },
+        auth::{verify_auth_mac, AUTH_NONCE_LEN},
         component::{
             Component, ComponentInfo, Control, ControlType, PubSub, PubSubShort, PubSubType,
         },
-        ManagedString,
+        schema::SchemaHash,
+        sys::is_sys_path,
+        ManagedString, MaxPathLen,
     },
-    core::default::Default,
-    heapless::{consts, ArrayLength, Vec},
+    core::{
+        default::Default,
+        future::Future,
+        mem::replace,
+        pin::Pin,
+        task::{Context, Poll},
+    },
+    heapless::{consts, ArrayLength, String, Vec},
 };
 
 pub use anachro_icd::{self, Name, Path, PubSubPath, Uuid, Version};
 use defmt::Format;
 pub use postcard::from_bytes_cobs;
 
-type ClientStore = Vec<Client, consts::U8>;
+pub use crate::acl::{AclRule, ClientMatch};
+pub use crate::auth::AuthConfig;
+#[cfg(feature = "std")]
+pub use crate::host::StdBrokerHost;
+pub use crate::host::{BrokerHost, HostEvent, HostedTransport};
+#[cfg(feature = "std")]
+pub use crate::snapshot::FileStore;
+pub use crate::snapshot::{
+    FixedStore, FixedStoreError, SnapshotError, SnapshotStore, StoreError, SNAPSHOT_VERSION,
+};
+
+mod acl;
+mod auth;
+mod host;
+mod snapshot;
+mod sys;
+
+/// The most clients that can be registered with a Broker
+pub(crate) type MaxClients = consts::U8;
+type ClientStore = Vec<Client, MaxClients>;
 
 /// The Broker Interface
 ///
@@ -28,15 +66,33 @@ type ClientStore = Vec<Client, consts::U8>;
 ///
 /// Currently the max capacity is fixed with a maximum of 8
 /// clients connected. Each Client may subscribe up to 8 topics.
-/// Each Client may register up to 8 shortcodes.
+/// Each Client may register up to 8 shortcodes, or be assigned them
+/// by the broker, see `ControlType::AssignPubSubShortIds`.
 ///
 /// In the future, these limits may be configurable.
 ///
 /// As a note, the Broker currently creates a sizable object, due
 /// to the fixed upper limits
+///
+/// The state of all clients can be saved with `Broker::snapshot`, and
+/// restored after a reboot with `Broker::restore`.
+///
+/// The paths each client may publish and subscribe to can be restricted
+/// with `Broker::add_acl_rule`, and clients can be required to
+/// authenticate with a pre-shared key with `Broker::set_auth`.
+///
+/// The Broker publishes connection events and statistics on the paths
+/// described in `anachro_icd::sys`, which are sent by `Broker::process_sys`.
 #[derive(Default)]
 pub struct Broker {
     clients: ClientStore,
+    acl: Acl,
+    auth: Option<AuthConfig>,
+    sys: Sys,
+
+    /// The path of the message being published, if it was published
+    /// using a shortcode
+    short_path: String<MaxPathLen>,
 }
 
 #[derive(Debug, PartialEq, Eq, Format)]
@@ -49,6 +105,9 @@ pub enum ServerError {
     UnknownShortcode,
     InternalError,
     DeserializeFailure,
+
+    /// The transport reported an error, but has not been closed
+    LinkFailure,
 }
 
 pub const RESET_MESSAGE: Arbitrator = Arbitrator::Control(AControl {
@@ -99,7 +158,7 @@ impl Broker {
             .iter()
             .position(|c| &c.id == id)
             .ok_or(ServerError::UnknownClient)?;
-        self.clients.swap_remove(pos);
+        self.remove_at(pos);
         Ok(())
     }
 
@@ -107,11 +166,84 @@ impl Broker {
     ///
     /// This could be necessary if the connection to a client breaks or times out.
     pub fn reset_client(&mut self, id: &Uuid) -> Result<(), ServerError> {
-        let mut client = self.client_by_id_mut(id)?;
-        client.state = ClientState::SessionEstablished;
+        let Broker { clients, sys, .. } = self;
+        let client = clients
+            .iter_mut()
+            .find(|c| &c.id == id)
+            .ok_or(ServerError::UnknownClient)?;
+
+        if let ClientState::Connected(state) =
+            replace(&mut client.state, ClientState::SessionEstablished)
+        {
+            sys.disconnected(client.id, state.name);
+        }
         Ok(())
     }
 
+    /// Restrict the paths that clients may publish and subscribe to
+    ///
+    /// Until a rule has been added, all clients may publish and subscribe
+    /// to any path. Once one has been added, each publish and subscribe
+    /// must be allowed by a rule that applies to the client, otherwise the
+    /// client is sent a `PubSubError::NotAuthorized`.
+    ///
+    /// Up to 16 rules may be added.
+    pub fn add_acl_rule(&mut self, rule: AclRule) -> Result<(), ServerError> {
+        self.acl.add(rule)
+    }
+
+    /// Remove all access control rules, allowing all clients to publish
+    /// and subscribe to any path
+    pub fn clear_acl_rules(&mut self) {
+        self.acl.clear();
+    }
+
+    /// Require clients to authenticate with a pre-shared key when they
+    /// register
+    ///
+    /// Clients that have already registered are not affected. Set to `None`
+    /// to register clients without authentication.
+    pub fn set_auth(&mut self, auth: Option<AuthConfig>) {
+        self.auth = auth;
+    }
+
+    /// Publish statistics on `$sys/stats` the next time `process_sys`
+    /// is called
+    ///
+    /// This is typically called periodically, e.g. from a timer.
+    pub fn schedule_stats(&mut self) {
+        self.sys.schedule_stats();
+    }
+
+    /// Send any pending system messages to subscribed clients
+    ///
+    /// Clients connecting and disconnecting are announced on
+    /// `$sys/clients/<name>/connected` and `$sys/clients/<name>/disconnected`,
+    /// and statistics are published on `$sys/stats` once scheduled with
+    /// `Broker::schedule_stats`. These messages are queued until this
+    /// is called, which should be done regularly, e.g. after each call
+    /// to `process_msg`.
+    pub fn process_sys<'a, SO: ServerIoOut<'a>>(
+        &'a mut self,
+        sio_out: &mut SO,
+    ) -> Result<(), ServerError> {
+        let Broker { clients, sys, .. } = self;
+        sys.process(clients, sio_out)
+    }
+
+    /// Send only the oldest pending system message, returning whether
+    /// more remain
+    ///
+    /// This needs at most two responses for each client: the message, and a
+    /// shortcode announcement before it.
+    pub(crate) fn process_sys_next<'a, SO: ServerIoOut<'a>>(
+        &'a mut self,
+        sio_out: &mut SO,
+    ) -> Result<bool, ServerError> {
+        let Broker { clients, sys, .. } = self;
+        sys.process_next(clients, sio_out)
+    }
+
     /// Process a single message from a client
     ///
     /// A message from a client will be processed. If processing this message
@@ -126,7 +258,7 @@ impl Broker {
     /// that client to force them to reconnect. You may also want to `remove_client`
     /// or `reset_client`, depending on the situation. This will hopefully be handled
     /// automatically in the future.
-    pub fn process_msg<'req, 'sio, 'me: 'req, SI: ServerIoIn, SO: ServerIoOut<'req>>(
+    pub fn process_msg<'req, 'sio, 'me: 'req, SI: ServerIoIn + ?Sized, SO: ServerIoOut<'req>>(
         &'me mut self,
         sio_in: &'req mut SI,
         sio_out: &'sio mut SO,
@@ -150,16 +282,73 @@ impl Broker {
                         defmt::error!("Broker: Got Bad Deserialize");
                         return Err(ServerError::DeserializeFailure);
                     }
+                    ServerIoError::Disconnected => {
+                        defmt::error!("Broker: Transport Closed");
+                        return Err(ServerError::ClientDisconnected);
+                    }
+                    ServerIoError::MisroutedResponse => {
+                        defmt::error!("Broker: Misrouted Response");
+                        return Err(ServerError::InternalError);
+                    }
+                    ServerIoError::LinkFailure => {
+                        defmt::error!("Broker: Link Failure");
+                        return Err(ServerError::LinkFailure);
+                    }
                 }
             }
         };
 
+        // Only registration is accepted from a client that has not connected
+        // yet. Anything else resets that client, so that it registers again.
+        let registering = match &msg {
+            Component::Control(Control { ty, .. }) => matches!(
+                ty,
+                ControlType::RegisterComponent(_)
+                    | ControlType::RegisterBridge(_)
+                    | ControlType::Authenticate { .. }
+            ),
+            Component::PubSub(_) => false,
+        };
+        let connected = self.client_by_id_mut(&source)?.state.as_connected().is_ok();
+        if !registering && !connected {
+            defmt::warn!("Broker: Request before registration");
+            return sio_out
+                .push_response(Response {
+                    dest: source,
+                    msg: RESET_MESSAGE,
+                })
+                .map_err(|_| ServerError::ResourcesExhausted);
+        }
+
         match msg {
             Component::Control(ctrl) => {
                 defmt::info!("Broker: Got Control");
-                let client = self.client_by_id_mut(&source)?;
+                let Broker {
+                    clients, auth, sys, ..
+                } = self;
+                let (client, others) = split_client(clients, &source)?;
+
+                // Registering again ends the current session, along with
+                // its subscriptions and shortcodes
+                let register = matches!(
+                    ctrl.ty,
+                    ControlType::RegisterComponent(_) | ControlType::RegisterBridge(_)
+                );
+                let ended = match &client.state {
+                    ClientState::Connected(state) if register => Some(state.name.clone()),
+                    _ => None,
+                };
+                let was_connected = client.state.as_connected().is_ok() && ended.is_none();
+                let reply = client.process_control(&ctrl, auth.as_ref(), &others)?;
 
-                if let Some(msg) = client.process_control(&ctrl)? {
+                if let Some(name) = ended {
+                    sys.disconnected(client.id, name);
+                }
+                if let (false, Ok(state)) = (was_connected, client.state.as_connected()) {
+                    sys.connected(client.id, &state.name, state.version);
+                }
+
+                if let Some(msg) = reply {
                     defmt::info!("Broker: Reply Control");
                     sio_out
                         .push_response(msg)
@@ -168,18 +357,46 @@ impl Broker {
             }
             Component::PubSub(PubSub { ref path, ref ty }) => match ty {
                 PubSubType::Pub { ref payload } => {
-                    self.process_publish(sio_out, path, payload, source)?;
+                    self.process_publish(sio_out, path, payload, None, source, None)?;
                 }
-                PubSubType::Sub => {
+                PubSubType::TypedPub { payload, schema } => {
+                    self.process_publish(sio_out, path, payload, Some(*schema), source, None)?;
+                }
+                PubSubType::BridgedPub { payload, origin } => {
+                    // The origin stops messages from being forwarded in loops,
+                    // so only bridges may set it
                     let client = self.client_by_id_mut(&source)?;
+                    let bridge = matches!(client.state.as_connected(), Ok(state) if state.bridge);
+
+                    if bridge {
+                        self.process_publish(sio_out, path, payload, None, source, Some(*origin))?;
+                    } else {
+                        defmt::warn!("Broker: Bridged publish from a non-bridge");
+                        sio_out
+                            .push_response(Response {
+                                dest: source,
+                                msg: Arbitrator::Control(AControl {
+                                    seq: 0,
+                                    response: Err(ControlError::NotBridge),
+                                }),
+                            })
+                            .map_err(|_| ServerError::ResourcesExhausted)?;
+                    }
+                }
+                PubSubType::Sub | PubSubType::TypedSub { .. } => {
+                    let schema = match ty {
+                        PubSubType::TypedSub { schema } => Some(*schema),
+                        _ => None,
+                    };
+                    let Broker { clients, acl, .. } = self;
+                    let (client, others) = split_client(clients, &source)?;
                     sio_out
-                        .push_response(client.process_subscribe(path)?)
+                        .push_response(client.process_subscribe(path, acl, schema, &others)?)
                         .map_err(|_| ServerError::ResourcesExhausted)?;
                 }
                 PubSubType::Unsub => {
                     let client = self.client_by_id_mut(&source)?;
                     client.process_unsub(path)?;
-                    todo!()
                 }
             },
         }
@@ -197,27 +414,43 @@ impl Broker {
             .ok_or(ServerError::UnknownClient)
     }
 
+    /// Remove the client at `pos`, announcing its disconnection
+    fn remove_at(&mut self, pos: usize) {
+        let client = self.clients.swap_remove(pos);
+        if let ClientState::Connected(state) = client.state {
+            self.sys.disconnected(client.id, state.name);
+        }
+    }
+
     fn process_publish<'req, 'sio, 'me: 'req, SO: ServerIoOut<'req>>(
         &'me mut self,
         sio: &'sio mut SO,
         path: &PubSubPath<'req>,
         payload: &'req [u8],
+        schema: Option<SchemaHash>,
         source: Uuid,
+        origin: Option<Uuid>,
     ) -> Result<(), ServerError> {
         // TODO: Make sure we're not publishing to wildcards
+        let Broker {
+            clients,
+            acl,
+            sys,
+            short_path,
+            ..
+        } = self;
 
         // First, find the sender's path
-        let source_id = self
-            .clients
+        let source_id = clients
             .iter()
             .filter_map(|c| c.state.as_connected().ok().map(|x| (c, x)))
             .find(|(c, _x)| c.id == source)
             .ok_or(ServerError::UnknownClient)?;
-        let path = match path {
+        let (path, schema) = match path {
             // TODO: I need to make sure this is &'req, NOT &'path! That would only happen
             // if I had an Owned string here.
             PubSubPath::Long(lp) => {
-                match lp {
+                let lp = match lp {
                     ManagedString::Owned(_) => {
                         // So, we should never have an owned string here.
                         // Having one would severely mess up our lifetimes,
@@ -228,65 +461,209 @@ impl Broker {
                         return Err(ServerError::InternalError);
                     }
                     ManagedString::Borrow(lp) => *lp,
-                }
+                };
+
+                // Untyped publishes use the type registered with a shortcode
+                let registered = source_id.1.shortcuts.iter().find(|s| s.long.as_str() == lp);
+                (lp, schema.or_else(|| registered.and_then(|s| s.schema)))
             }
-            PubSubPath::Short(sid) => &source_id
-                .1
-                .shortcuts
+            PubSubPath::Short(sid) => {
+                let shortcut = source_id
+                    .1
+                    .shortcuts
+                    .iter()
+                    .find(|s| &s.short == sid)
+                    .ok_or(ServerError::UnknownShortcode)?;
+                let long = shortcut.long.as_str();
+
+                // NOTE: The path is copied, as subscribers may be assigned
+                // shortcodes while it is borrowed by their messages
+                short_path.clear();
+                short_path
+                    .push_str(long)
+                    .map_err(|_| ServerError::InternalError)?;
+                let short_path: &'me String<MaxPathLen> = short_path;
+                (short_path.as_str(), schema.or(shortcut.schema))
+            }
+        };
+
+        // Only the Broker may publish on system paths
+        if is_sys_path(path) || !acl.allows_publish(&source, source_id.1.name.as_str(), path) {
+            defmt::warn!("Broker: Publish not authorized");
+            sio.push_response(Response {
+                dest: source,
+                msg: Arbitrator::PubSub(Err(PubSubError::NotAuthorized)),
+            })
+            .map_err(|_| ServerError::ResourcesExhausted)?;
+            return Ok(());
+        }
+
+        // Every client must agree on the type of the payload
+        let mismatch = schema.and_then(|schema| {
+            clients
                 .iter()
-                .find(|s| &s.short == sid)
-                .ok_or(ServerError::UnknownShortcode)?
-                .long
-                .as_str(),
+                .filter_map(|c| c.state.as_connected().ok())
+                .find_map(|state| state.schema_mismatch(path, schema))
+        });
+        if let Some(registered) = mismatch {
+            defmt::warn!("Broker: Publish schema mismatch");
+            sio.push_response(Response {
+                dest: source,
+                msg: Arbitrator::PubSub(Err(PubSubError::SchemaMismatch { registered })),
+            })
+            .map_err(|_| ServerError::ResourcesExhausted)?;
+            return Ok(());
+        }
+
+        sys.counters.published(&source, path, payload.len());
+        deliver(
+            clients,
+            &mut sys.counters,
+            sio,
+            path,
+            payload,
+            Some(source),
+            origin,
+        )
+    }
+}
+
+/// Send a message published on `path` to each subscribed client, other
+/// than its `source`
+///
+/// If a message can not be pushed, it is counted as dropped, the remaining
+/// clients are still attempted, and `ServerError::ResourcesExhausted` is
+/// returned.
+///
+/// Subscribers that have asked for shortcodes to be assigned are assigned
+/// one for `path`, if possible, which is announced before the message.
+fn deliver<'a, SO: ServerIoOut<'a>>(
+    clients: &mut ClientStore,
+    counters: &mut Counters,
+    sio: &mut SO,
+    path: &'a str,
+    payload: &'a [u8],
+    source: Option<Uuid>,
+    origin: Option<Uuid>,
+) -> Result<(), ServerError> {
+    let mut result = Ok(());
+
+    // Find all applicable destinations, max of 1 per destination
+    for client in clients.iter_mut() {
+        let id = client.id;
+        let state = match client.state.as_connected_mut() {
+            Ok(state) => state,
+            Err(_) => continue,
         };
 
-        // Then, find all applicable destinations, max of 1 per destination
-        'client: for (client, state) in self
-            .clients
+        if Some(id) == source {
+            // Don't send messages back to the sender
+            continue;
+        }
+
+        if !state
+            .subscriptions
             .iter()
-            .filter_map(|c| c.state.as_connected().ok().map(|x| (c, x)))
+            .any(|subt| anachro_icd::matches(subt.path.as_str(), path))
         {
-            if client.id == source {
-                // Don't send messages back to the sender
-                continue;
-            }
+            continue;
+        }
 
-            for subt in state.subscriptions.iter() {
-                if anachro_icd::matches(subt.as_str(), path) {
-                    // Does the destination have a shortcut for this?
-                    for short in state.shortcuts.iter() {
-                        // NOTE: we use path, NOT subt, as it may contain wildcards
-                        if path == short.long.as_str() {
-                            let msg = Arbitrator::PubSub(Ok(arbitrator::PubSubResponse::SubMsg(
-                                SubMsg {
-                                    path: PubSubPath::Short(short.short),
-                                    payload,
-                                },
-                            )));
-                            sio.push_response(Response {
-                                dest: client.id,
-                                msg,
-                            })
-                            .map_err(|_| ServerError::ResourcesExhausted)?;
-                            continue 'client;
+        // Does the destination have a shortcut for this?
+        // NOTE: we use path, NOT subt, as it may contain wildcards
+        let short = match state.shortcuts.iter().find(|s| path == s.long.as_str()) {
+            Some(short) => Some(short.short),
+            None => match state.assign_shortcuts {
+                Some(seq) => match state.assign_shortcut(path, None) {
+                    Some(short_id) => {
+                        let announced = sio.push_response(Response {
+                            dest: id,
+                            msg: Arbitrator::Control(arbitrator::Control {
+                                seq,
+                                response: Ok(ControlResponse::PubSubShortAssignment {
+                                    long_name: path,
+                                    short_id,
+                                }),
+                            }),
+                        });
+
+                        if announced.is_err() {
+                            // The message would use a shortcode the client
+                            // doesn't know
+                            defmt::warn!("Broker: Message dropped");
+                            state.remove_shortcut(short_id);
+                            counters.dropped(&id);
+                            result = Err(ServerError::ResourcesExhausted);
+                            continue;
                         }
+                        Some(short_id)
                     }
+                    None => None,
+                },
+                None => None,
+            },
+        };
 
-                    let msg = Arbitrator::PubSub(Ok(arbitrator::PubSubResponse::SubMsg(SubMsg {
-                        path: PubSubPath::Long(Path::borrow_from_str(path)),
-                        payload,
-                    })));
-                    sio.push_response(Response {
-                        dest: client.id,
-                        msg,
-                    })
-                    .map_err(|_| ServerError::ResourcesExhausted)?;
-                    continue 'client;
-                }
+        let path = match short {
+            Some(short) => PubSubPath::Short(short),
+            None => PubSubPath::Long(Path::borrow_from_str(path)),
+        };
+
+        let msg = SubMsg { path, payload };
+        let msg = if state.bridge {
+            arbitrator::PubSubResponse::BridgedSubMsg { msg, origin }
+        } else {
+            arbitrator::PubSubResponse::SubMsg(msg)
+        };
+
+        let pushed = sio.push_response(Response {
+            dest: id,
+            msg: Arbitrator::PubSub(Ok(msg)),
+        });
+
+        match pushed {
+            Ok(()) => counters.delivered(&id, payload.len()),
+            Err(_) => {
+                defmt::warn!("Broker: Message dropped");
+                counters.dropped(&id);
+                result = Err(ServerError::ResourcesExhausted);
             }
         }
+    }
 
-        Ok(())
+    result
+}
+
+/// Find the client with the given `id`, along with all other clients
+fn split_client<'a>(
+    clients: &'a mut [Client],
+    id: &Uuid,
+) -> Result<(&'a mut Client, Others<'a>), ServerError> {
+    let pos = clients
+        .iter()
+        .position(|c| &c.id == id)
+        .ok_or(ServerError::UnknownClient)?;
+    let (before, rest) = clients.split_at_mut(pos);
+    let (client, after) = rest.split_first_mut().ok_or(ServerError::InternalError)?;
+
+    Ok((client, Others { before, after }))
+}
+
+/// The clients other than the one a request is being processed for
+struct Others<'a> {
+    before: &'a [Client],
+    after: &'a [Client],
+}
+
+impl<'a> Others<'a> {
+    /// Find a schema, other than `schema`, that a connected client has
+    /// registered for a path overlapping `path`
+    fn schema_mismatch(&self, path: &str, schema: SchemaHash) -> Option<SchemaHash> {
+        self.before
+            .iter()
+            .chain(self.after.iter())
+            .filter_map(|c| c.state.as_connected().ok())
+            .find_map(|state| state.schema_mismatch(path, schema))
     }
 }
 
@@ -296,83 +673,221 @@ struct Client {
 }
 
 impl Client {
-    fn process_control(&mut self, ctrl: &Control) -> Result<Option<Response>, ServerError> {
+    fn process_control<'a>(
+        &mut self,
+        ctrl: &Control<'a>,
+        auth: Option<&AuthConfig>,
+        others: &Others,
+    ) -> Result<Option<Response<'a>>, ServerError> {
         let response;
 
         let next = match &ctrl.ty {
-            ControlType::RegisterComponent(ComponentInfo { name, version }) => match &self.state {
-                ClientState::SessionEstablished | ClientState::Connected(_) => {
+            ControlType::RegisterComponent(ComponentInfo { name, version })
+            | ControlType::RegisterBridge(ComponentInfo { name, version }) => match &self.state {
+                ClientState::SessionEstablished
+                | ClientState::Authenticating(_)
+                | ClientState::Connected(_) => {
                     defmt::info!("Broker: Got Register");
 
-                    let resp = Arbitrator::Control(arbitrator::Control {
-                        seq: ctrl.seq,
-                        response: Ok(arbitrator::ControlResponse::ComponentRegistration(self.id)),
-                    });
+                    let bridge = matches!(ctrl.ty, ControlType::RegisterBridge(_));
+
+                    let name = name
+                        .try_to_owned()
+                        .map_err(|_| ServerError::ResourcesExhausted)?;
+
+                    let (resp, next) = match auth {
+                        None => {
+                            defmt::info!("Broker: Reply Connected");
+
+                            let resp =
+                                Ok(arbitrator::ControlResponse::ComponentRegistration(self.id));
+                            let next = ClientState::Connected(ConnectedState {
+                                name,
+                                version: *version,
+                                bridge,
+                                assign_shortcuts: None,
+                                subscriptions: Vec::new(),
+                                shortcuts: Vec::new(),
+                            });
+                            (resp, next)
+                        }
+                        Some(auth) if auth.key_for(name.as_str()).is_some() => {
+                            defmt::info!("Broker: Reply Challenge");
+
+                            let nonce = (auth.nonce)();
+                            let resp = Ok(arbitrator::ControlResponse::AuthChallenge(nonce));
+                            let next = ClientState::Authenticating(PendingAuth {
+                                name,
+                                version: *version,
+                                bridge,
+                                nonce,
+                            });
+                            (resp, next)
+                        }
+                        Some(_) => {
+                            defmt::warn!("Broker: No key for client");
+
+                            let resp = Err(ControlError::AuthenticationFailed);
+                            (resp, ClientState::SessionEstablished)
+                        }
+                    };
 
                     response = Some(Response {
                         dest: self.id,
-                        msg: resp,
+                        msg: Arbitrator::Control(arbitrator::Control {
+                            seq: ctrl.seq,
+                            response: resp,
+                        }),
                     });
 
+                    Some(next)
+                }
+            },
+            ControlType::Authenticate { mac } => {
+                let pending = match &self.state {
+                    ClientState::Authenticating(pending) => pending,
+                    _ => {
+                        defmt::warn!("Broker: Unexpected Authenticate");
+                        return Ok(Some(Response {
+                            dest: self.id,
+                            msg: Arbitrator::Control(arbitrator::Control {
+                                seq: ctrl.seq,
+                                response: Err(ControlError::AuthenticationFailed),
+                            }),
+                        }));
+                    }
+                };
+
+                let name = pending.name.as_str();
+                let verified = match auth.and_then(|a| a.key_for(name)) {
+                    Some(key) => verify_auth_mac(key, &pending.nonce, name, mac),
+                    None => false,
+                };
+
+                let (resp, next) = if verified {
                     defmt::info!("Broker: Reply Connected");
 
-                    Some(ClientState::Connected(ConnectedState {
-                        name: name
-                            .try_to_owned()
-                            .map_err(|_| ServerError::ResourcesExhausted)?,
-                        version: *version,
+                    let resp = Ok(arbitrator::ControlResponse::ComponentRegistration(self.id));
+                    let next = ClientState::Connected(ConnectedState {
+                        name: pending.name.clone(),
+                        version: pending.version,
+                        bridge: pending.bridge,
+                        assign_shortcuts: None,
                         subscriptions: Vec::new(),
                         shortcuts: Vec::new(),
-                    }))
-                }
-            },
+                    });
+                    (resp, next)
+                } else {
+                    // A new challenge is needed for another attempt
+                    defmt::warn!("Broker: Authentication failed");
+                    let resp = Err(ControlError::AuthenticationFailed);
+                    (resp, ClientState::SessionEstablished)
+                };
+
+                response = Some(Response {
+                    dest: self.id,
+                    msg: Arbitrator::Control(arbitrator::Control {
+                        seq: ctrl.seq,
+                        response: resp,
+                    }),
+                });
+
+                Some(next)
+            }
             ControlType::RegisterPubSubShortId(PubSubShort {
                 long_name,
                 short_id,
             }) => {
                 let state = self.state.as_connected_mut()?;
 
-                if long_name.contains('#') || long_name.contains('+') {
-                    // TODO: How to handle wildcards + short names?
-                    let resp = Arbitrator::Control(arbitrator::Control {
-                        seq: ctrl.seq,
-                        response: Err(arbitrator::ControlError::NoWildcardsInShorts),
-                    });
-
-                    response = Some(Response {
-                        dest: self.id,
-                        msg: resp,
-                    });
+                // A shortcode names a single path. Messages matched by a
+                // wildcard subscription are instead assigned a shortcode for
+                // each path they were published on, if the client has sent
+                // an `AssignPubSubShortIds`
+                let resp = if long_name.contains('#') || long_name.contains('+') {
+                    Err(ControlError::NoWildcardsInShorts)
                 } else {
-                    let shortcut_exists = state
-                        .shortcuts
-                        .iter()
-                        .any(|sc| (sc.long.as_str() == *long_name) && (sc.short == *short_id));
-
-                    if !shortcut_exists {
-                        state
-                            .shortcuts
-                            .push(Shortcut {
-                                long: Path::try_from_str(long_name).unwrap(),
-                                short: *short_id,
-                            })
-                            .map_err(|_| ServerError::ResourcesExhausted)?;
+                    match state.shortcuts.iter().find(|sc| sc.short == *short_id) {
+                        Some(sc) if sc.long.as_str() == *long_name => {
+                            Ok(ControlResponse::PubSubShortRegistration(*short_id))
+                        }
+                        Some(_) => {
+                            // Either chosen by the client before, or assigned
+                            // by the Broker
+                            defmt::warn!("Broker: Shortcode already in use");
+                            Err(ControlError::ShortIdInUse)
+                        }
+                        None => {
+                            state
+                                .shortcuts
+                                .push(Shortcut {
+                                    long: Path::try_from_str(long_name)
+                                        .map_err(|_| ServerError::ResourcesExhausted)?,
+                                    short: *short_id,
+                                    schema: None,
+                                })
+                                .map_err(|_| ServerError::ResourcesExhausted)?;
+                            Ok(ControlResponse::PubSubShortRegistration(*short_id))
+                        }
                     }
+                };
 
-                    let resp = Arbitrator::Control(arbitrator::Control {
+                response = Some(Response {
+                    dest: self.id,
+                    msg: Arbitrator::Control(arbitrator::Control {
                         seq: ctrl.seq,
-                        response: Ok(arbitrator::ControlResponse::PubSubShortRegistration(
-                            *short_id,
-                        )),
-                    });
+                        response: resp,
+                    }),
+                });
 
-                    response = Some(Response {
-                        dest: self.id,
-                        msg: resp,
-                    });
-                }
+                None
+            }
+            ControlType::AssignPubSubShortIds => {
+                let state = self.state.as_connected_mut()?;
+                state.assign_shortcuts = Some(ctrl.seq);
+
+                response = Some(Response {
+                    dest: self.id,
+                    msg: Arbitrator::Control(arbitrator::Control {
+                        seq: ctrl.seq,
+                        response: Ok(ControlResponse::PubSubShortAssignmentEnabled),
+                    }),
+                });
+
+                None
+            }
+            ControlType::RequestPubSubShortId { long_name, schema } => {
+                let state = self.state.as_connected_mut()?;
+                let mismatch = schema.and_then(|schema| {
+                    state
+                        .schema_mismatch(long_name, schema)
+                        .or_else(|| others.schema_mismatch(long_name, schema))
+                });
+
+                let resp = if long_name.contains('#') || long_name.contains('+') {
+                    Err(ControlError::NoWildcardsInShorts)
+                } else if Path::try_from_str(long_name).is_err() {
+                    return Err(ServerError::ResourcesExhausted);
+                } else if let Some(registered) = mismatch {
+                    defmt::warn!("Broker: Schema mismatch");
+                    Err(ControlError::SchemaMismatch { registered })
+                } else {
+                    match state.assign_shortcut(long_name, *schema) {
+                        Some(short_id) => Ok(ControlResponse::PubSubShortAssignment {
+                            long_name,
+                            short_id,
+                        }),
+                        None => Err(ControlError::ShortIdsExhausted),
+                    }
+                };
 
-                // TODO: Dupe check?
+                response = Some(Response {
+                    dest: self.id,
+                    msg: Arbitrator::Control(arbitrator::Control {
+                        seq: ctrl.seq,
+                        response: resp,
+                    }),
+                });
 
                 None
             }
@@ -388,6 +903,9 @@ impl Client {
     fn process_subscribe<'a, 'b>(
         &mut self,
         path: &'a PubSubPath<'b>,
+        acl: &Acl,
+        schema: Option<SchemaHash>,
+        others: &Others,
     ) -> Result<Response<'b>, ServerError> {
         let state = self.state.as_connected_mut()?;
 
@@ -403,17 +921,41 @@ impl Client {
                 .as_str(),
         };
 
+        if !acl.allows_subscribe(&self.id, state.name.as_str(), path_str) {
+            defmt::warn!("Broker: Subscribe not authorized");
+            return Ok(Response {
+                dest: self.id,
+                msg: Arbitrator::PubSub(Err(PubSubError::NotAuthorized)),
+            });
+        }
+
+        let mismatch = schema.and_then(|schema| {
+            state
+                .schema_mismatch(path_str, schema)
+                .or_else(|| others.schema_mismatch(path_str, schema))
+        });
+        if let Some(registered) = mismatch {
+            defmt::warn!("Broker: Schema mismatch");
+            return Ok(Response {
+                dest: self.id,
+                msg: Arbitrator::PubSub(Err(PubSubError::SchemaMismatch { registered })),
+            });
+        }
+
         // Only push if not a dupe
-        if state
+        match state
             .subscriptions
-            .iter()
-            .find(|s| s.as_str() == path_str)
-            .is_none()
+            .iter_mut()
+            .find(|s| s.path.as_str() == path_str)
         {
-            state
+            Some(existing) => existing.schema = schema.or(existing.schema),
+            None => state
                 .subscriptions
-                .push(Path::try_from_str(path_str).unwrap())
-                .map_err(|_| ServerError::ResourcesExhausted)?;
+                .push(Subscription {
+                    path: Path::try_from_str(path_str).unwrap(),
+                    schema,
+                })
+                .map_err(|_| ServerError::ResourcesExhausted)?,
         }
 
         let resp = Arbitrator::PubSub(Ok(arbitrator::PubSubResponse::SubAck {
@@ -426,10 +968,31 @@ impl Client {
         })
     }
 
-    fn process_unsub(&mut self, _path: &PubSubPath) -> Result<(), ServerError> {
-        let _state = self.state.as_connected_mut()?;
+    fn process_unsub(&mut self, path: &PubSubPath) -> Result<(), ServerError> {
+        let state = self.state.as_connected_mut()?;
 
-        todo!()
+        // Determine canonical path
+        let path_str = match path {
+            PubSubPath::Long(lp) => lp.as_str(),
+            PubSubPath::Short(sid) => state
+                .shortcuts
+                .iter()
+                .find(|s| &s.short == sid)
+                .ok_or(ServerError::UnknownShortcode)?
+                .long
+                .as_str(),
+        };
+
+        // Unsubscribing from a path that was never subscribed to is not an error
+        if let Some(pos) = state
+            .subscriptions
+            .iter()
+            .position(|s| s.path.as_str() == path_str)
+        {
+            state.subscriptions.swap_remove(pos);
+        }
+
+        Ok(())
     }
 }
 
@@ -437,6 +1000,7 @@ impl Client {
 #[derive(Debug)]
 enum ClientState {
     SessionEstablished,
+    Authenticating(PendingAuth),
     Connected(ConnectedState),
 }
 
@@ -456,18 +1020,97 @@ impl ClientState {
     }
 }
 
+/// A client that has registered, but has not answered its
+/// authentication challenge yet
+#[derive(Debug)]
+struct PendingAuth {
+    name: Name<'static>,
+    version: Version,
+    bridge: bool,
+    nonce: [u8; AUTH_NONCE_LEN],
+}
+
 #[derive(Debug)]
 struct ConnectedState {
     name: Name<'static>,
     version: Version,
-    subscriptions: Vec<Path<'static>, consts::U8>,
+
+    /// Did the client register as a bridge?
+    bridge: bool,
+
+    /// The sequence number of the client's `AssignPubSubShortIds`, if
+    /// it has asked for shortcodes to be assigned
+    assign_shortcuts: Option<u16>,
+
+    subscriptions: Vec<Subscription, consts::U8>,
     shortcuts: Vec<Shortcut, consts::U8>,
 }
 
+impl ConnectedState {
+    /// Find or assign a shortcode for `long`, recording its `schema` if
+    /// one is given
+    ///
+    /// Returns `None` if no more shortcodes can be held.
+    fn assign_shortcut(&mut self, long: &str, schema: Option<SchemaHash>) -> Option<u16> {
+        if let Some(short) = self.shortcuts.iter_mut().find(|s| s.long.as_str() == long) {
+            short.schema = schema.or(short.schema);
+            return Some(short.short);
+        }
+
+        // Use the lowest shortcode that isn't taken yet
+        let short = (0..=u16::MAX).find(|id| self.shortcuts.iter().all(|s| s.short != *id))?;
+        let long = Path::try_from_str(long).ok()?;
+        self.shortcuts
+            .push(Shortcut {
+                long,
+                short,
+                schema,
+            })
+            .ok()?;
+        Some(short)
+    }
+
+    /// Find a schema, other than `schema`, that this client has registered
+    /// for a path overlapping `path`
+    fn schema_mismatch(&self, path: &str, schema: SchemaHash) -> Option<SchemaHash> {
+        let subscriptions = self.subscriptions.iter().map(|s| (&s.path, s.schema));
+        let shortcuts = self.shortcuts.iter().map(|s| (&s.long, s.schema));
+
+        subscriptions
+            .chain(shortcuts)
+            .find_map(|(registered_path, registered)| match registered {
+                Some(registered)
+                    if registered != schema
+                        && anachro_icd::overlaps(registered_path.as_str(), path) =>
+                {
+                    Some(registered)
+                }
+                _ => None,
+            })
+    }
+
+    fn remove_shortcut(&mut self, short: u16) {
+        if let Some(pos) = self.shortcuts.iter().position(|s| s.short == short) {
+            self.shortcuts.swap_remove(pos);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Subscription {
+    path: Path<'static>,
+
+    /// The schema hash of the payload type, if the client gave one
+    schema: Option<SchemaHash>,
+}
+
 #[derive(Debug)]
 struct Shortcut {
     long: Path<'static>,
     short: u16,
+
+    /// The schema hash of the payload type, if the client gave one
+    schema: Option<SchemaHash>,
 }
 
 /// A request FROM the Client, TO the Broker
@@ -492,12 +1135,76 @@ pub struct Response<'a> {
 pub enum ServerIoError {
     ResponsePushFailed,
     DeserializeFailure,
+
+    /// The underlying transport has been closed
+    Disconnected,
+
+    /// The response is addressed to a client that is not reached through
+    /// this transport
+    MisroutedResponse,
+
+    /// The underlying transport reported an error while driving the link
+    LinkFailure,
 }
 
 pub trait ServerIoIn {
     fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError>;
 }
 
+/// An extension of `ServerIoIn` for transports that can notify a task
+/// when they are ready to make progress.
+///
+/// The futures provided do not depend on any specific executor.
+pub trait AsyncServerIoIn: ServerIoIn {
+    /// Poll whether a request is ready to be received.
+    ///
+    /// Returns `Poll::Ready` once a call to `ServerIoIn::recv` is expected to
+    /// make progress. Otherwise, the waker in `cx` is registered, and will be
+    /// woken when the transport should be polled again.
+    fn poll_recv_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ServerIoError>>;
+
+    /// Receive one request FROM a Client, TO the Broker
+    ///
+    /// This has the same semantics as `ServerIoIn::recv`, but waits until
+    /// the transport is ready before attempting to receive.
+    fn recv_async(&mut self) -> RecvFuture<'_, Self>
+    where
+        Self: Sized,
+    {
+        RecvFuture { io: Some(self) }
+    }
+}
+
+/// A future returned by `AsyncServerIoIn::recv_async`
+pub struct RecvFuture<'a, S: AsyncServerIoIn> {
+    io: Option<&'a mut S>,
+}
+
+impl<'a, S: AsyncServerIoIn> Future for RecvFuture<'a, S> {
+    type Output = Result<Option<Request<'a>>, ServerIoError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let io = self
+            .io
+            .as_mut()
+            .expect("RecvFuture polled after completion");
+
+        match io.poll_recv_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => {
+                self.io = None;
+                return Poll::Ready(Err(e));
+            }
+            Poll::Ready(Ok(())) => {}
+        }
+
+        // NOTE: The request borrows the io for the lifetime of this future,
+        // so the io is given up here, and the future is complete.
+        let io = self.io.take().unwrap();
+        Poll::Ready(ServerIoIn::recv(io))
+    }
+}
+
 pub trait ServerIoOut<'resp> {
     fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError>;
 }
@@ -511,3 +1218,440 @@ where
             .map_err(|_| ServerIoError::ResponsePushFailed)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use anachro_icd::arbitrator::PubSubResponse;
+    use std::boxed::Box;
+
+    pub(crate) const VERSION: Version = Version {
+        major: 0,
+        minor: 1,
+        trivial: 0,
+        misc: 0,
+    };
+
+    /// A response, serialized so that it no longer borrows the broker
+    #[derive(Debug)]
+    pub(crate) struct Sent {
+        pub(crate) dest: Uuid,
+        bytes: std::vec::Vec<u8>,
+    }
+
+    impl Sent {
+        pub(crate) fn new(resp: &Response) -> Self {
+            let mut buf = [0u8; 1024];
+            let used = postcard::to_slice(&resp.msg, &mut buf).unwrap();
+            Sent {
+                dest: resp.dest,
+                bytes: used.to_vec(),
+            }
+        }
+
+        pub(crate) fn msg(&self) -> Arbitrator<'_> {
+            postcard::from_bytes(&self.bytes).unwrap()
+        }
+    }
+
+    pub(crate) type Responses = std::vec::Vec<Sent>;
+
+    /// A `ServerIoIn` holding a single request
+    struct OneRequest(Option<Request<'static>>);
+
+    impl ServerIoIn for OneRequest {
+        fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+            Ok(self.0.take())
+        }
+    }
+
+    pub(crate) fn uuid(n: u8) -> Uuid {
+        Uuid::from_bytes([n; 16])
+    }
+
+    /// Process `msg` from `source`, returning the responses
+    pub(crate) fn try_process(
+        broker: &mut Broker,
+        source: Uuid,
+        msg: Component<'static>,
+    ) -> Result<Responses, ServerError> {
+        // NOTE: The responses borrow the request, so it is leaked
+        let input = Box::leak(Box::new(OneRequest(Some(Request { source, msg }))));
+        let mut responses: Vec<Response, consts::U16> = Vec::new();
+        broker.process_msg(input, &mut responses)?;
+        Ok(responses.iter().map(Sent::new).collect())
+    }
+
+    pub(crate) fn process(broker: &mut Broker, source: Uuid, msg: Component<'static>) -> Responses {
+        try_process(broker, source, msg).unwrap()
+    }
+
+    pub(crate) fn control(seq: u16, ty: ControlType<'static>) -> Component<'static> {
+        Component::Control(Control { seq, ty })
+    }
+
+    pub(crate) fn pubsub(path: &'static str, ty: PubSubType<'static>) -> Component<'static> {
+        Component::PubSub(PubSub {
+            path: PubSubPath::Long(Path::borrow_from_str(path)),
+            ty,
+        })
+    }
+
+    pub(crate) fn control_reply(
+        seq: u16,
+        response: Result<ControlResponse<'static>, ControlError>,
+    ) -> Arbitrator<'static> {
+        Arbitrator::Control(AControl { seq, response })
+    }
+
+    pub(crate) fn sub_msg(
+        path: PubSubPath<'static>,
+        payload: &'static [u8],
+    ) -> Arbitrator<'static> {
+        Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(SubMsg { path, payload })))
+    }
+
+    pub(crate) fn long(path: &'static str) -> PubSubPath<'static> {
+        PubSubPath::Long(Path::borrow_from_str(path))
+    }
+
+    pub(crate) fn register(name: &'static str) -> ControlType<'static> {
+        ControlType::RegisterComponent(ComponentInfo {
+            name: Name::borrow_from_str(name),
+            version: VERSION,
+        })
+    }
+
+    /// Register a client with the broker, and complete its registration
+    pub(crate) fn connect(broker: &mut Broker, id: Uuid, name: &'static str) {
+        broker.register_client(&id).unwrap();
+        let resp = process(broker, id, control(1, register(name)));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(1, Ok(ControlResponse::ComponentRegistration(id)))
+        );
+    }
+
+    pub(crate) fn subscribe(broker: &mut Broker, id: Uuid, path: &'static str) {
+        let resp = process(broker, id, pubsub(path, PubSubType::Sub));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(
+            resp[0].msg(),
+            Arbitrator::PubSub(Ok(PubSubResponse::SubAck { path: long(path) }))
+        );
+    }
+
+    pub(crate) fn publish(
+        broker: &mut Broker,
+        id: Uuid,
+        path: &'static str,
+        payload: &'static [u8],
+    ) -> Responses {
+        process(broker, id, pubsub(path, PubSubType::Pub { payload }))
+    }
+
+    #[test]
+    fn wildcard_shortcode_assignment() {
+        let mut broker = Broker::new();
+        let (sub, publ) = (uuid(1), uuid(2));
+        connect(&mut broker, sub, "sub");
+        connect(&mut broker, publ, "pub");
+
+        let resp = process(
+            &mut broker,
+            sub,
+            control(5, ControlType::AssignPubSubShortIds),
+        );
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(5, Ok(ControlResponse::PubSubShortAssignmentEnabled))
+        );
+        subscribe(&mut broker, sub, "led/+/color");
+
+        // The assignment is announced before the first message using it
+        let resp = publish(&mut broker, publ, "led/front/color", &[1]);
+        assert_eq!(resp.len(), 2);
+        assert!(resp.iter().all(|r| r.dest == sub));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(
+                5,
+                Ok(ControlResponse::PubSubShortAssignment {
+                    long_name: "led/front/color",
+                    short_id: 0,
+                })
+            )
+        );
+        assert_eq!(resp[1].msg(), sub_msg(PubSubPath::Short(0), &[1]));
+
+        let resp = publish(&mut broker, publ, "led/front/color", &[2]);
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].msg(), sub_msg(PubSubPath::Short(0), &[2]));
+
+        // Each path matched by the wildcard is assigned its own shortcode
+        let resp = publish(&mut broker, publ, "led/back/color", &[3]);
+        assert_eq!(resp.len(), 2);
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(
+                5,
+                Ok(ControlResponse::PubSubShortAssignment {
+                    long_name: "led/back/color",
+                    short_id: 1,
+                })
+            )
+        );
+        assert_eq!(resp[1].msg(), sub_msg(PubSubPath::Short(1), &[3]));
+    }
+
+    #[test]
+    fn shortcode_collision() {
+        let mut broker = Broker::new();
+        let (client, sub) = (uuid(1), uuid(2));
+        connect(&mut broker, client, "client");
+        connect(&mut broker, sub, "sub");
+        subscribe(&mut broker, sub, "a/b");
+        subscribe(&mut broker, sub, "c/d");
+
+        let request = |seq, long_name| {
+            control(
+                seq,
+                ControlType::RequestPubSubShortId {
+                    long_name,
+                    schema: None,
+                },
+            )
+        };
+        let register = |seq, long_name, short_id| {
+            control(
+                seq,
+                ControlType::RegisterPubSubShortId(PubSubShort {
+                    long_name,
+                    short_id,
+                }),
+            )
+        };
+
+        let resp = process(&mut broker, client, request(2, "a/b"));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(
+                2,
+                Ok(ControlResponse::PubSubShortAssignment {
+                    long_name: "a/b",
+                    short_id: 0,
+                })
+            )
+        );
+
+        // The assigned shortcode can't be registered for another path...
+        let resp = process(&mut broker, client, register(3, "c/d", 0));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(3, Err(ControlError::ShortIdInUse))
+        );
+
+        // ...but registering it again for the same path is fine
+        let resp = process(&mut broker, client, register(4, "a/b", 0));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(4, Ok(ControlResponse::PubSubShortRegistration(0)))
+        );
+
+        // Registered shortcodes are not assigned
+        let resp = process(&mut broker, client, register(5, "c/d", 1));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(5, Ok(ControlResponse::PubSubShortRegistration(1)))
+        );
+        let resp = process(&mut broker, client, request(6, "e/f"));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(
+                6,
+                Ok(ControlResponse::PubSubShortAssignment {
+                    long_name: "e/f",
+                    short_id: 2,
+                })
+            )
+        );
+
+        let resp = process(&mut broker, client, register(7, "g/#", 3));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(7, Err(ControlError::NoWildcardsInShorts))
+        );
+
+        // Each shortcode is published on its own path
+        for (short, path) in &[(0, "a/b"), (1, "c/d")] {
+            let msg = Component::PubSub(PubSub {
+                path: PubSubPath::Short(*short),
+                ty: PubSubType::Pub { payload: &[9] },
+            });
+            let resp = process(&mut broker, client, msg);
+            assert_eq!(resp.len(), 1);
+            assert_eq!(resp[0].dest, sub);
+            assert_eq!(resp[0].msg(), sub_msg(long(path), &[9]));
+        }
+    }
+
+    #[test]
+    fn bridged_publish_requires_bridge() {
+        let mut broker = Broker::new();
+        let (client, bridge, sub) = (uuid(1), uuid(2), uuid(3));
+        connect(&mut broker, client, "client");
+        connect(&mut broker, sub, "sub");
+        subscribe(&mut broker, sub, "a/b");
+
+        broker.register_client(&bridge).unwrap();
+        let info = ComponentInfo {
+            name: Name::borrow_from_str("bridge"),
+            version: VERSION,
+        };
+        let resp = process(
+            &mut broker,
+            bridge,
+            control(1, ControlType::RegisterBridge(info)),
+        );
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(1, Ok(ControlResponse::ComponentRegistration(bridge)))
+        );
+
+        let bridged = || {
+            pubsub(
+                "a/b",
+                PubSubType::BridgedPub {
+                    payload: &[1],
+                    origin: uuid(9),
+                },
+            )
+        };
+
+        let resp = process(&mut broker, client, bridged());
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, client);
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(0, Err(ControlError::NotBridge))
+        );
+
+        let resp = process(&mut broker, bridge, bridged());
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, sub);
+        assert_eq!(resp[0].msg(), sub_msg(long("a/b"), &[1]));
+    }
+
+    #[test]
+    fn publish_schema_mismatch() {
+        let mut broker = Broker::new();
+        let (sub, publ) = (uuid(1), uuid(2));
+        connect(&mut broker, sub, "sub");
+        connect(&mut broker, publ, "pub");
+
+        let resp = process(
+            &mut broker,
+            sub,
+            pubsub("temp/#", PubSubType::TypedSub { schema: 1 }),
+        );
+        assert_eq!(
+            resp[0].msg(),
+            Arbitrator::PubSub(Ok(PubSubResponse::SubAck {
+                path: long("temp/#")
+            }))
+        );
+        let mismatch = Arbitrator::PubSub(Err(PubSubError::SchemaMismatch { registered: 1 }));
+
+        // Long path
+        let typed = |schema| PubSubType::TypedPub {
+            payload: &[1],
+            schema,
+        };
+        let resp = process(&mut broker, publ, pubsub("temp/a", typed(2)));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, publ);
+        assert_eq!(resp[0].msg(), mismatch);
+
+        let resp = process(&mut broker, publ, pubsub("temp/a", typed(1)));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, sub);
+        assert_eq!(resp[0].msg(), sub_msg(long("temp/a"), &[1]));
+
+        // Short path
+        let short = |ty| {
+            Component::PubSub(PubSub {
+                path: PubSubPath::Short(7),
+                ty,
+            })
+        };
+        let resp = process(
+            &mut broker,
+            publ,
+            control(
+                2,
+                ControlType::RegisterPubSubShortId(PubSubShort {
+                    long_name: "temp/b",
+                    short_id: 7,
+                }),
+            ),
+        );
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(2, Ok(ControlResponse::PubSubShortRegistration(7)))
+        );
+        let resp = process(&mut broker, publ, short(typed(2)));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, publ);
+        assert_eq!(resp[0].msg(), mismatch);
+    }
+
+    #[test]
+    fn unsubscribe() {
+        let mut broker = Broker::new();
+        let (sub, publ) = (uuid(1), uuid(2));
+        connect(&mut broker, sub, "sub");
+        connect(&mut broker, publ, "pub");
+        subscribe(&mut broker, sub, "a/b");
+        subscribe(&mut broker, sub, "c/d");
+
+        // Long path
+        let resp = process(&mut broker, sub, pubsub("a/b", PubSubType::Unsub));
+        assert!(resp.is_empty());
+        assert!(publish(&mut broker, publ, "a/b", &[1]).is_empty());
+        assert_eq!(publish(&mut broker, publ, "c/d", &[2]).len(), 1);
+
+        // Short path
+        let short = |short_id| {
+            Component::PubSub(PubSub {
+                path: PubSubPath::Short(short_id),
+                ty: PubSubType::Unsub,
+            })
+        };
+        let resp = process(
+            &mut broker,
+            sub,
+            control(
+                2,
+                ControlType::RegisterPubSubShortId(PubSubShort {
+                    long_name: "c/d",
+                    short_id: 4,
+                }),
+            ),
+        );
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(2, Ok(ControlResponse::PubSubShortRegistration(4)))
+        );
+        assert!(process(&mut broker, sub, short(4)).is_empty());
+        assert!(publish(&mut broker, publ, "c/d", &[3]).is_empty());
+
+        // Unsubscribing from an unknown path is ignored, but an unknown
+        // shortcode can not be resolved
+        assert!(process(&mut broker, sub, pubsub("e/f", PubSubType::Unsub)).is_empty());
+        assert_eq!(
+            try_process(&mut broker, sub, short(9)).err(),
+            Some(ServerError::UnknownShortcode)
+        );
+    }
+}
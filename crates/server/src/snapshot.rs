@@ -0,0 +1,570 @@
+//! # Broker Snapshots
+//!
+//! The state of a `Broker`, including each client's name, subscriptions
+//! and shortcodes, can be serialized into a versioned postcard blob with
+//! `Broker::snapshot`, and restored with `Broker::restore`. This allows a
+//! broker to reboot without requiring every client to re-register.
+//!
+//! Snapshots can be kept in any `SnapshotStore`. A `FixedStore` is
+//! provided for firmware, which can be written to and read from a flash
+//! region as a single block, and with the `std` feature enabled, a
+//! `FileStore` is provided for host brokers.
+
+use crate::{Broker, Client, ClientState, ClientStore, ConnectedState, Shortcut, Subscription};
+use anachro_icd::{schema::SchemaHash, Name, Path, Uuid, Version};
+use defmt::Format;
+use heapless::{consts, Vec};
+use postcard::{take_from_bytes, to_slice};
+use serde::{Deserialize, Serialize};
+
+/// The version of the snapshot format
+///
+/// This is incremented whenever the format changes. Snapshots with a
+/// different version are rejected by `Broker::restore`.
+pub const SNAPSHOT_VERSION: u16 = 4;
+
+/// The size of the header of a `FixedStore`: the length of the snapshot,
+/// and its checksum, each as a little endian u32
+const FIXED_HEADER: usize = 8;
+
+#[derive(Debug, PartialEq, Eq, Format)]
+pub enum SnapshotError {
+    /// The buffer is too small to hold the snapshot
+    BufferTooSmall,
+
+    /// The snapshot could not be decoded
+    Corrupt,
+
+    /// The snapshot was made with a different `SNAPSHOT_VERSION`
+    UnsupportedVersion,
+
+    /// The snapshot holds more clients, subscriptions or shortcodes than
+    /// the Broker can hold
+    ResourcesExhausted,
+}
+
+/// An error while saving or loading a snapshot from a `SnapshotStore`
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreError<E> {
+    Snapshot(SnapshotError),
+    Storage(E),
+}
+
+/// Persistent storage for a single Broker snapshot
+pub trait SnapshotStore {
+    type Error;
+
+    /// Replace the stored snapshot with `snapshot`
+    fn store(&mut self, snapshot: &[u8]) -> Result<(), Self::Error>;
+
+    /// Copy the stored snapshot into `buf`
+    ///
+    /// Returns `Ok(None)` if no snapshot has been stored.
+    fn load<'a>(&mut self, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Self::Error>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<'a> {
+    /// NOTE: This must remain the first field, so that it can be checked
+    /// before the rest of the snapshot is decoded
+    version: u16,
+
+    #[serde(borrow)]
+    clients: Vec<ClientSnapshot<'a>, consts::U8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClientSnapshot<'a> {
+    id: Uuid,
+
+    /// `None` if the client has not registered yet
+    #[serde(borrow)]
+    connected: Option<ConnectedSnapshot<'a>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConnectedSnapshot<'a> {
+    #[serde(borrow)]
+    name: Name<'a>,
+    version: Version,
+    bridge: bool,
+    assign_shortcuts: Option<u16>,
+    #[serde(borrow)]
+    subscriptions: Vec<SubscriptionSnapshot<'a>, consts::U8>,
+    #[serde(borrow)]
+    shortcuts: Vec<ShortcutSnapshot<'a>, consts::U8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SubscriptionSnapshot<'a> {
+    #[serde(borrow)]
+    path: Path<'a>,
+    schema: Option<SchemaHash>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ShortcutSnapshot<'a> {
+    #[serde(borrow)]
+    long: Path<'a>,
+    short: u16,
+    schema: Option<SchemaHash>,
+}
+
+impl Broker {
+    /// Serialize the state of all registered clients into `buf`
+    ///
+    /// On success, the used portion of `buf` is returned.
+    pub fn snapshot<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], SnapshotError> {
+        let mut clients = Vec::new();
+
+        for client in self.clients.iter() {
+            let connected = match &client.state {
+                // NOTE: Clients that have not authenticated will need to
+                // register again
+                ClientState::SessionEstablished | ClientState::Authenticating(_) => None,
+                ClientState::Connected(state) => Some(ConnectedSnapshot::from_state(state)?),
+            };
+
+            clients
+                .push(ClientSnapshot {
+                    id: client.id,
+                    connected,
+                })
+                .map_err(|_| SnapshotError::ResourcesExhausted)?;
+        }
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients,
+        };
+
+        to_slice(&snapshot, buf).map_err(|_| SnapshotError::BufferTooSmall)
+    }
+
+    /// Replace the state of all clients with a snapshot
+    ///
+    /// Restored clients that had registered are announced on
+    /// `$sys/clients/<name>/connected`.
+    ///
+    /// If the snapshot can not be restored, the Broker is left unchanged.
+    pub fn restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let (version, _) = take_from_bytes::<u16>(bytes).map_err(|_| SnapshotError::Corrupt)?;
+        if version != SNAPSHOT_VERSION {
+            defmt::warn!("Broker: Snapshot version mismatch");
+            return Err(SnapshotError::UnsupportedVersion);
+        }
+
+        let (snapshot, _) =
+            take_from_bytes::<Snapshot>(bytes).map_err(|_| SnapshotError::Corrupt)?;
+
+        let mut clients = ClientStore::new();
+        for client in snapshot.clients.iter() {
+            let state = match &client.connected {
+                None => ClientState::SessionEstablished,
+                Some(connected) => ClientState::Connected(connected.to_state()?),
+            };
+
+            clients
+                .push(Client {
+                    id: client.id,
+                    state,
+                })
+                .map_err(|_| SnapshotError::ResourcesExhausted)?;
+        }
+
+        self.clients = clients;
+
+        // Restored clients are announced as if they had just connected
+        let Broker { clients, sys, .. } = self;
+        for client in clients.iter() {
+            if let Ok(state) = client.state.as_connected() {
+                sys.connected(client.id, &state.name, state.version);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialize the state of all clients, and keep it in `store`
+    ///
+    /// `scratch` must be large enough to hold the snapshot.
+    pub fn save_snapshot<S: SnapshotStore>(
+        &self,
+        store: &mut S,
+        scratch: &mut [u8],
+    ) -> Result<(), StoreError<S::Error>> {
+        let used = self.snapshot(scratch).map_err(StoreError::Snapshot)?;
+        store.store(used).map_err(StoreError::Storage)
+    }
+
+    /// Restore the state of all clients from the snapshot kept in `store`
+    ///
+    /// `scratch` must be large enough to hold the snapshot. Returns
+    /// `Ok(false)` if no snapshot has been stored, in which case the Broker
+    /// is left unchanged.
+    pub fn load_snapshot<S: SnapshotStore>(
+        &mut self,
+        store: &mut S,
+        scratch: &mut [u8],
+    ) -> Result<bool, StoreError<S::Error>> {
+        match store.load(scratch).map_err(StoreError::Storage)? {
+            Some(bytes) => {
+                self.restore(bytes).map_err(StoreError::Snapshot)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl<'a> ConnectedSnapshot<'a> {
+    fn from_state(state: &'a ConnectedState) -> Result<Self, SnapshotError> {
+        let mut subscriptions = Vec::new();
+        for sub in state.subscriptions.iter() {
+            subscriptions
+                .push(SubscriptionSnapshot {
+                    path: sub.path.as_borrowed(),
+                    schema: sub.schema,
+                })
+                .map_err(|_| SnapshotError::ResourcesExhausted)?;
+        }
+
+        let mut shortcuts = Vec::new();
+        for sc in state.shortcuts.iter() {
+            shortcuts
+                .push(ShortcutSnapshot {
+                    long: sc.long.as_borrowed(),
+                    short: sc.short,
+                    schema: sc.schema,
+                })
+                .map_err(|_| SnapshotError::ResourcesExhausted)?;
+        }
+
+        Ok(ConnectedSnapshot {
+            name: state.name.as_borrowed(),
+            version: state.version,
+            bridge: state.bridge,
+            assign_shortcuts: state.assign_shortcuts,
+            subscriptions,
+            shortcuts,
+        })
+    }
+
+    fn to_state(&self) -> Result<ConnectedState, SnapshotError> {
+        let mut subscriptions = Vec::new();
+        for sub in self.subscriptions.iter() {
+            subscriptions
+                .push(Subscription {
+                    path: sub
+                        .path
+                        .try_to_owned()
+                        .map_err(|_| SnapshotError::ResourcesExhausted)?,
+                    schema: sub.schema,
+                })
+                .map_err(|_| SnapshotError::ResourcesExhausted)?;
+        }
+
+        let mut shortcuts = Vec::new();
+        for sc in self.shortcuts.iter() {
+            shortcuts
+                .push(Shortcut {
+                    long: sc
+                        .long
+                        .try_to_owned()
+                        .map_err(|_| SnapshotError::ResourcesExhausted)?,
+                    short: sc.short,
+                    schema: sc.schema,
+                })
+                .map_err(|_| SnapshotError::ResourcesExhausted)?;
+        }
+
+        Ok(ConnectedState {
+            name: self
+                .name
+                .try_to_owned()
+                .map_err(|_| SnapshotError::ResourcesExhausted)?,
+            version: self.version,
+            bridge: self.bridge,
+            assign_shortcuts: self.assign_shortcuts,
+            subscriptions,
+            shortcuts,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Format)]
+pub enum FixedStoreError {
+    /// The snapshot does not fit in the store, or the buffer it is being
+    /// loaded into
+    TooLarge,
+
+    /// The stored snapshot failed its checksum, e.g. because writing it
+    /// was interrupted
+    Corrupt,
+}
+
+/// A `SnapshotStore` backed by a fixed buffer of `N` bytes
+///
+/// The buffer holds the length and checksum of the snapshot, followed by
+/// the snapshot itself, and is intended to be written to (and read back
+/// from) a flash region as a single block, using `as_bytes` and
+/// `from_bytes`. A buffer of erased flash (all `0xFF`) is treated as
+/// holding no snapshot.
+pub struct FixedStore<const N: usize> {
+    buf: [u8; N],
+}
+
+impl<const N: usize> Default for FixedStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> FixedStore<N> {
+    /// Create an empty store, matching erased flash
+    pub const fn new() -> Self {
+        Self { buf: [0xFF; N] }
+    }
+
+    /// Create a store from the contents of a flash region
+    ///
+    /// At most `N` bytes are used.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut store = Self::new();
+        let len = bytes.len().min(N);
+        store.buf[..len].copy_from_slice(&bytes[..len]);
+        store
+    }
+
+    /// The contents of the store, to be written to a flash region
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl<const N: usize> SnapshotStore for FixedStore<N> {
+    type Error = FixedStoreError;
+
+    fn store(&mut self, snapshot: &[u8]) -> Result<(), FixedStoreError> {
+        if N < FIXED_HEADER || snapshot.len() > (N - FIXED_HEADER) {
+            return Err(FixedStoreError::TooLarge);
+        }
+
+        let (header, body) = self.buf.split_at_mut(FIXED_HEADER);
+        header[..4].copy_from_slice(&(snapshot.len() as u32).to_le_bytes());
+        header[4..].copy_from_slice(&checksum(snapshot).to_le_bytes());
+
+        let (used, unused) = body.split_at_mut(snapshot.len());
+        used.copy_from_slice(snapshot);
+        unused.iter_mut().for_each(|b| *b = 0xFF);
+
+        Ok(())
+    }
+
+    fn load<'a>(&mut self, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, FixedStoreError> {
+        if N < FIXED_HEADER {
+            return Ok(None);
+        }
+
+        let (header, body) = self.buf.split_at(FIXED_HEADER);
+        let mut word = [0u8; 4];
+
+        word.copy_from_slice(&header[..4]);
+        let len = u32::from_le_bytes(word);
+        if len == u32::MAX {
+            // Erased
+            return Ok(None);
+        }
+
+        word.copy_from_slice(&header[4..]);
+        let sum = u32::from_le_bytes(word);
+
+        let data = body
+            .get(..len as usize)
+            .filter(|data| checksum(data) == sum)
+            .ok_or(FixedStoreError::Corrupt)?;
+
+        let out = buf.get_mut(..data.len()).ok_or(FixedStoreError::TooLarge)?;
+        out.copy_from_slice(data);
+
+        Ok(Some(out))
+    }
+}
+
+/// A 32-bit FNV-1a hash, to detect incomplete writes
+fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(feature = "std")]
+pub use self::std_store::FileStore;
+
+#[cfg(feature = "std")]
+mod std_store {
+    use super::SnapshotStore;
+    use std::{
+        fs, io,
+        path::{Path, PathBuf},
+    };
+
+    /// A `SnapshotStore` backed by a file
+    ///
+    /// Snapshots are written to a temporary file first, which then replaces
+    /// the previous snapshot, so an interrupted write does not lose it.
+    pub struct FileStore {
+        path: PathBuf,
+    }
+
+    impl FileStore {
+        pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+            Self { path: path.into() }
+        }
+
+        /// The path of the snapshot file
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+    }
+
+    impl SnapshotStore for FileStore {
+        type Error = io::Error;
+
+        fn store(&mut self, snapshot: &[u8]) -> io::Result<()> {
+            let tmp = self.path.with_extension("tmp");
+            fs::write(&tmp, snapshot)?;
+            fs::rename(&tmp, &self.path)
+        }
+
+        fn load<'a>(&mut self, buf: &'a mut [u8]) -> io::Result<Option<&'a [u8]>> {
+            let data = match fs::read(&self.path) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+
+            let out = buf.get_mut(..data.len()).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "snapshot larger than buffer")
+            })?;
+            out.copy_from_slice(&data);
+
+            Ok(Some(out))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{connect, long, publish, sub_msg, subscribe, uuid};
+
+    /// A broker with two clients, one subscribed to the other
+    fn broker() -> Broker {
+        let mut broker = Broker::new();
+        connect(&mut broker, uuid(1), "sub");
+        connect(&mut broker, uuid(2), "pub");
+        subscribe(&mut broker, uuid(1), "a/#");
+        broker.register_client(&uuid(3)).unwrap();
+        broker
+    }
+
+    fn snapshot(broker: &Broker) -> std::vec::Vec<u8> {
+        let mut buf = [0u8; 1024];
+        broker.snapshot(&mut buf).unwrap().to_vec()
+    }
+
+    /// Check that `restored` behaves as `broker()`
+    fn check_restored(restored: &mut Broker) {
+        assert_eq!(snapshot(restored), snapshot(&broker()));
+
+        let resp = publish(restored, uuid(2), "a/b", &[1]);
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, uuid(1));
+        assert_eq!(resp[0].msg(), sub_msg(long("a/b"), &[1]));
+    }
+
+    #[test]
+    fn fixed_store_round_trip() {
+        let mut scratch = [0u8; 1024];
+        let mut store = FixedStore::<1024>::new();
+        broker().save_snapshot(&mut store, &mut scratch).unwrap();
+
+        // As if read back from flash
+        let mut store = FixedStore::<1024>::from_bytes(store.as_bytes());
+        let mut restored = Broker::new();
+        assert_eq!(restored.load_snapshot(&mut store, &mut scratch), Ok(true));
+        check_restored(&mut restored);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn file_store_round_trip() {
+        let dir =
+            std::env::temp_dir().join(std::format!("anachro-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut store = FileStore::new(dir.join("round-trip.bin"));
+        let mut scratch = [0u8; 1024];
+
+        let mut restored = Broker::new();
+        assert!(!restored.load_snapshot(&mut store, &mut scratch).unwrap());
+
+        broker().save_snapshot(&mut store, &mut scratch).unwrap();
+        assert!(restored.load_snapshot(&mut store, &mut scratch).unwrap());
+        check_restored(&mut restored);
+
+        // A failed write leaves the previous snapshot intact
+        std::fs::create_dir_all(store.path().with_extension("tmp")).unwrap();
+        assert!(Broker::new()
+            .save_snapshot(&mut store, &mut scratch)
+            .is_err());
+
+        let mut restored = Broker::new();
+        assert!(restored.load_snapshot(&mut store, &mut scratch).unwrap());
+        check_restored(&mut restored);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = snapshot(&broker());
+        bytes[0] = (SNAPSHOT_VERSION + 1) as u8;
+
+        let mut restored = broker();
+        subscribe(&mut restored, uuid(2), "c/d");
+        let before = snapshot(&restored);
+        assert_eq!(
+            restored.restore(&bytes),
+            Err(SnapshotError::UnsupportedVersion)
+        );
+        assert_eq!(snapshot(&restored), before);
+    }
+
+    #[test]
+    fn corrupted_checksum() {
+        let mut scratch = [0u8; 1024];
+        let mut store = FixedStore::<1024>::new();
+        broker().save_snapshot(&mut store, &mut scratch).unwrap();
+
+        let mut flash = store.as_bytes().to_vec();
+        flash[FIXED_HEADER + 4] ^= 0x01;
+        let mut store = FixedStore::<1024>::from_bytes(&flash);
+
+        let mut restored = Broker::new();
+        assert_eq!(
+            restored.load_snapshot(&mut store, &mut scratch),
+            Err(StoreError::Storage(FixedStoreError::Corrupt))
+        );
+        assert!(restored.clients.is_empty());
+    }
+
+    #[test]
+    fn erased_flash() {
+        let mut scratch = [0u8; 1024];
+        let mut store = FixedStore::<1024>::from_bytes(&[0xFF; 1024]);
+
+        let mut restored = broker();
+        assert_eq!(restored.load_snapshot(&mut store, &mut scratch), Ok(false));
+        assert_eq!(snapshot(&restored), snapshot(&broker()));
+    }
+}
@@ -0,0 +1,534 @@
+//! # System Topics
+//!
+//! The Broker announces clients connecting and disconnecting, and
+//! periodically publishes statistics, on the paths described in
+//! `anachro_icd::sys`. These messages are queued as they occur, and are
+//! sent to subscribed clients by `Broker::process_sys`.
+
+use crate::{deliver, ClientStore, ServerError, ServerIoOut};
+use anachro_icd::{
+    sys::{ClientConnected, ClientStats, Stats, TopicStats, STATS_PATH},
+    Name, Path, Uuid, Version,
+};
+use core::{fmt::Write, ops::Range, str::from_utf8};
+use heapless::{consts, String, Vec};
+use postcard::to_slice;
+use serde::Serialize;
+
+#[derive(Debug)]
+enum EventKind {
+    Connected(Version),
+    Disconnected,
+}
+
+#[derive(Debug)]
+struct Event {
+    uuid: Uuid,
+    name: Name<'static>,
+    kind: EventKind,
+}
+
+#[derive(Debug)]
+struct TopicCounter {
+    path: Path<'static>,
+    messages: u32,
+    bytes: u32,
+}
+
+#[derive(Debug)]
+struct ClientCounter {
+    uuid: Uuid,
+    name: Name<'static>,
+    published: u32,
+    bytes_in: u32,
+    delivered: u32,
+    bytes_out: u32,
+    dropped: u32,
+}
+
+/// Message and byte counts, reported on `$sys/stats`
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    messages: u32,
+    bytes: u32,
+    dropped: u32,
+    topics: Vec<TopicCounter, consts::U8>,
+    clients: Vec<ClientCounter, consts::U8>,
+}
+
+impl Counters {
+    /// Count a message published by `source` on `path`
+    pub(crate) fn published(&mut self, source: &Uuid, path: &str, len: usize) {
+        let len = len as u32;
+        self.messages = self.messages.wrapping_add(1);
+        self.bytes = self.bytes.wrapping_add(len);
+
+        if let Some(client) = self.client_mut(source) {
+            client.published = client.published.wrapping_add(1);
+            client.bytes_in = client.bytes_in.wrapping_add(len);
+        }
+
+        let pos = match self.topics.iter().position(|t| t.path.as_str() == path) {
+            Some(pos) => pos,
+            None => {
+                let topic = match Path::try_from_str(path) {
+                    Ok(path) => TopicCounter {
+                        path,
+                        messages: 0,
+                        bytes: 0,
+                    },
+                    Err(()) => return,
+                };
+
+                // Once full, further paths are only counted in the totals
+                if self.topics.push(topic).is_err() {
+                    return;
+                }
+                self.topics.len() - 1
+            }
+        };
+
+        let topic = &mut self.topics[pos];
+        topic.messages = topic.messages.wrapping_add(1);
+        topic.bytes = topic.bytes.wrapping_add(len);
+    }
+
+    /// Count a message delivered to `dest`
+    pub(crate) fn delivered(&mut self, dest: &Uuid, len: usize) {
+        if let Some(client) = self.client_mut(dest) {
+            client.delivered = client.delivered.wrapping_add(1);
+            client.bytes_out = client.bytes_out.wrapping_add(len as u32);
+        }
+    }
+
+    /// Count a message that could not be delivered to `dest`
+    pub(crate) fn dropped(&mut self, dest: &Uuid) {
+        self.dropped = self.dropped.wrapping_add(1);
+
+        if let Some(client) = self.client_mut(dest) {
+            client.dropped = client.dropped.wrapping_add(1);
+        }
+    }
+
+    fn client_mut(&mut self, uuid: &Uuid) -> Option<&mut ClientCounter> {
+        self.clients.iter_mut().find(|c| &c.uuid == uuid)
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        Stats {
+            messages: self.messages,
+            bytes: self.bytes,
+            dropped: self.dropped,
+            topics: self
+                .topics
+                .iter()
+                .map(|t| TopicStats {
+                    path: t.path.as_borrowed(),
+                    messages: t.messages,
+                    bytes: t.bytes,
+                })
+                .collect(),
+            clients: self
+                .clients
+                .iter()
+                .map(|c| ClientStats {
+                    name: c.name.as_borrowed(),
+                    uuid: c.uuid,
+                    published: c.published,
+                    bytes_in: c.bytes_in,
+                    delivered: c.delivered,
+                    bytes_out: c.bytes_out,
+                    dropped: c.dropped,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The state of the system topics of a Broker
+#[derive(Default)]
+pub(crate) struct Sys {
+    events: Vec<Event, consts::U8>,
+    stats_due: bool,
+    pub(crate) counters: Counters,
+
+    /// The paths and payloads of the messages sent by the last call
+    /// to `process` or `process_next`
+    buf: Vec<u8, consts::U2048>,
+}
+
+impl Sys {
+    /// Announce that a client has completed its registration
+    pub(crate) fn connected(&mut self, uuid: Uuid, name: &Name<'static>, version: Version) {
+        let counter = ClientCounter {
+            uuid,
+            name: name.clone(),
+            published: 0,
+            bytes_in: 0,
+            delivered: 0,
+            bytes_out: 0,
+            dropped: 0,
+        };
+        match self.counters.client_mut(&uuid) {
+            Some(existing) => *existing = counter,
+            None => {
+                // There is a counter for each connected client, so this
+                // can not fail
+                self.counters.clients.push(counter).ok();
+            }
+        }
+
+        self.push_event(Event {
+            uuid,
+            name: name.clone(),
+            kind: EventKind::Connected(version),
+        });
+    }
+
+    /// Announce that a connected client has disconnected, or been reset
+    pub(crate) fn disconnected(&mut self, uuid: Uuid, name: Name<'static>) {
+        if let Some(pos) = self.counters.clients.iter().position(|c| c.uuid == uuid) {
+            self.counters.clients.swap_remove(pos);
+        }
+
+        self.push_event(Event {
+            uuid,
+            name,
+            kind: EventKind::Disconnected,
+        });
+    }
+
+    pub(crate) fn schedule_stats(&mut self) {
+        self.stats_due = true;
+    }
+
+    fn push_event(&mut self, event: Event) {
+        if self.events.push(event).is_err() {
+            defmt::warn!("Broker: System event dropped");
+        }
+    }
+
+    /// Send all pending system messages to their subscribers
+    pub(crate) fn process<'a, SO: ServerIoOut<'a>>(
+        &'a mut self,
+        clients: &mut ClientStore,
+        sio: &mut SO,
+    ) -> Result<(), ServerError> {
+        let Sys {
+            events,
+            stats_due,
+            counters,
+            buf,
+        } = self;
+
+        if events.is_empty() && !*stats_due {
+            return Ok(());
+        }
+
+        // First, encode the path and payload of each message...
+        let mut msgs: Vec<(Range<usize>, Range<usize>), consts::U9> = Vec::new();
+        buf.clear();
+
+        for event in events.iter() {
+            if let Ok(msg) = encode_event(buf, event) {
+                msgs.push(msg).map_err(|_| ServerError::InternalError)?;
+            } else {
+                defmt::warn!("Broker: System event dropped");
+            }
+        }
+        events.clear();
+
+        if *stats_due {
+            if let Ok(msg) = encode(buf, STATS_PATH, &counters.stats()) {
+                msgs.push(msg).map_err(|_| ServerError::InternalError)?;
+            } else {
+                defmt::warn!("Broker: Statistics dropped");
+            }
+            *stats_due = false;
+        }
+
+        // ...then send them, borrowing from the buffer
+        let buf: &'a Vec<u8, consts::U2048> = buf;
+        let mut result = Ok(());
+
+        for (path, payload) in msgs {
+            let path = from_utf8(&buf[path]).map_err(|_| ServerError::InternalError)?;
+            let res = deliver(clients, counters, sio, path, &buf[payload], None, None);
+            result = result.and(res);
+        }
+
+        result
+    }
+
+    /// Send only the oldest pending system message to its subscribers,
+    /// returning whether more remain
+    ///
+    /// A single message is delivered to at most every client, along with a
+    /// shortcode announcement for each.
+    pub(crate) fn process_next<'a, SO: ServerIoOut<'a>>(
+        &'a mut self,
+        clients: &mut ClientStore,
+        sio: &mut SO,
+    ) -> Result<bool, ServerError> {
+        let Sys {
+            events,
+            stats_due,
+            counters,
+            buf,
+        } = self;
+
+        buf.clear();
+        let encoded = if !events.is_empty() {
+            let encoded = encode_event(buf, &events[0]);
+            if encoded.is_err() {
+                defmt::warn!("Broker: System event dropped");
+            }
+
+            // Remove the event, keeping the rest in order
+            for i in 1..events.len() {
+                events.swap(i - 1, i);
+            }
+            events.pop();
+            encoded
+        } else if *stats_due {
+            *stats_due = false;
+            let encoded = encode(buf, STATS_PATH, &counters.stats());
+            if encoded.is_err() {
+                defmt::warn!("Broker: Statistics dropped");
+            }
+            encoded
+        } else {
+            return Ok(false);
+        };
+
+        let more = !events.is_empty() || *stats_due;
+        let (path, payload) = match encoded {
+            Ok(msg) => msg,
+            Err(()) => return Ok(more),
+        };
+
+        let buf: &'a Vec<u8, consts::U2048> = buf;
+        let path = from_utf8(&buf[path]).map_err(|_| ServerError::InternalError)?;
+        deliver(clients, counters, sio, path, &buf[payload], None, None).map(|_| more)
+    }
+}
+
+/// Append the path and payload announcing `event` to `buf`, returning
+/// their positions
+fn encode_event(
+    buf: &mut Vec<u8, consts::U2048>,
+    event: &Event,
+) -> Result<(Range<usize>, Range<usize>), ()> {
+    let mut path: String<consts::U127> = String::new();
+    match event.kind {
+        EventKind::Connected(version) => {
+            write!(path, "$sys/clients/{}/connected", event.name.as_str()).map_err(drop)?;
+            let payload = ClientConnected {
+                uuid: event.uuid,
+                version,
+            };
+            encode(buf, &path, &payload)
+        }
+        EventKind::Disconnected => {
+            write!(path, "$sys/clients/{}/disconnected", event.name.as_str()).map_err(drop)?;
+            encode(buf, &path, &event.uuid)
+        }
+    }
+}
+
+/// Append a path and serialized payload to `buf`, returning their positions
+fn encode<T: Serialize>(
+    buf: &mut Vec<u8, consts::U2048>,
+    path: &str,
+    payload: &T,
+) -> Result<(Range<usize>, Range<usize>), ()> {
+    let start = buf.len();
+    buf.extend_from_slice(path.as_bytes())?;
+    let mid = buf.len();
+
+    buf.resize_default(buf.capacity())?;
+    match to_slice(payload, &mut buf[mid..]) {
+        Ok(used) => {
+            let end = mid + used.len();
+            buf.truncate(end);
+            Ok((start..mid, mid..end))
+        }
+        Err(_) => {
+            buf.truncate(start);
+            Err(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test::{
+        connect, control, control_reply, process, publish, register, subscribe, uuid, Responses,
+        Sent, VERSION,
+    };
+    use crate::{AuthConfig, Broker, ControlResponse, Response};
+    use anachro_icd::{
+        arbitrator::{Arbitrator, PubSubError, PubSubResponse, SubMsg},
+        auth::AUTH_NONCE_LEN,
+        sys::{ClientConnected, Stats},
+        PubSubPath, Uuid,
+    };
+    use heapless::{consts, Vec};
+    use postcard::from_bytes;
+
+    fn process_sys(broker: &mut Broker) -> Responses {
+        let mut responses: Vec<Response, consts::U16> = Vec::new();
+        broker.process_sys(&mut responses).unwrap();
+        responses.iter().map(Sent::new).collect()
+    }
+
+    /// Check that `sent` is a message on `path`, returning its payload
+    fn payload<'a>(sent: &'a Sent, path: &str) -> &'a [u8] {
+        match sent.msg() {
+            Arbitrator::PubSub(Ok(PubSubResponse::SubMsg(SubMsg {
+                path: PubSubPath::Long(long),
+                payload,
+            }))) => {
+                assert_eq!(long.as_str(), path);
+                payload
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_events() {
+        let mut broker = Broker::new();
+        let (watcher, all, client) = (uuid(1), uuid(2), uuid(3));
+        connect(&mut broker, watcher, "watcher");
+        connect(&mut broker, all, "all");
+        subscribe(&mut broker, watcher, "$sys/#");
+        subscribe(&mut broker, all, "#");
+
+        // Events are queued until processed, even before subscribing
+        let resp = process_sys(&mut broker);
+        assert_eq!(resp.len(), 2);
+        assert!(resp.iter().all(|r| r.dest == watcher));
+        payload(&resp[0], "$sys/clients/watcher/connected");
+        payload(&resp[1], "$sys/clients/all/connected");
+        assert!(process_sys(&mut broker).is_empty());
+
+        connect(&mut broker, client, "client");
+        let resp = process_sys(&mut broker);
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, watcher);
+        let connected: ClientConnected =
+            from_bytes(payload(&resp[0], "$sys/clients/client/connected")).unwrap();
+        assert_eq!(
+            connected,
+            ClientConnected {
+                uuid: client,
+                version: VERSION,
+            }
+        );
+
+        broker.reset_client(&client).unwrap();
+        let resp = process_sys(&mut broker);
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, watcher);
+        let uuid: Uuid = from_bytes(payload(&resp[0], "$sys/clients/client/disconnected")).unwrap();
+        assert_eq!(uuid, client);
+    }
+
+    #[test]
+    fn reregister() {
+        let mut broker = Broker::new();
+        let (watcher, client) = (uuid(1), uuid(2));
+        connect(&mut broker, watcher, "watcher");
+        connect(&mut broker, client, "client");
+        subscribe(&mut broker, watcher, "$sys/clients/#");
+        process_sys(&mut broker);
+
+        // Registering again ends the previous session
+        let resp = process(&mut broker, client, control(1, register("renamed")));
+        assert_eq!(resp.len(), 1);
+        let resp = process_sys(&mut broker);
+        assert_eq!(resp.len(), 2);
+        payload(&resp[0], "$sys/clients/client/disconnected");
+        payload(&resp[1], "$sys/clients/renamed/connected");
+
+        // Even if the client must then authenticate
+        broker.set_auth(Some(AuthConfig {
+            keys: &[("renamed", b"key")],
+            nonce: || [0; AUTH_NONCE_LEN],
+        }));
+        let resp = process(&mut broker, client, control(2, register("renamed")));
+        assert_eq!(
+            resp[0].msg(),
+            control_reply(2, Ok(ControlResponse::AuthChallenge([0; AUTH_NONCE_LEN])))
+        );
+        let resp = process_sys(&mut broker);
+        assert_eq!(resp.len(), 1);
+        let uuid: Uuid =
+            from_bytes(payload(&resp[0], "$sys/clients/renamed/disconnected")).unwrap();
+        assert_eq!(uuid, client);
+    }
+
+    #[test]
+    fn stats() {
+        let mut broker = Broker::new();
+        let (watcher, publ, sub) = (uuid(1), uuid(2), uuid(3));
+        connect(&mut broker, watcher, "watcher");
+        connect(&mut broker, publ, "pub");
+        connect(&mut broker, sub, "sub");
+        subscribe(&mut broker, watcher, "$sys/stats");
+        subscribe(&mut broker, sub, "a/b");
+        assert!(process_sys(&mut broker).is_empty());
+
+        assert_eq!(publish(&mut broker, publ, "a/b", &[1, 2, 3]).len(), 1);
+
+        // Statistics are only published once scheduled
+        assert!(process_sys(&mut broker).is_empty());
+        broker.schedule_stats();
+        let resp = process_sys(&mut broker);
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].dest, watcher);
+
+        let stats: Stats = from_bytes(payload(&resp[0], "$sys/stats")).unwrap();
+        assert_eq!((stats.messages, stats.bytes, stats.dropped), (1, 3, 0));
+        assert_eq!(stats.topics.len(), 1);
+        assert_eq!(stats.topics[0].path.as_str(), "a/b");
+        assert_eq!((stats.topics[0].messages, stats.topics[0].bytes), (1, 3));
+
+        let client = |name: &str| {
+            stats
+                .clients
+                .iter()
+                .find(|c| c.name.as_str() == name)
+                .unwrap()
+        };
+        assert_eq!(client("pub").published, 1);
+        assert_eq!(client("pub").bytes_in, 3);
+        assert_eq!(client("sub").delivered, 1);
+        assert_eq!(client("watcher").delivered, 0);
+
+        assert!(process_sys(&mut broker).is_empty());
+    }
+
+    #[test]
+    fn publish_not_authorized() {
+        let mut broker = Broker::new();
+        let (watcher, client) = (uuid(1), uuid(2));
+        connect(&mut broker, watcher, "watcher");
+        connect(&mut broker, client, "client");
+        subscribe(&mut broker, watcher, "$sys/#");
+        process_sys(&mut broker);
+
+        for path in &["$sys/stats", "$sys/clients/client/connected", "$sys/other"] {
+            let resp = publish(&mut broker, client, path, &[1]);
+            assert_eq!(resp.len(), 1);
+            assert_eq!(resp[0].dest, client);
+            assert_eq!(
+                resp[0].msg(),
+                Arbitrator::PubSub(Err(PubSubError::NotAuthorized))
+            );
+        }
+
+        assert!(process_sys(&mut broker).is_empty());
+    }
+}
@@ -0,0 +1,406 @@
+use embedded_hal::{
+    digital::v2::{InputPin, OutputPin},
+    spi::FullDuplex,
+};
+
+use crate::ORC;
+use anachro_spi::{arbitrator::EncLogicLLArbitrator, Error, Result};
+
+/// An Arbitrator LL driver for any `embedded-hal` SPI operating in
+/// peripheral mode
+///
+/// Bytes are moved one at a time in `process()`, so it must be called
+/// at least as often as the Component clocks bytes, e.g. from the SPI
+/// interrupt, unless the SPI driver buffers data internally.
+pub struct EhSpiArbLL<SPI, GO, CSN>
+where
+    SPI: FullDuplex<u8> + Send,
+    GO: OutputPin + Send,
+    CSN: InputPin + Send,
+{
+    spi: SPI,
+    go_pin: GO,
+    csn_pin: CSN,
+    go_active: bool,
+    exchange: Exchange,
+}
+
+enum Exchange {
+    Idle,
+    Pending(PendingExchange),
+    Complete(usize),
+    Aborted,
+}
+
+struct PendingExchange {
+    data_out: *const u8,
+    data_out_len: usize,
+    data_in: *mut u8,
+    data_in_max: usize,
+    idx: usize,
+    begun: bool,
+}
+
+// SAFETY: The raw pointers are only dereferenced while the exchange is
+// pending, and the caller guarantees they remain valid until then
+unsafe impl Send for PendingExchange {}
+
+impl PendingExchange {
+    fn next_out(&self) -> u8 {
+        if self.idx < self.data_out_len {
+            // SAFETY: The caller guarantees `data_out` is valid for
+            // `data_out_len` bytes until the exchange is completed
+            unsafe { *self.data_out.add(self.idx) }
+        } else {
+            ORC
+        }
+    }
+
+    fn push_in(&mut self, byte: u8) {
+        if self.idx < self.data_in_max {
+            // SAFETY: The caller guarantees `data_in` is valid for
+            // `data_in_max` bytes until the exchange is completed
+            unsafe {
+                *self.data_in.add(self.idx) = byte;
+            }
+        }
+        self.idx += 1;
+    }
+
+    fn amt_in(&self) -> usize {
+        self.idx.min(self.data_in_max)
+    }
+
+    fn amt_out(&self) -> usize {
+        self.idx.min(self.data_out_len)
+    }
+}
+
+impl<SPI, GO, CSN> EhSpiArbLL<SPI, GO, CSN>
+where
+    SPI: FullDuplex<u8> + Send,
+    GO: OutputPin + Send,
+    CSN: InputPin + Send,
+{
+    pub fn new(spi: SPI, mut go_pin: GO, csn_pin: CSN) -> Self {
+        go_pin.set_high().ok();
+        Self {
+            spi,
+            go_pin,
+            csn_pin,
+            go_active: false,
+            exchange: Exchange::Idle,
+        }
+    }
+
+    /// Release the underlying SPI peripheral and pins
+    pub fn free(self) -> (SPI, GO, CSN) {
+        (self.spi, self.go_pin, self.csn_pin)
+    }
+
+    fn is_csn_active(&self) -> Result<bool> {
+        self.csn_pin.is_low().map_err(|_| Error::GpioError)
+    }
+
+    fn send(&mut self, byte: u8) -> Result<()> {
+        nb::block!(self.spi.send(byte)).map_err(|_| Error::SpiError)
+    }
+}
+
+impl<SPI, GO, CSN> EncLogicLLArbitrator for EhSpiArbLL<SPI, GO, CSN>
+where
+    SPI: FullDuplex<u8> + Send,
+    GO: OutputPin + Send,
+    CSN: InputPin + Send,
+{
+    /// Move any clocked bytes, and check whether the exchange has ended
+    fn process(&mut self) -> Result<()> {
+        let csn_active = self.is_csn_active()?;
+
+        let mut pending = match core::mem::replace(&mut self.exchange, Exchange::Idle) {
+            Exchange::Pending(pending) => pending,
+            other => {
+                self.exchange = other;
+                return Ok(());
+            }
+        };
+
+        loop {
+            match self.spi.read() {
+                Ok(byte) => {
+                    pending.begun = true;
+                    pending.push_in(byte);
+                    let next = pending.next_out();
+                    if let Err(e) = self.send(next) {
+                        self.exchange = Exchange::Aborted;
+                        return Err(e);
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => {
+                    self.exchange = Exchange::Aborted;
+                    return Err(Error::SpiError);
+                }
+            }
+        }
+
+        pending.begun |= csn_active;
+
+        self.exchange = if pending.begun && !csn_active {
+            // The Component has released CSn, the exchange is over
+            let complete = pending.amt_in() == pending.data_in_max
+                && pending.amt_out() == pending.data_out_len;
+
+            if complete && (pending.idx != 0) {
+                Exchange::Complete(pending.amt_in())
+            } else {
+                Exchange::Aborted
+            }
+        } else {
+            Exchange::Pending(pending)
+        };
+
+        Ok(())
+    }
+
+    fn is_go_active(&mut self) -> Result<bool> {
+        Ok(self.go_active)
+    }
+
+    fn notify_go(&mut self) -> Result<()> {
+        self.go_pin.set_low().map_err(|_| Error::GpioError)?;
+        self.go_active = true;
+        Ok(())
+    }
+
+    fn clear_go(&mut self) -> Result<()> {
+        self.go_pin.set_high().map_err(|_| Error::GpioError)?;
+        self.go_active = false;
+        Ok(())
+    }
+
+    fn prepare_exchange(
+        &mut self,
+        data_out: *const u8,
+        data_out_len: usize,
+        data_in: *mut u8,
+        data_in_max: usize,
+    ) -> Result<()> {
+        match self.exchange {
+            Exchange::Idle => {}
+            _ => return Err(Error::IncorrectState),
+        }
+
+        let pending = PendingExchange {
+            data_out,
+            data_out_len,
+            data_in,
+            data_in_max,
+            idx: 0,
+            begun: false,
+        };
+
+        // Pre-load the first byte, so it is ready when the Component
+        // begins clocking
+        self.send(pending.next_out())?;
+
+        self.exchange = Exchange::Pending(pending);
+        self.notify_go()
+    }
+
+    fn has_exchange_begun(&self) -> Result<bool> {
+        match &self.exchange {
+            Exchange::Pending(pending) if pending.begun => Ok(true),
+            Exchange::Pending(_) => self.is_csn_active(),
+            Exchange::Complete(_) | Exchange::Aborted => Ok(true),
+            Exchange::Idle => Ok(false),
+        }
+    }
+
+    fn is_exchange_active(&self) -> Result<bool> {
+        match self.exchange {
+            Exchange::Idle => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn complete_exchange(&mut self) -> Result<usize> {
+        match core::mem::replace(&mut self.exchange, Exchange::Idle) {
+            Exchange::Idle => Err(Error::IncorrectState),
+            Exchange::Pending(pending) => {
+                self.exchange = Exchange::Pending(pending);
+                Err(Error::TransactionBusy)
+            }
+            Exchange::Complete(amt) => Ok(amt),
+            Exchange::Aborted => {
+                self.clear_go().ok();
+                Err(Error::TransactionAborted)
+            }
+        }
+    }
+
+    fn abort_exchange(&mut self) -> Result<usize> {
+        self.clear_go().ok();
+
+        match core::mem::replace(&mut self.exchange, Exchange::Idle) {
+            Exchange::Idle | Exchange::Aborted => Ok(0),
+            Exchange::Pending(pending) => Ok(pending.amt_in()),
+            Exchange::Complete(amt) => Ok(amt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockFullDuplex, MockInput, MockOutput};
+
+    fn setup() -> (
+        EhSpiArbLL<MockFullDuplex, MockOutput, MockInput>,
+        MockFullDuplex,
+        MockOutput,
+        MockInput,
+    ) {
+        let spi = MockFullDuplex::default();
+        let go = MockOutput::default();
+        let csn = MockInput::default();
+        csn.set_high(true);
+        let ll = EhSpiArbLL::new(spi.clone(), go.clone(), csn.clone());
+        (ll, spi, go, csn)
+    }
+
+    #[test]
+    fn go_pin() {
+        let (mut ll, _spi, go, _csn) = setup();
+
+        assert!(go.is_high());
+        assert!(!ll.is_go_active().unwrap());
+        ll.notify_go().unwrap();
+        assert!(!go.is_high());
+        assert!(ll.is_go_active().unwrap());
+        ll.clear_go().unwrap();
+        assert!(go.is_high());
+        assert!(!ll.is_go_active().unwrap());
+    }
+
+    #[test]
+    fn exchange() {
+        let (mut ll, spi, go, csn) = setup();
+
+        let out = [9u8, 8, 7, 6];
+        let mut inc = [0u8; 4];
+
+        ll.prepare_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+        assert!(!go.is_high());
+        assert!(ll.is_exchange_active().unwrap());
+        assert!(!ll.has_exchange_begun().unwrap());
+
+        // The Component selects us, and clocks half of the data
+        csn.set_high(false);
+        spi.clock_in(&[1, 2]);
+        ll.process().unwrap();
+        assert!(ll.has_exchange_begun().unwrap());
+        assert!(matches!(ll.complete_exchange(), Err(Error::TransactionBusy)));
+
+        // ...then the rest of the data
+        spi.clock_in(&[3, 4]);
+        ll.process().unwrap();
+        csn.set_high(true);
+        ll.process().unwrap();
+
+        assert_eq!(ll.complete_exchange().unwrap(), 4);
+        assert!(!ll.is_exchange_active().unwrap());
+        assert_eq!(inc, [1, 2, 3, 4]);
+        assert_eq!(&spi.sent()[..4], &out[..]);
+    }
+
+    #[test]
+    fn exchange_uneven() {
+        let (mut ll, spi, _go, csn) = setup();
+
+        let out = [5u8; 2];
+        let mut inc = [0u8; 6];
+
+        ll.prepare_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+
+        csn.set_high(false);
+        spi.clock_in(&[1, 2, 3, 4, 5, 6]);
+        ll.process().unwrap();
+        csn.set_high(true);
+        ll.process().unwrap();
+
+        assert_eq!(ll.complete_exchange().unwrap(), 6);
+        assert_eq!(inc, [1, 2, 3, 4, 5, 6]);
+
+        // Outgoing data is padded with the ORC
+        assert_eq!(&spi.sent()[..6], &[5, 5, ORC, ORC, ORC, ORC]);
+    }
+
+    #[test]
+    fn short_exchange() {
+        let (mut ll, spi, go, csn) = setup();
+
+        let out = [9u8; 4];
+        let mut inc = [0u8; 4];
+
+        ll.prepare_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+
+        csn.set_high(false);
+        spi.clock_in(&[1, 2]);
+        ll.process().unwrap();
+        csn.set_high(true);
+        ll.process().unwrap();
+
+        assert!(matches!(
+            ll.complete_exchange(),
+            Err(Error::TransactionAborted)
+        ));
+        assert!(go.is_high());
+        assert!(!ll.is_exchange_active().unwrap());
+    }
+
+    #[test]
+    fn abort() {
+        let (mut ll, spi, go, csn) = setup();
+
+        let out = [9u8; 4];
+        let mut inc = [0u8; 4];
+
+        ll.prepare_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+        assert!(matches!(
+            ll.prepare_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len()),
+            Err(Error::IncorrectState)
+        ));
+
+        csn.set_high(false);
+        spi.clock_in(&[1, 2, 3]);
+        ll.process().unwrap();
+
+        assert_eq!(ll.abort_exchange().unwrap(), 3);
+        assert!(go.is_high());
+        assert!(!ll.is_exchange_active().unwrap());
+    }
+
+    #[test]
+    fn spi_error() {
+        let (mut ll, spi, _go, csn) = setup();
+
+        let out = [9u8; 4];
+        let mut inc = [0u8; 4];
+
+        ll.prepare_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+
+        csn.set_high(false);
+        spi.fail();
+        assert!(matches!(ll.process(), Err(Error::SpiError)));
+        assert!(matches!(
+            ll.complete_exchange(),
+            Err(Error::TransactionAborted)
+        ));
+    }
+}
@@ -0,0 +1,287 @@
+use embedded_hal::{
+    blocking::spi::Transfer,
+    digital::v2::{InputPin, OutputPin},
+};
+
+use crate::ORC;
+use anachro_spi::{component::EncLogicLLComponent, Error, Result};
+
+/// The number of bytes sent to the SPI peripheral at a time
+const CHUNK_SIZE: usize = 32;
+
+/// A Component LL driver for any blocking `embedded-hal` SPI
+///
+/// As the SPI transfer is blocking, exchanges are completed as soon as
+/// they are begun, and are reported as complete on the next poll.
+pub struct EhSpiComLL<SPI, CSN, GO>
+where
+    SPI: Transfer<u8>,
+    CSN: OutputPin,
+    GO: InputPin,
+{
+    spi: SPI,
+    csn_pin: CSN,
+    go_pin: GO,
+    exchange: Exchange,
+}
+
+enum Exchange {
+    Idle,
+    Complete(usize),
+}
+
+impl<SPI, CSN, GO> EhSpiComLL<SPI, CSN, GO>
+where
+    SPI: Transfer<u8>,
+    CSN: OutputPin,
+    GO: InputPin,
+{
+    pub fn new(spi: SPI, mut csn_pin: CSN, go_pin: GO) -> Self {
+        csn_pin.set_high().ok();
+        Self {
+            spi,
+            csn_pin,
+            go_pin,
+            exchange: Exchange::Idle,
+        }
+    }
+
+    /// Release the underlying SPI peripheral and pins
+    pub fn free(self) -> (SPI, CSN, GO) {
+        (self.spi, self.csn_pin, self.go_pin)
+    }
+}
+
+impl<SPI, CSN, GO> EncLogicLLComponent for EhSpiComLL<SPI, CSN, GO>
+where
+    SPI: Transfer<u8>,
+    CSN: OutputPin,
+    GO: InputPin,
+{
+    /// Process low level messages
+    fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Set the CSn line low (active)
+    fn notify_csn(&mut self) -> Result<()> {
+        self.csn_pin.set_low().map_err(|_| Error::GpioError)
+    }
+
+    /// Set the CSn line high (inactive)
+    fn clear_csn(&mut self) -> Result<()> {
+        self.csn_pin.set_high().map_err(|_| Error::GpioError)
+    }
+
+    /// Query whether the GO line is low (active)
+    fn is_go_active(&mut self) -> Result<bool> {
+        self.go_pin.is_low().map_err(|_| Error::GpioError)
+    }
+
+    /// Exchange data with the Arbitrator.
+    ///
+    /// `max(data_out_len, data_in_max)` bytes are clocked. Once the outgoing
+    /// data is exhausted, the ORC is sent. Incoming data past `data_in_max`
+    /// is discarded.
+    // NOTE: The validity of the pointers is part of the trait's contract
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn begin_exchange(
+        &mut self,
+        data_out: *const u8,
+        data_out_len: usize,
+        data_in: *mut u8,
+        data_in_max: usize,
+    ) -> Result<()> {
+        if let Exchange::Complete(_) = self.exchange {
+            return Err(Error::IncorrectState);
+        }
+
+        // SAFETY: The caller guarantees that both buffers are valid for the
+        // given lengths until the exchange is completed. As the transfer is
+        // blocking, they are not referenced after this function returns.
+        let (data_out, data_in) = unsafe {
+            (
+                core::slice::from_raw_parts(data_out, data_out_len),
+                core::slice::from_raw_parts_mut(data_in, data_in_max),
+            )
+        };
+
+        let total = data_out_len.max(data_in_max);
+        let mut chunk = [ORC; CHUNK_SIZE];
+        let mut idx = 0;
+
+        while idx < total {
+            let len = (total - idx).min(CHUNK_SIZE);
+
+            for (i, byte) in chunk[..len].iter_mut().enumerate() {
+                *byte = data_out.get(idx + i).copied().unwrap_or(ORC);
+            }
+
+            let received = self
+                .spi
+                .transfer(&mut chunk[..len])
+                .map_err(|_| Error::SpiError)?;
+
+            for (i, byte) in received.iter().enumerate() {
+                if let Some(inc) = data_in.get_mut(idx + i) {
+                    *inc = *byte;
+                }
+            }
+
+            idx += len;
+        }
+
+        self.exchange = Exchange::Complete(data_in_max);
+        Ok(())
+    }
+
+    /// Is a `exchange` action still in progress?
+    fn is_exchange_active(&self) -> Result<bool> {
+        match self.exchange {
+            Exchange::Idle => Ok(false),
+            Exchange::Complete(_) => Ok(true),
+        }
+    }
+
+    /// Complete an `exchange` action, returning the number of bytes received
+    fn complete_exchange(&mut self) -> Result<usize> {
+        match core::mem::replace(&mut self.exchange, Exchange::Idle) {
+            Exchange::Idle => Err(Error::IncorrectState),
+            Exchange::Complete(amt) => Ok(amt),
+        }
+    }
+
+    /// Stop the `exchange` action immediately
+    ///
+    /// As exchanges are blocking, they are always completed already
+    fn abort_exchange(&mut self) -> Result<usize> {
+        match core::mem::replace(&mut self.exchange, Exchange::Idle) {
+            Exchange::Idle => Ok(0),
+            Exchange::Complete(amt) => Ok(amt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::{MockInput, MockOutput, MockTransfer};
+
+    fn setup() -> (
+        EhSpiComLL<MockTransfer, MockOutput, MockInput>,
+        MockTransfer,
+        MockOutput,
+        MockInput,
+    ) {
+        let spi = MockTransfer::default();
+        let csn = MockOutput::default();
+        let go = MockInput::default();
+        let ll = EhSpiComLL::new(spi.clone(), csn.clone(), go.clone());
+        (ll, spi, csn, go)
+    }
+
+    #[test]
+    fn pins() {
+        let (mut ll, _spi, csn, go) = setup();
+
+        // CSn starts inactive
+        assert!(csn.is_high());
+        ll.notify_csn().unwrap();
+        assert!(!csn.is_high());
+        ll.clear_csn().unwrap();
+        assert!(csn.is_high());
+
+        go.set_high(false);
+        assert!(ll.is_go_active().unwrap());
+        go.set_high(true);
+        assert!(!ll.is_go_active().unwrap());
+    }
+
+    #[test]
+    fn exchange_same_size() {
+        let (mut ll, spi, _csn, _go) = setup();
+        spi.respond_with(&[5, 6, 7, 8]);
+
+        let out = [1u8, 2, 3, 4];
+        let mut inc = [0u8; 4];
+
+        assert!(!ll.is_exchange_active().unwrap());
+        ll.begin_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+        assert!(ll.is_exchange_active().unwrap());
+        assert_eq!(ll.complete_exchange().unwrap(), 4);
+        assert!(!ll.is_exchange_active().unwrap());
+
+        assert_eq!(spi.sent(), vec![1, 2, 3, 4]);
+        assert_eq!(inc, [5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn exchange_short_out() {
+        let (mut ll, spi, _csn, _go) = setup();
+        let response: Vec<u8> = (0..100).collect();
+        spi.respond_with(&response);
+
+        let out = [1u8, 2];
+        let mut inc = [0u8; 100];
+
+        ll.begin_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+        assert_eq!(ll.complete_exchange().unwrap(), 100);
+
+        // Outgoing data is padded with the ORC
+        let mut expected = vec![ORC; 100];
+        expected[..2].copy_from_slice(&out);
+        assert_eq!(spi.sent(), expected);
+        assert_eq!(&inc[..], &response[..]);
+    }
+
+    #[test]
+    fn exchange_short_in() {
+        let (mut ll, spi, _csn, _go) = setup();
+        spi.respond_with(&[9; 40]);
+
+        let out = [3u8; 40];
+        let mut inc = [0u8; 4];
+
+        ll.begin_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+        assert_eq!(ll.complete_exchange().unwrap(), 4);
+        assert_eq!(spi.sent(), vec![3u8; 40]);
+        assert_eq!(inc, [9; 4]);
+    }
+
+    #[test]
+    fn spi_error() {
+        let (mut ll, spi, _csn, _go) = setup();
+        spi.fail();
+
+        let out = [1u8; 4];
+        let mut inc = [0u8; 4];
+
+        assert!(matches!(
+            ll.begin_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len()),
+            Err(Error::SpiError),
+        ));
+        assert!(!ll.is_exchange_active().unwrap());
+    }
+
+    #[test]
+    fn incorrect_state() {
+        let (mut ll, _spi, _csn, _go) = setup();
+
+        assert!(matches!(ll.complete_exchange(), Err(Error::IncorrectState)));
+        assert_eq!(ll.abort_exchange().unwrap(), 0);
+
+        let out = [1u8; 4];
+        let mut inc = [0u8; 4];
+        ll.begin_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len())
+            .unwrap();
+        assert!(matches!(
+            ll.begin_exchange(out.as_ptr(), out.len(), inc.as_mut_ptr(), inc.len()),
+            Err(Error::IncorrectState),
+        ));
+        assert_eq!(ll.abort_exchange().unwrap(), 4);
+        assert!(!ll.is_exchange_active().unwrap());
+    }
+}
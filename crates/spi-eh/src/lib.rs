@@ -0,0 +1,21 @@
+//! # Generic `embedded-hal` drivers for the Anachro SPI transport
+//!
+//! This crate implements the `EncLogicLLComponent` and `EncLogicLLArbitrator`
+//! traits from `anachro-spi` for any SPI peripheral and GPIO pins that
+//! implement the `embedded-hal` traits.
+//!
+//! * The Component (SPI Controller) uses a blocking `Transfer<u8>` SPI,
+//!   an `OutputPin` for CSn, and an `InputPin` for GO.
+//! * The Arbitrator (SPI Peripheral) uses a `FullDuplex<u8>` SPI operating
+//!   in peripheral mode, an `OutputPin` for GO, and an `InputPin` for CSn.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod arbitrator;
+pub mod component;
+
+#[cfg(test)]
+mod mock;
+
+/// The byte sent when there is no more outgoing data (the ORC)
+const ORC: u8 = 0x00;
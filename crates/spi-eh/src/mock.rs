@@ -0,0 +1,164 @@
+//! Mock SPI and GPIO implementations for host testing
+//!
+//! Each mock is a cheaply cloneable handle to shared state, so that tests
+//! can keep a handle while the driver owns another.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use embedded_hal::{
+    blocking::spi::Transfer,
+    digital::v2::{InputPin, OutputPin},
+    spi::FullDuplex,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct MockError;
+
+#[derive(Clone, Default)]
+pub struct MockOutput {
+    high: Arc<AtomicBool>,
+}
+
+impl MockOutput {
+    pub fn is_high(&self) -> bool {
+        self.high.load(Ordering::SeqCst)
+    }
+}
+
+impl OutputPin for MockOutput {
+    type Error = MockError;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.high.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.high.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MockInput {
+    high: Arc<AtomicBool>,
+}
+
+impl MockInput {
+    pub fn set_high(&self, high: bool) {
+        self.high.store(high, Ordering::SeqCst);
+    }
+}
+
+impl InputPin for MockInput {
+    type Error = MockError;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.high.load(Ordering::SeqCst))
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.high.load(Ordering::SeqCst))
+    }
+}
+
+#[derive(Default)]
+struct SpiState {
+    /// Bytes to be received by the driver
+    incoming: VecDeque<u8>,
+
+    /// Bytes sent by the driver
+    sent: Vec<u8>,
+
+    /// Should the next operation fail?
+    fail: bool,
+}
+
+/// A mock blocking SPI controller
+#[derive(Clone, Default)]
+pub struct MockTransfer {
+    state: Arc<Mutex<SpiState>>,
+}
+
+impl MockTransfer {
+    /// Set the bytes the peripheral will respond with. Once these are
+    /// exhausted, zeroes are returned.
+    pub fn respond_with(&self, data: &[u8]) {
+        self.state.lock().unwrap().incoming.extend(data.iter());
+    }
+
+    pub fn sent(&self) -> Vec<u8> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    pub fn fail(&self) {
+        self.state.lock().unwrap().fail = true;
+    }
+}
+
+impl Transfer<u8> for MockTransfer {
+    type Error = MockError;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail {
+            return Err(MockError);
+        }
+
+        for word in words.iter_mut() {
+            state.sent.push(*word);
+            *word = state.incoming.pop_front().unwrap_or(0);
+        }
+        Ok(words)
+    }
+}
+
+/// A mock SPI peripheral, clocked by the test
+#[derive(Clone, Default)]
+pub struct MockFullDuplex {
+    state: Arc<Mutex<SpiState>>,
+}
+
+impl MockFullDuplex {
+    /// Clock bytes from the Controller into the peripheral
+    pub fn clock_in(&self, data: &[u8]) {
+        self.state.lock().unwrap().incoming.extend(data.iter());
+    }
+
+    /// All bytes loaded by the driver, including any pre-loaded
+    /// byte that has not yet been clocked out
+    pub fn sent(&self) -> Vec<u8> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    pub fn fail(&self) {
+        self.state.lock().unwrap().fail = true;
+    }
+}
+
+impl FullDuplex<u8> for MockFullDuplex {
+    type Error = MockError;
+
+    fn read(&mut self) -> nb::Result<u8, Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail {
+            return Err(nb::Error::Other(MockError));
+        }
+        state.incoming.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+
+    fn send(&mut self, word: u8) -> nb::Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.fail {
+            return Err(nb::Error::Other(MockError));
+        }
+        state.sent.push(word);
+        Ok(())
+    }
+}
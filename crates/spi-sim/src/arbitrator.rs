@@ -0,0 +1,157 @@
+use anachro_spi::{
+    arbitrator::{AsyncEncLogicLLArbitrator, EncLogicLLArbitrator},
+    Error, Result,
+};
+use std::task::Waker;
+
+use crate::{ArbSide, SimLink};
+
+/// The Arbitrator end of a simulated link
+pub struct SimArbLL {
+    link: SimLink,
+    data_in: *mut u8,
+}
+
+// SAFETY: `data_in` is only dereferenced while an exchange is prepared,
+// and the caller guarantees it remains valid until then
+unsafe impl Send for SimArbLL {}
+
+impl SimArbLL {
+    pub(crate) fn new(link: SimLink) -> Self {
+        Self {
+            link,
+            data_in: core::ptr::null_mut(),
+        }
+    }
+}
+
+impl EncLogicLLArbitrator for SimArbLL {
+    fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn is_go_active(&mut self) -> Result<bool> {
+        Ok(self.link.state().go)
+    }
+
+    fn notify_go(&mut self) -> Result<()> {
+        self.link.state().set_go(true);
+        Ok(())
+    }
+
+    fn clear_go(&mut self) -> Result<()> {
+        self.link.state().set_go(false);
+        Ok(())
+    }
+
+    /// Prepare data to be exchanged. The outgoing data is copied
+    /// immediately, and incoming data is copied once the exchange
+    /// is completed.
+    // NOTE: The validity of the pointers is part of the trait's contract
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn prepare_exchange(
+        &mut self,
+        data_out: *const u8,
+        data_out_len: usize,
+        data_in: *mut u8,
+        data_in_max: usize,
+    ) -> Result<()> {
+        let mut state = self.link.state();
+
+        if !matches!(state.arb, ArbSide::Idle) {
+            return Err(Error::IncorrectState);
+        }
+
+        // SAFETY: The caller guarantees `data_out` is valid for
+        // `data_out_len` bytes
+        let data_out = unsafe { core::slice::from_raw_parts(data_out, data_out_len) };
+
+        state.arb = ArbSide::Prepared {
+            data_out: data_out.to_vec(),
+            data_in_max,
+        };
+        state.set_go(true);
+        self.data_in = data_in;
+
+        Ok(())
+    }
+
+    fn has_exchange_begun(&self) -> Result<bool> {
+        let state = self.link.state();
+        match state.arb {
+            ArbSide::Idle => Ok(false),
+            ArbSide::Prepared { .. } => Ok(state.csn_seen()),
+            ArbSide::Done { .. } => Ok(true),
+        }
+    }
+
+    fn is_exchange_active(&self) -> Result<bool> {
+        match self.link.state().arb {
+            ArbSide::Idle => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn complete_exchange(&mut self) -> Result<usize> {
+        let mut state = self.link.state();
+
+        let (transfer, data_out_len, data_in_max) =
+            match core::mem::replace(&mut state.arb, ArbSide::Idle) {
+                ArbSide::Idle => return Err(Error::IncorrectState),
+                ArbSide::Done {
+                    transfer,
+                    data_out_len,
+                    data_in_max,
+                } if transfer.is_done(self.link.clock()) => (transfer, data_out_len, data_in_max),
+                other => {
+                    state.arb = other;
+                    return Err(Error::TransactionBusy);
+                }
+            };
+
+        // Like a real SPI peripheral, a short exchange is an aborted one
+        let complete = (transfer.clocked != 0)
+            && (transfer.clocked >= data_in_max)
+            && (transfer.clocked >= data_out_len);
+
+        if !complete {
+            state.set_go(false);
+            return Err(Error::TransactionAborted);
+        }
+
+        // SAFETY: The caller guarantees `data_in` is valid for `data_in_max`
+        // bytes until the exchange is completed, and `transfer.data` has
+        // been truncated to `data_in_max` bytes.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                transfer.data.as_ptr(),
+                self.data_in,
+                transfer.data.len(),
+            );
+        }
+
+        Ok(transfer.data.len())
+    }
+
+    fn abort_exchange(&mut self) -> Result<usize> {
+        let mut state = self.link.state();
+        state.set_go(false);
+
+        match core::mem::replace(&mut state.arb, ArbSide::Idle) {
+            ArbSide::Idle | ArbSide::Prepared { .. } => Ok(0),
+            ArbSide::Done { transfer, .. } => Ok(transfer.data.len()),
+        }
+    }
+}
+
+impl AsyncEncLogicLLArbitrator for SimArbLL {
+    /// The simulated clock only moves when advanced by hand, so a waker
+    /// with a timeout is woken immediately. Otherwise, it is woken when
+    /// the CSn line changes, or when an exchange is clocked.
+    fn register_waker(&mut self, waker: &Waker, timeout_us: Option<u32>) {
+        match timeout_us {
+            Some(_) => waker.wake_by_ref(),
+            None => self.link.state().register_arb_waker(waker),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use anachro_spi::{
+    component::{AsyncEncLogicLLComponent, EncLogicLLComponent},
+    Error, Result,
+};
+use groundhog::RollingTimer;
+use std::task::Waker;
+
+use crate::{SimLink, Transfer};
+
+/// The Component end of a simulated link
+pub struct SimComLL {
+    link: SimLink,
+    exchange: Exchange,
+}
+
+enum Exchange {
+    Idle,
+
+    /// The exchange was dropped, and will never complete
+    Lost,
+
+    Pending(Transfer),
+}
+
+impl SimComLL {
+    pub(crate) fn new(link: SimLink) -> Self {
+        Self {
+            link,
+            exchange: Exchange::Idle,
+        }
+    }
+}
+
+impl EncLogicLLComponent for SimComLL {
+    /// Process low level messages
+    fn process(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Set the CSn line active
+    fn notify_csn(&mut self) -> Result<()> {
+        self.link.state().set_csn(true);
+        Ok(())
+    }
+
+    /// Set the CSn line inactive
+    fn clear_csn(&mut self) -> Result<()> {
+        self.link.state().set_csn(false);
+        Ok(())
+    }
+
+    /// Query whether the GO line is active
+    fn is_go_active(&mut self) -> Result<bool> {
+        Ok(self.link.state().go_seen())
+    }
+
+    /// Exchange data with the Arbitrator.
+    ///
+    /// The data is moved immediately, but the exchange is not reported as
+    /// complete until any injected delay has passed.
+    // NOTE: The validity of the pointers is part of the trait's contract
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn begin_exchange(
+        &mut self,
+        data_out: *const u8,
+        data_out_len: usize,
+        data_in: *mut u8,
+        data_in_max: usize,
+    ) -> Result<()> {
+        if !matches!(self.exchange, Exchange::Idle) {
+            return Err(Error::IncorrectState);
+        }
+
+        // SAFETY: The caller guarantees that both buffers are valid for the
+        // given lengths until the exchange is completed. They are not
+        // referenced after this function returns.
+        let (data_out, data_in) = unsafe {
+            (
+                core::slice::from_raw_parts(data_out, data_out_len),
+                core::slice::from_raw_parts_mut(data_in, data_in_max),
+            )
+        };
+
+        let now = self.link.clock().get_ticks();
+        let transfer = self.link.state().clock(now, data_out, data_in_max);
+
+        self.exchange = match transfer {
+            Some(transfer) => {
+                data_in[..transfer.data.len()].copy_from_slice(&transfer.data);
+                Exchange::Pending(transfer)
+            }
+            None => Exchange::Lost,
+        };
+
+        Ok(())
+    }
+
+    /// Is a `exchange` action still in progress?
+    fn is_exchange_active(&self) -> Result<bool> {
+        match self.exchange {
+            Exchange::Idle => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    /// Complete an `exchange` action, returning the number of bytes received
+    fn complete_exchange(&mut self) -> Result<usize> {
+        match &self.exchange {
+            Exchange::Idle => Err(Error::IncorrectState),
+            Exchange::Lost => Err(Error::TransactionBusy),
+            Exchange::Pending(transfer) if !transfer.is_done(self.link.clock()) => {
+                Err(Error::TransactionBusy)
+            }
+            Exchange::Pending(transfer) => {
+                let amt = transfer.data.len();
+                self.exchange = Exchange::Idle;
+                Ok(amt)
+            }
+        }
+    }
+
+    /// Stop the `exchange` action immediately
+    fn abort_exchange(&mut self) -> Result<usize> {
+        match core::mem::replace(&mut self.exchange, Exchange::Idle) {
+            Exchange::Idle | Exchange::Lost => Ok(0),
+            Exchange::Pending(transfer) => Ok(transfer.data.len()),
+        }
+    }
+}
+
+impl AsyncEncLogicLLComponent for SimComLL {
+    /// The simulated clock only moves when advanced by hand, so a waker
+    /// with a timeout, or waiting on a delayed exchange, is woken
+    /// immediately. Otherwise, it is woken when the GO line changes.
+    fn register_waker(&mut self, waker: &Waker, timeout_us: Option<u32>) {
+        let delayed = match &self.exchange {
+            Exchange::Pending(transfer) => !transfer.is_done(self.link.clock()),
+            _ => false,
+        };
+
+        if timeout_us.is_some() || delayed {
+            waker.wake_by_ref();
+        } else {
+            self.link.state().register_com_waker(waker);
+        }
+    }
+}
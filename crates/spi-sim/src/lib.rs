@@ -0,0 +1,521 @@
+//! # A deterministic, in-memory link simulator for the Anachro SPI transport
+//!
+//! This crate provides a paired `EncLogicLLArbitrator` and
+//! `EncLogicLLComponent` implementation that exchange data in memory,
+//! rather than over a real SPI bus. Time only moves when the shared
+//! `groundhog::MockTimer` is advanced, so tests using the simulator are
+//! fully deterministic.
+//!
+//! Faults may be injected into upcoming exchanges with `SimLink::inject`,
+//! and the GO and CSn lines may be stalled, in order to exercise the
+//! timeout and recovery paths of both state machines.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    task::Waker,
+};
+
+use groundhog::{MockTimer, RollingTimer};
+
+pub mod arbitrator;
+pub mod component;
+
+pub use crate::{arbitrator::SimArbLL, component::SimComLL};
+
+/// The byte sent when there is no more outgoing data (the ORC)
+const ORC: u8 = 0x00;
+
+/// A fault applied to a single exchange on the link
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The exchange is lost, and never completes on either side
+    Drop,
+
+    /// The byte at `index` is XORed with `xor`, in both directions
+    Corrupt { index: usize, xor: u8 },
+
+    /// Only the first N bytes are clocked
+    Truncate(usize),
+
+    /// The exchange completes N microseconds after it was begun
+    Delay(u32),
+}
+
+/// The result of a single exchange, as seen by one side of the link
+pub(crate) struct Transfer {
+    /// The bytes received, limited to the receiver's buffer size
+    pub(crate) data: Vec<u8>,
+
+    /// The number of bytes actually clocked
+    pub(crate) clocked: usize,
+
+    pub(crate) t_start: u32,
+    pub(crate) delay_us: u32,
+}
+
+impl Transfer {
+    pub(crate) fn is_done(&self, clock: &MockTimer) -> bool {
+        clock.micros_since(self.t_start) >= self.delay_us
+    }
+}
+
+/// The Arbitrator's side of the current exchange
+pub(crate) enum ArbSide {
+    Idle,
+    Prepared {
+        data_out: Vec<u8>,
+        data_in_max: usize,
+    },
+    Done {
+        transfer: Transfer,
+        data_out_len: usize,
+        data_in_max: usize,
+    },
+}
+
+pub(crate) struct LinkState {
+    pub(crate) go: bool,
+    pub(crate) csn: bool,
+    go_stalled: bool,
+    csn_stalled: bool,
+    faults: VecDeque<Fault>,
+    exchanges: usize,
+    pub(crate) arb: ArbSide,
+    arb_waker: Option<Waker>,
+    com_waker: Option<Waker>,
+}
+
+impl LinkState {
+    /// The GO line, as seen by the Component
+    pub(crate) fn go_seen(&self) -> bool {
+        self.go && !self.go_stalled
+    }
+
+    /// The CSn line, as seen by the Arbitrator
+    pub(crate) fn csn_seen(&self) -> bool {
+        self.csn && !self.csn_stalled
+    }
+
+    /// Drive the GO line, waking the Component if it changed
+    pub(crate) fn set_go(&mut self, go: bool) {
+        if self.go != go {
+            self.go = go;
+            wake(&mut self.com_waker);
+        }
+    }
+
+    /// Drive the CSn line, waking the Arbitrator if it changed
+    pub(crate) fn set_csn(&mut self, csn: bool) {
+        if self.csn != csn {
+            self.csn = csn;
+            wake(&mut self.arb_waker);
+        }
+    }
+
+    pub(crate) fn register_arb_waker(&mut self, waker: &Waker) {
+        self.arb_waker = Some(waker.clone());
+    }
+
+    pub(crate) fn register_com_waker(&mut self, waker: &Waker) {
+        self.com_waker = Some(waker.clone());
+    }
+
+    /// Clock an exchange started by the Component.
+    ///
+    /// Returns the Component's side of the exchange, or `None` if the
+    /// exchange was dropped.
+    pub(crate) fn clock(
+        &mut self,
+        now: u32,
+        data_out: &[u8],
+        data_in_max: usize,
+    ) -> Option<Transfer> {
+        let fault = self.faults.pop_front();
+
+        let mut clocked = data_out.len().max(data_in_max);
+        let mut delay_us = 0;
+        let mut corrupt = None;
+
+        match fault {
+            Some(Fault::Drop) => return None,
+            Some(Fault::Corrupt { index, xor }) => corrupt = Some((index, xor)),
+            Some(Fault::Truncate(n)) => clocked = clocked.min(n),
+            Some(Fault::Delay(us)) => delay_us = us,
+            None => {}
+        }
+
+        // The Arbitrator only takes part if it has prepared an exchange,
+        // and can see that it has been selected
+        let arb_out = if self.csn_seen() {
+            match core::mem::replace(&mut self.arb, ArbSide::Idle) {
+                ArbSide::Prepared {
+                    data_out,
+                    data_in_max,
+                } => Some((data_out, data_in_max)),
+                other => {
+                    self.arb = other;
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut to_arb = padded(data_out, clocked);
+        let mut to_com = padded(
+            arb_out
+                .as_ref()
+                .map(|(out, _)| out.as_slice())
+                .unwrap_or(&[]),
+            clocked,
+        );
+
+        if let Some((index, xor)) = corrupt {
+            for buf in &mut [&mut to_arb, &mut to_com] {
+                if let Some(byte) = buf.get_mut(index) {
+                    *byte ^= xor;
+                }
+            }
+        }
+
+        if let Some((arb_out, arb_in_max)) = arb_out {
+            wake(&mut self.arb_waker);
+            to_arb.truncate(arb_in_max);
+            self.arb = ArbSide::Done {
+                transfer: Transfer {
+                    data: to_arb,
+                    clocked,
+                    t_start: now,
+                    delay_us,
+                },
+                data_out_len: arb_out.len(),
+                data_in_max: arb_in_max,
+            };
+        }
+
+        self.exchanges += 1;
+
+        to_com.truncate(data_in_max);
+        Some(Transfer {
+            data: to_com,
+            clocked,
+            t_start: now,
+            delay_us,
+        })
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+fn padded(data: &[u8], len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| data.get(i).copied().unwrap_or(ORC))
+        .collect()
+}
+
+/// A handle to a simulated link, shared by both ends
+///
+/// The handle may be used to inject faults, and to observe the link.
+#[derive(Clone)]
+pub struct SimLink {
+    state: Arc<Mutex<LinkState>>,
+    clock: MockTimer,
+}
+
+impl SimLink {
+    /// Create a new link, returning a handle to the link, as well as
+    /// the Arbitrator and Component ends
+    pub fn new(clock: MockTimer) -> (Self, SimArbLL, SimComLL) {
+        let link = SimLink {
+            state: Arc::new(Mutex::new(LinkState {
+                go: false,
+                csn: false,
+                go_stalled: false,
+                csn_stalled: false,
+                faults: VecDeque::new(),
+                exchanges: 0,
+                arb: ArbSide::Idle,
+                arb_waker: None,
+                com_waker: None,
+            })),
+            clock,
+        };
+
+        let arb = SimArbLL::new(link.clone());
+        let com = SimComLL::new(link.clone());
+
+        (link, arb, com)
+    }
+
+    pub(crate) fn state(&self) -> MutexGuard<'_, LinkState> {
+        // NOTE: The lock is never held across a call that could panic
+        self.state.lock().unwrap()
+    }
+
+    pub(crate) fn clock(&self) -> &MockTimer {
+        &self.clock
+    }
+
+    /// Apply a fault to the next exchange that has not already had a
+    /// fault applied. Faults are applied in the order they were injected.
+    pub fn inject(&self, fault: Fault) {
+        self.state().faults.push_back(fault);
+    }
+
+    /// Remove any faults that have not yet been applied
+    pub fn clear_faults(&self) {
+        self.state().faults.clear();
+    }
+
+    /// While stalled, the Component sees the GO line as inactive
+    pub fn stall_go(&self, stalled: bool) {
+        let mut state = self.state();
+        state.go_stalled = stalled;
+        wake(&mut state.com_waker);
+    }
+
+    /// While stalled, the Arbitrator sees the CSn line as inactive, and
+    /// does not take part in any exchanges
+    pub fn stall_csn(&self, stalled: bool) {
+        let mut state = self.state();
+        state.csn_stalled = stalled;
+        wake(&mut state.arb_waker);
+    }
+
+    /// Is the Arbitrator driving the GO line active?
+    pub fn is_go_active(&self) -> bool {
+        self.state().go
+    }
+
+    /// Is the Component driving the CSn line active?
+    pub fn is_csn_active(&self) -> bool {
+        self.state().csn
+    }
+
+    /// The number of exchanges clocked by the Component, not including
+    /// dropped exchanges
+    pub fn exchanges(&self) -> usize {
+        self.state().exchanges
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anachro_server::Uuid;
+    use anachro_spi::{arbitrator::EncLogicHLArbitrator, component::EncLogicHLComponent};
+    use bbqueue::{consts::U1024, BBBuffer};
+    use std::{
+        future::Future,
+        pin::Pin,
+        ptr,
+        task::{Context, Poll, RawWaker, RawWakerVTable},
+    };
+
+    const STEP_US: u32 = 100;
+
+    struct Sim {
+        clock: MockTimer,
+        link: SimLink,
+        arb: EncLogicHLArbitrator<SimArbLL, U1024, MockTimer>,
+        com: EncLogicHLComponent<SimComLL, U1024, MockTimer>,
+    }
+
+    fn leak() -> &'static BBBuffer<U1024> {
+        Box::leak(Box::new(BBBuffer::new()))
+    }
+
+    impl Sim {
+        fn new() -> Self {
+            let clock = MockTimer::new();
+            let (link, arb_ll, com_ll) = SimLink::new(clock.clone());
+            let uuid = Uuid::from_bytes([0x42; 16]);
+
+            Sim {
+                arb: EncLogicHLArbitrator::new(uuid, arb_ll, clock.clone(), leak(), leak())
+                    .unwrap(),
+                com: EncLogicHLComponent::new(com_ll, clock.clone(), leak(), leak()).unwrap(),
+                clock,
+                link,
+            }
+        }
+
+        /// Run both sides for the given time, querying the Component
+        /// whenever the Arbitrator is idle
+        fn run_us(&mut self, us: u32) {
+            for _ in 0..(us / STEP_US) {
+                self.clock.advance_us(STEP_US);
+                self.arb.query_component().ok();
+                self.arb.poll().ok();
+                self.com.poll().ok();
+            }
+        }
+
+        fn arb_recv(&mut self) -> Option<Vec<u8>> {
+            self.arb.dequeue().map(|gr| {
+                let msg = gr.to_vec();
+                gr.release();
+                msg
+            })
+        }
+
+        fn com_recv(&mut self) -> Option<Vec<u8>> {
+            self.com.dequeue().map(|gr| {
+                let msg = gr.to_vec();
+                gr.release();
+                msg
+            })
+        }
+
+        /// Send a message in each direction, and check they both arrive
+        fn check_delivery(&mut self, us: u32) {
+            self.arb.enqueue(&[1, 2, 3, 4, 5]).unwrap();
+            self.com.enqueue(&[6, 7, 8]).unwrap();
+            self.run_us(us);
+            assert_eq!(self.com_recv().unwrap(), vec![1, 2, 3, 4, 5]);
+            assert_eq!(self.arb_recv().unwrap(), vec![6, 7, 8]);
+            assert!(self.com_recv().is_none());
+            assert!(self.arb_recv().is_none());
+        }
+    }
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+
+        // SAFETY: The vtable functions do nothing, and never touch the data pointer
+        unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn clean_link() {
+        let mut sim = Sim::new();
+        sim.check_delivery(10_000);
+        assert!(sim.link.exchanges() >= 2);
+    }
+
+    #[test]
+    fn dropped_exchange() {
+        let mut sim = Sim::new();
+        sim.link.inject(Fault::Drop);
+
+        // Nothing arrives until the Arbitrator times out the lost exchange
+        sim.arb.enqueue(&[1, 2, 3]).unwrap();
+        sim.run_us(100_000);
+        assert!(sim.com_recv().is_none());
+
+        sim.run_us(300_000);
+        assert_eq!(sim.com_recv().unwrap(), vec![1, 2, 3]);
+        sim.check_delivery(10_000);
+    }
+
+    #[test]
+    fn corrupted_header() {
+        let mut sim = Sim::new();
+
+        // Claim a body far larger than the incoming queue
+        sim.link.inject(Fault::Corrupt {
+            index: 1,
+            xor: 0x08,
+        });
+        sim.check_delivery(400_000);
+    }
+
+    #[test]
+    fn truncated_exchange() {
+        let mut sim = Sim::new();
+        sim.link.inject(Fault::Truncate(2));
+        sim.check_delivery(400_000);
+    }
+
+    #[test]
+    fn delayed_exchange() {
+        let mut sim = Sim::new();
+
+        // Within the Arbitrator's window, so the exchange succeeds
+        sim.link.inject(Fault::Delay(50_000));
+        sim.check_delivery(60_000);
+
+        // Outside of the window, the Arbitrator gives up and retries
+        sim.link.inject(Fault::Delay(300_000));
+        sim.check_delivery(400_000);
+    }
+
+    #[test]
+    fn stalled_go() {
+        let mut sim = Sim::new();
+        sim.link.stall_go(true);
+
+        sim.arb.enqueue(&[1, 2, 3]).unwrap();
+        sim.run_us(500_000);
+        assert!(sim.com_recv().is_none());
+        assert_eq!(sim.link.exchanges(), 0);
+        assert!(!sim.link.is_csn_active());
+
+        sim.link.stall_go(false);
+        sim.run_us(10_000);
+        assert_eq!(sim.com_recv().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn stalled_csn() {
+        let mut sim = Sim::new();
+        sim.link.stall_csn(true);
+
+        // The Component keeps clocking, but the Arbitrator never takes part
+        sim.arb.enqueue(&[1, 2, 3]).unwrap();
+        sim.run_us(500_000);
+        assert!(sim.com_recv().is_none());
+        assert!(sim.link.exchanges() > 0);
+
+        sim.link.stall_csn(false);
+        sim.run_us(300_000);
+        assert_eq!(sim.com_recv().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn async_exchange() {
+        let mut sim = Sim::new();
+        sim.arb.enqueue(&[1, 2, 3, 4, 5]).unwrap();
+        sim.com.enqueue(&[6, 7, 8]).unwrap();
+
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut query = sim.arb.query();
+        let mut exchange = sim.com.exchange();
+        let mut queried = None;
+        let mut exchanged = None;
+
+        for _ in 0..1_000 {
+            sim.clock.advance_us(STEP_US);
+            if queried.is_none() {
+                if let Poll::Ready(res) = Pin::new(&mut query).poll(&mut cx) {
+                    queried = Some(res);
+                }
+            }
+            if exchanged.is_none() {
+                if let Poll::Ready(res) = Pin::new(&mut exchange).poll(&mut cx) {
+                    exchanged = Some(res);
+                }
+            }
+            if queried.is_some() && exchanged.is_some() {
+                break;
+            }
+        }
+
+        queried.unwrap().unwrap();
+        exchanged.unwrap().unwrap();
+        assert_eq!(sim.com_recv().unwrap(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(sim.arb_recv().unwrap(), vec![6, 7, 8]);
+        assert!(!sim.link.is_go_active());
+        assert!(!sim.link.is_csn_active());
+    }
+}
@@ -1,34 +1,151 @@
-use std::net::TcpStream;
-
-use std::default::Default;
-use std::io::{ErrorKind, Read, Write};
+//! # A TCP transport for the Anachro SPI protocol
+//!
+//! This crate emulates the SPI link (including the GO and CSn lines) over
+//! a TCP connection, allowing the `anachro-spi` state machines to be run
+//! on a host.
+//!
+//! The Component connects to the Arbitrator. If the connection is lost,
+//! the Component will attempt to reconnect when it is polled, at most
+//! once every `RECONNECT_INTERVAL`.
+
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
 
 use serde::{Deserialize, Serialize};
 
-use postcard::{from_bytes_cobs, to_stdvec_cobs};
+use postcard::to_stdvec_cobs;
+
+use anachro_cobs_buf::{Buffer, FeedResult};
 
 use anachro_spi::{
     arbitrator::EncLogicLLArbitrator, component::EncLogicLLComponent, Error, Result,
 };
 
+/// How long the Component will wait when attempting to reconnect
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long the Component will wait after a failed attempt to reconnect
+/// before trying again, so that polling does not block on every call
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long a single message may take to be written, before the
+/// connection is considered lost
+const SEND_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The largest encoded message that can be received over a link. This
+/// must be able to hold the largest exchange payload, with some overhead.
+const MAX_LINK_FRAME: usize = 8 * 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 enum TcpSpiMsg {
-    ReadyState(bool),
+    CsnState(bool),
     GoState(bool),
-    Payload(Vec<u8>),
+
+    /// The data sent in an exchange. The Arbitrator responds to each
+    /// payload with the same `id`, so that the Component can discard
+    /// responses to exchanges it has already given up on.
+    Payload {
+        id: u32,
+        data: Vec<u8>,
+    },
 }
 
-pub struct TcpSpiComLL {
+/// Does this error mean the other side has closed the connection?
+fn is_hang_up(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe
+    )
+}
+
+/// A non-blocking, COBS framed stream of `TcpSpiMsg`s
+struct Link {
     stream: TcpStream,
-    go_state: Option<bool>,
-    ready_state: bool,
-    incoming_payload: Option<Vec<u8>>,
-    pending_data: Vec<u8>,
-    pending_exchange: Option<PendingExchange>,
+    frames: Box<Buffer<MAX_LINK_FRAME>>,
 }
 
-// TODO: Is this sound?
-unsafe impl Send for PendingExchange {}
+impl Link {
+    /// Set up the stream, and send an initial message declaring the
+    /// state of our control line
+    fn new(stream: TcpStream, init: &TcpSpiMsg) -> Result<Self> {
+        stream
+            .set_nonblocking(true)
+            .map_err(|_| Error::ConnectionFailure)?;
+        stream.set_nodelay(true).ok();
+
+        let mut link = Link {
+            stream,
+            frames: Box::new(Buffer::new()),
+        };
+        link.send(init).map_err(|_| Error::ConnectionFailure)?;
+
+        Ok(link)
+    }
+
+    /// Send a message, returning `ErrorKind::TimedOut` if it could not be
+    /// written within `SEND_TIMEOUT`. The stream may contain a partial
+    /// message after any error, so the link must not be used again.
+    fn send(&mut self, msg: &TcpSpiMsg) -> io::Result<()> {
+        let payload = to_stdvec_cobs(msg).map_err(|_| io::Error::from(ErrorKind::InvalidData))?;
+        let mut remaining = &payload[..];
+        let deadline = Instant::now() + SEND_TIMEOUT;
+
+        // NOTE: `write_all` can't be used on a non-blocking stream, as a
+        // partial write followed by `WouldBlock` would corrupt the stream
+        while !remaining.is_empty() {
+            match self.stream.write(remaining) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => remaining = &remaining[n..],
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        return Err(ErrorKind::TimedOut.into());
+                    }
+                    std::thread::yield_now();
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Receive all currently available messages.
+    ///
+    /// Returns `Ok(false)` if the other side has hung up. Malformed
+    /// messages are discarded.
+    fn recv(&mut self, msgs: &mut Vec<TcpSpiMsg>) -> io::Result<bool> {
+        let mut buf = [0u8; 1024];
+
+        loop {
+            let n = match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(true),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if is_hang_up(&e) => return Ok(false),
+                Err(e) => return Err(e),
+            };
+
+            let mut window = &buf[..n];
+            while !window.is_empty() {
+                window = match self.frames.feed::<TcpSpiMsg>(window) {
+                    FeedResult::Consumed => break,
+                    FeedResult::OverFull(remaining) => remaining,
+                    FeedResult::DeserError(remaining) => remaining,
+                    FeedResult::Success { data, remaining } => {
+                        msgs.push(data);
+                        remaining
+                    }
+                };
+            }
+        }
+    }
+}
 
 struct PendingExchange {
     data_out: *const u8,
@@ -37,113 +154,178 @@ struct PendingExchange {
     data_in_max: usize,
 }
 
+// SAFETY: The raw pointers are only dereferenced while the exchange is
+// pending, and the caller guarantees they remain valid until then
+unsafe impl Send for PendingExchange {}
+
+impl PendingExchange {
+    fn data_out(&self) -> Vec<u8> {
+        // SAFETY: The caller guarantees `data_out` is valid for
+        // `data_out_len` bytes until the exchange is completed
+        unsafe { core::slice::from_raw_parts(self.data_out, self.data_out_len) }.to_vec()
+    }
+
+    /// Copy received data in, returning the number of bytes copied
+    fn fill_in(&self, data: &[u8]) -> usize {
+        // SAFETY: The caller guarantees `data_in` is valid for
+        // `data_in_max` bytes until the exchange is completed
+        let data_in = unsafe { core::slice::from_raw_parts_mut(self.data_in, self.data_in_max) };
+
+        let copy_amt = self.data_in_max.min(data.len());
+        data_in[..copy_amt].copy_from_slice(&data[..copy_amt]);
+        copy_amt
+    }
+}
+
+pub struct TcpSpiComLL {
+    addr: SocketAddr,
+    link: Option<Link>,
+    next_reconnect: Instant,
+    go_state: bool,
+    csn_state: bool,
+    exchange_id: u32,
+    incoming_payloads: VecDeque<(u32, Vec<u8>)>,
+    pending_exchange: Option<PendingExchange>,
+}
+
 impl TcpSpiComLL {
-    pub fn new(mut stream: TcpStream) -> Self {
-        let init_msg = to_stdvec_cobs(&TcpSpiMsg::ReadyState(false)).unwrap();
+    /// Connect to an Arbitrator at the given address
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr).map_err(|_| Error::ConnectionFailure)?;
+        Self::new(stream)
+    }
 
-        // Send init message declaring GO state
-        stream.write_all(&init_msg).unwrap();
+    /// Use an existing connection to an Arbitrator. If the connection is
+    /// lost, the Component will reconnect to the same address.
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        let addr = stream.peer_addr().map_err(|_| Error::ConnectionFailure)?;
+        let link = Link::new(stream, &TcpSpiMsg::CsnState(false))?;
 
-        TcpSpiComLL {
-            stream,
-            go_state: None,
-            ready_state: false,
-            incoming_payload: None,
-            pending_data: Vec::default(),
+        Ok(TcpSpiComLL {
+            addr,
+            link: Some(link),
+            next_reconnect: Instant::now(),
+            go_state: false,
+            csn_state: false,
+            exchange_id: 0,
+            incoming_payloads: VecDeque::new(),
             pending_exchange: None,
-        }
+        })
     }
-}
 
-impl EncLogicLLComponent for TcpSpiComLL {
-    fn process(&mut self) -> Result<()> {
-        let mut buf = [0u8; 1024];
+    /// Is there currently a connection to the Arbitrator?
+    pub fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
 
-        // Receive incoming messages
-        loop {
-            match self.stream.read(&mut buf) {
-                Ok(n) if n > 0 => {
-                    self.pending_data.extend_from_slice(&buf[..n]);
-                }
-                Ok(_) => {
-                    break;
-                }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("TCP Error: {:?}", e);
-                    panic!()
-                }
-            }
-        }
+    fn disconnect(&mut self) {
+        self.link = None;
+        self.go_state = false;
+        self.csn_state = false;
+        self.incoming_payloads.clear();
+    }
 
-        // Process any messages
-        while let Some(p) = self.pending_data.iter().position(|c| *c == 0x00) {
-            let mut remainder = self.pending_data.split_off(p + 1);
-            core::mem::swap(&mut remainder, &mut self.pending_data);
-            let mut payload = remainder;
+    /// Attempt to reconnect, unless the last attempt failed less than
+    /// `RECONNECT_INTERVAL` ago
+    fn reconnect(&mut self) -> Result<()> {
+        let now = Instant::now();
+        if now < self.next_reconnect {
+            return Err(Error::ConnectionFailure);
+        }
 
-            // println!("TCP: got {:?}", payload);
+        let link = TcpStream::connect_timeout(&self.addr, CONNECT_TIMEOUT)
+            .map_err(|_| Error::ConnectionFailure)
+            .and_then(|stream| Link::new(stream, &TcpSpiMsg::CsnState(false)));
 
-            if let Ok(msg) = from_bytes_cobs::<TcpSpiMsg>(&mut payload) {
-                match msg {
-                    TcpSpiMsg::GoState(state) => {
-                        // println!("Go is now: {}", state);
-                        self.go_state = Some(state);
-                    }
-                    TcpSpiMsg::ReadyState(_) => {
-                        panic!("We're a component! No one should be sending us ready state!");
-                    }
-                    TcpSpiMsg::Payload(payload) => {
-                        // println!("Payload!");
-                        assert!(self.incoming_payload.is_none(), "DATA LOSS");
-                        self.incoming_payload = Some(payload);
-                    }
-                }
+        match link {
+            Ok(link) => {
+                self.link = Some(link);
+                Ok(())
+            }
+            Err(e) => {
+                self.next_reconnect = now + RECONNECT_INTERVAL;
+                Err(e)
             }
         }
-
-        Ok(())
     }
 
-    fn is_ready_active(&mut self) -> Result<bool> {
-        Ok(self.ready_state)
+    fn send(&mut self, msg: &TcpSpiMsg) -> Result<()> {
+        let link = self.link.as_mut().ok_or(Error::ConnectionFailure)?;
+
+        link.send(msg).map_err(|e| {
+            self.disconnect();
+            if is_hang_up(&e) {
+                Error::ArbitratorHungUp
+            } else {
+                Error::ConnectionFailure
+            }
+        })
     }
+}
 
-    fn is_go_active(&mut self) -> Result<bool> {
-        self.go_state.ok_or(Error::ToDo)
+impl EncLogicLLComponent for TcpSpiComLL {
+    /// Receive any messages from the Arbitrator, or attempt to reconnect
+    /// if the connection has been lost.
+    ///
+    /// Returns `Error::ArbitratorHungUp` when the Arbitrator closes the
+    /// connection.
+    fn process(&mut self) -> Result<()> {
+        let link = match self.link.as_mut() {
+            Some(link) => link,
+            None => return self.reconnect(),
+        };
+
+        let mut msgs = Vec::new();
+        let result = link.recv(&mut msgs);
+
+        for msg in msgs {
+            match msg {
+                TcpSpiMsg::GoState(state) => self.go_state = state,
+                TcpSpiMsg::Payload { id, data } => self.incoming_payloads.push_back((id, data)),
+
+                // Only the Component drives CSn
+                TcpSpiMsg::CsnState(_) => {}
+            }
+        }
+
+        match result {
+            Ok(true) => Ok(()),
+            Ok(false) => {
+                self.disconnect();
+                Err(Error::ArbitratorHungUp)
+            }
+            Err(_) => {
+                self.disconnect();
+                Err(Error::ConnectionFailure)
+            }
+        }
     }
 
-    /// Set the READY line low (active)
-    fn notify_ready(&mut self) -> Result<()> {
-        self.ready_state = true;
-        let msg = TcpSpiMsg::ReadyState(true);
-        let payload = to_stdvec_cobs(&msg).map_err(|_| Error::ToDo)?;
-        self.stream.write_all(&payload).unwrap();
-        // .map_err(|_| Error::ToDo)?;
+    /// Set the CSn line low (active)
+    fn notify_csn(&mut self) -> Result<()> {
+        self.send(&TcpSpiMsg::CsnState(true))?;
+        self.csn_state = true;
         Ok(())
     }
 
-    /// Set the READY line high (inactive)
-    fn clear_ready(&mut self) -> Result<()> {
-        self.ready_state = false;
-        let msg = TcpSpiMsg::ReadyState(false);
-        let payload = to_stdvec_cobs(&msg).map_err(|_| Error::ToDo)?;
-        self.stream.write_all(&payload).map_err(|_| Error::ToDo)?;
+    /// Set the CSn line high (inactive)
+    fn clear_csn(&mut self) -> Result<()> {
+        if !self.csn_state {
+            return Ok(());
+        }
+        self.send(&TcpSpiMsg::CsnState(false))?;
+        self.csn_state = false;
         Ok(())
     }
 
-    /// Prepare data to be exchanged. The data MUST not be referenced
-    /// until `complete_exchange` or `abort_exchange` has been called.
-    ///
-    /// NOTE: Data will not be sent until `trigger_exchange` has been
-    /// called. This will automatically set the READY line if it is
-    /// not already active.
+    /// Query whether the GO line is low (active)
     ///
-    /// An error will be returned if an exchange is already in progress
-    // TODO: `embedded-dma`?
-    fn prepare_exchange(
+    /// GO is never active while disconnected
+    fn is_go_active(&mut self) -> Result<bool> {
+        Ok(self.go_state)
+    }
+
+    fn begin_exchange(
         &mut self,
         data_out: *const u8,
         data_out_len: usize,
@@ -151,38 +333,24 @@ impl EncLogicLLComponent for TcpSpiComLL {
         data_in_max: usize,
     ) -> Result<()> {
         if self.pending_exchange.is_some() {
-            println!("ALREADY IS PENDING");
-            return Err(Error::ToDo);
+            return Err(Error::IncorrectState);
         }
-        self.pending_exchange = Some(PendingExchange {
+
+        let exch = PendingExchange {
             data_out,
             data_out_len,
             data_in,
             data_in_max,
-        });
-        self.notify_ready()?;
-        Ok(())
-    }
-
-    /// Actually begin exchanging data
-    ///
-    /// Will return an error if READY and GO are not active
-    fn trigger_exchange(&mut self) -> Result<()> {
-        if !(self.is_go_active()? && self.ready_state) {
-            return Err(Error::ToDo);
-        }
-
-        let exch = match self.pending_exchange.as_ref() {
-            Some(ex) => ex,
-            None => return Err(Error::ToDo),
         };
+        self.exchange_id = self.exchange_id.wrapping_add(1);
 
         // Hello! I am pretending to be DMA!
-        let payload =
-            unsafe { core::slice::from_raw_parts(exch.data_out, exch.data_out_len) }.to_vec();
+        self.send(&TcpSpiMsg::Payload {
+            id: self.exchange_id,
+            data: exch.data_out(),
+        })?;
 
-        let msg = to_stdvec_cobs(&TcpSpiMsg::Payload(payload)).map_err(|_| Error::ToDo)?;
-        self.stream.write_all(&msg).map_err(|_| Error::ToDo)?;
+        self.pending_exchange = Some(exch);
 
         Ok(())
     }
@@ -192,188 +360,138 @@ impl EncLogicLLComponent for TcpSpiComLL {
         Ok(self.pending_exchange.is_some())
     }
 
-    /// Attempt to complete a `exchange` action.
-    ///
-    /// Returns `Ok(())` if the `exchange` completed successfully.
-    ///
-    /// If the exchange is successful and `clear_ready` is `true`,
-    /// then the READY line will be cleared.
-    ///
-    /// Will return an error if the exchange is still in progress.
-    /// If the exchange is still in progress, `clear_ready` is ignored.
-    ///
-    /// Use `abort_exchange` to force the exchange to completion even
-    /// if it is still in progress.
-    fn complete_exchange(&mut self, clear_ready: bool) -> Result<usize> {
-        let is_go_active = self.is_go_active()?;
-
-        let exch = match self.pending_exchange.take() {
-            Some(ex) => ex,
-            None => return Err(Error::ToDo),
-        };
+    fn complete_exchange(&mut self) -> Result<usize> {
+        let exch = self
+            .pending_exchange
+            .as_ref()
+            .ok_or(Error::IncorrectState)?;
 
-        let inc = match self.incoming_payload.take() {
-            None if !is_go_active => {
-                // println!("No go!");
-                return Err(Error::ArbitratorHungUp);
-            }
-            None => {
-                self.pending_exchange = Some(exch);
-                // Still busy
-                return Err(Error::ToDo);
+        // Discard any responses to exchanges we have already given up on
+        while let Some((id, _)) = self.incoming_payloads.front() {
+            if *id == self.exchange_id {
+                break;
             }
-            Some(inc) => inc,
-        };
-
-        // It's me, DMA!
-        let out_slice = unsafe { core::slice::from_raw_parts_mut(exch.data_in, exch.data_in_max) };
-
-        let copy_amt = exch.data_in_max.min(inc.len());
-
-        out_slice[..copy_amt].copy_from_slice(&inc[..copy_amt]);
-
-        if clear_ready {
-            self.clear_ready()?;
+            self.incoming_payloads.pop_front();
         }
 
-        if !is_go_active {
-            Err(Error::ArbitratorHungUp)
-        } else {
-            Ok(copy_amt)
+        match self.incoming_payloads.pop_front() {
+            Some((_, data)) => {
+                // It's me, DMA!
+                let amt = exch.fill_in(&data);
+                self.pending_exchange = None;
+                Ok(amt)
+            }
+            None if !self.go_state => Err(Error::ArbitratorHungUp),
+            None => Err(Error::TransactionBusy),
         }
     }
 
-    /// Stop the `exchange` action immediately
-    ///
-    /// Returns `Ok(())` if the exchange had already been completed.
-    ///
-    /// In all cases, the READY line will be cleared.
-    ///
-    /// If the exchange had not yet completed, an Error containing the
-    /// number of successfully sent bytes will be returned.
+    /// Stop the `exchange` action immediately. Any response that
+    /// arrives later is discarded.
     fn abort_exchange(&mut self) -> Result<usize> {
-        self.clear_ready().ok();
-
-        let exch = match self.pending_exchange.take() {
-            Some(ex) => ex,
-            None => return Err(Error::ToDo),
-        };
-
-        let inc = match self.incoming_payload.take() {
-            None => {
-                return Err(Error::IncompleteTransaction(0));
-            }
-            Some(inc) => inc,
-        };
-
-        // It's me, DMA!
-        let out_slice = unsafe { core::slice::from_raw_parts_mut(exch.data_in, exch.data_in_max) };
-
-        let copy_amt = exch.data_in_max.min(inc.len());
-
-        out_slice[..copy_amt].copy_from_slice(&inc[..copy_amt]);
-
-        Ok(copy_amt)
+        self.pending_exchange = None;
+        Ok(0)
     }
 }
 
 pub struct TcpSpiArbLL {
-    stream: TcpStream,
+    link: Option<Link>,
     go_state: bool,
-    ready_state: Option<bool>,
-    incoming_payload: Option<Vec<u8>>,
-    pending_data: Vec<u8>,
+    csn_state: bool,
+    incoming_payloads: VecDeque<(u32, Vec<u8>)>,
     pending_exchange: Option<PendingExchange>,
 }
 
 impl TcpSpiArbLL {
-    pub fn new(mut stream: TcpStream) -> Self {
-        let init_msg = to_stdvec_cobs(&TcpSpiMsg::GoState(false)).unwrap();
-
-        // Send init message declaring GO state
-        stream.write_all(&init_msg).unwrap();
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        let link = Link::new(stream, &TcpSpiMsg::GoState(false))?;
 
-        TcpSpiArbLL {
-            stream,
+        Ok(TcpSpiArbLL {
+            link: Some(link),
             go_state: false,
-            ready_state: None,
-            incoming_payload: None,
-            pending_data: Vec::default(),
+            csn_state: false,
+            incoming_payloads: VecDeque::new(),
             pending_exchange: None,
-        }
+        })
+    }
+
+    /// Is there currently a connection to the Component?
+    pub fn is_connected(&self) -> bool {
+        self.link.is_some()
+    }
+
+    fn disconnect(&mut self) {
+        self.link = None;
+        self.go_state = false;
+        self.csn_state = false;
+        self.incoming_payloads.clear();
+    }
+
+    fn send(&mut self, msg: &TcpSpiMsg) -> Result<()> {
+        let link = self.link.as_mut().ok_or(Error::ConnectionFailure)?;
+
+        link.send(msg).map_err(|_| {
+            self.disconnect();
+            Error::ConnectionFailure
+        })
     }
 }
 
 impl EncLogicLLArbitrator for TcpSpiArbLL {
+    /// Receive any messages from the Component.
+    ///
+    /// Returns `Error::ConnectionFailure` once the Component has closed
+    /// the connection. The Component is expected to reconnect with a new
+    /// connection.
     fn process(&mut self) -> Result<()> {
-        let mut buf = [0u8; 1024];
+        let link = self.link.as_mut().ok_or(Error::ConnectionFailure)?;
 
-        // Receive incoming messages
-        loop {
-            match self.stream.read(&mut buf) {
-                Ok(n) if n > 0 => {
-                    self.pending_data.extend_from_slice(&buf[..n]);
-                }
-                Ok(_) => {
-                    break;
-                }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => {
-                    break;
-                }
-                Err(e) => {
-                    eprintln!("TCP Error: {:?}", e);
-                    return Err(Error::ConnectionFailure);
-                }
-            }
-        }
-
-        // Process any messages
-        while let Some(p) = self.pending_data.iter().position(|c| *c == 0x00) {
-            let mut remainder = self.pending_data.split_off(p + 1);
-            core::mem::swap(&mut remainder, &mut self.pending_data);
-            let mut payload = remainder;
+        let mut msgs = Vec::new();
+        let result = link.recv(&mut msgs);
 
-            // println!("TCP: got {:?}", payload);
+        for msg in msgs {
+            match msg {
+                TcpSpiMsg::CsnState(state) => {
+                    self.csn_state = state;
 
-            if let Ok(msg) = from_bytes_cobs::<TcpSpiMsg>(&mut payload) {
-                match msg {
-                    TcpSpiMsg::ReadyState(state) => {
-                        // println!("Ready is now: {}", state);
-                        self.ready_state = Some(state);
-                    }
-                    TcpSpiMsg::GoState(_) => {
-                        panic!("We're an arbitrator! No one should tell us Go state!");
-                    }
-                    TcpSpiMsg::Payload(payload) => {
-                        // println!("Payload!");
-                        assert!(self.incoming_payload.is_none(), "DATALOSS");
-                        self.incoming_payload = Some(payload);
+                    // Releasing CSn ends any exchange, so payloads that
+                    // were never responded to are stale
+                    if !state {
+                        self.incoming_payloads.clear();
                     }
                 }
+                TcpSpiMsg::Payload { id, data } => {
+                    // This may arrive before the exchange is prepared,
+                    // so hold on to it until then
+                    self.incoming_payloads.push_back((id, data));
+                }
+
+                // Only the Arbitrator drives GO
+                TcpSpiMsg::GoState(_) => {}
             }
         }
 
-        Ok(())
-    }
-
-    fn is_ready_active(&mut self) -> Result<bool> {
-        self.ready_state.ok_or(Error::ToDo)
+        match result {
+            Ok(true) => Ok(()),
+            Ok(false) | Err(_) => {
+                self.disconnect();
+                Err(Error::ConnectionFailure)
+            }
+        }
     }
 
     fn notify_go(&mut self) -> Result<()> {
+        self.send(&TcpSpiMsg::GoState(true))?;
         self.go_state = true;
-        let msg = TcpSpiMsg::GoState(true);
-        let payload = to_stdvec_cobs(&msg).map_err(|_| Error::ToDo)?;
-        self.stream.write_all(&payload).map_err(|_| Error::ToDo)?;
         Ok(())
     }
 
     fn clear_go(&mut self) -> Result<()> {
-        // println!("cleargo");
+        if !self.go_state {
+            return Ok(());
+        }
+        self.send(&TcpSpiMsg::GoState(false))?;
         self.go_state = false;
-        let msg = TcpSpiMsg::GoState(false);
-        let payload = to_stdvec_cobs(&msg).map_err(|_| Error::ToDo)?;
-        self.stream.write_all(&payload).map_err(|_| Error::ToDo)?;
         Ok(())
     }
 
@@ -389,12 +507,11 @@ impl EncLogicLLArbitrator for TcpSpiArbLL {
         data_in_max: usize,
     ) -> Result<()> {
         if self.pending_exchange.is_some() {
-            println!("ALREADY PENDING");
-            return Err(Error::ToDo);
+            return Err(Error::IncorrectState);
         }
-        if !self.is_ready_active()? {
-            println!("NOT ACTIVE");
-            return Err(Error::ToDo);
+
+        if self.link.is_none() {
+            return Err(Error::ConnectionFailure);
         }
 
         self.pending_exchange = Some(PendingExchange {
@@ -404,28 +521,31 @@ impl EncLogicLLArbitrator for TcpSpiArbLL {
             data_in_max,
         });
 
-        self.notify_go()?;
+        self.notify_go()
+    }
 
-        Ok(())
+    fn has_exchange_begun(&self) -> Result<bool> {
+        Ok(self.csn_state || !self.incoming_payloads.is_empty())
     }
 
     fn is_exchange_active(&self) -> Result<bool> {
         Ok(self.pending_exchange.is_some())
     }
 
-    fn complete_exchange(&mut self, clear_go: bool) -> Result<usize> {
-        let exch = match self.pending_exchange.take() {
-            Some(ex) => ex,
-            None => return Err(Error::ToDo),
-        };
+    fn complete_exchange(&mut self) -> Result<usize> {
+        if self.pending_exchange.is_none() {
+            return Err(Error::IncorrectState);
+        }
 
-        let inc = match self.incoming_payload.take() {
-            None => {
-                self.pending_exchange = Some(exch);
-                return Err(Error::IncompleteTransaction(0));
-            }
-            Some(inc) => inc,
+        // Only the most recent payload can still be waiting for a response
+        let (id, data) = match self.incoming_payloads.pop_back() {
+            Some(payload) => payload,
+            None if self.link.is_none() => return Err(Error::ConnectionFailure),
+            None => return Err(Error::TransactionBusy),
         };
+        self.incoming_payloads.clear();
+
+        let exch = self.pending_exchange.take().ok_or(Error::IncorrectState)?;
 
         // So, this is actually responding. This is to prevent
         // sending messages if the client hangs up instead of
@@ -433,48 +553,135 @@ impl EncLogicLLArbitrator for TcpSpiArbLL {
         // by a SPI peripheral as the client clocks out data
 
         // Hello! I am pretending to be DMA!
-        let payload =
-            unsafe { core::slice::from_raw_parts(exch.data_out, exch.data_out_len) }.to_vec();
-
-        let msg = to_stdvec_cobs(&TcpSpiMsg::Payload(payload)).map_err(|_| Error::ToDo)?;
-        self.stream.write_all(&msg).map_err(|_| Error::ToDo)?;
+        self.send(&TcpSpiMsg::Payload {
+            id,
+            data: exch.data_out(),
+        })?;
 
         // It's me, DMA!
-        let out_slice = unsafe { core::slice::from_raw_parts_mut(exch.data_in, exch.data_in_max) };
-
-        let copy_amt = exch.data_in_max.min(inc.len());
-
-        out_slice[..copy_amt].copy_from_slice(&inc[..copy_amt]);
-
-        if clear_go {
-            self.clear_go()?;
-        }
-
-        Ok(copy_amt)
+        Ok(exch.fill_in(&data))
     }
 
     fn abort_exchange(&mut self) -> Result<usize> {
         self.clear_go().ok();
+        self.pending_exchange = None;
+        Ok(0)
+    }
+}
 
-        let exch = match self.pending_exchange.take() {
-            Some(ex) => ex,
-            None => return Err(Error::ToDo),
-        };
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{net::TcpListener, thread::sleep};
 
-        let inc = match self.incoming_payload.take() {
-            None => {
-                return Err(Error::IncompleteTransaction(0));
-            }
-            Some(inc) => inc,
-        };
+    fn pair() -> (TcpListener, TcpSpiArbLL, TcpSpiComLL) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let com = TcpSpiComLL::connect(listener.local_addr().unwrap()).unwrap();
+        let arb = TcpSpiArbLL::new(listener.accept().unwrap().0).unwrap();
+        (listener, arb, com)
+    }
 
-        // It's me, DMA!
-        let out_slice = unsafe { core::slice::from_raw_parts_mut(exch.data_in, exch.data_in_max) };
+    /// Wait up to a second for the condition to become true
+    fn wait_for<F: FnMut() -> bool>(mut f: F) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !f() {
+            assert!(Instant::now() < deadline, "timed out");
+            sleep(Duration::from_millis(1));
+        }
+    }
 
-        let copy_amt = exch.data_in_max.min(inc.len());
+    #[test]
+    fn hang_up() {
+        let (_listener, arb, mut com) = pair();
+        drop(arb);
+        wait_for(|| match com.process() {
+            Ok(()) => false,
+            Err(e) => matches!(e, Error::ArbitratorHungUp),
+        });
+        assert!(!com.is_connected());
+
+        let (_listener, mut arb, com) = pair();
+        drop(com);
+        wait_for(|| match arb.process() {
+            Ok(()) => false,
+            Err(e) => matches!(e, Error::ConnectionFailure),
+        });
+        assert!(!arb.is_connected());
+    }
 
-        out_slice[..copy_amt].copy_from_slice(&inc[..copy_amt]);
+    #[test]
+    fn reconnect() {
+        let (listener, mut arb, mut com) = pair();
+        let addr = listener.local_addr().unwrap();
+        arb.notify_go().unwrap();
+        wait_for(|| com.process().is_ok() && com.is_go_active().unwrap());
+
+        // Nothing is listening, so the first attempt fails
+        drop(listener);
+        drop(arb);
+        wait_for(|| com.process().is_err() && !com.is_connected());
+        assert!(!com.is_go_active().unwrap());
+        assert!(com.process().is_err());
+
+        // Further attempts wait for the reconnect interval to pass
+        let listener = TcpListener::bind(addr).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert!(com.process().is_err());
+        assert!(listener.accept().is_err());
+
+        sleep(RECONNECT_INTERVAL);
+        com.process().unwrap();
+        assert!(com.is_connected());
+
+        listener.set_nonblocking(false).unwrap();
+        let mut arb = TcpSpiArbLL::new(listener.accept().unwrap().0).unwrap();
+        arb.notify_go().unwrap();
+        wait_for(|| com.process().is_ok() && com.is_go_active().unwrap());
+    }
 
-        Ok(copy_amt)
+    #[test]
+    fn payload_before_go() {
+        let (_listener, mut arb, mut com) = pair();
+        let mut com_in = [0u8; 4];
+        let mut arb_in = [0u8; 4];
+        let stale = [1u8, 2, 3, 4];
+        let com_out = [5u8, 6, 7, 8];
+        let arb_out = [9u8, 10, 11, 12];
+
+        // The Component gives up on its first exchange, and begins another,
+        // before the Arbitrator has prepared an exchange
+        com.begin_exchange(stale.as_ptr(), 4, com_in.as_mut_ptr(), 4)
+            .unwrap();
+        com.abort_exchange().unwrap();
+        com.begin_exchange(com_out.as_ptr(), 4, com_in.as_mut_ptr(), 4)
+            .unwrap();
+
+        wait_for(|| {
+            arb.process().unwrap();
+            arb.incoming_payloads.len() == 2
+        });
+        assert!(arb.has_exchange_begun().unwrap());
+        assert_eq!(arb.incoming_payloads[0], (1, stale.to_vec()));
+
+        // Only the most recent payload is responded to
+        arb.prepare_exchange(arb_out.as_ptr(), 4, arb_in.as_mut_ptr(), 4)
+            .unwrap();
+        assert_eq!(arb.complete_exchange().unwrap(), 4);
+        assert_eq!(arb_in, com_out);
+        assert!(arb.incoming_payloads.is_empty());
+
+        wait_for(|| {
+            com.process().unwrap();
+            match com.complete_exchange() {
+                Ok(amt) => {
+                    assert_eq!(amt, 4);
+                    true
+                }
+                Err(Error::TransactionBusy) => false,
+                Err(e) => panic!("{:?}", e),
+            }
+        });
+        assert_eq!(com_in, arb_out);
+        assert!(com.is_go_active().unwrap());
     }
 }
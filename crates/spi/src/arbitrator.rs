@@ -1,20 +1,21 @@
 use crate::{BBFullDuplex, Error, Result};
 
+use anachro_client::{serialized_size_cobs, to_slice_cobs};
 use anachro_server::{
-    anachro_icd::Uuid,
-    // Response,
-    from_bytes_cobs,
-    Request,
-    // ServerIoOut,
-    ServerIoError,
-    ServerIoIn,
+    anachro_icd::Uuid, from_bytes_cobs, AsyncServerIoIn, HostedTransport, Request, Response,
+    ServerIoError, ServerIoIn, ServerIoOut,
 };
 use bbqueue::{
     framed::{FrameGrantR, FrameGrantW},
     ArrayLength, BBBuffer,
 };
 
-use groundhog::RollingTimer;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use groundhog::{Deadline, RollingDuration, RollingTimer};
 
 const T_WINDOW_US: u32 = 250_000;
 const T_STEP_US: u32 = 100_000;
@@ -82,6 +83,17 @@ pub trait EncLogicLLArbitrator: Send {
     fn abort_exchange(&mut self) -> Result<usize>;
 }
 
+/// An `EncLogicLLArbitrator` that can wake a task when it makes progress
+pub trait AsyncEncLogicLLArbitrator: EncLogicLLArbitrator {
+    /// Register a waker to be woken when the Component begins or ends an
+    /// exchange, or when the prepared exchange completes.
+    ///
+    /// If `timeout_us` is `Some`, the waker must also be woken after at
+    /// most that many microseconds. Implementations without access to a
+    /// timer may wake the waker immediately instead.
+    fn register_waker(&mut self, waker: &Waker, timeout_us: Option<u32>);
+}
+
 enum ArbState<RT, CT>
 where
     RT: RollingTimer<Tick = u32>,
@@ -89,26 +101,26 @@ where
 {
     Idle,
     HeaderStart {
-        t_window: RT::Tick,
-        t_step: RT::Tick,
+        t_window: Deadline<RT>,
+        t_step: Deadline<RT>,
     },
     HeaderPrepped {
-        t_window: RT::Tick,
-        t_step: RT::Tick,
+        t_window: Deadline<RT>,
+        t_step: Deadline<RT>,
     },
     HeaderXfer {
-        t_window: RT::Tick,
+        t_window: Deadline<RT>,
         // TODO: max single transfer timer?
         // Based on 4 byte timing?
     },
     BodyPrepped {
-        t_window: RT::Tick,
-        t_step: RT::Tick,
+        t_window: Deadline<RT>,
+        t_step: Deadline<RT>,
         fgr: Option<FrameGrantR<'static, CT>>,
         fgw: Option<FrameGrantW<'static, CT>>,
     },
     BodyXfer {
-        t_window: RT::Tick,
+        t_window: Deadline<RT>,
         fgr: Option<FrameGrantR<'static, CT>>,
         fgw: Option<FrameGrantW<'static, CT>>,
         // TODO: max single transfer timer?
@@ -183,23 +195,38 @@ where
         ret
     }
 
-    // TODO: `enqueue_with` function or something for zero-copy grants
     pub fn enqueue(&mut self, msg: &[u8]) -> Result<()> {
-        defmt::info!("enqueing message - {:?} bytes", msg.len());
         defmt::trace!("message: {:?}", msg);
         let len = msg.len();
-        let mut wgr = self.outgoing_msgs.prod.grant(len)?;
-        wgr.copy_from_slice(msg);
-        wgr.commit(len);
+        self.enqueue_with(len, |buf| {
+            buf.copy_from_slice(msg);
+            len
+        })?;
         Ok(())
     }
 
+    /// Enqueue a message by writing it directly into the outgoing queue
+    ///
+    /// `f` is given a buffer of `max_len` bytes, and returns the number of
+    /// bytes it actually used. If `f` returns zero, nothing is enqueued.
+    pub fn enqueue_with<F>(&mut self, max_len: usize, f: F) -> Result<usize>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut wgr = self.outgoing_msgs.prod.grant(max_len)?;
+        let used = f(&mut wgr).min(max_len);
+        if used != 0 {
+            defmt::info!("enqueing message - {:?} bytes", used);
+            wgr.commit(used);
+        }
+        Ok(used)
+    }
+
     pub fn query_component(&mut self) -> Result<()> {
         if let ArbState::Idle = self.current_state {
-            let now = self.timer.get_ticks();
             self.current_state = ArbState::HeaderStart {
-                t_window: now,
-                t_step: now,
+                t_window: self.window_deadline(),
+                t_step: self.step_deadline(),
             };
             defmt::info!("Arbitrator: Idle -> HeaderStart");
             Ok(())
@@ -208,6 +235,16 @@ where
         }
     }
 
+    /// Start the deadline for a complete header and body exchange
+    fn window_deadline(&self) -> Deadline<RT> {
+        Deadline::new(&self.timer, RollingDuration::from_micros(T_WINDOW_US))
+    }
+
+    /// Start the deadline for a single step of an exchange
+    fn step_deadline(&self) -> Deadline<RT> {
+        Deadline::new(&self.timer, RollingDuration::from_micros(T_STEP_US))
+    }
+
     fn timeout_violated(&self, state: &ArbState<RT, CT>) -> bool {
         match state {
             ArbState::Idle => false,
@@ -216,8 +253,8 @@ where
             | ArbState::BodyPrepped {
                 t_window, t_step, ..
             } => {
-                let window_bad = self.timer.micros_since(*t_window) > T_WINDOW_US;
-                let step_bad = self.timer.micros_since(*t_step) > T_STEP_US;
+                let window_bad = t_window.is_expired(&self.timer);
+                let step_bad = t_step.is_expired(&self.timer);
                 if window_bad {
                     defmt::warn!("Window timeout!");
                 }
@@ -227,7 +264,7 @@ where
                 window_bad || step_bad
             }
             ArbState::HeaderXfer { t_window } | ArbState::BodyXfer { t_window, .. } => {
-                let window_bad = self.timer.micros_since(*t_window) > T_WINDOW_US;
+                let window_bad = t_window.is_expired(&self.timer);
                 if window_bad {
                     defmt::warn!("Window timeout!");
                 }
@@ -309,7 +346,7 @@ where
 
                 ArbState::HeaderPrepped {
                     t_window,
-                    t_step: self.timer.get_ticks(),
+                    t_step: self.step_deadline(),
                 }
             }
             ArbState::HeaderPrepped { t_window, t_step } => {
@@ -362,7 +399,11 @@ where
                         Some(wgr)
                     };
 
-                    let rgr = if let Some(rgr) = self.outgoing_msgs.cons.read() {
+                    // Only send what was promised in the header, even if a
+                    // message has been enqueued since then
+                    let rgr = if let Some(rgr) =
+                        self.outgoing_msgs.cons.read().filter(|_| amt_out != 0)
+                    {
                         defmt::error!("Sending {:?}", &rgr[..]);
                         out_len = rgr.len();
                         out_ptr = rgr.as_ptr();
@@ -378,7 +419,7 @@ where
                     defmt::info!("Arbitrator: HeaderXfer -> BodyPrepped");
                     ArbState::BodyPrepped {
                         t_window,
-                        t_step: self.timer.get_ticks(),
+                        t_step: self.step_deadline(),
                         fgr: rgr,
                         fgw: wgr,
                     }
@@ -417,11 +458,10 @@ where
                         gr.commit(amt);
                     }
 
-                    let now = self.timer.get_ticks();
                     defmt::info!("Arbitrator: BodyXfer -> HeaderStart");
                     ArbState::HeaderStart {
-                        t_window: now,
-                        t_step: now,
+                        t_window: self.window_deadline(),
+                        t_step: self.step_deadline(),
                     }
                 } else {
                     ArbState::BodyXfer { t_window, fgr, fgw }
@@ -433,6 +473,129 @@ where
     }
 }
 
+impl<LL, CT, RT> EncLogicHLArbitrator<LL, CT, RT>
+where
+    CT: ArrayLength<u8>,
+    LL: AsyncEncLogicLLArbitrator,
+    RT: RollingTimer<Tick = u32>,
+{
+    /// Query the Component, completing once the Component has no
+    /// more data to exchange
+    pub fn query(&mut self) -> QueryFuture<'_, LL, CT, RT> {
+        QueryFuture {
+            arb: self,
+            started: false,
+        }
+    }
+
+    /// Drive the link, returning `Poll::Ready` once the arbitrator is idle.
+    ///
+    /// If the arbitrator is not idle, the waker in `cx` will be woken
+    /// once the link can make more progress.
+    pub fn poll_link(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if let Err(e) = self.poll() {
+            return Poll::Ready(Err(e));
+        }
+
+        if let ArbState::Idle = self.current_state {
+            Poll::Ready(Ok(()))
+        } else {
+            self.register_waker(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    fn register_waker(&mut self, waker: &Waker) {
+        match self.wake_within_us() {
+            // There's nothing to wait for, poll again right away
+            Some(0) => waker.wake_by_ref(),
+            timeout => self.ll.register_waker(waker, timeout),
+        }
+    }
+
+    /// How long until the current state must be checked for a timeout?
+    fn wake_within_us(&self) -> Option<u32> {
+        let remaining = |deadline: &Deadline<RT>| deadline.remaining(&self.timer).as_micros();
+
+        match &self.current_state {
+            ArbState::Idle => None,
+            ArbState::HeaderStart { .. } => Some(0),
+            ArbState::HeaderPrepped { t_window, t_step }
+            | ArbState::BodyPrepped {
+                t_window, t_step, ..
+            } => Some(remaining(t_window).min(remaining(t_step))),
+            ArbState::HeaderXfer { t_window } | ArbState::BodyXfer { t_window, .. } => {
+                Some(remaining(t_window))
+            }
+        }
+    }
+}
+
+/// A future returned by `EncLogicHLArbitrator::query`
+pub struct QueryFuture<'a, LL, CT, RT>
+where
+    LL: AsyncEncLogicLLArbitrator,
+    CT: ArrayLength<u8>,
+    RT: RollingTimer<Tick = u32>,
+{
+    arb: &'a mut EncLogicHLArbitrator<LL, CT, RT>,
+    started: bool,
+}
+
+impl<'a, LL, CT, RT> Future for QueryFuture<'a, LL, CT, RT>
+where
+    LL: AsyncEncLogicLLArbitrator,
+    CT: ArrayLength<u8>,
+    RT: RollingTimer<Tick = u32>,
+{
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.started {
+            if let Err(e) = self.arb.query_component() {
+                return Poll::Ready(Err(e));
+            }
+            self.started = true;
+        }
+
+        self.arb.poll_link(cx)
+    }
+}
+
+impl<LL, CT, RT> AsyncServerIoIn for EncLogicHLArbitrator<LL, CT, RT>
+where
+    CT: ArrayLength<u8>,
+    LL: AsyncEncLogicLLArbitrator,
+    RT: RollingTimer<Tick = u32>,
+{
+    /// Wait for a message from the Component
+    ///
+    /// NOTE: Messages are only received while an exchange is in progress,
+    /// so `query` must be used to start exchanges with the Component.
+    fn poll_recv_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<core::result::Result<(), ServerIoError>> {
+        // Release any previously received message
+        self.current_grant = None;
+
+        if let Err(e) = self.poll() {
+            defmt::error!("Link error while receiving: {:?}", e);
+            return Poll::Ready(Err(match e {
+                Error::ConnectionFailure => ServerIoError::Disconnected,
+                _ => ServerIoError::LinkFailure,
+            }));
+        }
+
+        if self.incoming_msgs.cons.read().is_some() {
+            Poll::Ready(Ok(()))
+        } else {
+            self.register_waker(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
 impl<LL, CT, RT> ServerIoIn for EncLogicHLArbitrator<LL, CT, RT>
 where
     CT: ArrayLength<u8>,
@@ -478,3 +641,59 @@ where
         }
     }
 }
+
+impl<'resp, LL, CT, RT> ServerIoOut<'resp> for EncLogicHLArbitrator<LL, CT, RT>
+where
+    CT: ArrayLength<u8>,
+    LL: EncLogicLLArbitrator,
+    RT: RollingTimer<Tick = u32>,
+{
+    /// Serialize a response for this Component directly into the outgoing queue
+    fn push_response(&mut self, resp: Response<'resp>) -> core::result::Result<(), ServerIoError> {
+        if resp.dest != self.uuid {
+            defmt::error!("Response is not for this component!");
+            return Err(ServerIoError::MisroutedResponse);
+        }
+
+        let len =
+            serialized_size_cobs(&resp.msg).map_err(|_| ServerIoError::ResponsePushFailed)?;
+
+        let used = self
+            .enqueue_with(len, |buf| {
+                to_slice_cobs(&resp.msg, buf)
+                    .map(|used| used.len())
+                    .unwrap_or(0)
+            })
+            .map_err(|_| ServerIoError::ResponsePushFailed)?;
+
+        if used == 0 {
+            Err(ServerIoError::ResponsePushFailed)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<LL, CT, RT> HostedTransport for EncLogicHLArbitrator<LL, CT, RT>
+where
+    CT: ArrayLength<u8>,
+    LL: EncLogicLLArbitrator,
+    RT: RollingTimer<Tick = u32>,
+{
+    /// Poll the link. Only a lost connection is reported as a failure, as
+    /// the link recovers from all other errors on its own.
+    fn poll(&mut self) -> core::result::Result<(), ServerIoError> {
+        match EncLogicHLArbitrator::poll(self) {
+            Err(Error::ConnectionFailure) => Err(ServerIoError::Disconnected),
+            Err(_) => {
+                defmt::warn!("Arbitrator link error");
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn client(&self) -> Option<Uuid> {
+        Some(self.uuid)
+    }
+}
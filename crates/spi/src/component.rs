@@ -7,10 +7,15 @@ use bbqueue::{
 
 use anachro_client::{
     anachro_icd::{arbitrator::Arbitrator, component::Component},
-    from_bytes_cobs, to_slice_cobs, ClientIo, ClientIoError,
+    from_bytes_cobs, to_slice_cobs, AsyncClientIo, ClientIo, ClientIoError,
 };
 
-use groundhog::RollingTimer;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use groundhog::{Deadline, RollingDuration, RollingTimer};
 
 const T_MIN_US: u32 = 1000;
 
@@ -63,6 +68,17 @@ pub trait EncLogicLLComponent {
     fn abort_exchange(&mut self) -> Result<usize>;
 }
 
+/// An `EncLogicLLComponent` that can wake a task when it makes progress
+pub trait AsyncEncLogicLLComponent: EncLogicLLComponent {
+    /// Register a waker to be woken when the GO line changes state,
+    /// or when the current exchange completes.
+    ///
+    /// If `timeout_us` is `Some`, the waker must also be woken after at
+    /// most that many microseconds. Implementations without access to a
+    /// timer may wake the waker immediately instead.
+    fn register_waker(&mut self, waker: &Waker, timeout_us: Option<u32>);
+}
+
 #[derive(Debug)]
 enum SendingState<CT, RT>
 where
@@ -70,15 +86,15 @@ where
     RT: RollingTimer,
 {
     Idle,
-    HeaderStart(RT::Tick),
+    HeaderStart(Deadline<RT>),
     HeaderXfer,
-    HeaderComplete(RT::Tick),
-    BodyStart(RT::Tick),
+    HeaderComplete(Deadline<RT>),
+    BodyStart(Deadline<RT>),
     BodyXfer(
         Option<FrameGrantR<'static, CT>>,
         Option<FrameGrantW<'static, CT>>,
     ),
-    BodyComplete(RT::Tick),
+    BodyComplete(Deadline<RT>),
 }
 
 pub struct EncLogicHLComponent<LL, CT, RT>
@@ -140,12 +156,34 @@ where
 
     pub fn enqueue(&mut self, msg: &[u8]) -> Result<()> {
         let len = msg.len();
-        let mut wgr = self.outgoing_msgs.prod.grant(len)?;
-        wgr.copy_from_slice(msg);
-        wgr.commit(len);
+        self.enqueue_with(len, |buf| {
+            buf.copy_from_slice(msg);
+            len
+        })?;
         Ok(())
     }
 
+    /// Enqueue a message by writing it directly into the outgoing queue
+    ///
+    /// `f` is given a buffer of `max_len` bytes, and returns the number of
+    /// bytes it actually used. If `f` returns zero, nothing is enqueued.
+    pub fn enqueue_with<F>(&mut self, max_len: usize, f: F) -> Result<usize>
+    where
+        F: FnOnce(&mut [u8]) -> usize,
+    {
+        let mut wgr = self.outgoing_msgs.prod.grant(max_len)?;
+        let used = f(&mut wgr).min(max_len);
+        if used != 0 {
+            wgr.commit(used);
+        }
+        Ok(used)
+    }
+
+    /// Start the minimum delay between changes of the CSn line
+    fn min_deadline(&self) -> Deadline<RT> {
+        Deadline::new(&self.timer, RollingDuration::from_micros(T_MIN_US))
+    }
+
     pub fn poll(&mut self) -> Result<()> {
         // First things first, set the current state to idle. If we bail out
         // at any point after this, we'll just be sitting back in the idle state
@@ -211,12 +249,12 @@ where
                 defmt::info!("Component: Idle -> HeaderStart");
 
                 self.ll.notify_csn()?;
-                SendingState::HeaderStart(self.timer.get_ticks())
+                SendingState::HeaderStart(self.min_deadline())
             }
             SendingState::HeaderStart(t_start) => {
                 debug_assert!(!exchange_active);
 
-                if self.timer.micros_since(t_start) > T_MIN_US {
+                if t_start.is_expired(&self.timer) {
                     let next_amt = self
                         .outgoing_msgs
                         .cons
@@ -261,7 +299,7 @@ where
 
                     defmt::info!("Component: HeaderXfer -> HeaderComplete");
 
-                    SendingState::HeaderComplete(self.timer.get_ticks())
+                    SendingState::HeaderComplete(self.min_deadline())
                 } else {
                     SendingState::HeaderXfer
                 }
@@ -269,12 +307,12 @@ where
             SendingState::HeaderComplete(t_start) => {
                 debug_assert!(!exchange_active);
 
-                if self.timer.micros_since(t_start) > T_MIN_US {
+                if t_start.is_expired(&self.timer) {
                     self.ll.notify_csn()?;
 
                     defmt::info!("Component: HeaderComplete -> BodyStart");
 
-                    SendingState::BodyStart(self.timer.get_ticks())
+                    SendingState::BodyStart(self.min_deadline())
                 } else {
                     SendingState::HeaderComplete(t_start)
                 }
@@ -282,7 +320,7 @@ where
             SendingState::BodyStart(t_start) => {
                 debug_assert!(!exchange_active);
 
-                if self.timer.micros_since(t_start) > T_MIN_US {
+                if t_start.is_expired(&self.timer) {
                     let out_ptr;
                     let out_len;
                     let in_ptr;
@@ -307,13 +345,22 @@ where
                         defmt::error!("How about {:?}", amt_in);
                         let aiau = amt_in as usize;
                         defmt::error!("Reqesting {:?}", aiau);
-                        let mut wgr = self.incoming_msgs.prod.grant(aiau).unwrap(); //?;
+                        let mut wgr = match self.incoming_msgs.prod.grant(aiau) {
+                            Ok(wgr) => wgr,
+                            Err(e) => {
+                                defmt::error!("No room for incoming message!");
+                                self.ll.clear_csn()?;
+                                return Err(e.into());
+                            }
+                        };
                         in_len = amt_in as usize;
                         in_ptr = wgr.as_mut_ptr();
                         Some(wgr)
                     };
 
-                    let rgr = match self.outgoing_msgs.cons.read() {
+                    // Only send what was promised in the header, even if a
+                    // message has been enqueued since then
+                    let rgr = match self.outgoing_msgs.cons.read().filter(|_| amt_out != 0) {
                         Some(rgr) => {
                             debug_assert!(
                                 rgr.len() == u32::from_le_bytes(self.smol_buf_out) as usize
@@ -370,7 +417,7 @@ where
 
                     defmt::info!("Component: BodyXfer -> BodyComplete");
 
-                    SendingState::BodyComplete(self.timer.get_ticks())
+                    SendingState::BodyComplete(self.min_deadline())
                 } else {
                     SendingState::BodyXfer(fgr, fgw)
                 }
@@ -378,12 +425,12 @@ where
             SendingState::BodyComplete(t_start) => {
                 debug_assert!(!exchange_active);
 
-                if self.timer.micros_since(t_start) > T_MIN_US {
+                if t_start.is_expired(&self.timer) {
                     self.ll.notify_csn()?;
 
                     defmt::info!("Component: BodyComplete -> HeaderStart");
 
-                    SendingState::HeaderStart(self.timer.get_ticks())
+                    SendingState::HeaderStart(self.min_deadline())
                 } else {
                     SendingState::BodyComplete(t_start)
                 }
@@ -394,6 +441,122 @@ where
     }
 }
 
+impl<LL, CT, RT> EncLogicHLComponent<LL, CT, RT>
+where
+    CT: ArrayLength<u8>,
+    LL: AsyncEncLogicLLComponent,
+    RT: RollingTimer<Tick = u32>,
+{
+    /// Wait for the Arbitrator to begin an exchange, completing once
+    /// the Arbitrator has ended it
+    pub fn exchange(&mut self) -> ExchangeFuture<'_, LL, CT, RT> {
+        ExchangeFuture {
+            com: self,
+            started: false,
+        }
+    }
+
+    fn register_waker(&mut self, waker: &Waker) {
+        match self.wake_within_us() {
+            // There's nothing to wait for, poll again right away
+            Some(0) => waker.wake_by_ref(),
+            timeout => self.ll.register_waker(waker, timeout),
+        }
+    }
+
+    /// How long until the current state must be polled again?
+    fn wake_within_us(&self) -> Option<u32> {
+        match &self.send_state {
+            SendingState::Idle | SendingState::HeaderXfer | SendingState::BodyXfer(..) => None,
+            SendingState::HeaderStart(t_start)
+            | SendingState::HeaderComplete(t_start)
+            | SendingState::BodyStart(t_start)
+            | SendingState::BodyComplete(t_start) => {
+                Some(t_start.remaining(&self.timer).as_micros())
+            }
+        }
+    }
+}
+
+/// A future returned by `EncLogicHLComponent::exchange`
+pub struct ExchangeFuture<'a, LL, CT, RT>
+where
+    LL: AsyncEncLogicLLComponent,
+    CT: ArrayLength<u8>,
+    RT: RollingTimer<Tick = u32>,
+{
+    com: &'a mut EncLogicHLComponent<LL, CT, RT>,
+    started: bool,
+}
+
+impl<'a, LL, CT, RT> Future for ExchangeFuture<'a, LL, CT, RT>
+where
+    LL: AsyncEncLogicLLComponent,
+    CT: ArrayLength<u8>,
+    RT: RollingTimer<Tick = u32>,
+{
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        if let Err(e) = this.com.poll() {
+            return Poll::Ready(Err(e));
+        }
+
+        if let SendingState::Idle = this.com.send_state {
+            if this.started {
+                return Poll::Ready(Ok(()));
+            }
+        } else {
+            this.started = true;
+        }
+
+        this.com.register_waker(cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<LL, CT, RT> AsyncClientIo for EncLogicHLComponent<LL, CT, RT>
+where
+    CT: ArrayLength<u8>,
+    LL: AsyncEncLogicLLComponent,
+    RT: RollingTimer<Tick = u32>,
+{
+    /// Wait for a message from the Arbitrator, driving the link
+    fn poll_recv_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<core::result::Result<(), ClientIoError>> {
+        // Release any previously received message
+        self.current_grant = None;
+
+        if let Err(e) = self.poll() {
+            defmt::error!("Link error while receiving: {:?}", e);
+            return Poll::Ready(Err(match e {
+                Error::ConnectionFailure | Error::ArbitratorHungUp => ClientIoError::Disconnected,
+                _ => ClientIoError::LinkFailure,
+            }));
+        }
+
+        if self.incoming_msgs.cons.read().is_some() {
+            Poll::Ready(Ok(()))
+        } else {
+            self.register_waker(cx.waker());
+            Poll::Pending
+        }
+    }
+
+    /// Outgoing messages are queued until the Arbitrator begins an
+    /// exchange, so sending is always ready
+    fn poll_send_ready(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<core::result::Result<(), ClientIoError>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
 impl<LL, CT, RT> ClientIo for EncLogicHLComponent<LL, CT, RT>
 where
     CT: ArrayLength<u8>,
@@ -0,0 +1,94 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use anachro_client::{
+    anachro_icd::{arbitrator::Arbitrator, component::Component},
+    ClientIo, ClientIoError,
+};
+use postcard::{from_bytes, to_stdvec};
+
+use crate::MAX_DATAGRAM;
+
+/// A Client IO, sending and receiving datagrams to/from a single Broker
+pub struct UdpClientIo {
+    socket: UdpSocket,
+    buf: Vec<u8>,
+}
+
+impl UdpClientIo {
+    /// Bind to an ephemeral port, and connect to the Broker at `broker`
+    pub fn connect<A: ToSocketAddrs>(broker: A) -> io::Result<Self> {
+        let broker = broker
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "No broker address"))?;
+
+        let local: SocketAddr = if broker.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(broker)?;
+        Self::new(socket)
+    }
+
+    /// Use a socket that has already been connected to the Broker
+    ///
+    /// The socket will be set as non-blocking.
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            buf: vec![0u8; MAX_DATAGRAM],
+        })
+    }
+
+    /// The local address of the socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+impl ClientIo for UdpClientIo {
+    /// Receive one message FROM the Broker
+    ///
+    /// Datagrams that do not contain a valid message are discarded.
+    fn recv(&mut self) -> Result<Option<Arbitrator>, ClientIoError> {
+        let len = loop {
+            match self.socket.recv(&mut self.buf) {
+                Ok(n) if from_bytes::<Arbitrator>(&self.buf[..n]).is_ok() => break n,
+                Ok(_) => {}
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => return Ok(None),
+                    ErrorKind::Interrupted => {}
+
+                    // Nothing is listening at the Broker's address
+                    _ => return Err(ClientIoError::Disconnected),
+                },
+            }
+        };
+
+        from_bytes(&self.buf[..len])
+            .map(Some)
+            .map_err(|_| ClientIoError::ParsingError)
+    }
+
+    /// Send one message TO the Broker
+    fn send(&mut self, msg: &Component) -> Result<(), ClientIoError> {
+        let ser = to_stdvec(msg).map_err(|_| ClientIoError::ParsingError)?;
+
+        if ser.len() > MAX_DATAGRAM {
+            return Err(ClientIoError::OutputFull);
+        }
+
+        match self.socket.send(&ser) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(ClientIoError::OutputFull),
+            Err(_) => Err(ClientIoError::Disconnected),
+        }
+    }
+}
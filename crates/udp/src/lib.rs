@@ -0,0 +1,142 @@
+//! # A UDP transport for the Anachro Protocol
+//!
+//! This crate carries one postcard serialized message per UDP datagram,
+//! which makes it suitable for host-side tools and simulated devices.
+//!
+//! Clients use [`UdpClientIo`](struct.UdpClientIo.html), which talks to a
+//! single Broker. Brokers use the [`UdpServerIn`](struct.UdpServerIn.html)
+//! and [`UdpServerOut`](struct.UdpServerOut.html) pair, which assign a
+//! `Uuid` to each peer socket address.
+//!
+//! As UDP is connectionless, the Broker forgets peers that have not sent a
+//! message within the idle timeout. Clients should send messages (such as
+//! a subscription refresh) often enough to remain connected.
+
+mod client;
+mod server;
+
+pub use crate::{
+    client::UdpClientIo,
+    server::{PeerEvent, UdpServerIn, UdpServerOut},
+};
+
+/// The largest datagram that will be sent or received
+///
+/// This fits in a single Ethernet frame, without fragmentation.
+pub const MAX_DATAGRAM: usize = 1472;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anachro_client::{
+        anachro_icd::{
+            arbitrator::{Arbitrator, Control as AControl, ControlResponse},
+            component::{Component, ComponentInfo, Control, ControlType},
+            Name, Version,
+        },
+        ClientIo,
+    };
+    use anachro_server::{Broker, Response, ServerIoIn, ServerIoOut};
+    use std::{
+        net::UdpSocket,
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    fn register() -> Component<'static> {
+        Component::Control(Control {
+            seq: 3,
+            ty: ControlType::RegisterComponent(ComponentInfo {
+                name: Name::borrow_from_str("udp-test"),
+                version: Version {
+                    major: 0,
+                    minor: 1,
+                    trivial: 0,
+                    misc: 0,
+                },
+            }),
+        })
+    }
+
+    /// Poll the server until an event occurs, or a second has passed
+    fn wait_event(sin: &mut UdpServerIn) -> Option<PeerEvent> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if let Some(evt) = sin.poll() {
+                return Some(evt);
+            }
+            sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn broker_roundtrip() {
+        let (mut sin, mut sout) =
+            UdpServerIn::bind("127.0.0.1:0", Duration::from_secs(10)).unwrap();
+        let mut client = UdpClientIo::connect(sin.local_addr().unwrap()).unwrap();
+        let mut broker = Broker::default();
+
+        client.send(&register()).unwrap();
+
+        let uuid = match wait_event(&mut sin) {
+            Some(PeerEvent::Connected(uuid)) => uuid,
+            evt => panic!("Unexpected event: {:?}", evt),
+        };
+        broker.register_client(&uuid).unwrap();
+        broker.process_msg(&mut sin, &mut sout).unwrap();
+
+        let start = Instant::now();
+        let resp = loop {
+            if let Some(msg) = client.recv().unwrap() {
+                break msg;
+            }
+            assert!(start.elapsed() < Duration::from_secs(1));
+            sleep(Duration::from_millis(1));
+        };
+
+        assert_eq!(
+            resp,
+            Arbitrator::Control(AControl {
+                seq: 3,
+                response: Ok(ControlResponse::ComponentRegistration(uuid)),
+            })
+        );
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (mut sin, mut sout) =
+            UdpServerIn::bind("127.0.0.1:0", Duration::from_millis(50)).unwrap();
+        let mut client = UdpClientIo::connect(sin.local_addr().unwrap()).unwrap();
+
+        client.send(&register()).unwrap();
+        let uuid = match wait_event(&mut sin) {
+            Some(PeerEvent::Connected(uuid)) => uuid,
+            evt => panic!("Unexpected event: {:?}", evt),
+        };
+
+        // The request is delivered once the event has been taken
+        let req = sin.recv().unwrap().unwrap();
+        assert_eq!(req.source, uuid);
+        assert_eq!(req.msg, register());
+
+        assert_eq!(wait_event(&mut sin), Some(PeerEvent::TimedOut(uuid)));
+        assert!(sout
+            .push_response(Response {
+                dest: uuid,
+                msg: Arbitrator::ObjStore,
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn garbage_ignored() {
+        let (mut sin, _sout) = UdpServerIn::bind("127.0.0.1:0", Duration::from_secs(10)).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.send_to(&[0xFF; 4], sin.local_addr().unwrap()).unwrap();
+
+        assert_eq!(wait_event(&mut sin), None);
+        assert_eq!(sin.dropped_datagrams(), 1);
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anachro_server::{
+    anachro_icd::component::Component, Request, Response, ServerIoError, ServerIoIn, ServerIoOut,
+    Uuid,
+};
+use postcard::{from_bytes, to_stdvec};
+use rand::random;
+
+use crate::MAX_DATAGRAM;
+
+/// A change in the set of known peers
+///
+/// These should be forwarded to the Broker, using `Broker::register_client`
+/// and `Broker::remove_client` respectively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// A message was received from a new peer
+    Connected(Uuid),
+
+    /// No message has been received from a peer within the idle timeout
+    TimedOut(Uuid),
+}
+
+struct Peer {
+    addr: SocketAddr,
+    uuid: Uuid,
+    last_seen: Instant,
+}
+
+/// The receiving half of a Broker's UDP socket
+///
+/// Each peer socket address that sends a valid message is assigned a
+/// `Uuid`, which is reported with `PeerEvent::Connected`. Requests from
+/// the peer are held back until all pending events have been taken
+/// with `poll`, so that the peer can be registered with the Broker
+/// first.
+pub struct UdpServerIn {
+    socket: Arc<UdpSocket>,
+    peers: Arc<Mutex<Vec<Peer>>>,
+    idle_timeout: Duration,
+    events: VecDeque<PeerEvent>,
+    buf: Vec<u8>,
+
+    /// The source and length of a received request in `buf`
+    held: Option<(Uuid, usize)>,
+
+    dropped_datagrams: usize,
+}
+
+/// The sending half of a Broker's UDP socket
+pub struct UdpServerOut {
+    socket: Arc<UdpSocket>,
+    peers: Arc<Mutex<Vec<Peer>>>,
+}
+
+impl UdpServerIn {
+    /// Bind a non-blocking socket to `addr`
+    ///
+    /// Peers that have not sent a message in `idle_timeout` are forgotten.
+    pub fn bind<A: ToSocketAddrs>(
+        addr: A,
+        idle_timeout: Duration,
+    ) -> io::Result<(UdpServerIn, UdpServerOut)> {
+        let socket = Arc::new(UdpSocket::bind(addr)?);
+        socket.set_nonblocking(true)?;
+        let peers = Arc::new(Mutex::new(Vec::new()));
+
+        Ok((
+            UdpServerIn {
+                socket: socket.clone(),
+                peers: peers.clone(),
+                idle_timeout,
+                events: VecDeque::new(),
+                buf: vec![0u8; MAX_DATAGRAM],
+                held: None,
+                dropped_datagrams: 0,
+            },
+            UdpServerOut { socket, peers },
+        ))
+    }
+
+    /// The local address of the socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// The number of datagrams that have been discarded, due to not
+    /// containing a valid message
+    pub fn dropped_datagrams(&self) -> usize {
+        self.dropped_datagrams
+    }
+
+    /// Check for new or timed out peers
+    ///
+    /// This should be called until it returns `None` before each call
+    /// to `Broker::process_msg`.
+    pub fn poll(&mut self) -> Option<PeerEvent> {
+        self.expire_peers();
+
+        if self.events.is_empty() && self.held.is_none() {
+            self.read_datagram();
+        }
+
+        self.events.pop_front()
+    }
+
+    /// Forget all peers that have been idle for too long
+    fn expire_peers(&mut self) {
+        let now = Instant::now();
+        let timeout = self.idle_timeout;
+        let mut peers = self.peers.lock().unwrap();
+
+        for peer in peers.iter() {
+            if now.duration_since(peer.last_seen) >= timeout {
+                self.events.push_back(PeerEvent::TimedOut(peer.uuid));
+
+                if matches!(self.held, Some((uuid, _)) if uuid == peer.uuid) {
+                    self.held = None;
+                }
+            }
+        }
+
+        peers.retain(|peer| now.duration_since(peer.last_seen) < timeout);
+    }
+
+    /// Attempt to receive one valid request into `buf`, returning whether
+    /// a request is now held
+    fn read_datagram(&mut self) -> bool {
+        let (len, addr) = loop {
+            match self.socket.recv_from(&mut self.buf) {
+                Ok((n, addr)) if from_bytes::<Component>(&self.buf[..n]).is_ok() => {
+                    break (n, addr)
+                }
+                Ok(_) => self.dropped_datagrams += 1,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+
+                // Errors on an unconnected socket refer to previously
+                // sent datagrams, and are not fatal
+                Err(_) => return false,
+            }
+        };
+
+        let mut peers = self.peers.lock().unwrap();
+        let uuid = match peers.iter_mut().find(|peer| peer.addr == addr) {
+            Some(peer) => {
+                peer.last_seen = Instant::now();
+                peer.uuid
+            }
+            None => {
+                let uuid_buf: u128 = random();
+                let uuid = Uuid::from_bytes(uuid_buf.to_le_bytes());
+                peers.push(Peer {
+                    addr,
+                    uuid,
+                    last_seen: Instant::now(),
+                });
+                self.events.push_back(PeerEvent::Connected(uuid));
+                uuid
+            }
+        };
+
+        self.held = Some((uuid, len));
+        true
+    }
+}
+
+impl ServerIoIn for UdpServerIn {
+    /// Receive one request FROM a Client
+    ///
+    /// Returns `Ok(None)` if no request is available, or if there are
+    /// events that have not yet been taken with `poll`.
+    fn recv<'a, 'b: 'a>(&'b mut self) -> Result<Option<Request<'b>>, ServerIoError> {
+        self.expire_peers();
+
+        if self.held.is_none() && !self.read_datagram() {
+            return Ok(None);
+        }
+
+        if !self.events.is_empty() {
+            return Ok(None);
+        }
+
+        match self.held.take() {
+            // The datagram was already checked by `read_datagram`
+            Some((source, len)) => Ok(from_bytes(&self.buf[..len])
+                .ok()
+                .map(|msg| Request { source, msg })),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'resp> ServerIoOut<'resp> for UdpServerOut {
+    /// Send one response TO a Client
+    ///
+    /// Responses to unknown or timed out peers are rejected.
+    fn push_response(&mut self, resp: Response<'resp>) -> Result<(), ServerIoError> {
+        let addr = self
+            .peers
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|peer| peer.uuid == resp.dest)
+            .map(|peer| peer.addr)
+            .ok_or(ServerIoError::MisroutedResponse)?;
+
+        let ser = to_stdvec(&resp.msg).map_err(|_| ServerIoError::ResponsePushFailed)?;
+
+        if ser.len() > MAX_DATAGRAM {
+            return Err(ServerIoError::ResponsePushFailed);
+        }
+
+        self.socket
+            .send_to(&ser, addr)
+            .map(drop)
+            .map_err(|_| ServerIoError::ResponsePushFailed)
+    }
+}
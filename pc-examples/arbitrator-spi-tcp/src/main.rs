@@ -7,6 +7,7 @@ use anachro_spi::{
     Error as SpiError, Result as SpiResult,
 };
 use anachro_spi_tcp::TcpSpiArbLL;
+use groundhog::StdTimer;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 
@@ -38,11 +39,11 @@ fn main() {
     while let Ok((stream, addr)) = listener.accept() {
         let mut last_tx = Instant::now();
 
-        stream.set_nonblocking(true).unwrap();
         println!("{:?} connected", addr);
         let mut arb = EncLogicHLArbitrator::new(
             Uuid::from_bytes([0u8; 16]), // TODO
-            TcpSpiArbLL::new(stream),
+            TcpSpiArbLL::new(stream).unwrap(),
+            StdTimer::new(),
             &BB_OUT,
             &BB_INP,
         )
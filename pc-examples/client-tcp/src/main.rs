@@ -10,6 +10,7 @@ use serde::{Deserialize, Serialize};
 
 use anachro_spi::component::EncLogicHLComponent;
 use anachro_spi_tcp::TcpSpiComLL;
+use groundhog::StdTimer;
 
 use bbqueue::{consts::*, BBBuffer, ConstBBBuffer};
 
@@ -39,7 +40,13 @@ fn main() {
     let stream = TcpStream::connect("127.0.0.1:8080").unwrap();
     stream.set_nonblocking(true).unwrap();
 
-    let mut cio = EncLogicHLComponent::new(TcpSpiComLL::new(stream), &BUF_OUT, &BUF_INP).unwrap();
+    let mut cio = EncLogicHLComponent::new(
+        TcpSpiComLL::new(stream).unwrap(),
+        StdTimer::new(),
+        &BUF_OUT,
+        &BUF_INP,
+    )
+    .unwrap();
 
     // name: &str,
     // version: Version,
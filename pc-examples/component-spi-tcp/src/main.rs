@@ -1,6 +1,6 @@
 use anachro_spi::component::EncLogicHLComponent;
 use anachro_spi_tcp::TcpSpiComLL;
-use std::net::TcpStream;
+use groundhog::StdTimer;
 
 use std::time::{Duration, Instant};
 
@@ -10,15 +10,18 @@ static BB_OUT: BBBuffer<U2048> = BBBuffer(ConstBBBuffer::new());
 static BB_INP: BBBuffer<U2048> = BBBuffer(ConstBBBuffer::new());
 
 fn main() {
-    let stream = TcpStream::connect("127.0.0.1:8080").unwrap();
-    stream.set_nonblocking(true).unwrap();
+    let ll = TcpSpiComLL::connect("127.0.0.1:8080").unwrap();
 
     println!("Component connected!");
-    let mut com = EncLogicHLComponent::new(TcpSpiComLL::new(stream), &BB_OUT, &BB_INP).unwrap();
+    let mut com = EncLogicHLComponent::new(ll, StdTimer::new(), &BB_OUT, &BB_INP).unwrap();
 
     let mut last_tx = Instant::now();
 
-    while let Ok(_) = com.poll() {
+    loop {
+        if let Err(e) = com.poll() {
+            println!("==> Link error: {:?}", e);
+        }
+
         while let Some(msg) = com.dequeue() {
             println!("==> Got HL msg: {:?}", &msg[..]);
             msg.release();
@@ -218,6 +218,8 @@ fn main() -> ! {
                             ClientIoError::ParsingError => defmt::info!("ClientApp: parseerr"),
                             ClientIoError::NoData => defmt::info!("ClientApp: nodata"),
                             ClientIoError::OutputFull => defmt::info!("ClientApp: out full"),
+                            ClientIoError::MessageTooLarge => defmt::info!("ClientApp: too large"),
+                            ClientIoError::Disconnected => defmt::info!("ClientApp: disconnected"),
                         }
                         defmt::info!("ClientApp: Cl Io Er");
                     }
@@ -18,13 +18,11 @@ use nrf52840_hal::{
 };
 
 use anachro_client::{pubsub_table, Client, ClientIoError, Error};
-use anachro_server::{Broker, Uuid};
+use anachro_server::{BrokerHost, HostedTransport, Uuid};
 
 use anachro_icd::Version;
 use anachro_spi::{arbitrator::EncLogicHLArbitrator, component::EncLogicHLComponent};
 use anachro_spi_nrf52::{arbitrator::NrfSpiArbLL, component::NrfSpiComLL};
-use heapless::{consts, Vec as HVec};
-use postcard::to_slice_cobs;
 
 use serde::{Deserialize, Serialize};
 
@@ -83,13 +81,13 @@ const RPI_UUID: Uuid = Uuid::from_bytes([12u8; 16]);
 #[rtic::app(device = crate::hal::pac, peripherals = true, monotonic = groundhog_nrf52::GlobalRollingTimer)]
 const APP: () = {
     struct Resources {
-        broker: Broker,
+        host: BrokerHost<U1024>,
 
-        anachro_uarte_key: AnachroUarte<U2048, U2048, U512>,
+        anachro_uarte_key: AnachroUarte<U2048, U2048, 512>,
         uarte_timer_key: UarteTimer<TIMER2>,
         uarte_irq_key: UarteIrq<U2048, U2048, Ppi0, UARTE0>,
 
-        anachro_uarte_rpi: AnachroUarte<U2048, U2048, U512>,
+        anachro_uarte_rpi: AnachroUarte<U2048, U2048, 512>,
         uarte_timer_rpi: UarteTimer<TIMER3>,
         uarte_irq_rpi: UarteIrq<U2048, U2048, Ppi1, UARTE1>,
 
@@ -224,17 +222,17 @@ const APP: () = {
 
         // ------------------------
         // Setup Broker
+        //
+        // Each transport reaches a single client, which is registered
+        // by the host when the transport is first polled
 
-        let mut broker = Broker::default();
-        broker.register_client(&KEYBOARD_UUID).unwrap();
-        broker.register_client(&CPU_UUID).unwrap();
-        broker.register_client(&RPI_UUID).unwrap();
+        let host = BrokerHost::new();
 
         // Spawn periodic tasks
         ctx.spawn.anachro_periodic().ok();
 
         init::LateResources {
-            broker,
+            host,
             anachro_spis: arb_port,
 
             anachro_uarte_key: an_uarte,
@@ -256,153 +254,20 @@ const APP: () = {
         // }
     }
 
-    #[task(resources = [broker, anachro_uarte_key, anachro_uarte_rpi, anachro_spis], schedule = [anachro_periodic])]
+    #[task(resources = [host, anachro_uarte_key, anachro_uarte_rpi, anachro_spis], schedule = [anachro_periodic])]
     fn anachro_periodic(ctx: anachro_periodic::Context) {
         static mut LAST_QUERY: u32 = 0;
 
-        let broker = ctx.resources.broker;
+        let host = ctx.resources.host;
         let uarte = ctx.resources.anachro_uarte_key;
         let uarte_rpi = ctx.resources.anachro_uarte_rpi;
         let spis = ctx.resources.anachro_spis;
         let timer = GlobalRollingTimer::new();
 
-        if let Err(e) = spis.poll() {
-            defmt::error!("spis poll err: {:?}", e);
-        }
-
-        let mut serout_spis: HVec<HVec<u8, consts::U128>, consts::U16> = HVec::new();
-        let mut serout_uarte: HVec<HVec<u8, consts::U128>, consts::U16> = HVec::new();
-        let mut serout_uarte_rpi: HVec<HVec<u8, consts::U128>, consts::U16> = HVec::new();
-
-        let mut out_msgs_uarte: HVec<_, consts::U32> = HVec::new();
-        match broker.process_msg(uarte, &mut out_msgs_uarte) {
-            Ok(_) => {}
-            Err(e) => {
-                defmt::error!("uarte broker proc msg: {:?}", e);
-                // arb_001::exit();
-            }
-        }
-
-        for msg in out_msgs_uarte {
-            let buf = match msg.dest {
-                CPU_UUID => &mut serout_spis,
-                KEYBOARD_UUID => &mut serout_uarte,
-                RPI_UUID => &mut serout_uarte_rpi,
-                _ => {
-                    defmt::warn!("Unknown dest!");
-                    continue;
-                }
-            };
-
-            defmt::info!("Out message!");
-            use postcard::to_vec_cobs;
-            if let Ok(resp) = to_vec_cobs(&msg.msg) {
-                defmt::info!("resp out: {:?}", &resp[..]);
-                buf.push(resp).unwrap();
-            } else {
-                defmt::error!("Ser failed!");
-                arb_001::exit();
-            }
-        }
-
-
-        let mut out_msgs_rpi: HVec<_, consts::U32> = HVec::new();
-        match broker.process_msg(uarte_rpi, &mut out_msgs_rpi) {
-            Ok(_) => {}
-            Err(e) => {
-                defmt::error!("uarte broker proc msg: {:?}", e);
-                // arb_001::exit();
-            }
-        }
-
-        for msg in out_msgs_rpi {
-            let buf = match msg.dest {
-                CPU_UUID => &mut serout_spis,
-                KEYBOARD_UUID => &mut serout_uarte,
-                RPI_UUID => &mut serout_uarte_rpi,
-                _ => {
-                    defmt::warn!("Unknown dest!");
-                    continue;
-                }
-            };
-
-            defmt::info!("Out message!");
-            use postcard::to_vec_cobs;
-            if let Ok(resp) = to_vec_cobs(&msg.msg) {
-                defmt::info!("resp out: {:?}", &resp[..]);
-                buf.push(resp).unwrap();
-            } else {
-                defmt::error!("Ser failed!");
-                arb_001::exit();
-            }
-        }
-
-        let mut out_msgs_spis: HVec<_, consts::U32> = HVec::new();
-        match broker.process_msg(spis, &mut out_msgs_spis) {
-            Ok(_) => {}
-            Err(e) => {
-                defmt::error!("spis broker proc msg: {:?}", e);
-                // arb_001::exit();
-            }
-        }
-
-        for msg in out_msgs_spis {
-            let buf = match msg.dest {
-                CPU_UUID => &mut serout_spis,
-                KEYBOARD_UUID => &mut serout_uarte,
-                RPI_UUID => &mut serout_uarte_rpi,
-                _ => {
-                    defmt::warn!("Unknown dest!");
-                    continue;
-                }
-            };
-
-            defmt::info!("Out message!");
-            use postcard::to_vec_cobs;
-            if let Ok(resp) = to_vec_cobs(&msg.msg) {
-                defmt::info!("resp out: {:?}", &resp[..]);
-                buf.push(resp).unwrap();
-            } else {
-                defmt::error!("Ser failed!");
-                arb_001::exit();
-            }
-        }
-
-        if !(serout_spis.is_empty() && serout_uarte.is_empty() && serout_uarte_rpi.is_empty()) {
-            defmt::info!(
-                "broker sending {:?} msgs",
-                serout_spis.len() + serout_uarte.len()
-            );
-        }
-
-        for msg in serout_uarte {
-            match uarte.enqueue(&msg) {
-                Ok(_) => defmt::info!("uarte enqueued."),
-                Err(()) => {
-                    defmt::error!("uarte enqueue failed!");
-                    arb_001::exit();
-                }
-            }
-        }
-
-        for msg in serout_uarte_rpi {
-            match uarte_rpi.enqueue(&msg) {
-                Ok(_) => defmt::info!("uarte enqueued."),
-                Err(()) => {
-                    defmt::error!("uarte enqueue failed!");
-                    arb_001::exit();
-                }
-            }
-        }
-
-        for msg in serout_spis {
-            match spis.enqueue(&msg) {
-                Ok(_) => defmt::info!("spis enqueued."),
-                Err(e) => {
-                    defmt::error!("spis enqueue failed! - {:?}", e);
-                    arb_001::exit();
-                }
-            }
+        // Responses are routed to the transport of their destination client
+        let transports: &mut [&mut dyn HostedTransport] = &mut [uarte, uarte_rpi, &mut *spis];
+        if let Err(e) = host.poll(transports) {
+            defmt::error!("broker host poll: {:?}", e);
         }
 
         // TODO: Round-robin each different device
@@ -93,7 +93,7 @@ fn main() -> ! {
 
     let mut timer = Timer::new(board.TIMER0);
 
-    let buf: Buffer<U512> = Buffer::new();
+    let buf: Buffer<512> = Buffer::new();
 
     let mut an_uarte = AnachroUarte::new(app, buf, Uuid::from_bytes([42u8; 16]));
 
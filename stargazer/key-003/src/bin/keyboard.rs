@@ -82,7 +82,7 @@ const COLORS: &[RGB8] = &[
 const APP: () = {
     struct Resources {
         client: Client,
-        anachro_uarte: AnachroUarte<U2048, U2048, U512>,
+        anachro_uarte: AnachroUarte<U2048, U2048, 512>,
         uarte_timer: UarteTimer<TIMER2>,
         uarte_irq: UarteIrq<U2048, U2048, Ppi0, UARTE0>,
         rows: [Pin<Output<PushPull>>; 8],
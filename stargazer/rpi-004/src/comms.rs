@@ -7,64 +7,14 @@ use std::{
 };
 
 pub struct CommsCtx {
-    uart: UartAnachro,
+    uart: CobsStream<Box<dyn SerialPort>>,
     client: Client,
     ever_connected: bool,
 }
 
-use anachro_icd::{
-    arbitrator::Arbitrator,
-    component::Component,
-    Version,
-};
-use anachro_client::{ClientIo, ClientIoError, Client, Error};
-use postcard::{from_bytes_cobs, to_stdvec_cobs};
-
-struct UartAnachro {
-    port: Box<dyn SerialPort>,
-    scratch: Vec<u8>,
-    current: Option<Vec<u8>>
-}
-
-
-impl ClientIo for UartAnachro {
-    fn recv(&mut self) -> core::result::Result<Option<Arbitrator>, ClientIoError> {
-        let mut scratch = [0u8; 1024];
-
-        loop {
-            match self.port.read(&mut scratch) {
-                Ok(n) if n > 0 => {
-                    self.scratch.extend_from_slice(&scratch[..n]);
-
-                    if let Some(p) = self.scratch.iter().position(|c| *c == 0x00) {
-                        let mut remainder = self.scratch.split_off(p + 1);
-                        core::mem::swap(&mut remainder, &mut self.scratch);
-                        self.current = Some(remainder);
-
-                        if let Some(ref mut payload) = self.current {
-                            if let Ok(msg) = from_bytes_cobs::<Arbitrator>(payload.as_mut_slice()) {
-                                // println!("GIVING: {:?}", msg);
-                                return Ok(Some(msg));
-                            }
-                        }
-
-                        return Err(ClientIoError::ParsingError);
-                    }
-                }
-                Ok(_) => return Ok(None),
-                Err(_) => return Ok(None),
-            }
-        }
-    }
-    fn send(&mut self, msg: &Component) -> core::result::Result<(), ClientIoError> {
-        // println!("SENDING: {:?}", msg);
-        let ser = to_stdvec_cobs(msg).map_err(|_| ClientIoError::ParsingError)?;
-        self.port
-            .write_all(&ser)
-            .map_err(|_| ClientIoError::OutputFull)?;
-        Ok(())
-    }
-}
+use anachro_icd::Version;
+use anachro_client::{ClientIoError, Client, Error};
+use anachro_cobs_stream::{CobsStream, DEFAULT_MAX_FRAME};
 
 impl CommsCtx {
     pub fn new(uart: &str) -> Result<Self> {
@@ -96,11 +46,7 @@ impl CommsCtx {
         );
 
         Ok(Self {
-            uart: UartAnachro {
-                port,
-                scratch: vec![],
-                current: None,
-            },
+            uart: CobsStream::new(port, DEFAULT_MAX_FRAME),
             client,
             ever_connected: false,
         })